@@ -8,8 +8,10 @@ mod yatp_callback {
     use std::sync::*;
     use yatp::task::callback::Handle;
 
-    pub fn ping_pong(b: &mut Bencher<'_>, ping_count: usize) {
-        let pool = yatp::Builder::new("ping_pong").build_callback_pool();
+    pub fn ping_pong(b: &mut Bencher<'_>, ping_count: usize, recycle_cap: usize) {
+        let pool = yatp::Builder::new("ping_pong")
+            .callback_recycle_cap(recycle_cap)
+            .build_callback_pool();
 
         let (done_tx, done_rx) = mpsc::sync_channel(1000);
         let rem = Arc::new(AtomicUsize::new(0));
@@ -54,7 +56,7 @@ mod yatp_future {
     use std::sync::*;
     use tokio::sync::oneshot;
     use yatp::queue::priority::TaskPriorityProvider;
-    use yatp::task::future::TaskCell;
+    use yatp::task::future::{self, TaskCell};
 
     fn ping_pong(b: &mut Bencher<'_>, pool: yatp::ThreadPool<TaskCell>, ping_count: usize) {
         let (done_tx, done_rx) = mpsc::sync_channel(1000);
@@ -107,6 +109,44 @@ mod yatp_future {
         ping_pong(b, pool, ping_count)
     }
 
+    pub fn ping_pong_local(b: &mut Bencher<'_>, ping_count: usize) {
+        let pool = yatp::Builder::new("ping_pong").build_future_pool();
+        let (done_tx, done_rx) = mpsc::sync_channel(1000);
+        let rem = Arc::new(AtomicUsize::new(0));
+
+        b.iter(|| {
+            let done_tx = done_tx.clone();
+            let rem = rem.clone();
+            rem.store(ping_count, Ordering::Relaxed);
+
+            pool.spawn(async move {
+                for _ in 0..ping_count {
+                    let rem = rem.clone();
+                    let done_tx = done_tx.clone();
+
+                    future::spawn(async move {
+                        let (tx1, rx1) = oneshot::channel();
+                        let (tx2, rx2) = oneshot::channel();
+
+                        future::spawn(async move {
+                            rx1.await.unwrap();
+                            tx2.send(()).unwrap();
+                        });
+
+                        tx1.send(()).unwrap();
+                        rx2.await.unwrap();
+
+                        if 1 == rem.fetch_sub(1, Ordering::Relaxed) {
+                            done_tx.send(()).unwrap();
+                        }
+                    });
+                }
+            });
+
+            done_rx.recv().unwrap();
+        });
+    }
+
     pub fn ping_pong_priority(b: &mut Bencher<'_>, ping_count: usize) {
         struct ConstantPriorityPrivider;
         impl TaskPriorityProvider for ConstantPriorityPrivider {
@@ -226,13 +266,19 @@ pub fn ping_pong(b: &mut Criterion) {
             yatp_future::ping_pong_single_level(b, *i)
         });
         group.bench_with_input(BenchmarkId::new("yatp::callback", i), i, |b, i| {
-            yatp_callback::ping_pong(b, *i)
+            yatp_callback::ping_pong(b, *i, 0)
+        });
+        group.bench_with_input(BenchmarkId::new("yatp::callback::recycle", i), i, |b, i| {
+            yatp_callback::ping_pong(b, *i, 1024)
         });
         group.bench_with_input(
             BenchmarkId::new("yatp::future::multilevel", i),
             i,
             |b, i| yatp_future::ping_pong_multilevel(b, *i),
         );
+        group.bench_with_input(BenchmarkId::new("yatp::future::local", i), i, |b, i| {
+            yatp_future::ping_pong_local(b, *i)
+        });
         group.bench_with_input(BenchmarkId::new("yatp::future::priority", i), i, |b, i| {
             yatp_future::ping_pong_priority(b, *i)
         });
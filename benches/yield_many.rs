@@ -1,5 +1,7 @@
 // Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
 
+#![allow(clippy::let_unit_value)]
+
 use criterion::*;
 use std::future::*;
 use std::pin::Pin;
@@ -7,6 +7,12 @@
 pub mod metrics;
 pub mod pool;
 pub mod queue;
+pub mod sync;
 pub mod task;
+#[cfg(any(test, feature = "test-util"))]
+pub mod testing;
+
+mod timer;
 
 pub use self::pool::{Builder, Remote, ThreadPool};
+pub use self::task::future::in_worker_thread;
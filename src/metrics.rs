@@ -1,6 +1,9 @@
 // Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
 
 //! Metrics of the thread pool.
+//!
+//! Without the default `metrics` feature, the metrics are still defined but
+//! the thread pool never updates the ones on its hot paths.
 
 use lazy_static::lazy_static;
 use prometheus::*;
@@ -71,6 +74,135 @@ lazy_static! {
     )
     .unwrap();
 
+    /// Spin iterations of workers that found no task.
+    pub static ref WORKER_SPIN_TOTAL: IntCounterVec = IntCounterVec::new(
+        new_opts(
+            "yatp_worker_spin_total",
+            "Total number of spin iterations of workers that found no task"
+        ),
+        &["name"]
+    )
+    .unwrap();
+
+    /// Times tasks are moved to the global queue instead of the local queue
+    /// of the current worker when woken up.
+    pub static ref TASK_MIGRATION_TOTAL: IntCounterVec = IntCounterVec::new(
+        new_opts(
+            "yatp_task_migration_total",
+            "Total number of tasks woken up to the global queue"
+        ),
+        &["name"]
+    )
+    .unwrap();
+
+    /// Times hot futures are rescheduled because they reach the repoll limit.
+    pub static ref REPOLL_LIMIT_REACHED_TOTAL: IntCounterVec = IntCounterVec::new(
+        new_opts(
+            "yatp_repoll_limit_reached_total",
+            "Total number of futures rescheduled for reaching the repoll limit"
+        ),
+        &["name"]
+    )
+    .unwrap();
+
+    /// The number of awake workers, observed every time a worker goes to
+    /// sleep or wakes up.
+    pub static ref ACTIVE_WORKERS_COUNT: HistogramVec = HistogramVec::new(
+        new_histogram_opts(
+            "yatp_active_workers_count",
+            "Bucketed histogram of the number of awake workers",
+            exponential_buckets(1.0, 2.0, 10).unwrap()
+        ),
+        &["name"]
+    )
+    .unwrap();
+
+    /// Time between a task being woken up and a worker picking it.
+    pub static ref WAKEUP_LATENCY: HistogramVec = HistogramVec::new(
+        new_histogram_opts(
+            "yatp_wakeup_latency_seconds",
+            "Bucketed histogram of time from a task being woken up to being picked",
+            exponential_buckets(0.00001, 2.0, 20).unwrap()
+        ),
+        &["name"]
+    )
+    .unwrap();
+
+    /// Time between a task being spawned and a worker picking it for the
+    /// first time.
+    pub static ref FIRST_POLL_LATENCY: HistogramVec = HistogramVec::new(
+        new_histogram_opts(
+            "yatp_first_poll_latency_seconds",
+            "Bucketed histogram of time from a task being spawned to its first poll",
+            exponential_buckets(0.00001, 2.0, 20).unwrap()
+        ),
+        &["name"]
+    )
+    .unwrap();
+
+    /// Times the runner of a worker panics when it's started.
+    pub static ref WORKER_START_PANIC_TOTAL: IntCounterVec = IntCounterVec::new(
+        new_opts(
+            "yatp_worker_start_panic_total",
+            "Total number of workers exited because their runners panicked on start"
+        ),
+        &["name"]
+    )
+    .unwrap();
+
+    /// Times the stall detector finds a worker handling a single task for
+    /// too long.
+    pub static ref WORKER_STALL_TOTAL: IntCounterVec = IntCounterVec::new(
+        new_opts(
+            "yatp_worker_stall_total",
+            "Total number of stalls reported by the stall detector"
+        ),
+        &["name"]
+    )
+    .unwrap();
+
+    /// Tasks dropped without being handled because the pool is shut down.
+    pub static ref TASKS_DROPPED_ON_SHUTDOWN: IntCounterVec = IntCounterVec::new(
+        new_opts(
+            "yatp_tasks_dropped_on_shutdown_total",
+            "Total number of queued tasks dropped when the pool is shut down"
+        ),
+        &["name"]
+    )
+    .unwrap();
+
+    /// Times stealing from the global queue has to retry because of
+    /// concurrent accesses.
+    pub static ref INJECTOR_CONTENTION_TOTAL: IntCounterVec = IntCounterVec::new(
+        new_opts(
+            "yatp_injector_contention_total",
+            "Total number of retries of stealing from the global queue"
+        ),
+        &["name"]
+    )
+    .unwrap();
+
+    /// Time tasks of a kind wait in queue before being handled.
+    pub static ref TASK_KIND_WAIT_DURATION: HistogramVec = HistogramVec::new(
+        new_histogram_opts(
+            "yatp_task_kind_wait_duration",
+            "Bucketed histogram of task wait time in queue per task kind",
+            exponential_buckets(0.00001, 2.0, 20).unwrap()
+        ),
+        &["name", "kind"]
+    )
+    .unwrap();
+
+    /// Times tasks of a kind are handled.
+    pub static ref TASK_KIND_HANDLED_TOTAL: IntCounterVec = IntCounterVec::new(
+        new_opts(
+            "yatp_task_kind_handled_total",
+            "Total number of times tasks are handled per task kind"
+        ),
+        &["name", "kind"]
+    )
+    .unwrap();
+
     static ref NAMESPACE: Mutex<Option<String>> = Mutex::new(None);
 }
 
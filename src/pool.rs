@@ -6,24 +6,210 @@
 //! the crate, you need to implement `Runner` trait.
 
 mod builder;
+mod group;
 mod runner;
 pub(crate) mod spawn;
 mod worker;
 
-pub use self::builder::{Builder, SchedConfig};
+#[cfg(test)]
+pub(crate) use self::builder::MockClock;
+pub(crate) use self::builder::Stopwatch;
+pub use self::builder::{
+    AttachedWorker, BackoffStrategy, Builder, ConfigError, ContextHook, OverflowPolicy,
+    PartitionFn, SchedConfig, SharedPoolBuilder, ShedPolicy, TaskPanicHandler, TimeSource,
+    WokenTaskOrder,
+};
+pub use self::group::PoolGroup;
 pub use self::runner::{CloneRunnerBuilder, Runner, RunnerBuilder};
 pub(crate) use self::spawn::WeakRemote;
-pub use self::spawn::{build_spawn, Local, Remote};
+pub use self::spawn::{build_spawn, Local, Remote, SpawnHandle, SpawnPlacement, Unparker};
+pub(crate) use self::worker::CurrentTaskGuard;
+pub use self::worker::{current_group_id, current_task_id, TaskPanic};
 
-use crate::queue::{TaskCell, WithExtras};
-use std::mem;
-use std::sync::Mutex;
+use crate::pool::spawn::WorkerGroup;
+use crate::queue::{QueueKind, QueueStats, TaskCell, WithExtras};
+use parking_lot_core::{UnparkToken, DEFAULT_PARK_TOKEN};
+use std::cell::Cell;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::task::{Context, Poll, Waker};
 use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use std::{mem, ptr};
+
+/// Tracks the number of worker threads that haven't exited yet.
+struct LiveWorkers {
+    count: AtomicUsize,
+    wakers: Mutex<ShutdownWakers>,
+}
+
+/// Wakers of the `Shutdown` futures waiting for workers to exit.
+#[derive(Default)]
+struct ShutdownWakers {
+    wakers: Vec<(u64, Waker)>,
+    next_id: u64,
+}
+
+impl LiveWorkers {
+    fn new(count: usize) -> LiveWorkers {
+        LiveWorkers {
+            count: AtomicUsize::new(count),
+            wakers: Mutex::default(),
+        }
+    }
+
+    /// Whether the current thread is a worker of the pool.
+    fn is_current(self: &Arc<Self>) -> bool {
+        CURRENT_POOL.with(|p| p.get() == Arc::as_ptr(self))
+    }
+
+    /// Blocks until at most `target` workers are alive.
+    fn wait(&self, target: usize) {
+        let addr = &self.count as *const AtomicUsize as usize;
+        while self.count.load(Ordering::SeqCst) > target {
+            unsafe {
+                parking_lot_core::park(
+                    addr,
+                    || self.count.load(Ordering::SeqCst) > target,
+                    || {},
+                    |_, _| {},
+                    DEFAULT_PARK_TOKEN,
+                    None,
+                );
+            }
+        }
+    }
+}
+
+thread_local! {
+    /// The live workers of the pool the current thread is a worker of.
+    static CURRENT_POOL: Cell<*const LiveWorkers> = const { Cell::new(ptr::null()) };
+}
+
+/// Marks a worker thread as exited when dropped, even if it panics.
+struct ExitGuard(Arc<LiveWorkers>);
+
+impl ExitGuard {
+    /// Marks the current thread as a worker of the pool, it's called on the
+    /// worker thread before it starts.
+    fn enter(self) -> ExitGuard {
+        CURRENT_POOL.with(|p| p.set(Arc::as_ptr(&self.0)));
+        self
+    }
+}
+
+impl Drop for ExitGuard {
+    fn drop(&mut self) {
+        let _ = CURRENT_POOL.try_with(|p| {
+            if p.get() == Arc::as_ptr(&self.0) {
+                p.set(ptr::null());
+            }
+        });
+        let count = &self.0.count;
+        count.fetch_sub(1, Ordering::SeqCst);
+        unsafe {
+            parking_lot_core::unpark_all(count as *const AtomicUsize as usize, UnparkToken(0));
+        }
+        let wakers = mem::take(&mut self.0.wakers.lock().unwrap().wakers);
+        for (_, w) in wakers {
+            w.wake();
+        }
+    }
+}
+
+/// A future that resolves when the worker threads of a pool have exited.
+///
+/// It's returned by `ThreadPool::shutdown_async`.
+pub struct Shutdown {
+    live_workers: Arc<LiveWorkers>,
+    // The number of workers that are allowed to be alive when resolved. It's
+    // 1 when awaited on a worker of the same pool, otherwise 0.
+    target: usize,
+    /// The id of the registered waker.
+    id: Option<u64>,
+}
+
+impl Future for Shutdown {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        let live_workers = &this.live_workers;
+        if live_workers.count.load(Ordering::SeqCst) <= this.target {
+            return Poll::Ready(());
+        }
+        let mut wakers = live_workers.wakers.lock().unwrap();
+        let registered = this
+            .id
+            .and_then(|id| wakers.wakers.iter_mut().find(|(i, _)| *i == id));
+        match registered {
+            Some((_, waker)) => {
+                if !waker.will_wake(cx.waker()) {
+                    *waker = cx.waker().clone();
+                }
+            }
+            None => {
+                // Not registered yet, or taken by an exited worker.
+                let id = wakers.next_id;
+                wakers.next_id += 1;
+                wakers.wakers.push((id, cx.waker().clone()));
+                this.id = Some(id);
+            }
+        }
+        drop(wakers);
+        // Check again in case the last worker exits before the waker is
+        // registered.
+        if live_workers.count.load(Ordering::SeqCst) <= this.target {
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Shutdown {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            let mut wakers = self.live_workers.wakers.lock().unwrap();
+            wakers.wakers.retain(|(i, _)| *i != id);
+        }
+    }
+}
+
+/// Keeps a worker parked, returned by `ThreadPool::quiesce_worker`.
+///
+/// The worker resumes handling tasks when all its guards are dropped.
+pub struct WorkerGuard<T: TaskCell + Send> {
+    remote: Remote<T>,
+    id: usize,
+}
+
+impl<T: TaskCell + Send> WorkerGuard<T> {
+    /// Waits until the worker has parked or `timeout` elapses. Returns
+    /// whether the worker has parked.
+    ///
+    /// The worker parks after its current task is finished, so it must not
+    /// be called by that task, which would always time out.
+    pub fn wait_quiesced(&self, timeout: Duration) -> bool {
+        self.remote.core.wait_quiesced_parked(self.id, timeout)
+    }
+}
+
+impl<T: TaskCell + Send> Drop for WorkerGuard<T> {
+    fn drop(&mut self) {
+        self.remote.core.resume_worker(self.id);
+    }
+}
 
 /// A generic thread pool.
 pub struct ThreadPool<T: TaskCell + Send> {
     remote: Remote<T>,
-    threads: Mutex<Vec<JoinHandle<()>>>,
+    /// It's shared with the supervisor, which adds restarted workers.
+    threads: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    /// Background threads that are not workers, like the supervisor.
+    helpers: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    live_workers: Arc<LiveWorkers>,
 }
 
 impl<T: TaskCell + Send> ThreadPool<T> {
@@ -60,16 +246,197 @@ impl<T: TaskCell + Send> ThreadPool<T> {
         self.remote.scale_workers(new_thread_count);
     }
 
+    /// Makes the worker at `index` park after it finishes its current task,
+    /// until the returned guard is dropped.
+    ///
+    /// It allows maintaining a single worker, like changing its affinity,
+    /// without pausing the whole pool. Tasks in the local queue of the worker
+    /// are moved to the global queue for other workers, but tasks pinned to
+    /// it wait until it resumes. It returns immediately, use
+    /// `WorkerGuard::wait_quiesced` to wait for the worker to park.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is not less than `max_thread_count`.
+    pub fn quiesce_worker(&self, index: usize) -> WorkerGuard<T> {
+        let max = self.remote.core.config().max_thread_count;
+        assert!(index < max, "worker index {} out of range {}", index, max);
+        self.remote.core.quiesce_worker(index + 1);
+        WorkerGuard {
+            remote: self.remote.clone(),
+            id: index + 1,
+        }
+    }
+
     /// Shutdowns the pool.
     ///
     /// Closes the queue and wait for all threads to exit.
     pub fn shutdown(&self) {
+        shutdown_pool(
+            &self.remote,
+            &self.threads,
+            &self.helpers,
+            &self.live_workers,
+        );
+    }
+
+    /// Closes the queue and wakes up all threads without waiting for them to
+    /// exit.
+    ///
+    /// Returns whether it's the call that shuts down the pool. Threads are
+    /// joined by a later `shutdown` or when the pool is dropped.
+    pub fn try_shutdown(&self) -> bool {
+        self.remote.stop()
+    }
+
+    /// Shutdowns the pool after all queued tasks are handled.
+    ///
+    /// New tasks spawned by remotes are dropped since it's called, but tasks
+    /// spawned by running tasks through their `Local`, like the children
+    /// spawned by callbacks, are still handled. So the pool only stops after
+    /// tasks stop spawning children. Futures that are still pending when the
+    /// queues become empty are dropped as `shutdown`.
+    ///
+    /// It must not be called from a thread of the pool, which would wait for
+    /// the calling task itself.
+    pub fn shutdown_drain(&self) {
+        let core = &self.remote.core;
+        core.mark_draining();
+        core.wait_idle(None);
+        self.shutdown();
+    }
+
+    /// Shutdowns the pool and calls `f` with every task left in the queues.
+    ///
+    /// New tasks spawned by remotes are dropped since it's called. Workers
+    /// are joined like `shutdown`, but the tasks they leave are passed to `f`
+    /// one by one instead of being dropped, so they can be spawned somewhere
+    /// else. Futures waiting to be woken up are not in any queue and are not
+    /// passed to `f`.
+    ///
+    /// Like `shutdown_drain`, it must not be called from a thread of the pool.
+    pub fn drain_with(&self, mut f: impl FnMut(T)) {
+        let core = &self.remote.core;
+        core.mark_draining();
+        core.keep_leftovers();
+        self.shutdown();
+        while let Some(t) = core.pop_leftover() {
+            f(t);
+        }
+    }
+
+    /// Blocks until no task is queued or being handled, or `timeout` elapses.
+    ///
+    /// Returns whether the pool became idle in time. Children are counted
+    /// before their parent is marked done, so a chain of tasks is only idle
+    /// after its last task finishes. Futures waiting to be woken up are not
+    /// counted as they are not in any queue.
+    ///
+    /// Like `shutdown_drain`, it must not be called from a thread of the pool.
+    pub fn wait_idle(&self, timeout: Duration) -> bool {
+        let deadline = std::time::Instant::now() + timeout;
+        self.remote.core.wait_idle(Some(deadline))
+    }
+
+    /// Shutdowns the pool without blocking.
+    ///
+    /// Closes the queue and returns a future that resolves when all threads
+    /// have exited. If it's called from a thread of the pool, the future
+    /// resolves when all the other threads have exited, but awaiting it on a
+    /// thread of the same pool is still discouraged as the pool may drop the
+    /// awaiting task before it's woken up.
+    pub fn shutdown_async(&self) -> Shutdown {
         self.remote.stop();
-        let mut threads = mem::take(&mut *self.threads.lock().unwrap());
-        let curr_id = thread::current().id();
-        for j in threads.drain(..) {
-            if curr_id != j.thread().id() {
-                j.join().unwrap();
+        Shutdown {
+            live_workers: self.live_workers.clone(),
+            target: self.live_workers.is_current() as usize,
+            id: None,
+        }
+    }
+
+    /// Returns the kind of the queue the pool is built with.
+    ///
+    /// Generic code can use it to tell whether hints like task levels and
+    /// priority classes take effect.
+    pub fn queue_type(&self) -> QueueKind {
+        self.remote.core.queue_kind()
+    }
+
+    /// Returns the lengths of the task queue of the pool.
+    ///
+    /// It's meant for exporting queue depth to external monitoring systems.
+    /// The lengths are only a point-in-time view and may be stale as soon as
+    /// they are returned.
+    pub fn queue_stats(&self) -> QueueStats {
+        self.remote.core.queue_stats()
+    }
+
+    /// Returns the number of tasks handled by each worker, indexed by the
+    /// worker index.
+    ///
+    /// Burst workers follow the regular workers. A big skew between workers
+    /// usually means tasks are not balanced well by stealing.
+    pub fn per_worker_handled(&self) -> Vec<u64> {
+        self.remote.core.handled_tasks()
+    }
+
+    /// Checks whether the pool is still making progress.
+    ///
+    /// Returns false if every regular worker has been handling a single task
+    /// for longer than `stall_threshold`, usually because they are all blocked,
+    /// so supervisors can decide to restart the pool. It always returns true
+    /// unless `Builder::health_check` or the stall detector is enabled.
+    pub fn is_healthy(&self, stall_threshold: Duration) -> bool {
+        let core = &self.remote.core;
+        !core.config().track_handling() || core.has_progressing_worker(stall_threshold)
+    }
+
+    /// Replaces the runners of all workers with the ones built by `f`.
+    ///
+    /// Every worker ends its runner and starts a new one built by `f` before
+    /// handling its next task, so no task is in flight during the swap and
+    /// queued tasks are kept. Sleeping workers swap after being woken up.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `R` is not the type of the runners the pool is built with.
+    pub fn reconfigure_runner<R>(&self, f: impl Fn() -> R + Send + Sync + 'static)
+    where
+        R: Runner<TaskCell = T> + 'static,
+    {
+        self.remote.core.set_runner_factory(Box::new(f));
+    }
+
+    /// Runs `f` once on every worker with the index of the worker, and waits
+    /// until all of them finish. `task` wraps a closure into a task.
+    ///
+    /// Returns early if the pool is shut down before all the workers run it,
+    /// in which case `f` may not run on some workers.
+    pub(crate) fn broadcast_by<F, W>(&self, f: F, task: impl Fn(Box<dyn FnOnce() + Send>) -> W)
+    where
+        F: Fn(usize) + Send + Sync + Clone + 'static,
+        W: WithExtras<T>,
+    {
+        if self.remote.core.is_shutdown() {
+            return;
+        }
+        let worker_count = self.remote.core.config().max_thread_count;
+        let (tx, rx) = mpsc::channel();
+        for i in 0..worker_count {
+            let (f, tx) = (f.clone(), tx.clone());
+            self.remote.spawn_on(
+                i,
+                task(Box::new(move || {
+                    f(i);
+                    let _ = tx.send(());
+                })),
+            );
+        }
+        drop(tx);
+        // Tasks dropped on shutdown drop their senders too.
+        for _ in 0..worker_count {
+            if rx.recv().is_err() {
+                return;
             }
         }
     }
@@ -78,6 +445,40 @@ impl<T: TaskCell + Send> ThreadPool<T> {
     pub fn remote(&self) -> &Remote<T> {
         &self.remote
     }
+
+    /// Returns a handle that can only spawn tasks to the thread pool.
+    pub fn spawn_handle(&self) -> SpawnHandle<T> {
+        SpawnHandle::new(self.remote.clone())
+    }
+}
+
+/// Closes the queue of a pool and waits for its threads to exit, except the
+/// calling one.
+fn shutdown_pool<T: TaskCell + Send>(
+    remote: &Remote<T>,
+    threads: &Mutex<Vec<JoinHandle<()>>>,
+    helpers: &Mutex<Vec<JoinHandle<()>>>,
+    live_workers: &Arc<LiveWorkers>,
+) {
+    remote.stop();
+    remote.core.shutdown_burst_workers();
+    let mut threads = mem::take(&mut *threads.lock().unwrap());
+    let curr_id = thread::current().id();
+    for j in threads.drain(..) {
+        if curr_id != j.thread().id() {
+            j.join().unwrap();
+        }
+    }
+    // Attached workers run on threads that can't be joined.
+    let in_pool = live_workers.is_current();
+    live_workers.wait(in_pool as usize);
+    // Helpers may wait for all workers to exit, including the calling one.
+    if !in_pool {
+        let helpers = mem::take(&mut *helpers.lock().unwrap());
+        for j in helpers {
+            j.join().unwrap();
+        }
+    }
 }
 
 impl<T: TaskCell + Send> Drop for ThreadPool<T> {
@@ -87,5 +488,54 @@ impl<T: TaskCell + Send> Drop for ThreadPool<T> {
     }
 }
 
+/// Thread pools sharing the same worker threads.
+///
+/// It's built by [`SharedPoolBuilder`], with one [`Remote`] for each queue.
+pub struct SharedThreadPool<T: TaskCell + Send> {
+    remotes: Vec<Remote<T>>,
+    group: Arc<WorkerGroup>,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl<T: TaskCell + Send> SharedThreadPool<T> {
+    /// Gets the remote of the queue at the given index.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the index is out of range.
+    pub fn remote(&self, index: usize) -> &Remote<T> {
+        &self.remotes[index]
+    }
+
+    /// Gets the remotes of all queues.
+    pub fn remotes(&self) -> &[Remote<T>] {
+        &self.remotes
+    }
+
+    /// Shutdowns all queues of the pool.
+    ///
+    /// Closes the queues and wait for all threads to exit.
+    pub fn shutdown(&self) {
+        for remote in &self.remotes {
+            remote.stop();
+        }
+        self.group.mark_shutdown(0);
+        let mut threads = mem::take(&mut *self.threads.lock().unwrap());
+        let curr_id = thread::current().id();
+        for j in threads.drain(..) {
+            if curr_id != j.thread().id() {
+                j.join().unwrap();
+            }
+        }
+    }
+}
+
+impl<T: TaskCell + Send> Drop for SharedThreadPool<T> {
+    /// Will shutdown the thread pool if it has not.
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
 #[cfg(test)]
 mod tests;
@@ -1,20 +1,285 @@
 // Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
 
-use crate::pool::spawn::QueueCore;
-use crate::pool::worker::WorkerThread;
-use crate::pool::{CloneRunnerBuilder, Local, Remote, Runner, RunnerBuilder, ThreadPool};
-use crate::queue::{self, multilevel, priority, LocalQueue, QueueType, TaskCell};
+use crate::metrics::WORKER_SPIN_TOTAL;
+use crate::pool::spawn::{BurstSpawner, QueueCore, WorkerGroup};
+use crate::pool::worker::{DeadWorker, SharedWorkerThread, TaskPanic, WorkerThread};
+use crate::pool::{
+    CloneRunnerBuilder, ExitGuard, LiveWorkers, Local, Remote, Runner, RunnerBuilder,
+    SharedThreadPool, SpawnHandle, ThreadPool,
+};
+use crate::queue::{
+    self, multilevel, priority, Extras, LocalQueue, QueueType, TaskCell, WithExtras,
+};
 use crate::task::{callback, future};
+use log::warn;
+use std::any::Any;
+#[cfg(test)]
+use std::cell::Cell;
 use std::sync::{
     atomic::{AtomicUsize, Ordering},
-    Arc, Mutex,
+    mpsc, Arc, Barrier, Mutex, Weak,
 };
-use std::thread;
-use std::time::Duration;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use std::{error, fmt, mem};
+
+/// What to do with a new task when all workers are busy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Pushes the task to the queue.
+    Queue,
+    /// Runs the task on the spawning thread if there are at least
+    /// `queue_threshold` tasks waiting in the global queue. It only applies
+    /// to task cells that can be run in place, like callback tasks, other
+    /// tasks are still queued.
+    RunOnCaller {
+        /// The number of queued tasks to start running tasks on the caller.
+        queue_threshold: usize,
+    },
+}
+
+/// Rejects tasks of low priority when the pool is overloaded, see
+/// [`Builder::shed_overload`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ShedPolicy {
+    /// The number of pending tasks above which the pool is overloaded.
+    pub high_water: usize,
+    /// Tasks at this level or higher are rejected when the pool is overloaded.
+    pub min_level: u8,
+}
+
+/// Where a future woken up on a worker is queued, relative to the tasks
+/// spawned on the worker.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WokenTaskOrder {
+    /// Queues the future like a newly spawned task.
+    Spawned,
+    /// Queues the future before all the tasks in the local queue. It favors
+    /// the latency of work in progress, but keeps waking futures can starve
+    /// newly spawned tasks.
+    Front,
+    /// Queues the future to the global queue behind the local tasks. It
+    /// favors new work, at the cost of locality as the future may be stolen
+    /// by other workers.
+    Back,
+}
+
+/// The clock used to measure how long tasks run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeSource {
+    /// The wall clock time.
+    Wall,
+    /// The CPU time of the worker thread, so time spent on blocking doesn't
+    /// count. It falls back to the wall clock time on platforms that don't
+    /// support it.
+    ThreadCpu,
+}
+
+impl TimeSource {
+    /// Starts measuring the elapsed time.
+    pub(crate) fn start(self) -> Stopwatch {
+        #[cfg(test)]
+        if let Some(now) = MOCK_CLOCK.with(Cell::get) {
+            return Stopwatch::Mock(now);
+        }
+        match self {
+            TimeSource::ThreadCpu => match thread_cpu_time() {
+                Some(t) => Stopwatch::ThreadCpu(t),
+                None => Stopwatch::Wall(Instant::now()),
+            },
+            TimeSource::Wall => Stopwatch::Wall(Instant::now()),
+        }
+    }
+}
+
+/// Measures the elapsed time with a [`TimeSource`].
+pub(crate) enum Stopwatch {
+    Wall(Instant),
+    ThreadCpu(Duration),
+    #[cfg(test)]
+    Mock(Duration),
+}
+
+impl Stopwatch {
+    pub(crate) fn elapsed(&self) -> Duration {
+        match self {
+            Stopwatch::Wall(begin) => begin.elapsed(),
+            Stopwatch::ThreadCpu(begin) => thread_cpu_time()
+                .map(|t| t.saturating_sub(*begin))
+                .unwrap_or_default(),
+            #[cfg(test)]
+            Stopwatch::Mock(begin) => MOCK_CLOCK
+                .with(Cell::get)
+                .map(|t| t.saturating_sub(*begin))
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    /// The time of the mock clock of the current thread, `None` if it's not
+    /// installed.
+    static MOCK_CLOCK: Cell<Option<Duration>> = const { Cell::new(None) };
+}
+
+/// A clock advanced by hand, which replaces the time source of stopwatches
+/// started on the current thread until it's dropped.
+#[cfg(test)]
+pub(crate) struct MockClock(());
+
+#[cfg(test)]
+impl MockClock {
+    pub(crate) fn install() -> MockClock {
+        MOCK_CLOCK.with(|c| c.set(Some(Duration::ZERO)));
+        MockClock(())
+    }
+
+    /// Advances the mock clock of the current thread. It can be called
+    /// without the guard, like from a future polled on the thread.
+    pub(crate) fn advance(d: Duration) {
+        MOCK_CLOCK.with(|c| c.set(c.get().map(|t| t + d)));
+    }
+}
+
+#[cfg(test)]
+impl Drop for MockClock {
+    fn drop(&mut self) {
+        MOCK_CLOCK.with(|c| c.set(None));
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn thread_cpu_time() -> Option<Duration> {
+    let mut t = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    if unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut t) } != 0 {
+        return None;
+    }
+    Some(Duration::new(t.tv_sec as u64, t.tv_nsec as u32))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn thread_cpu_time() -> Option<Duration> {
+    None
+}
+
+/// The error returned when the configurations of a thread pool are invalid.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The name prefix of threads is empty or only contains whitespaces.
+    ///
+    /// Threads would be named like "-0" and can't be told apart from the
+    /// threads of other pools.
+    EmptyName,
+    /// A per worker stack size is 0.
+    ZeroStackSize,
+    /// The level time thresholds are not one less than the number of levels
+    /// or not strictly increasing.
+    InvalidLevelThresholds,
+    /// The task queue has no levels to apply level time thresholds to.
+    NoTaskLevels,
+    /// A strict priority queue is configured with no classes.
+    NoPriorityClasses,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::EmptyName => write!(f, "thread name prefix should not be empty"),
+            ConfigError::ZeroStackSize => write!(f, "stack size should be greater than 0"),
+            ConfigError::InvalidLevelThresholds => write!(
+                f,
+                "level thresholds should be strictly increasing for every level but the last"
+            ),
+            ConfigError::NoTaskLevels => write!(f, "task queue has no levels"),
+            ConfigError::NoPriorityClasses => {
+                write!(f, "strict priority queue should have at least 1 class")
+            }
+        }
+    }
+}
+
+impl error::Error for ConfigError {}
+
+/// How the interval between actions rate limited by the scheduler changes,
+/// see [`Builder::wake_backoff`] and [`Builder::alloc_slot_backoff`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackoffStrategy {
+    /// Always waits for the same interval.
+    Fixed(Duration),
+    /// Starts with `base`, and doubles the interval up to `max` every time
+    /// the action is taken again within twice the interval. It goes back to
+    /// `base` once the action is not needed for that long.
+    Exponential {
+        /// The initial interval.
+        base: Duration,
+        /// The maximum interval.
+        max: Duration,
+    },
+}
+
+impl BackoffStrategy {
+    /// The interval to wait after a calm period.
+    pub(crate) fn base(self) -> Duration {
+        match self {
+            BackoffStrategy::Fixed(interval) => interval,
+            BackoffStrategy::Exponential { base, .. } => base,
+        }
+    }
+
+    /// The interval to wait after the action is taken again soon.
+    pub(crate) fn grow(self, interval: Duration) -> Duration {
+        match self {
+            BackoffStrategy::Fixed(interval) => interval,
+            BackoffStrategy::Exponential { base, max } => {
+                std::cmp::min(std::cmp::max(interval, base) * 2, max)
+            }
+        }
+    }
+}
+
+impl From<Duration> for BackoffStrategy {
+    fn from(interval: Duration) -> BackoffStrategy {
+        BackoffStrategy::Fixed(interval)
+    }
+}
+
+/// Chooses the worker to handle a task by its extras, see
+/// [`Builder::partition_fn`].
+pub type PartitionFn = Arc<dyn Fn(&Extras) -> usize + Send + Sync>;
+
+/// Handles the panics of tasks, see [`Builder::on_task_panic`].
+pub type TaskPanicHandler = Arc<dyn Fn(&TaskPanic) + Send + Sync>;
+
+/// A context captured by [`ContextHook`], type erased.
+pub(crate) type Context = Arc<dyn Any + Send + Sync>;
+
+/// Captures the context of the spawning thread and restores it on workers,
+/// see [`Builder::context_hook`].
+#[derive(Clone)]
+pub struct ContextHook {
+    capture: Arc<dyn Fn() -> Context + Send + Sync>,
+    restore: Arc<dyn Fn(&Context) + Send + Sync>,
+}
+
+impl ContextHook {
+    pub(crate) fn capture(&self) -> Context {
+        (self.capture)()
+    }
+
+    pub(crate) fn restore(&self, ctx: &Context) {
+        (self.restore)(ctx)
+    }
+}
 
 /// Configuration for schedule algorithm.
 pub struct SchedConfig {
-    /// The maximum number of running threads at the same time.
+    /// The maximum number of running threads at the same time. Defaults to
+    /// `num_cpus::get()`, which is capped by the CPU quota of the cgroup if
+    /// there is one.
     pub max_thread_count: usize,
     /// The core number of running threads at the same time. It
     /// is defined as the AtomicUsize because it needs to be used
@@ -26,16 +291,99 @@ pub struct SchedConfig {
     /// The maximum tries to rerun an unfinished task before pushing
     /// back to queue.
     pub max_inplace_spin: usize,
+    /// The number of times a worker that finds no task spins, with the
+    /// number of busy loops doubling each time, before it starts yielding.
+    pub max_pop_spin: usize,
+    /// The number of times a worker that finds no task yields its thread
+    /// after spinning before it goes to sleep.
+    pub max_pop_yield: usize,
     /// The maximum allowed idle time for a thread. Thread will only be
     /// woken up when algorithm thinks it needs more worker.
     pub max_idle_time: Duration,
     /// The maximum time to wait for a task before increasing the
     /// running thread slots.
     pub max_wait_time: Duration,
-    /// The minimum interval between waking a thread.
-    pub wake_backoff: Duration,
-    /// The minimum interval between increasing running threads.
-    pub alloc_slot_backoff: Duration,
+    /// The minimum interval between waking a thread when some threads are
+    /// awake.
+    pub wake_backoff: BackoffStrategy,
+    /// The minimum interval between spawning burst threads.
+    pub alloc_slot_backoff: BackoffStrategy,
+    /// Whether to keep at least `min_thread_count` threads awake when there
+    /// are pending tasks in the global queue.
+    pub min_awake_under_load: bool,
+    /// What to do with a new task when all workers are busy.
+    pub overflow_policy: OverflowPolicy,
+    /// The maximum number of extra threads spawned when the global queue
+    /// keeps backlogged. They exit after being idle for `extra_idle_time`.
+    pub burst_thread_count: usize,
+    /// The global queue is considered backlogged when it has more tasks than
+    /// this threshold.
+    pub burst_queue_threshold: usize,
+    /// The maximum number of freed callback task allocations each worker
+    /// keeps for reuse. 0 disables recycling.
+    pub callback_recycle_cap: usize,
+    /// The clock used to measure how long tasks run in multilevel queues.
+    pub time_source: TimeSource,
+    /// The maximum number of tasks in the local queue of a worker, tasks
+    /// spawned locally go to the global queue when it's full. 0 means
+    /// unbounded.
+    pub local_queue_capacity: usize,
+    /// Warns about workers that handle a single task for longer than this.
+    /// `None` disables the stall detector.
+    pub stall_threshold: Option<Duration>,
+    /// Whether to track the tasks being handled for `ThreadPool::is_healthy`.
+    pub health_check: bool,
+    /// The minimum interval between restarting workers that die of panics.
+    /// `None` doesn't restart them.
+    pub worker_restart_interval: Option<Duration>,
+    /// Handles the panics of tasks. `None` lets a panicking task unwind its
+    /// worker.
+    pub on_task_panic: Option<TaskPanicHandler>,
+    /// How long the first `min_thread_count` workers keep looking for tasks
+    /// before going to sleep. They never exit, a longer idle time only
+    /// trades CPU for lower wake latency.
+    pub core_idle_time: Duration,
+    /// How long burst workers stay idle before exiting. `None` falls back to
+    /// `max_idle_time`.
+    pub extra_idle_time: Option<Duration>,
+    /// The number of shards of the global queue. Spawning threads push to
+    /// different shards to reduce contention, at the cost of weaker FIFO
+    /// order between them. Only the single level queue supports sharding.
+    pub injector_shards: usize,
+    /// Chooses the worker a task spawned to the global queue goes to by its
+    /// extras, modulo `max_thread_count`. `None` leaves tasks in the global
+    /// queue for any worker to pick.
+    pub partition_fn: Option<PartitionFn>,
+    /// Whether to run callback tasks on the spawning thread when the pool
+    /// has no worker threads, that is `max_thread_count` is 0.
+    pub inline_if_no_workers: bool,
+    /// Propagates a context from the spawning thread to the worker handling
+    /// the task.
+    pub context_hook: Option<ContextHook>,
+    /// Where a future woken up on a worker is queued.
+    pub woken_task_order: WokenTaskOrder,
+    /// Which tasks `Remote::try_spawn` rejects when the pool is overloaded.
+    /// `None` never rejects tasks.
+    pub shed_policy: Option<ShedPolicy>,
+    /// Seeds the random decisions of the scheduler, like the order of stealing
+    /// from other workers and choosing the level to pop in multilevel queues.
+    /// `None` seeds them from entropy.
+    pub deterministic_seed: Option<u64>,
+    /// Whether a copy of the extras of every queued task is kept aside for
+    /// `Remote::snapshot_queue`.
+    pub track_queued_tasks: bool,
+}
+
+impl SchedConfig {
+    /// Whether workers record when they start and finish handling tasks.
+    pub(crate) fn track_handling(&self) -> bool {
+        self.stall_threshold.is_some() || self.health_check
+    }
+
+    /// The idle time after which burst workers exit.
+    pub(crate) fn extra_idle_time(&self) -> Duration {
+        self.extra_idle_time.unwrap_or(self.max_idle_time)
+    }
 }
 
 impl Default for SchedConfig {
@@ -45,10 +393,33 @@ impl Default for SchedConfig {
             core_thread_count: AtomicUsize::new(0),
             min_thread_count: 1,
             max_inplace_spin: 4,
+            max_pop_spin: 3,
+            max_pop_yield: 7,
             max_idle_time: Duration::from_millis(1),
             max_wait_time: Duration::from_millis(1),
-            wake_backoff: Duration::from_millis(1),
-            alloc_slot_backoff: Duration::from_millis(2),
+            wake_backoff: BackoffStrategy::Fixed(Duration::from_millis(1)),
+            alloc_slot_backoff: BackoffStrategy::Fixed(Duration::from_millis(2)),
+            min_awake_under_load: false,
+            overflow_policy: OverflowPolicy::Queue,
+            burst_thread_count: 0,
+            burst_queue_threshold: 0,
+            callback_recycle_cap: 0,
+            time_source: TimeSource::Wall,
+            local_queue_capacity: 0,
+            stall_threshold: None,
+            health_check: false,
+            worker_restart_interval: None,
+            on_task_panic: None,
+            core_idle_time: Duration::from_millis(0),
+            extra_idle_time: None,
+            injector_shards: 1,
+            partition_fn: None,
+            inline_if_no_workers: false,
+            context_hook: None,
+            woken_task_order: WokenTaskOrder::Spawned,
+            shed_policy: None,
+            deterministic_seed: None,
+            track_queued_tasks: false,
         }
     }
 }
@@ -60,14 +431,56 @@ impl Clone for SchedConfig {
             min_thread_count: self.min_thread_count,
             core_thread_count: AtomicUsize::new(self.core_thread_count.load(Ordering::SeqCst)),
             max_inplace_spin: self.max_inplace_spin,
+            max_pop_spin: self.max_pop_spin,
+            max_pop_yield: self.max_pop_yield,
             max_idle_time: self.max_idle_time,
             max_wait_time: self.max_wait_time,
             wake_backoff: self.wake_backoff,
             alloc_slot_backoff: self.alloc_slot_backoff,
+            min_awake_under_load: self.min_awake_under_load,
+            overflow_policy: self.overflow_policy,
+            burst_thread_count: self.burst_thread_count,
+            burst_queue_threshold: self.burst_queue_threshold,
+            callback_recycle_cap: self.callback_recycle_cap,
+            time_source: self.time_source,
+            local_queue_capacity: self.local_queue_capacity,
+            stall_threshold: self.stall_threshold,
+            health_check: self.health_check,
+            worker_restart_interval: self.worker_restart_interval,
+            on_task_panic: self.on_task_panic.clone(),
+            core_idle_time: self.core_idle_time,
+            extra_idle_time: self.extra_idle_time,
+            injector_shards: self.injector_shards,
+            partition_fn: self.partition_fn.clone(),
+            inline_if_no_workers: self.inline_if_no_workers,
+            context_hook: self.context_hook.clone(),
+            woken_task_order: self.woken_task_order,
+            shed_policy: self.shed_policy,
+            deterministic_seed: self.deterministic_seed,
+            track_queued_tasks: self.track_queued_tasks,
         }
     }
 }
 
+/// A worker of a thread pool that runs on a thread created by the caller, see
+/// [`LazyBuilder::build_attached`].
+pub struct AttachedWorker {
+    index: usize,
+    run: Box<dyn FnOnce() + Send>,
+}
+
+impl AttachedWorker {
+    /// Returns the index of the worker, which is the index of its local queue.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Runs the worker on the current thread until the pool is shut down.
+    pub fn run(self) {
+        (self.run)()
+    }
+}
+
 /// A builder for lazy spawning.
 pub struct LazyBuilder<T> {
     builder: Builder,
@@ -88,36 +501,451 @@ impl<T> LazyBuilder<T>
 where
     T: TaskCell + Send + 'static,
 {
+    /// Pushes `tasks` to the global queue in order, so they are queued before
+    /// any worker starts and are picked in FIFO order once workers come
+    /// alive.
+    pub fn with_initial_tasks<I>(self, tasks: I) -> LazyBuilder<T>
+    where
+        I: IntoIterator,
+        I::Item: WithExtras<T>,
+    {
+        let remote = Remote::new(self.core.clone());
+        for t in tasks {
+            remote.spawn_queued(t);
+        }
+        self
+    }
+
     /// Spawns all the required threads.
     ///
     /// There will be `max_thread_count` threads spawned. Generally only a few
     /// will keep running in the background, most of them are put to sleep
-    /// immediately.
-    pub fn build<F>(self, mut factory: F) -> ThreadPool<T>
+    /// immediately. Dead workers are not restarted, use `build_supervised`
+    /// for `Builder::restart_dead_workers`.
+    pub fn build<F>(self, factory: F) -> ThreadPool<T>
+    where
+        F: RunnerBuilder,
+        F::Runner: Runner<TaskCell = T> + Send + 'static,
+    {
+        self.build_impl(factory, None, None, None)
+    }
+
+    /// Same as `build`, but restarts dead workers if
+    /// `Builder::restart_dead_workers` is set.
+    ///
+    /// `factory` is moved to the supervisor thread to build runners for the
+    /// restarted workers, so it needs to be `Send`.
+    pub fn build_supervised<F>(self, factory: F) -> ThreadPool<T>
+    where
+        F: RunnerBuilder + Send + 'static,
+        F::Runner: Runner<TaskCell = T> + Send + 'static,
+    {
+        self.build_impl(factory, None, None, Some(|f| Box::new(f)))
+    }
+
+    /// Same as `build`, but blocks until every worker has started its runner.
+    ///
+    /// Workers are fully initialized when it returns, so tasks spawned right
+    /// after don't wait for threads to start up, which avoids latency spikes
+    /// of the first requests.
+    pub fn build_prewarmed<F>(self, factory: F) -> ThreadPool<T>
+    where
+        F: RunnerBuilder,
+        F::Runner: Runner<TaskCell = T> + Send + 'static,
+    {
+        let thread_count = self.builder.sched_config.max_thread_count;
+        let barrier = Arc::new(Barrier::new(thread_count + 1));
+        let pool = self.build_impl(factory, Some(barrier.clone()), None, None);
+        barrier.wait();
+        pool
+    }
+
+    /// Same as `build`, but runs the workers on threads created by the caller
+    /// instead of spawning them.
+    ///
+    /// Each returned worker owns the local queue at its index, and becomes a
+    /// worker of the pool once `AttachedWorker::run` is called on a thread.
+    /// Tasks are not handled until the workers run. Burst workers and the
+    /// stall detector still spawn their own threads if they are enabled.
+    ///
+    /// Once a worker runs, `ThreadPool::shutdown` waits for it to exit. A
+    /// worker that is never run is not waited for. Prewarming as in
+    /// `build_prewarmed`, `Builder::restart_dead_workers`, stack sizes and
+    /// thread names don't apply to attached workers, as their threads are
+    /// owned by the caller.
+    pub fn build_attached<F>(self, factory: F) -> (ThreadPool<T>, Vec<AttachedWorker>)
     where
         F: RunnerBuilder,
         F::Runner: Runner<TaskCell = T> + Send + 'static,
     {
-        let mut threads = Vec::with_capacity(self.builder.sched_config.max_thread_count);
-        for (i, local_queue) in self.local_queues.into_iter().enumerate() {
+        let mut workers = Vec::new();
+        let pool = self.build_impl(factory, None, Some(&mut workers), None);
+        (pool, workers)
+    }
+
+    fn build_impl<F>(
+        self,
+        mut factory: F,
+        start_barrier: Option<Arc<Barrier>>,
+        mut attached: Option<&mut Vec<AttachedWorker>>,
+        into_restarter: Option<IntoRestarter<F>>,
+    ) -> ThreadPool<T>
+    where
+        F: RunnerBuilder,
+        F::Runner: Runner<TaskCell = T> + Send + 'static,
+    {
+        let thread_count = self.builder.sched_config.max_thread_count;
+        self.core.set_runner_type::<F::Runner>();
+        let threads = Arc::new(Mutex::new(Vec::with_capacity(thread_count)));
+        // Attached workers are counted once they run.
+        let spawned_count = if attached.is_some() { 0 } else { thread_count };
+        let live_workers = Arc::new(LiveWorkers::new(spawned_count));
+        let (dead_tx, dead_rx) = mpsc::channel();
+        let restart_interval = match (
+            self.builder.sched_config.worker_restart_interval,
+            into_restarter,
+        ) {
+            (Some(interval), Some(into_restarter)) => Some((interval, into_restarter)),
+            (Some(_), None) => {
+                warn!(
+                    "dead workers of {} are not restarted as the pool is not supervised",
+                    self.builder.name_prefix
+                );
+                None
+            }
+            (None, _) => None,
+        };
+        let dead_tx = restart_interval.map(|_| dead_tx);
+        let spin_counter = WORKER_SPIN_TOTAL.with_label_values(&[&self.builder.name_prefix]);
+        let mut local_queues = self.local_queues.into_iter().enumerate();
+        for (i, local_queue) in local_queues.by_ref().take(thread_count) {
             let runner = factory.build();
-            let name = format!("{}-{}", self.builder.name_prefix, i);
-            let mut builder = thread::Builder::new().name(name);
-            if let Some(size) = self.builder.stack_size {
+            let mut builder = thread::Builder::new().name(self.builder.thread_name(i));
+            if let Some(size) = self.builder.stack_size_of(i) {
                 builder = builder.stack_size(size)
             }
             let local = Local::new(i + 1, local_queue, self.core.clone());
-            let thd = WorkerThread::new(local, runner);
-            threads.push(
+            let mut thd = WorkerThread::new(local, runner, spin_counter.clone());
+            if let Some(barrier) = &start_barrier {
+                thd = thd.with_start_barrier(barrier.clone());
+            }
+            if let Some(attached) = attached.as_mut() {
+                let live_workers = live_workers.clone();
+                attached.push(AttachedWorker {
+                    index: i,
+                    run: Box::new(move || {
+                        // `shutdown` waits for the worker from now on.
+                        live_workers.count.fetch_add(1, Ordering::SeqCst);
+                        let _guard = ExitGuard(live_workers).enter();
+                        thd.run();
+                    }),
+                });
+                continue;
+            }
+            let guard = ExitGuard(live_workers.clone());
+            let dead_tx = dead_tx.clone();
+            threads.lock().unwrap().push(
                 builder
                     .spawn(move || {
-                        thd.run();
+                        let _guard = guard.enter();
+                        match dead_tx {
+                            Some(dead) => thd.run_supervised(dead),
+                            None => thd.run(),
+                        }
                     })
                     .unwrap(),
             );
         }
+        // The workers hold the only senders, so the supervisor exits once
+        // they have all exited.
+        drop(dead_tx);
+        // The rest local queues are for burst workers.
+        let core = &self.core;
+        let builder = &self.builder;
+        let idle: Vec<_> = local_queues
+            .map(|(i, local_queue)| {
+                let local = Local::new(i + 1, local_queue, core.clone());
+                BurstWorker {
+                    name: builder.thread_name(i),
+                    stack_size: builder.stack_size_of(i),
+                    thd: WorkerThread::new(local, factory.build(), spin_counter.clone()),
+                    started: false,
+                }
+            })
+            .collect();
+        if !idle.is_empty() {
+            self.core.set_burst_spawner(Box::new(BurstWorkers {
+                idle_time: self.builder.sched_config.extra_idle_time(),
+                idle: Arc::new(Mutex::new(idle)),
+                threads: Mutex::new(Vec::new()),
+            }));
+        }
+        let helpers = Arc::new(Mutex::new(Vec::new()));
+        if let Some((min_interval, into_restarter)) = restart_interval {
+            let builder = &self.builder;
+            let supervisor = Supervisor {
+                dead_rx,
+                factory: into_restarter(factory),
+                threads: threads.clone(),
+                live_workers: live_workers.clone(),
+                threads_of_workers: (0..thread_count)
+                    .map(|i| (builder.thread_name(i), builder.stack_size_of(i)))
+                    .collect(),
+                min_interval,
+            };
+            helpers.lock().unwrap().push(
+                thread::Builder::new()
+                    .name(format!("{}-supervisor", self.builder.name_prefix))
+                    .spawn(move || supervisor.run())
+                    .unwrap(),
+            );
+        }
+        if let Some(threshold) = self.builder.sched_config.stall_threshold {
+            let core = Arc::downgrade(&self.core);
+            helpers.lock().unwrap().push(
+                thread::Builder::new()
+                    .name(format!("{}-stall-detector", self.builder.name_prefix))
+                    .spawn(move || detect_stalls(core, threshold))
+                    .unwrap(),
+            );
+        }
         ThreadPool {
             remote: Remote::new(self.core),
+            threads,
+            helpers,
+            live_workers,
+        }
+    }
+}
+
+/// Builds runners for restarted workers on the supervisor thread.
+type Restarter<R> = Box<dyn RunnerBuilder<Runner = R> + Send>;
+
+/// Boxes the runner builder of a supervised pool.
+type IntoRestarter<F> = fn(F) -> Restarter<<F as RunnerBuilder>::Runner>;
+
+/// Restarts the workers that die of panics until all workers have exited.
+struct Supervisor<T, R> {
+    dead_rx: mpsc::Receiver<DeadWorker<T, R>>,
+    /// Builds fresh runners for restarted workers, as the runner of a dead
+    /// worker may be left in a broken state by the panic.
+    factory: Restarter<R>,
+    threads: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    live_workers: Arc<LiveWorkers>,
+    /// The names and stack sizes of the threads of workers.
+    threads_of_workers: Vec<(String, Option<usize>)>,
+    min_interval: Duration,
+}
+
+impl<T, R> Supervisor<T, R>
+where
+    T: TaskCell + Send + 'static,
+    R: Runner<TaskCell = T> + Send + 'static,
+{
+    fn run(mut self) {
+        let mut last_restart: Option<Instant> = None;
+        while let Ok(dead) = self.dead_rx.recv() {
+            if let Some(elapsed) = last_restart.map(|t| t.elapsed()) {
+                if elapsed < self.min_interval {
+                    thread::sleep(self.min_interval - elapsed);
+                }
+            }
+            last_restart = Some(Instant::now());
+            self.restart(dead);
+        }
+    }
+
+    fn restart(&mut self, dead: DeadWorker<T, R>) {
+        let DeadWorker { mut thd, tx } = dead;
+        // Holds the lock so the worker is either joined by `shutdown` or
+        // not restarted at all.
+        let mut threads = self.threads.lock().unwrap();
+        let id = thd.local().id();
+        if thd.local().core().is_shutdown() {
+            thd.clear();
+            return;
+        }
+        let (name, stack_size) = &self.threads_of_workers[id - 1];
+        warn!("worker thread {} died of a panic, restarting it", name);
+        let mut builder = thread::Builder::new().name(name.clone());
+        if let Some(size) = *stack_size {
+            builder = builder.stack_size(size);
+        }
+        thd.reset_runner(self.factory.build());
+        self.live_workers.count.fetch_add(1, Ordering::SeqCst);
+        thd.local().core().mark_alive(id);
+        thd.local().core().mark_woken();
+        let guard = ExitGuard(self.live_workers.clone());
+        threads.push(
+            builder
+                .spawn(move || {
+                    let _guard = guard.enter();
+                    thd.run_supervised(tx);
+                })
+                .unwrap(),
+        );
+    }
+}
+
+/// Checks the workers every half of `threshold` until the pool is shut down.
+fn detect_stalls<T>(core: Weak<QueueCore<T>>, threshold: Duration) {
+    let mut reported = Vec::new();
+    while let Some(core) = core.upgrade() {
+        if core.sleep_unless_shutdown(threshold / 2) {
+            return;
+        }
+        core.check_stalls(threshold, &mut reported);
+    }
+}
+
+/// A burst worker that can be resumed after its thread exits.
+struct BurstWorker<T, R> {
+    name: String,
+    stack_size: Option<usize>,
+    thd: WorkerThread<T, R>,
+    started: bool,
+}
+
+/// Spawns burst workers from a fixed set of idle workers.
+struct BurstWorkers<T, R> {
+    idle_time: Duration,
+    idle: Arc<Mutex<Vec<BurstWorker<T, R>>>>,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl<T, R> BurstSpawner for BurstWorkers<T, R>
+where
+    T: TaskCell + Send + 'static,
+    R: Runner<TaskCell = T> + Send + 'static,
+{
+    fn spawn(&self) -> bool {
+        let mut worker = match self.idle.lock().unwrap().pop() {
+            Some(worker) => worker,
+            None => return false,
+        };
+        let mut builder = thread::Builder::new().name(worker.name.clone());
+        if let Some(size) = worker.stack_size {
+            builder = builder.stack_size(size)
+        }
+        let idle = self.idle.clone();
+        let idle_time = self.idle_time;
+        let res = builder.spawn(move || {
+            let core = worker.thd.local().core().clone();
+            if worker.thd.run_until_idle(idle_time, worker.started) {
+                worker.started = true;
+                idle.lock().unwrap().push(worker);
+            }
+            core.burst_worker_exited();
+        });
+        match res {
+            Ok(handle) => {
+                let mut threads = self.threads.lock().unwrap();
+                threads.retain(|h| !h.is_finished());
+                threads.push(handle);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn shutdown(&self) {
+        let threads = mem::take(&mut *self.threads.lock().unwrap());
+        let curr_id = thread::current().id();
+        for j in threads {
+            if curr_id != j.thread().id() {
+                j.join().unwrap();
+            }
+        }
+        let idle = mem::take(&mut *self.idle.lock().unwrap());
+        for mut worker in idle {
+            if worker.started {
+                worker.thd.end();
+            }
+        }
+    }
+}
+
+/// A builder for thread pools that share the same worker threads.
+///
+/// Every queue added to it gets its own [`Remote`], while tasks of all queues
+/// are handled by the same `max_thread_count` threads, which poll the queues
+/// in turn. It caps the total concurrency of several small pools without
+/// mixing their tasks in one queue. Burst workers, scaling workers and the
+/// overflow policy are not supported by shared pools.
+pub struct SharedPoolBuilder {
+    builder: Builder,
+    queue_types: Vec<QueueType>,
+}
+
+impl SharedPoolBuilder {
+    /// Creates a builder using the configurations of `builder`.
+    pub fn new(builder: &Builder) -> SharedPoolBuilder {
+        SharedPoolBuilder {
+            builder: builder.clone(),
+            queue_types: Vec::new(),
+        }
+    }
+
+    /// Adds a queue to the pool. Queues are indexed in the order they are
+    /// added.
+    pub fn queue(mut self, queue_type: impl Into<QueueType>) -> SharedPoolBuilder {
+        self.queue_types.push(queue_type.into());
+        self
+    }
+
+    /// Spawns the shared threads.
+    ///
+    /// Every thread builds one runner for each queue with `runner_builder`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no queue is added.
+    pub fn build<T, B>(self, mut runner_builder: B) -> SharedThreadPool<T>
+    where
+        T: TaskCell + Send + 'static,
+        B: RunnerBuilder,
+        B::Runner: Runner<TaskCell = T> + Send + 'static,
+    {
+        assert!(!self.queue_types.is_empty(), "no queue is added");
+        let builder = self.builder;
+        let thread_count = builder.sched_config.max_thread_count;
+        let group = Arc::new(WorkerGroup::new(thread_count));
+        let mut cores = Vec::with_capacity(self.queue_types.len());
+        let mut local_queues = Vec::with_capacity(self.queue_types.len());
+        for queue_type in self.queue_types {
+            let shards = builder.sched_config.injector_shards;
+            let seed = builder.sched_config.deterministic_seed;
+            let (injector, locals) = queue::build_seeded(queue_type, thread_count, shards, seed);
+            let mut config = builder.sched_config.clone();
+            config.burst_thread_count = 0;
+            config.overflow_policy = OverflowPolicy::Queue;
+            let core = QueueCore::new(injector, config)
+                .with_metrics_name(&builder.name_prefix)
+                .with_worker_group(group.clone());
+            cores.push(Arc::new(core));
+            local_queues.push(locals.into_iter());
+        }
+        let spin_counter = WORKER_SPIN_TOTAL.with_label_values(&[&builder.name_prefix]);
+        let mut threads = Vec::with_capacity(thread_count);
+        for i in 0..thread_count {
+            let queues = cores
+                .iter()
+                .zip(&mut local_queues)
+                .map(|(core, locals)| {
+                    let local = Local::new(i + 1, locals.next().unwrap(), core.clone());
+                    (local, runner_builder.build())
+                })
+                .collect();
+            let thd = SharedWorkerThread::new(i + 1, queues, group.clone(), spin_counter.clone());
+            let mut thread_builder = thread::Builder::new().name(builder.thread_name(i));
+            if let Some(size) = builder.stack_size_of(i) {
+                thread_builder = thread_builder.stack_size(size)
+            }
+            threads.push(thread_builder.spawn(move || thd.run()).unwrap());
+        }
+        SharedThreadPool {
+            remotes: cores.into_iter().map(Remote::new).collect(),
+            group,
             threads: Mutex::new(threads),
         }
     }
@@ -127,7 +955,9 @@ where
 #[derive(Clone)]
 pub struct Builder {
     name_prefix: String,
+    name_fn: Option<Arc<dyn Fn(usize) -> String + Send + Sync>>,
     stack_size: Option<usize>,
+    stack_sizes: Vec<usize>,
     sched_config: SchedConfig,
 }
 
@@ -136,16 +966,19 @@ impl Builder {
     pub fn new(name_prefix: impl Into<String>) -> Builder {
         Builder {
             name_prefix: name_prefix.into(),
+            name_fn: None,
             stack_size: None,
+            stack_sizes: Vec::new(),
             sched_config: SchedConfig::default(),
         }
     }
 
     /// Sets the maximum number of running threads at the same time.
+    ///
+    /// 0 spawns no worker threads, which is only allowed along with
+    /// `inline_if_no_workers`. Otherwise it falls back to the default.
     pub fn max_thread_count(&mut self, count: usize) -> &mut Self {
-        if count > 0 {
-            self.sched_config.max_thread_count = count;
-        }
+        self.sched_config.max_thread_count = count;
         self
     }
 
@@ -178,6 +1011,25 @@ impl Builder {
         self
     }
 
+    /// Sets the number of times a worker that finds no task spins before it
+    /// starts yielding, 3 by default.
+    ///
+    /// The busy loops double on every spin, so large counts are capped.
+    pub fn max_pop_spin(&mut self, count: usize) -> &mut Self {
+        self.sched_config.max_pop_spin = count;
+        self
+    }
+
+    /// Sets the number of times a worker that finds no task yields its
+    /// thread before it goes to sleep, 7 by default.
+    ///
+    /// Machines with many cores may prefer fewer yields, which makes idle
+    /// workers sleep sooner.
+    pub fn max_pop_yield(&mut self, count: usize) -> &mut Self {
+        self.sched_config.max_pop_yield = count;
+        self
+    }
+
     /// Sets the maximum allowed idle time for a thread. Thread will only be
     /// woken up when algorithm thinks it needs more worker.
     pub fn max_idle_time(&mut self, time: Duration) -> &mut Self {
@@ -185,6 +1037,85 @@ impl Builder {
         self
     }
 
+    /// Sets how long the first `min_thread_count` workers keep looking for
+    /// tasks before going to sleep.
+    ///
+    /// Other workers go to sleep as soon as they find no tasks. Core workers
+    /// never exit, so it only lowers their wake latency at the cost of CPU.
+    pub fn core_idle_time(&mut self, time: Duration) -> &mut Self {
+        self.sched_config.core_idle_time = time;
+        self
+    }
+
+    /// Sets how long burst workers stay idle before exiting. It's
+    /// `max_idle_time` by default.
+    pub fn extra_idle_time(&mut self, time: Duration) -> &mut Self {
+        self.sched_config.extra_idle_time = Some(time);
+        self
+    }
+
+    /// Sets the number of shards of the global queue, 1 by default.
+    ///
+    /// Tasks spawned by the same thread go to the same shard and workers
+    /// steal from all the shards, so more shards reduce contention when many
+    /// threads spawn tasks concurrently, but tasks spawned by different
+    /// threads may not be handled in the order they are spawned. Only the
+    /// single level queue supports sharding.
+    pub fn injector_shards(&mut self, shards: usize) -> &mut Self {
+        if shards > 0 {
+            self.sched_config.injector_shards = shards;
+        }
+        self
+    }
+
+    /// Sets a function choosing the worker to handle a task by its extras,
+    /// for example by a key in the metadata, so tasks of the same key share
+    /// the caches of one worker.
+    ///
+    /// Tasks that would go to the global queue are pushed to the worker at
+    /// `f(extras) % max_thread_count` instead, like `Remote::spawn_on`.
+    /// Partitioned tasks are never stolen by other workers, so they wait for
+    /// their worker even when others are idle. They go to the global queue
+    /// only if their worker has exited because its runner panicked on start.
+    /// Tasks spawned to a local queue are not affected. It's called on every
+    /// spawn, so it must be cheap.
+    pub fn partition_fn<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&Extras) -> usize + Send + Sync + 'static,
+    {
+        self.sched_config.partition_fn = Some(Arc::new(f));
+        self
+    }
+
+    /// Sets a pair of functions propagating a context, like a tracing span,
+    /// from the spawning thread to the task.
+    ///
+    /// `capture` is called on the spawning thread when a task is spawned, the
+    /// returned context is kept in the extras of the task. `restore` is called
+    /// with it on the worker before the task is handled, which covers every
+    /// poll of a future, and with the context captured on the worker right
+    /// before that after the task is handled, even if it panics. Tasks
+    /// spawned without a context, like those run on the caller, are handled
+    /// as usual.
+    pub fn context_hook<C, F, G>(&mut self, capture: F, restore: G) -> &mut Self
+    where
+        C: Send + 'static,
+        F: Fn() -> C + Send + Sync + 'static,
+        G: Fn(&C) + Send + Sync + 'static,
+    {
+        self.sched_config.context_hook = Some(ContextHook {
+            // The context is only required to be `Send`, the mutex makes it
+            // shareable along with the extras.
+            capture: Arc::new(move || Arc::new(Mutex::new(capture()))),
+            restore: Arc::new(move |ctx| {
+                if let Some(ctx) = ctx.downcast_ref::<Mutex<C>>() {
+                    restore(&ctx.lock().unwrap_or_else(|e| e.into_inner()));
+                }
+            }),
+        });
+        self
+    }
+
     /// Sets the maximum time to wait for a task before increasing the
     /// running thread slots.
     pub fn max_wait_time(&mut self, time: Duration) -> &mut Self {
@@ -192,15 +1123,233 @@ impl Builder {
         self
     }
 
-    /// Sets the minimum interval between waking a thread.
-    pub fn wake_backoff(&mut self, time: Duration) -> &mut Self {
-        self.sched_config.wake_backoff = time;
+    /// Sets the minimum interval between waking a thread, either a duration
+    /// or a [`BackoffStrategy`].
+    ///
+    /// It only applies when some threads are awake. A wake within the
+    /// interval is not dropped but deferred until the interval elapses.
+    /// Exponential backoff reduces wake storms under bursts of tasks.
+    pub fn wake_backoff(&mut self, backoff: impl Into<BackoffStrategy>) -> &mut Self {
+        self.sched_config.wake_backoff = backoff.into();
+        self
+    }
+
+    /// Sets the minimum interval between spawning burst threads once the
+    /// first one is spawned, either a duration or a [`BackoffStrategy`].
+    pub fn alloc_slot_backoff(&mut self, backoff: impl Into<BackoffStrategy>) -> &mut Self {
+        self.sched_config.alloc_slot_backoff = backoff.into();
+        self
+    }
+
+    /// Sets whether to keep at least `min_thread_count` threads awake as long
+    /// as there are pending tasks in the global queue.
+    ///
+    /// By default, only one thread is woken up for every spawned task, so the
+    /// number of awake threads may oscillate under a steady load. Enabling it
+    /// reduces the latency of waking up threads at the cost of more idle CPU.
+    pub fn min_awake_under_load(&mut self, enable: bool) -> &mut Self {
+        self.sched_config.min_awake_under_load = enable;
+        self
+    }
+
+    /// Sets whether to run tasks on the spawning thread when the pool has no
+    /// worker threads.
+    ///
+    /// A pool with `max_thread_count` 0 never runs queued tasks. With this
+    /// enabled, callback tasks run synchronously on the caller of `spawn`
+    /// instead. Futures can't be run in place, they stay in the queue until
+    /// they are driven by other means.
+    pub fn inline_if_no_workers(&mut self, enable: bool) -> &mut Self {
+        self.sched_config.inline_if_no_workers = enable;
+        self
+    }
+
+    /// Seeds the random decisions of the scheduler to make them reproducible.
+    ///
+    /// It covers the order of stealing from other workers and choosing the
+    /// level to pop in multilevel queues, but not the timing of threads. So
+    /// the order of handling tasks is only fully reproducible when a single
+    /// worker drives the queue, like in tests.
+    pub fn deterministic_seed(&mut self, seed: u64) -> &mut Self {
+        self.sched_config.deterministic_seed = Some(seed);
+        self
+    }
+
+    /// Sets whether to keep track of the queued tasks for
+    /// [`Remote::snapshot_queue`].
+    ///
+    /// It's disabled by default as it costs a map update every time a task
+    /// is pushed or popped.
+    pub fn track_queued_tasks(&mut self, enable: bool) -> &mut Self {
+        self.sched_config.track_queued_tasks = enable;
+        self
+    }
+
+    /// Sets what to do with a new task when all workers are busy.
+    ///
+    /// `OverflowPolicy::RunOnCaller` bounds the latency of tasks by borrowing
+    /// the spawning thread, which is blocked until the task finishes.
+    pub fn overflow_policy(&mut self, policy: OverflowPolicy) -> &mut Self {
+        self.sched_config.overflow_policy = policy;
+        self
+    }
+
+    /// Sets where a future woken up on a worker is queued, relative to the
+    /// tasks spawned on the worker.
+    ///
+    /// `WokenTaskOrder::Front` lets futures that are already in progress
+    /// finish sooner, while `WokenTaskOrder::Back` lets new work start sooner.
+    /// Futures woken up outside the workers always go to the global queue.
+    pub fn woken_task_order(&mut self, order: WokenTaskOrder) -> &mut Self {
+        self.sched_config.woken_task_order = order;
         self
     }
 
-    /// Sets the minimum interval between increasing running threads.
-    pub fn alloc_slot_backoff(&mut self, time: Duration) -> &mut Self {
-        self.sched_config.alloc_slot_backoff = time;
+    /// Makes `Remote::try_spawn` reject tasks at `min_level` or higher when
+    /// there are more than `high_water` pending tasks.
+    ///
+    /// Levels are the ones of the multilevel and priority queues, where a
+    /// higher level means a lower priority. Other queues keep tasks at their
+    /// fixed level, or level 0. Shedding low priority tasks early keeps the
+    /// latency of the others when the pool is overloaded. `spawn` is not
+    /// affected.
+    pub fn shed_overload(&mut self, high_water: usize, min_level: u8) -> &mut Self {
+        self.sched_config.shed_policy = Some(ShedPolicy {
+            high_water,
+            min_level,
+        });
+        self
+    }
+
+    /// Sets the maximum number of extra threads that can be spawned when the
+    /// global queue keeps backlogged.
+    ///
+    /// A burst thread is spawned when the global queue has had more than
+    /// `burst_queue_threshold` tasks for longer than `max_wait_time`, and
+    /// more are spawned by `alloc_slot_backoff` while it stays so. Burst
+    /// threads exit after being idle for `max_idle_time` instead of going to
+    /// sleep. They are not limited by `max_thread_count`.
+    pub fn burst_thread_count(&mut self, count: usize) -> &mut Self {
+        self.sched_config.burst_thread_count = count;
+        self
+    }
+
+    /// Sets the number of queued tasks above which the global queue is
+    /// considered backlogged.
+    pub fn burst_queue_threshold(&mut self, count: usize) -> &mut Self {
+        self.sched_config.burst_queue_threshold = count;
+        self
+    }
+
+    /// Sets the maximum number of freed callback task allocations each worker
+    /// keeps for reuse.
+    ///
+    /// Small [`FnOnce`] callbacks spawned on a worker reuse the memory of
+    /// finished ones instead of allocating, which reduces the pressure on the
+    /// allocator for callback heavy workloads. It has no effect on other kinds
+    /// of tasks, nor on custom runners wrapping [`callback::Runner`] that
+    /// don't forward [`Runner::start`] to it.
+    ///
+    /// [`callback::Runner`]: crate::task::callback::Runner
+    pub fn callback_recycle_cap(&mut self, cap: usize) -> &mut Self {
+        self.sched_config.callback_recycle_cap = cap;
+        self
+    }
+
+    /// Sets the clock used to measure how long tasks run.
+    ///
+    /// Multilevel queues demote tasks by their running time. With
+    /// `TimeSource::ThreadCpu`, tasks that block on I/O are not demoted for
+    /// the time they are blocked.
+    pub fn time_source(&mut self, source: TimeSource) -> &mut Self {
+        self.sched_config.time_source = source;
+        self
+    }
+
+    /// Sets the function to name the spawned threads by worker index.
+    ///
+    /// Threads are named "prefix-index" by default. The returned names
+    /// should be unique to tell the workers apart.
+    pub fn thread_name_fn<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(usize) -> String + Send + Sync + 'static,
+    {
+        self.name_fn = Some(Arc::new(f));
+        self
+    }
+
+    fn thread_name(&self, index: usize) -> String {
+        match &self.name_fn {
+            Some(f) => f(index),
+            None => format!("{}-{}", self.name_prefix, index),
+        }
+    }
+
+    /// Sets the maximum number of tasks in the local queue of each worker.
+    ///
+    /// Tasks spawned locally, including woken up futures, are pushed to the
+    /// global queue instead when the local queue is full, so other workers can
+    /// pick them up. 0 means unbounded, which is the default.
+    pub fn local_queue_capacity(&mut self, capacity: usize) -> &mut Self {
+        self.sched_config.local_queue_capacity = capacity;
+        self
+    }
+
+    /// Enables a watchdog thread that logs a warning and increases
+    /// `yatp_worker_stall_total` when a worker handles a single task for
+    /// longer than `threshold`.
+    ///
+    /// It's usually caused by blocking calls in async tasks, which stall all
+    /// the other tasks of the worker. The watchdog checks the workers every
+    /// half of `threshold`, so a stall is reported at most once and up to 1.5
+    /// times `threshold` late. A zero `threshold` disables it.
+    pub fn stall_detector(&mut self, threshold: Duration) -> &mut Self {
+        self.sched_config.stall_threshold = Some(threshold).filter(|t| !t.is_zero());
+        self
+    }
+
+    /// Enables `ThreadPool::is_healthy` by tracking when workers start
+    /// handling tasks, which costs a clock read per task.
+    ///
+    /// The stall detector tracks the same, so it's not needed if the stall
+    /// detector is enabled.
+    pub fn health_check(&mut self, enable: bool) -> &mut Self {
+        self.sched_config.health_check = enable;
+        self
+    }
+
+    /// Restarts workers that die of panics, like a panicking callback, on
+    /// new threads with the same names.
+    ///
+    /// A restarted worker keeps its local queue, so the tasks queued on it
+    /// are not lost, and gets a new runner built by the runner builder of the
+    /// pool. Until then, tasks pinned to it go to the global queue. At most
+    /// one worker is restarted every `min_interval`, which avoids respawn
+    /// storms when tasks keep panicking. Workers run on attached threads are
+    /// not restarted.
+    ///
+    /// It only applies to pools built by the `build_*_pool` methods or
+    /// `LazyBuilder::build_supervised`, as the runner builder is moved to the
+    /// thread restarting the workers.
+    pub fn restart_dead_workers(&mut self, min_interval: Duration) -> &mut Self {
+        self.sched_config.worker_restart_interval = Some(min_interval);
+        self
+    }
+
+    /// Sets a function called with the panics of tasks on the worker.
+    ///
+    /// A panicking task is dropped and its panic is passed to `f` instead of
+    /// unwinding the worker, which goes on handling other tasks with the same
+    /// runner, so the runner must stay usable after `handle` panics. With the
+    /// `task-backtrace` feature, a panic hook captures the backtrace at the
+    /// panic point for [`TaskPanic::backtrace`]. It's installed in front of
+    /// the current hook when the first task is handled, so a hook set later
+    /// must call the previous one to keep backtraces captured.
+    pub fn on_task_panic<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&TaskPanic) + Send + Sync + 'static,
+    {
+        self.sched_config.on_task_panic = Some(Arc::new(f));
         self
     }
 
@@ -212,6 +1361,35 @@ impl Builder {
         self
     }
 
+    /// Sets the stack sizes of the spawned threads by worker index.
+    ///
+    /// The i-th worker uses `sizes[i % sizes.len()]`, which overrides the
+    /// size set by `stack_size`. It allows reserving a few workers with big
+    /// stacks for deep recursions without enlarging all of them. Every size
+    /// must be greater than 0, otherwise freezing the builder panics.
+    pub fn stack_sizes(&mut self, sizes: Vec<usize>) -> &mut Self {
+        self.stack_sizes = sizes;
+        self
+    }
+
+    fn stack_size_of(&self, index: usize) -> Option<usize> {
+        if self.stack_sizes.is_empty() {
+            return self.stack_size;
+        }
+        Some(self.stack_sizes[index % self.stack_sizes.len()])
+    }
+
+    /// Checks whether the configurations are valid.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.name_prefix.trim().is_empty() {
+            return Err(ConfigError::EmptyName);
+        }
+        if self.stack_sizes.contains(&0) {
+            return Err(ConfigError::ZeroStackSize);
+        }
+        Ok(())
+    }
+
     /// Freezes the configurations and returns the task scheduler and
     /// a builder to for lazy spawning threads.
     ///
@@ -240,7 +1418,20 @@ impl Builder {
     where
         T: TaskCell + Send,
     {
-        assert!(self.sched_config.min_thread_count <= self.sched_config.max_thread_count);
+        if self.sched_config.max_thread_count == 0 && !self.sched_config.inline_if_no_workers {
+            let mut builder = self.clone();
+            builder.sched_config.max_thread_count = SchedConfig::default().max_thread_count;
+            return builder.freeze_with_queue(queue_type);
+        }
+        assert!(
+            self.sched_config.max_thread_count == 0
+                || self.sched_config.min_thread_count <= self.sched_config.max_thread_count
+        );
+        assert!(
+            !self.stack_sizes.contains(&0),
+            "{}",
+            ConfigError::ZeroStackSize
+        );
         let core_thread_count = self.sched_config.core_thread_count.load(Ordering::SeqCst);
         if core_thread_count == 0 || core_thread_count > self.sched_config.max_thread_count {
             self.sched_config
@@ -251,8 +1442,16 @@ impl Builder {
                 .core_thread_count
                 .store(self.sched_config.min_thread_count, Ordering::SeqCst);
         }
-        let (injector, local_queues) = queue::build(queue_type, self.sched_config.max_thread_count);
-        let core = Arc::new(QueueCore::new(injector, self.sched_config.clone()));
+        let (injector, local_queues) = queue::build_seeded(
+            queue_type,
+            self.sched_config.max_thread_count + self.sched_config.burst_thread_count,
+            self.sched_config.injector_shards,
+            self.sched_config.deterministic_seed,
+        );
+        let core = Arc::new(
+            QueueCore::new(injector, self.sched_config.clone())
+                .with_metrics_name(&self.name_prefix),
+        );
 
         (
             Remote::new(core.clone()),
@@ -264,12 +1463,28 @@ impl Builder {
         )
     }
 
+    /// Same as `freeze_with_queue`, but validates the configurations and the
+    /// queue type first and returns an error if they are invalid.
+    pub fn try_freeze_with_queue<T>(
+        &self,
+        queue_type: QueueType,
+    ) -> Result<(Remote<T>, LazyBuilder<T>), ConfigError>
+    where
+        T: TaskCell + Send,
+    {
+        self.validate()?;
+        if let QueueType::StrictPriority { classes: 0 } = queue_type {
+            return Err(ConfigError::NoPriorityClasses);
+        }
+        Ok(self.freeze_with_queue(queue_type))
+    }
+
     /// Spawns a callback pool.
     ///
     /// It setups the pool with single level queue.
     pub fn build_callback_pool(&self) -> ThreadPool<callback::TaskCell> {
         let rb = CloneRunnerBuilder(callback::Runner::default());
-        self.build_with_queue_and_runner(QueueType::SingleLevel, rb)
+        self.build_supervised_with_queue_and_runner(QueueType::SingleLevel, rb)
     }
 
     /// Spawns a future pool.
@@ -277,7 +1492,17 @@ impl Builder {
     /// It setups the pool with single level queue.
     pub fn build_future_pool(&self) -> ThreadPool<future::TaskCell> {
         let fb = CloneRunnerBuilder(future::Runner::default());
-        self.build_with_queue_and_runner(QueueType::SingleLevel, fb)
+        self.build_supervised_with_queue_and_runner(QueueType::SingleLevel, fb)
+    }
+
+    /// Same as `build_future_pool`, but also returns a handle that can only
+    /// spawn futures to the pool.
+    pub fn build_future_pool_with_handle(
+        &self,
+    ) -> (ThreadPool<future::TaskCell>, SpawnHandle<future::TaskCell>) {
+        let pool = self.build_future_pool();
+        let handle = pool.spawn_handle();
+        (pool, handle)
     }
 
     /// Spawns a multilevel future pool.
@@ -287,7 +1512,10 @@ impl Builder {
         let fb = CloneRunnerBuilder(future::Runner::default());
         let queue_builder = multilevel::Builder::new(multilevel::Config::default());
         let runner_builder = queue_builder.runner_builder(fb);
-        self.build_with_queue_and_runner(QueueType::Multilevel(queue_builder), runner_builder)
+        self.build_supervised_with_queue_and_runner(
+            QueueType::Multilevel(queue_builder),
+            runner_builder,
+        )
     }
 
     /// Spawn a priority future pool.
@@ -300,7 +1528,10 @@ impl Builder {
         let fb = CloneRunnerBuilder(future::Runner::default());
         let queue_builder = priority::Builder::new(priority::Config::default(), priority_provider);
         let runner_builder = queue_builder.runner_builder(fb);
-        self.build_with_queue_and_runner(QueueType::Priority(queue_builder), runner_builder)
+        self.build_supervised_with_queue_and_runner(
+            QueueType::Priority(queue_builder),
+            runner_builder,
+        )
     }
 
     /// Spawns the thread pool immediately.
@@ -319,4 +1550,39 @@ impl Builder {
     {
         self.freeze_with_queue(queue_type).1.build(runner_builder)
     }
+
+    /// Same as `build_with_queue_and_runner`, but restarts dead workers if
+    /// `restart_dead_workers` is set. See `LazyBuilder::build_supervised`.
+    pub fn build_supervised_with_queue_and_runner<T, B>(
+        &self,
+        queue_type: QueueType,
+        runner_builder: B,
+    ) -> ThreadPool<T>
+    where
+        T: TaskCell + Send + 'static,
+        B: RunnerBuilder + Send + 'static,
+        B::Runner: Runner<TaskCell = T> + Send + 'static,
+    {
+        self.freeze_with_queue(queue_type)
+            .1
+            .build_supervised(runner_builder)
+    }
+
+    /// Same as `build_with_queue_and_runner`, but validates the configurations
+    /// first and returns an error if they are invalid.
+    pub fn try_build_with_queue_and_runner<T, B>(
+        &self,
+        queue_type: QueueType,
+        runner_builder: B,
+    ) -> Result<ThreadPool<T>, ConfigError>
+    where
+        T: TaskCell + Send + 'static,
+        B: RunnerBuilder,
+        B::Runner: Runner<TaskCell = T> + Send + 'static,
+    {
+        Ok(self
+            .try_freeze_with_queue(queue_type)?
+            .1
+            .build(runner_builder))
+    }
 }
@@ -0,0 +1,95 @@
+// Copyright 2026 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Pools built under a shared namespace.
+
+use super::{shutdown_pool, Builder, ThreadPool};
+use crate::queue::TaskCell;
+use std::sync::Mutex;
+
+type ShutdownFn = Box<dyn Fn() + Send + Sync>;
+
+/// A group of thread pools sharing a namespace.
+///
+/// Every pool in the group is named `{namespace}-{name}`, which is used as
+/// the name label of its metrics and the prefix of its thread names. Names
+/// are checked when pools are built, so two pools in a group never report
+/// metrics under the same label.
+///
+/// ```
+/// use yatp::pool::PoolGroup;
+///
+/// let group = PoolGroup::new("app");
+/// let io = group.build("io", |b| b.max_thread_count(2).build_future_pool());
+/// let cpu = group.build("cpu", |b| b.build_callback_pool());
+/// io.spawn(async {});
+/// cpu.spawn(|_: &mut yatp::task::callback::Handle<'_>| {});
+/// group.shutdown_all();
+/// ```
+pub struct PoolGroup {
+    namespace: String,
+    members: Mutex<Vec<(String, ShutdownFn)>>,
+}
+
+impl PoolGroup {
+    /// Creates an empty group.
+    pub fn new(namespace: impl Into<String>) -> PoolGroup {
+        PoolGroup {
+            namespace: namespace.into(),
+            members: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Builds a pool named `name` in the group.
+    ///
+    /// `build` is given a builder already named after the group, and should
+    /// configure it and build the pool with it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a pool with the same name has been built in the group.
+    pub fn build<T, F>(&self, name: &str, build: F) -> ThreadPool<T>
+    where
+        T: TaskCell + Send,
+        F: FnOnce(&mut Builder) -> ThreadPool<T>,
+    {
+        let full_name = format!("{}-{}", self.namespace, name);
+        let exists =
+            |members: &[(String, ShutdownFn)]| members.iter().any(|(n, _)| *n == full_name);
+        // The lock is not held while building, so a panic doesn't poison it.
+        if exists(&self.members.lock().unwrap()) {
+            panic!("pool {} already exists", full_name);
+        }
+        let pool = build(&mut Builder::new(full_name.clone()));
+        let mut members = self.members.lock().unwrap();
+        if exists(&members) {
+            drop(members);
+            panic!("pool {} already exists", full_name);
+        }
+        let remote = pool.remote.clone();
+        let threads = pool.threads.clone();
+        let helpers = pool.helpers.clone();
+        let live_workers = pool.live_workers.clone();
+        members.push((
+            full_name,
+            Box::new(move || shutdown_pool(&remote, &threads, &helpers, &live_workers)),
+        ));
+        pool
+    }
+
+    /// Returns the full names of the pools in the group, in the order they
+    /// are built.
+    pub fn names(&self) -> Vec<String> {
+        let members = self.members.lock().unwrap();
+        members.iter().map(|(n, _)| n.clone()).collect()
+    }
+
+    /// Shuts down every pool in the group, see [`ThreadPool::shutdown`].
+    ///
+    /// Pools are shut down one by one in the order they are built.
+    pub fn shutdown_all(&self) {
+        let members = self.members.lock().unwrap();
+        for (_, shutdown) in members.iter() {
+            shutdown();
+        }
+    }
+}
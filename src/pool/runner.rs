@@ -1,6 +1,7 @@
 // Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
 
 use crate::pool::Local;
+use crate::queue::TaskSource;
 
 /// In the model of yatp, any piece of logic aiming to be executed in a thread
 /// pool is called Task. There can be different definitions of Task. Some people
@@ -30,6 +31,22 @@ pub trait Runner {
     /// It's guaranteed to be the first method to call before anything else.
     fn start(&mut self, _local: &mut Local<Self::TaskCell>) {}
 
+    /// Called before pulling the next task to handle.
+    ///
+    /// Returns false to tell the scheduler the runner can't accept more tasks
+    /// right now, the worker will back off for a while and ask again. To avoid
+    /// wedging the worker, it will continue to handle tasks if the runner
+    /// keeps refusing after several retries.
+    fn can_accept(&self, _local: &Local<Self::TaskCell>) -> bool {
+        true
+    }
+
+    /// Called right before `handle` with where the task comes from.
+    ///
+    /// Runners doing NUMA or cache accounting can use it to tell local tasks
+    /// from stolen ones.
+    fn on_task_source(&mut self, _local: &mut Local<Self::TaskCell>, _source: TaskSource) {}
+
     /// Called when a task needs to be handled.
     ///
     /// It's possible that a task can't be finished in a single execution, in
@@ -4,14 +4,47 @@
 //! woken up when new tasks arrived and go to sleep when there are no
 //! tasks waiting to be handled.
 
-use crate::pool::SchedConfig;
-use crate::queue::{Extras, LocalQueue, Pop, TaskCell, TaskInjector, WithExtras};
+use crate::metrics::{
+    ACTIVE_WORKERS_COUNT, FIRST_POLL_LATENCY, INJECTOR_CONTENTION_TOTAL,
+    REPOLL_LIMIT_REACHED_TOTAL, TASKS_DROPPED_ON_SHUTDOWN, TASK_MIGRATION_TOTAL, WAKEUP_LATENCY,
+    WORKER_STALL_TOTAL, WORKER_START_PANIC_TOTAL,
+};
+use crate::pool::{BackoffStrategy, ConfigError, OverflowPolicy, SchedConfig};
+use crate::queue::{
+    multilevel, Extras, ExtrasSnapshot, IntoExtras, LocalQueue, Pop, QueueKind, QueueStats,
+    TaskCell, TaskContext, TaskInjector, TaskSource, WithExtras,
+};
+use crate::task::callback;
+use crate::timer::{self, TimerHandle};
+use crossbeam_deque::{Injector, Steal};
+use crossbeam_utils::CachePadded;
+use dashmap::DashMap;
 use fail::fail_point;
-use parking_lot_core::{FilterOp, ParkResult, ParkToken, UnparkToken};
+use log::warn;
+use parking_lot_core::{FilterOp, ParkResult, ParkToken, UnparkToken, DEFAULT_PARK_TOKEN};
+use prometheus::{Histogram, HistogramOpts, IntCounter};
+use std::any::{Any, TypeId};
+use std::collections::{HashMap, VecDeque};
 use std::sync::{
-    atomic::{AtomicUsize, Ordering},
-    Arc, Weak,
+    atomic::{AtomicBool, AtomicIsize, AtomicU64, AtomicUsize, Ordering},
+    Arc, Mutex, Weak,
 };
+use std::task::{Wake, Waker};
+use std::time::{Duration, Instant};
+
+/// Where a task is placed by [`Remote::spawn_reporting`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnPlacement {
+    /// The queue of the worker at the given index, either its local queue or
+    /// the queue of tasks pinned to it.
+    Local(usize),
+    /// The global queue shared by all workers.
+    Global,
+    /// The task is not queued. It's dropped as the pool is being drained or
+    /// shut down, or run on the calling thread because of
+    /// `OverflowPolicy::RunOnCaller`.
+    Rejected,
+}
 
 /// An usize is used to trace the threads that are working actively.
 /// To save additional memory and atomic operation, the number and
@@ -31,30 +64,702 @@ pub fn is_shutdown(cnt: usize) -> bool {
     cnt & SHUTDOWN_BIT == SHUTDOWN_BIT
 }
 
+/// The time constant of the exponentially weighted moving average of the
+/// spawn rate. Samples older than it have less than 1/e weight.
+const SPAWN_RATE_DECAY: Duration = Duration::from_secs(1);
+/// The minimum interval between two samples of the spawn rate.
+const SPAWN_RATE_MIN_INTERVAL: Duration = Duration::from_millis(10);
+
+struct SpawnRateSample {
+    time: Instant,
+    spawned: u64,
+    rate: Option<f64>,
+}
+
+/// Estimates the recent spawn rate.
+///
+/// Spawning only bumps a counter, the moving average is updated lazily when
+/// the rate is read.
+struct SpawnRate {
+    spawned: AtomicU64,
+    last: Mutex<SpawnRateSample>,
+}
+
+impl SpawnRate {
+    fn new() -> SpawnRate {
+        SpawnRate {
+            spawned: AtomicU64::new(0),
+            last: Mutex::new(SpawnRateSample {
+                time: Instant::now(),
+                spawned: 0,
+                rate: None,
+            }),
+        }
+    }
+
+    #[inline]
+    fn record(&self) {
+        self.spawned.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> f64 {
+        let mut last = self.last.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(last.time);
+        if elapsed < SPAWN_RATE_MIN_INTERVAL {
+            return last.rate.unwrap_or(0.0);
+        }
+        let spawned = self.spawned.load(Ordering::Relaxed);
+        let elapsed_secs = elapsed.as_secs_f64();
+        let current = (spawned - last.spawned) as f64 / elapsed_secs;
+        let rate = match last.rate {
+            Some(rate) => {
+                let alpha = 1.0 - (-elapsed_secs / SPAWN_RATE_DECAY.as_secs_f64()).exp();
+                rate + alpha * (current - rate)
+            }
+            None => current,
+        };
+        *last = SpawnRateSample {
+            time: now,
+            spawned,
+            rate: Some(rate),
+        };
+        rate
+    }
+}
+
+/// Rate limits an action of the scheduler by a [`BackoffStrategy`].
+///
+/// Times are nanoseconds since an instant plus 1, like
+/// `QueueCore::backlog_since`, so it can be driven by a mock clock.
+pub(crate) struct Backoff {
+    strategy: BackoffStrategy,
+    /// When the action is taken last time, 0 if it's never taken.
+    last: AtomicU64,
+    /// The interval to wait since `last` in nanoseconds.
+    interval: AtomicU64,
+}
+
+impl Backoff {
+    pub fn new(strategy: BackoffStrategy) -> Backoff {
+        Backoff {
+            strategy,
+            last: AtomicU64::new(0),
+            interval: AtomicU64::new(strategy.base().as_nanos() as u64),
+        }
+    }
+
+    /// Takes the action at `now` if the interval has elapsed since the last
+    /// one, otherwise returns when it can be taken.
+    ///
+    /// The interval grows if the action is taken again within twice the
+    /// interval, and goes back to the base one after a calm period.
+    pub fn try_acquire(&self, now: u64) -> Result<(), u64> {
+        let last = self.last.load(Ordering::Relaxed);
+        let interval = self.interval.load(Ordering::Relaxed);
+        if last != 0 && now < last + interval {
+            return Err(last + interval);
+        }
+        if self
+            .last
+            .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+        {
+            // Taken by another thread right now.
+            return Err(now + interval);
+        }
+        let next = if last != 0 && now.saturating_sub(last) < interval * 2 {
+            self.strategy.grow(Duration::from_nanos(interval))
+        } else {
+            self.strategy.base()
+        };
+        self.interval
+            .store(next.as_nanos() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Forgets the last action, so the next one is taken at once with the
+    /// base interval.
+    pub fn reset(&self) {
+        if self.last.swap(0, Ordering::Relaxed) != 0 {
+            self.interval
+                .store(self.strategy.base().as_nanos() as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the interval to wait after the last action.
+    #[cfg(test)]
+    pub fn interval(&self) -> Duration {
+        Duration::from_nanos(self.interval.load(Ordering::Relaxed))
+    }
+}
+
+/// Rate limits waking up workers by `SchedConfig::wake_backoff`.
+///
+/// A throttled wake is deferred to the shared timer instead of being dropped,
+/// otherwise a task could wait in the queue for as long as the awake workers
+/// are busy.
+struct WakeThrottle {
+    backoff: Backoff,
+    /// The instant `backoff` measures time from.
+    created: Instant,
+    /// The address to unpark and the maximum token of the workers to unpark
+    /// when the deferred wake fires. It's cleared once the core is dropped.
+    target: Mutex<Option<(usize, usize)>>,
+    /// Whether a deferred wake is scheduled.
+    deferred: AtomicBool,
+}
+
+impl WakeThrottle {
+    fn now(&self) -> u64 {
+        self.created.elapsed().as_nanos() as u64 + 1
+    }
+}
+
+impl Wake for WakeThrottle {
+    fn wake(self: Arc<Self>) {
+        // The core can't be dropped while the lock is held.
+        let target = self.target.lock().unwrap();
+        self.deferred.store(false, Ordering::SeqCst);
+        let (addr, max_token) = match *target {
+            Some(t) => t,
+            None => return,
+        };
+        let _ = self.backoff.try_acquire(self.now());
+        let mut unparked = false;
+        unsafe {
+            parking_lot_core::unpark_filter(
+                addr,
+                |p: ParkToken| {
+                    if !unparked && p.0 <= max_token {
+                        unparked = true;
+                        FilterOp::Unpark
+                    } else {
+                        FilterOp::Skip
+                    }
+                },
+                |_| UnparkToken(0),
+            );
+        }
+    }
+}
+
+/// Owns the `WakeThrottle` of a core, and cancels its deferred wake when the
+/// core is dropped.
+struct WakeThrottleGuard {
+    throttle: Arc<WakeThrottle>,
+    timer: Mutex<Option<TimerHandle>>,
+}
+
+impl WakeThrottleGuard {
+    fn new(strategy: BackoffStrategy, created: Instant) -> WakeThrottleGuard {
+        WakeThrottleGuard {
+            throttle: Arc::new(WakeThrottle {
+                backoff: Backoff::new(strategy),
+                created,
+                target: Mutex::new(None),
+                deferred: AtomicBool::new(false),
+            }),
+            timer: Mutex::new(None),
+        }
+    }
+
+    /// Returns whether a worker can be woken up now, which is always true if
+    /// `force` is. If not, a wake of one of the workers parked on `addr`
+    /// whose tokens are not greater than `max_token` is scheduled for when
+    /// the interval elapses.
+    fn try_wake(&self, addr: usize, max_token: usize, force: bool) -> bool {
+        let at = match self.throttle.backoff.try_acquire(self.throttle.now()) {
+            Ok(()) => return true,
+            Err(_) if force => return true,
+            Err(at) => at,
+        };
+        if !self.throttle.deferred.swap(true, Ordering::SeqCst) {
+            *self.throttle.target.lock().unwrap() = Some((addr, max_token));
+            let deadline = self.throttle.created + Duration::from_nanos(at - 1);
+            let waker = Waker::from(self.throttle.clone());
+            *self.timer.lock().unwrap() = Some(timer::register(deadline, waker));
+        }
+        false
+    }
+}
+
+impl Drop for WakeThrottleGuard {
+    fn drop(&mut self) {
+        // Cancelling the timer also breaks the cycle through its waker.
+        drop(self.timer.get_mut().unwrap().take());
+        *self.throttle.target.lock().unwrap() = None;
+    }
+}
+
+/// Spawns and shuts down burst workers of a thread pool.
+///
+/// Burst workers are extra threads spawned when the global queue keeps
+/// backlogged, they exit once idle instead of going to sleep.
+pub(crate) trait BurstSpawner: Send {
+    /// Spawns a burst worker. Returns false if there is no worker available.
+    fn spawn(&self) -> bool;
+
+    /// Waits for all running burst workers to exit and ends the idle ones.
+    fn shutdown(&self);
+}
+
+/// Worker threads shared by the queue cores of several thread pools.
+///
+/// Cores in a group wake up the shared workers instead of their own ones, so
+/// the total concurrency is capped by the number of shared workers.
+pub(crate) struct WorkerGroup {
+    /// Same format as `QueueCore::active_workers`.
+    active_workers: AtomicUsize,
+    thread_count: usize,
+}
+
+impl WorkerGroup {
+    pub fn new(thread_count: usize) -> WorkerGroup {
+        WorkerGroup {
+            active_workers: AtomicUsize::new(thread_count << WORKER_COUNT_SHIFT),
+            thread_count,
+        }
+    }
+
+    /// Wakes up a shared worker if not all of them are awake.
+    pub fn ensure_workers(&self, source: usize) {
+        let cnt = self.active_workers.load(Ordering::SeqCst);
+        if (cnt >> WORKER_COUNT_SHIFT) >= self.thread_count || is_shutdown(cnt) {
+            return;
+        }
+        let addr = self as *const WorkerGroup as usize;
+        unsafe {
+            parking_lot_core::unpark_one(addr, |_| UnparkToken(source));
+        }
+    }
+
+    /// Wakes up the shared worker with the given id if it's sleeping.
+    pub fn unpark_worker(&self, id: usize, source: usize) {
+        unpark_worker(self as *const WorkerGroup as usize, id, source);
+    }
+
+    /// Sets the shutdown bit and notify all shared workers.
+    pub fn mark_shutdown(&self, source: usize) {
+        self.active_workers.fetch_or(SHUTDOWN_BIT, Ordering::SeqCst);
+        let addr = self as *const WorkerGroup as usize;
+        unsafe {
+            parking_lot_core::unpark_all(addr, UnparkToken(source));
+        }
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        is_shutdown(self.active_workers.load(Ordering::SeqCst))
+    }
+
+    #[cfg(test)]
+    pub fn awake_workers(&self) -> usize {
+        self.active_workers.load(Ordering::SeqCst) >> WORKER_COUNT_SHIFT
+    }
+
+    /// Calls `pop` after marking the current worker asleep, and goes to sleep
+    /// until woken up if it returns nothing.
+    pub fn pop_or_sleep<P>(&self, id: usize, mut pop: impl FnMut() -> Option<P>) -> Option<P> {
+        let address = self as *const WorkerGroup as usize;
+        let mut task = None;
+        let res = unsafe {
+            parking_lot_core::park(
+                address,
+                || {
+                    if mark_sleep(&self.active_workers).is_none() {
+                        return false;
+                    }
+                    task = pop();
+                    task.is_none()
+                },
+                || {},
+                |_, _| {},
+                ParkToken(id),
+                None,
+            )
+        };
+        match res {
+            ParkResult::Unparked(_) | ParkResult::Invalid => {
+                mark_woken(&self.active_workers);
+                task
+            }
+            ParkResult::TimedOut => unreachable!(),
+        }
+    }
+}
+
+/// Decreases the awake worker count unless the shutdown bit is set. Returns
+/// the updated value on success.
+fn mark_sleep(active_workers: &AtomicUsize) -> Option<usize> {
+    let mut cnt = active_workers.load(Ordering::SeqCst);
+    loop {
+        if is_shutdown(cnt) {
+            return None;
+        }
+
+        match active_workers.compare_exchange_weak(
+            cnt,
+            cnt - WORKER_COUNT_BASE,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        ) {
+            Ok(_) => return Some(cnt - WORKER_COUNT_BASE),
+            Err(n) => cnt = n,
+        }
+    }
+}
+
+/// Wakes up the worker with the given id parked on `addr`.
+fn unpark_worker(addr: usize, id: usize, source: usize) {
+    unsafe {
+        parking_lot_core::unpark_filter(
+            addr,
+            |p: ParkToken| {
+                if p.0 == id {
+                    FilterOp::Unpark
+                } else {
+                    FilterOp::Skip
+                }
+            },
+            |_| UnparkToken(source),
+        );
+    }
+}
+
+/// Increases the awake worker count and returns the updated value.
+fn mark_woken(active_workers: &AtomicUsize) -> usize {
+    active_workers.fetch_add(WORKER_COUNT_BASE, Ordering::SeqCst) + WORKER_COUNT_BASE
+}
+
+/// Steals a task from the injector and turns it into a `Pop`.
+fn steal_pop<T: TaskCell>(queue: &Injector<T>, source: TaskSource) -> Option<Pop<T>> {
+    loop {
+        match queue.steal() {
+            Steal::Success(mut t) => {
+                let schedule_time = t.mut_extras().schedule_time.unwrap();
+                return Some(Pop::new(t, schedule_time, source));
+            }
+            Steal::Empty => return None,
+            Steal::Retry => {}
+        }
+    }
+}
+
 /// The core of queues.
 ///
 /// Every thread pool instance should have one and only `QueueCore`. It's
 /// saved in an `Arc` and shared between all worker threads and remote handles.
 pub(crate) struct QueueCore<T> {
     global_queue: TaskInjector<T>,
+    /// Tasks pinned to each worker, they are never stolen by other workers.
+    pinned_queues: Vec<Injector<T>>,
+    /// Whether each worker has exited for good as its runner panicked on
+    /// start. Tasks can't be pinned to them.
+    dead_workers: Box<[AtomicBool]>,
+    /// The quiesce states of regular workers, see `ThreadPool::quiesce_worker`.
+    quiesce: Box<[QuiesceState]>,
     active_workers: AtomicUsize,
+    spawn_rate: SpawnRate,
+    /// The number of running burst workers.
+    burst_workers: AtomicUsize,
+    /// Nanoseconds since `created` plus 1 when the global queue started to be
+    /// backlogged, 0 if it's not backlogged.
+    backlog_since: AtomicU64,
+    created: Instant,
+    /// Rate limits spawning burst workers after the first one.
+    alloc_backoff: Backoff,
+    wake_throttle: WakeThrottleGuard,
+    burst_spawner: Mutex<Option<Box<dyn BurstSpawner>>>,
+    /// The number of tasks that are queued or being handled, counted by the
+    /// worker pushing or finishing them to avoid contention. The first one is
+    /// for remotes, the others are indexed by worker ids. A counter can be
+    /// negative as a task may be finished by another worker.
+    pending_tasks: Box<[CachePadded<AtomicIsize>]>,
+    /// The number of threads waiting for `pending_tasks` to become 0.
+    idle_waiters: AtomicUsize,
+    /// The number of tasks handled by each worker.
+    handled: Vec<CachePadded<AtomicU64>>,
+    /// The tasks being handled by each worker, only tracked when the stall
+    /// detector is enabled.
+    handling: Vec<CachePadded<HandlingTask>>,
+    /// Whether new tasks from remotes are rejected while the pool is being
+    /// drained.
+    draining: AtomicBool,
+    /// Whether exiting workers keep the tasks left in their queues in
+    /// `leftovers` instead of dropping them.
+    keep_leftovers: AtomicBool,
+    leftovers: Injector<T>,
+    /// Tasks only handled when there are no other tasks to handle.
+    idle_queue: Injector<T>,
+    /// Groups whose tasks are dropped instead of being handled, along with
+    /// the epoch they are cancelled at.
+    cancelled_groups: DashMap<u64, usize>,
+    /// Increased every time a group is cancelled, so workers only need to
+    /// check `cancelled_groups` after any group is cancelled.
+    cancel_epoch: AtomicUsize,
+    /// The concurrency limits of groups along with their deferred tasks.
+    group_limits: DashMap<u64, Arc<GroupLimit<T>>>,
+    /// Whether any group has been limited, so workers only need to check
+    /// `group_limits` after that.
+    group_limited: AtomicBool,
+    task_migration: IntCounter,
+    repoll_limit_reached: IntCounter,
+    injector_contention: IntCounter,
+    /// Samples of awake workers taken on sleep and wake transitions.
+    active_workers_count: Histogram,
+    wakeup_latency: Histogram,
+    first_poll_latency: Histogram,
+    start_panic: IntCounter,
+    stalls: IntCounter,
+    dropped_on_shutdown: IntCounter,
+    /// The shared workers to wake up instead of the workers of this core.
+    group: Option<Arc<WorkerGroup>>,
+    name: String,
+    runner: Mutex<RunnerSlot>,
+    /// Increased every time the runner factory is replaced.
+    runner_epoch: AtomicUsize,
     config: SchedConfig,
 }
 
+/// The task being handled by a worker.
+#[derive(Default)]
+struct HandlingTask {
+    /// Nanoseconds since `QueueCore::created` plus 1 when the worker started
+    /// to handle the task, 0 if it's not handling any task.
+    since: AtomicU64,
+    task_id: AtomicU64,
+}
+
+/// The concurrency limit of a group.
+struct GroupLimit<T> {
+    /// 0 if the limit is removed, the group is kept until its permits are
+    /// released.
+    limit: AtomicUsize,
+    /// The number of tasks of the group holding permits.
+    running: AtomicUsize,
+    /// The length of `deferred`, increased before a task is deferred so a
+    /// releasing task doesn't miss it.
+    deferred_len: AtomicUsize,
+    /// Tasks waiting for a running task of the group to finish.
+    deferred: Mutex<VecDeque<T>>,
+}
+
+impl<T> GroupLimit<T> {
+    /// Takes a permit if the group is under its limit.
+    fn try_acquire(&self) -> bool {
+        let mut running = self.running.load(Ordering::SeqCst);
+        loop {
+            let limit = self.limit.load(Ordering::SeqCst);
+            if limit != 0 && running >= limit {
+                return false;
+            }
+            match self.running.compare_exchange_weak(
+                running,
+                running + 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return true,
+                Err(n) => running = n,
+            }
+        }
+    }
+
+    /// Takes up to `max` deferred tasks.
+    fn take_deferred(&self, max: usize) -> Vec<T> {
+        if self.deferred_len.load(Ordering::SeqCst) == 0 {
+            return Vec::new();
+        }
+        let mut deferred = self.deferred.lock().unwrap();
+        let n = max.min(deferred.len());
+        self.deferred_len.fetch_sub(n, Ordering::SeqCst);
+        deferred.drain(..n).collect()
+    }
+}
+
+/// Whether a worker is requested to stop handling tasks for a while.
+#[derive(Default)]
+struct QuiesceState {
+    /// The number of living `WorkerGuard`s of the worker.
+    requests: AtomicUsize,
+    /// Whether the worker has parked for the requests.
+    parked: AtomicBool,
+}
+
+/// Shared slot for replacing the runners of workers.
+struct RunnerSlot {
+    /// Type of the runners the pool is built with.
+    type_id: Option<TypeId>,
+    /// A `Box<dyn Fn() -> R + Send + Sync>` that builds new runners.
+    factory: Option<Arc<dyn Any + Send + Sync>>,
+}
+
 impl<T> QueueCore<T> {
-    pub fn new(global_queue: TaskInjector<T>, config: SchedConfig) -> QueueCore<T> {
+    pub fn new(mut global_queue: TaskInjector<T>, config: SchedConfig) -> QueueCore<T> {
+        global_queue.set_tracking(config.track_queued_tasks);
+        let worker_count = config.max_thread_count + config.burst_thread_count;
+        let created = Instant::now();
         QueueCore {
             global_queue,
+            pinned_queues: (0..worker_count).map(|_| Injector::new()).collect(),
+            dead_workers: (0..worker_count).map(|_| AtomicBool::new(false)).collect(),
+            quiesce: (0..config.max_thread_count)
+                .map(|_| QuiesceState::default())
+                .collect(),
             active_workers: AtomicUsize::new(config.max_thread_count << WORKER_COUNT_SHIFT),
+            spawn_rate: SpawnRate::new(),
+            burst_workers: AtomicUsize::new(0),
+            backlog_since: AtomicU64::new(0),
+            created,
+            alloc_backoff: Backoff::new(config.alloc_slot_backoff),
+            wake_throttle: WakeThrottleGuard::new(config.wake_backoff, created),
+            burst_spawner: Mutex::new(None),
+            pending_tasks: (0..=worker_count).map(|_| Default::default()).collect(),
+            idle_waiters: AtomicUsize::new(0),
+            handled: (0..worker_count).map(|_| Default::default()).collect(),
+            handling: (0..worker_count).map(|_| Default::default()).collect(),
+            draining: AtomicBool::new(false),
+            keep_leftovers: AtomicBool::new(false),
+            leftovers: Injector::new(),
+            idle_queue: Injector::new(),
+            cancelled_groups: DashMap::new(),
+            cancel_epoch: AtomicUsize::new(0),
+            group_limits: DashMap::new(),
+            group_limited: AtomicBool::new(false),
+            task_migration: IntCounter::new("_", "_").unwrap(),
+            repoll_limit_reached: IntCounter::new("_", "_").unwrap(),
+            injector_contention: IntCounter::new("_", "_").unwrap(),
+            active_workers_count: Histogram::with_opts(HistogramOpts::new("_", "_")).unwrap(),
+            wakeup_latency: Histogram::with_opts(HistogramOpts::new("_", "_")).unwrap(),
+            first_poll_latency: Histogram::with_opts(HistogramOpts::new("_", "_")).unwrap(),
+            start_panic: IntCounter::new("_", "_").unwrap(),
+            stalls: IntCounter::new("_", "_").unwrap(),
+            dropped_on_shutdown: IntCounter::new("_", "_").unwrap(),
+            group: None,
+            name: String::new(),
+            runner: Mutex::new(RunnerSlot {
+                type_id: None,
+                factory: None,
+            }),
+            runner_epoch: AtomicUsize::new(0),
             config,
         }
     }
 
+    /// Reports the metrics of the queue with the given name.
+    pub fn with_metrics_name(mut self, name: &str) -> QueueCore<T> {
+        self.name = name.to_owned();
+        self.task_migration = TASK_MIGRATION_TOTAL.with_label_values(&[name]);
+        self.repoll_limit_reached = REPOLL_LIMIT_REACHED_TOTAL.with_label_values(&[name]);
+        self.injector_contention = INJECTOR_CONTENTION_TOTAL.with_label_values(&[name]);
+        self.active_workers_count = ACTIVE_WORKERS_COUNT.with_label_values(&[name]);
+        self.wakeup_latency = WAKEUP_LATENCY.with_label_values(&[name]);
+        self.first_poll_latency = FIRST_POLL_LATENCY.with_label_values(&[name]);
+        self.start_panic = WORKER_START_PANIC_TOTAL.with_label_values(&[name]);
+        self.stalls = WORKER_STALL_TOTAL.with_label_values(&[name]);
+        self.dropped_on_shutdown = TASKS_DROPPED_ON_SHUTDOWN.with_label_values(&[name]);
+        self
+    }
+
+    /// Hands the scheduling of the queue over to shared workers.
+    pub fn with_worker_group(mut self, group: Arc<WorkerGroup>) -> QueueCore<T> {
+        self.group = Some(group);
+        self
+    }
+
+    /// Records a woken up task that is pushed to the global queue instead of
+    /// the local queue of the current worker.
+    #[inline]
+    pub fn record_migration(&self) {
+        if cfg!(feature = "metrics") {
+            self.task_migration.inc();
+        }
+    }
+
+    #[cfg(test)]
+    pub fn migration_count(&self) -> u64 {
+        self.task_migration.get()
+    }
+
+    /// Records a future that is rescheduled for reaching the repoll limit.
+    #[inline]
+    pub fn record_repoll_limit_reached(&self) {
+        if cfg!(feature = "metrics") {
+            self.repoll_limit_reached.inc();
+        }
+    }
+
+    #[cfg(all(test, feature = "metrics"))]
+    pub fn repoll_limit_reached_count(&self) -> u64 {
+        self.repoll_limit_reached.get()
+    }
+
+    /// Records retries of stealing from the global queue.
+    #[inline]
+    pub fn record_injector_contention(&self, retries: u64) {
+        if cfg!(feature = "metrics") {
+            self.injector_contention.inc_by(retries);
+        }
+    }
+
+    #[cfg(all(test, feature = "metrics"))]
+    pub fn injector_contention_count(&self) -> u64 {
+        self.injector_contention.get()
+    }
+
+    /// Observes the number of awake workers given the updated worker state.
+    ///
+    /// Workers only change state when they run out of tasks or get woken up,
+    /// so sampling here is cheap compared with parking and unparking.
+    #[inline]
+    fn observe_active_workers(&self, cnt: usize) {
+        if cfg!(feature = "metrics") {
+            self.active_workers_count
+                .observe((cnt >> WORKER_COUNT_SHIFT) as f64);
+        }
+    }
+
+    /// Records that the runner of worker `id` panicked on start, so the
+    /// worker exits without handling any task.
+    pub fn record_start_panic(&self, id: usize) {
+        self.start_panic.inc();
+        warn!(
+            "runner of worker {} of pool {} panicked on start, the worker exits",
+            id, self.name
+        );
+    }
+
+    /// Observes the time since the task was woken up, if it was.
+    #[inline]
+    fn observe_wakeup_latency(&self, extras: &mut Extras) {
+        if !cfg!(feature = "metrics") {
+            return;
+        }
+        if let Some(t) = extras.wake_time.take() {
+            self.wakeup_latency.observe(t.elapsed().as_secs_f64());
+        }
+    }
+
+    /// Observes the time since the task was spawned if it's picked for the
+    /// first time, which isolates the delay before a task starts running.
+    #[inline]
+    fn observe_first_poll_latency(&self, extras: &mut Extras) {
+        if cfg!(feature = "metrics") && !extras.polled {
+            extras.polled = true;
+            self.first_poll_latency
+                .observe(extras.start_time.elapsed().as_secs_f64());
+        }
+    }
+
     /// Ensures there are enough workers to handle pending tasks.
     ///
     /// If the method is going to wake up any threads, source is used to trace who triggers
     /// the action.
     pub fn ensure_workers(&self, source: usize) {
+        if let Some(group) = &self.group {
+            group.ensure_workers(source);
+            return;
+        }
         let cnt = self.active_workers.load(Ordering::SeqCst);
         if (cnt >> WORKER_COUNT_SHIFT) >= self.config.core_thread_count.load(Ordering::SeqCst)
             || is_shutdown(cnt)
@@ -63,15 +768,28 @@ impl<T> QueueCore<T> {
         }
 
         let addr = self as *const QueueCore<T> as usize;
-        let mut unparked_once = false;
+        // Wake up enough threads to reach the floor if required, otherwise
+        // only one thread.
+        let awake = cnt >> WORKER_COUNT_SHIFT;
+        // The awake workers may be all busy, so a throttled wake is deferred
+        // rather than dropped. Nothing is throttled if no one is awake.
+        let max_token = self.config.core_thread_count.load(Ordering::SeqCst);
+        if !self.wake_throttle.try_wake(addr, max_token, awake == 0) {
+            return;
+        }
+        let mut to_unpark = if self.config.min_awake_under_load {
+            self.config.min_thread_count.saturating_sub(awake).max(1)
+        } else {
+            1
+        };
 
         unsafe {
             parking_lot_core::unpark_filter(
                 addr,
                 |p: ParkToken| {
-                    if !unparked_once && p.0 <= self.config.core_thread_count.load(Ordering::SeqCst)
+                    if to_unpark > 0 && p.0 <= self.config.core_thread_count.load(Ordering::SeqCst)
                     {
-                        unparked_once = true;
+                        to_unpark -= 1;
                         FilterOp::Unpark
                     } else {
                         FilterOp::Skip
@@ -84,13 +802,25 @@ impl<T> QueueCore<T> {
 
     /// Sets the shutdown bit and notify all threads.
     ///
-    /// `source` is used to trace who triggers the action.
-    pub fn mark_shutdown(&self, source: usize) {
-        self.active_workers.fetch_or(SHUTDOWN_BIT, Ordering::SeqCst);
+    /// `source` is used to trace who triggers the action. Returns false if
+    /// the shutdown bit has already been set.
+    pub fn mark_shutdown(&self, source: usize) -> bool {
+        let cnt = self.active_workers.fetch_or(SHUTDOWN_BIT, Ordering::SeqCst);
         let addr = self as *const QueueCore<T> as usize;
         unsafe {
             parking_lot_core::unpark_all(addr, UnparkToken(source));
+            parking_lot_core::unpark_all(&self.handling as *const _ as usize, UnparkToken(source));
+            for q in self.quiesce.iter() {
+                parking_lot_core::unpark_all(&q.requests as *const _ as usize, UnparkToken(source));
+                parking_lot_core::unpark_all(&q.parked as *const _ as usize, UnparkToken(source));
+            }
         }
+        !is_shutdown(cnt)
+    }
+
+    /// Returns the number of threads that are not sleeping.
+    pub fn awake_workers(&self) -> usize {
+        self.active_workers.load(Ordering::SeqCst) >> WORKER_COUNT_SHIFT
     }
 
     /// Checks if the thread pool is shutting down.
@@ -103,68 +833,832 @@ impl<T> QueueCore<T> {
     ///
     /// It can be marked as sleep only when the pool is not shutting down.
     pub fn mark_sleep(&self) -> bool {
-        let mut cnt = self.active_workers.load(Ordering::SeqCst);
+        match mark_sleep(&self.active_workers) {
+            Some(cnt) => {
+                self.observe_active_workers(cnt);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Marks current thread as woken up states.
+    pub fn mark_woken(&self) {
+        let cnt = mark_woken(&self.active_workers);
+        self.observe_active_workers(cnt);
+    }
+
+    /// Scale workers.
+    pub fn scale_workers(&self, mut new_thread_count: usize) {
+        if new_thread_count == 0 || new_thread_count > self.config.max_thread_count {
+            new_thread_count = self.config.max_thread_count;
+        } else if new_thread_count < self.config.min_thread_count {
+            new_thread_count = self.config.min_thread_count;
+        }
+        self.config
+            .core_thread_count
+            .store(new_thread_count, Ordering::SeqCst);
+    }
+
+    /// Returns the number of running burst workers.
+    #[cfg(test)]
+    pub fn burst_workers(&self) -> usize {
+        self.burst_workers.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_burst_spawner(&self, spawner: Box<dyn BurstSpawner>) {
+        *self.burst_spawner.lock().unwrap() = Some(spawner);
+    }
+
+    /// Marks a burst worker as exited.
+    pub(crate) fn burst_worker_exited(&self) {
+        self.burst_workers.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Stops spawning burst workers and waits for the running ones to exit.
+    pub(crate) fn shutdown_burst_workers(&self) {
+        let spawner = self.burst_spawner.lock().unwrap().take();
+        if let Some(spawner) = spawner {
+            spawner.shutdown();
+        }
+    }
+
+    /// Records the type of the runners the pool is built with.
+    pub(crate) fn set_runner_type<R: 'static>(&self) {
+        self.runner.lock().unwrap().type_id = Some(TypeId::of::<R>());
+    }
+
+    /// Replaces the runner factory, workers will rebuild their runners with
+    /// it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `R` is not the type of the runners the pool is built with.
+    pub(crate) fn set_runner_factory<R: 'static>(&self, f: Box<dyn Fn() -> R + Send + Sync>) {
+        let mut runner = self.runner.lock().unwrap();
+        assert_eq!(
+            runner.type_id,
+            Some(TypeId::of::<R>()),
+            "runner type mismatch"
+        );
+        runner.factory = Some(Arc::new(f));
+        self.runner_epoch.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Builds a new runner if the runner factory has been replaced since
+    /// `epoch`, and updates `epoch` to the current one.
+    pub(crate) fn rebuild_runner<R: 'static>(&self, epoch: &mut usize) -> Option<R> {
+        if self.runner_epoch.load(Ordering::SeqCst) == *epoch {
+            return None;
+        }
+        let factory = {
+            let runner = self.runner.lock().unwrap();
+            *epoch = self.runner_epoch.load(Ordering::SeqCst);
+            runner.factory.clone()?
+        };
+        let f = factory.downcast_ref::<Box<dyn Fn() -> R + Send + Sync>>()?;
+        Some(f())
+    }
+
+    /// Returns the pending counter of the worker with the given id, 0 for
+    /// remotes.
+    #[inline]
+    fn pending_counter(&self, id: usize) -> &AtomicIsize {
+        self.pending_tasks.get(id).unwrap_or(&self.pending_tasks[0])
+    }
+
+    /// Records a task pushed to a queue by the worker with the given id, 0
+    /// for remotes.
+    #[inline]
+    fn record_spawn(&self, source: usize) {
+        self.pending_counter(source).fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Records a task spawned by users for `Remote::recent_spawn_rate`.
+    /// Tasks pushed again, like woken up futures, are not recorded.
+    #[inline]
+    pub fn record_spawn_rate(&self) {
+        self.spawn_rate.record();
+    }
+
+    /// Returns the number of tasks recorded by `record_spawn_rate`.
+    #[cfg(test)]
+    pub fn spawned_tasks(&self) -> u64 {
+        self.spawn_rate.spawned.load(Ordering::Relaxed)
+    }
+
+    /// Marks a popped task as handled by the worker with the given id.
+    #[inline]
+    pub fn task_done(&self, id: usize) {
+        self.pending_counter(id).fetch_sub(1, Ordering::SeqCst);
+        if self.idle_waiters.load(Ordering::SeqCst) > 0 {
+            let addr = &self.idle_waiters as *const AtomicUsize as usize;
+            unsafe {
+                parking_lot_core::unpark_all(addr, UnparkToken(id));
+            }
+        }
+    }
+
+    /// Blocks until no task is queued or being handled, or `deadline` is
+    /// reached. Returns whether the pool became idle.
+    pub fn wait_idle(&self, deadline: Option<Instant>) -> bool {
+        let addr = &self.idle_waiters as *const AtomicUsize as usize;
+        self.idle_waiters.fetch_add(1, Ordering::SeqCst);
+        let mut res = true;
+        while self.pending_tasks() > 0 {
+            // Woken up by every finished task, the count is checked again as
+            // the counters are summed without a lock.
+            let park = unsafe {
+                parking_lot_core::park(
+                    addr,
+                    || self.pending_tasks() > 0,
+                    || {},
+                    |_, _| {},
+                    ParkToken(0),
+                    deadline,
+                )
+            };
+            if let ParkResult::TimedOut = park {
+                res = self.pending_tasks() == 0;
+                break;
+            }
+        }
+        self.idle_waiters.fetch_sub(1, Ordering::SeqCst);
+        res
+    }
+
+    /// Records a task handled by the worker with the given id.
+    #[inline]
+    pub fn record_handled(&self, id: usize) {
+        // Ids of workers start from 1.
+        if let Some(c) = id.checked_sub(1).and_then(|i| self.handled.get(i)) {
+            c.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Records that the worker with the given id starts to handle a task.
+    pub fn record_handle_start(&self, id: usize, task_id: u64) {
+        if let Some(h) = id.checked_sub(1).and_then(|i| self.handling.get(i)) {
+            h.task_id.store(task_id, Ordering::Relaxed);
+            let since = self.created.elapsed().as_nanos() as u64 + 1;
+            h.since.store(since, Ordering::Release);
+        }
+    }
+
+    /// Records that the worker with the given id finishes handling a task.
+    pub fn record_handle_end(&self, id: usize) {
+        if let Some(h) = id.checked_sub(1).and_then(|i| self.handling.get(i)) {
+            h.since.store(0, Ordering::Release);
+        }
+    }
+
+    /// Warns about workers that have been handling the same task for longer
+    /// than `threshold`.
+    ///
+    /// `reported` keeps the start time of the last reported stall of every
+    /// worker, so a stall is only reported once.
+    pub fn check_stalls(&self, threshold: Duration, reported: &mut Vec<u64>) {
+        reported.resize(self.handling.len(), 0);
+        let now = self.created.elapsed().as_nanos() as u64 + 1;
+        for (i, h) in self.handling.iter().enumerate() {
+            let since = h.since.load(Ordering::Acquire);
+            if since == 0 || reported[i] == since {
+                continue;
+            }
+            let elapsed = Duration::from_nanos(now.saturating_sub(since));
+            if elapsed >= threshold {
+                reported[i] = since;
+                self.stalls.inc();
+                warn!(
+                    "worker {} of pool {} has been handling task {} for {:?}, it may be blocked",
+                    i,
+                    self.name,
+                    h.task_id.load(Ordering::Relaxed),
+                    elapsed
+                );
+            }
+        }
+    }
+
+    /// Sleeps for `timeout` or until the pool is shut down, and returns
+    /// whether it's shut down.
+    pub fn sleep_unless_shutdown(&self, timeout: Duration) -> bool {
+        // Only the stall detector parks on it.
+        let addr = &self.handling as *const _ as usize;
+        let deadline = Instant::now() + timeout;
+        while !self.is_shutdown() {
+            let res = unsafe {
+                parking_lot_core::park(
+                    addr,
+                    || !self.is_shutdown(),
+                    || {},
+                    |_, _| {},
+                    DEFAULT_PARK_TOKEN,
+                    Some(deadline),
+                )
+            };
+            if let ParkResult::TimedOut = res {
+                break;
+            }
+        }
+        self.is_shutdown()
+    }
+
+    /// Checks whether any regular worker is idle or has been handling its
+    /// current task for less than `threshold`.
+    pub fn has_progressing_worker(&self, threshold: Duration) -> bool {
+        let now = self.created.elapsed().as_nanos() as u64 + 1;
+        self.handling[..self.config.max_thread_count]
+            .iter()
+            .any(|h| {
+                let since = h.since.load(Ordering::Acquire);
+                since == 0 || Duration::from_nanos(now.saturating_sub(since)) < threshold
+            })
+    }
+
+    /// Returns the kind of the queue.
+    pub fn queue_kind(&self) -> QueueKind {
+        self.global_queue.kind()
+    }
+
+    /// Returns the number of tasks handled by each worker.
+    pub fn handled_tasks(&self) -> Vec<u64> {
+        self.handled
+            .iter()
+            .map(|c| c.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    /// Returns the number of tasks that are queued or being handled.
+    pub fn pending_tasks(&self) -> usize {
+        let sum: isize = self
+            .pending_tasks
+            .iter()
+            .map(|c| c.load(Ordering::SeqCst))
+            .sum();
+        sum.max(0) as usize
+    }
+
+    /// Rejects new tasks from remotes.
+    pub fn mark_draining(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    /// Checks whether new tasks from remotes are rejected.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Makes workers keep the tasks left in their queues when they exit, so
+    /// they can be taken by `pop_leftover`.
+    pub fn keep_leftovers(&self) {
+        self.keep_leftovers.store(true, Ordering::SeqCst);
+    }
+
+    /// Takes a task left by an exited worker.
+    pub fn pop_leftover(&self) -> Option<T> {
         loop {
-            if is_shutdown(cnt) {
+            match self.leftovers.steal() {
+                Steal::Success(t) => return Some(t),
+                Steal::Empty => return None,
+                Steal::Retry => {}
+            }
+        }
+    }
+
+    /// Cancels all the queued and future tasks of the group.
+    ///
+    /// Only the latest `MAX_CANCELLED_GROUPS` cancelled groups are kept, the
+    /// older ones are forgotten.
+    pub fn cancel_group(&self, group_id: u64) {
+        let epoch = self.cancel_epoch.fetch_add(1, Ordering::SeqCst) + 1;
+        self.cancelled_groups.insert(group_id, epoch);
+        // Prunes in batches, so it's amortized O(1) per cancellation.
+        if self.cancelled_groups.len() > MAX_CANCELLED_GROUPS * 2 {
+            let oldest = epoch.saturating_sub(MAX_CANCELLED_GROUPS);
+            self.cancelled_groups.retain(|_, e| *e > oldest);
+        }
+    }
+
+    /// Returns the number of cancelled groups remembered.
+    #[cfg(test)]
+    pub fn cancelled_groups(&self) -> usize {
+        self.cancelled_groups.len()
+    }
+
+    /// Checks whether the task belongs to a cancelled group.
+    fn is_cancelled(&self, extras: &Extras) -> bool {
+        match extras.group_id {
+            Some(id) if self.cancel_epoch.load(Ordering::SeqCst) > 0 => {
+                self.cancelled_groups.contains_key(&id)
+            }
+            _ => false,
+        }
+    }
+
+    pub fn config(&self) -> &SchedConfig {
+        &self.config
+    }
+}
+
+/// The maximum number of cancelled groups a pool remembers.
+const MAX_CANCELLED_GROUPS: usize = 4096;
+
+/// Returns the index of the pinned queue a no-steal task is bound to.
+fn bound_queue<T: TaskCell>(task: &mut T) -> Option<usize> {
+    let extras = task.mut_extras();
+    if extras.no_steal && extras.bound_worker > 0 {
+        Some(extras.bound_worker - 1)
+    } else {
+        None
+    }
+}
+
+impl<T: TaskCell + Send> QueueCore<T> {
+    /// Limits the number of tasks of the group handled at the same time. 0
+    /// removes the limit.
+    pub fn set_group_concurrency(&self, group_id: u64, limit: usize) {
+        let g = if limit == 0 {
+            match self.group_limits.get(&group_id) {
+                Some(g) => g.clone(),
+                None => return,
+            }
+        } else {
+            self.group_limited.store(true, Ordering::SeqCst);
+            self.group_limits
+                .entry(group_id)
+                .or_insert_with(|| {
+                    Arc::new(GroupLimit {
+                        limit: AtomicUsize::new(limit),
+                        running: AtomicUsize::new(0),
+                        deferred_len: AtomicUsize::new(0),
+                        deferred: Mutex::new(VecDeque::new()),
+                    })
+                })
+                .clone()
+        };
+        g.limit.store(limit, Ordering::SeqCst);
+        let free = match limit {
+            0 => usize::MAX,
+            _ => limit.saturating_sub(g.running.load(Ordering::SeqCst)),
+        };
+        // The deferred tasks take their permits when they are popped again.
+        for t in g.take_deferred(free) {
+            self.push(0, t);
+        }
+        self.remove_unlimited_group(group_id);
+    }
+
+    /// Removes the group if it has no limit and no permit is held.
+    fn remove_unlimited_group(&self, group_id: u64) {
+        self.group_limits.remove_if(&group_id, |_, g| {
+            g.limit.load(Ordering::SeqCst) == 0
+                && g.running.load(Ordering::SeqCst) == 0
+                && g.deferred_len.load(Ordering::SeqCst) == 0
+        });
+    }
+
+    /// Takes a permit of the group of the task before handling it.
+    ///
+    /// Returns the task back along with the group to release the permit to
+    /// after it finishes. Returns `None` if the group has reached its limit,
+    /// in which case the task is deferred until a task of the group finishes.
+    /// A future keeps its permit across polls until it finishes.
+    pub fn acquire_group_permit(&self, mut task: T) -> Option<(T, Option<u64>)> {
+        let extras = task.mut_extras();
+        let group_id = match extras.group_id {
+            Some(id) if extras.group_permit => return Some((task, Some(id))),
+            Some(id) if self.group_limited.load(Ordering::SeqCst) => id,
+            _ => return Some((task, None)),
+        };
+        let g = match self.group_limits.get(&group_id) {
+            Some(g) if g.limit.load(Ordering::SeqCst) != 0 => g.clone(),
+            _ => return Some((task, None)),
+        };
+        if !g.try_acquire() {
+            let mut deferred = g.deferred.lock().unwrap();
+            g.deferred_len.fetch_add(1, Ordering::SeqCst);
+            // A task of the group may have finished without seeing it.
+            if !g.try_acquire() {
+                deferred.push_back(task);
+                drop(deferred);
+                // It's counted again when it's pushed back.
+                self.task_done(0);
+                return None;
+            }
+            g.deferred_len.fetch_sub(1, Ordering::SeqCst);
+        }
+        task.mut_extras().group_permit = true;
+        Some((task, Some(group_id)))
+    }
+
+    /// Releases a permit taken by `acquire_group_permit` once its task
+    /// finishes, and schedules a deferred task of the group if there is one.
+    pub fn release_group_permit(&self, group_id: u64) {
+        let g = match self.group_limits.get(&group_id) {
+            Some(g) => g.clone(),
+            None => return,
+        };
+        g.running.fetch_sub(1, Ordering::SeqCst);
+        for t in g.take_deferred(1) {
+            self.push(0, t);
+        }
+        if g.limit.load(Ordering::SeqCst) == 0 {
+            self.remove_unlimited_group(group_id);
+        }
+    }
+
+    /// Takes all the deferred tasks of groups, so they are dropped or kept
+    /// like queued tasks when the pool is shut down.
+    fn take_all_deferred(&self) -> Vec<T> {
+        let groups: Vec<_> = self.group_limits.iter().map(|g| g.clone()).collect();
+        groups
+            .into_iter()
+            .flat_map(|g| g.take_deferred(usize::MAX))
+            .collect()
+    }
+
+    /// Pushes the task to the pinned queue of the worker at `index` and wakes
+    /// it up if it's sleeping.
+    ///
+    /// The task is dropped if the pool is shut down. If the worker has exited
+    /// for good, the task is pushed to the global queue instead.
+    fn push_pinned(&self, index: usize, mut task: T) -> SpawnPlacement {
+        if self.is_shutdown() {
+            return SpawnPlacement::Rejected;
+        }
+        if self.is_dead(index) {
+            // No-steal tasks can be bound to another worker now.
+            task.mut_extras().bound_worker = 0;
+            return self.push_global(0, task);
+        }
+        task.mut_extras().schedule_time = Some(Instant::now());
+        self.record_spawn(index + 1);
+        self.capture_context(task.mut_extras());
+        self.global_queue.track(task.mut_extras());
+        let queue = &self.pinned_queues[index];
+        queue.push(task);
+        fail_point!("push-pinned-before-check");
+        if self.is_shutdown() {
+            // The worker may have exited before the task is pushed. The
+            // dropped tasks are no longer pending.
+            while self.pop_pinned(index + 1).is_some() {
+                self.task_done(index + 1);
+            }
+            return SpawnPlacement::Rejected;
+        }
+        if self.is_dead(index) {
+            self.unpin_all(index);
+            return SpawnPlacement::Global;
+        }
+        self.unpark_worker(index + 1, 0);
+        SpawnPlacement::Local(index)
+    }
+
+    fn is_dead(&self, index: usize) -> bool {
+        self.dead_workers[index].load(Ordering::SeqCst)
+    }
+
+    /// Marks the worker with the given id as exited for good, and moves the
+    /// tasks pinned to it to the global queue so other workers handle them.
+    pub(crate) fn mark_dead(&self, id: usize) {
+        let index = match id.checked_sub(1) {
+            Some(index) if index < self.dead_workers.len() => index,
+            _ => return,
+        };
+        self.dead_workers[index].store(true, Ordering::SeqCst);
+        self.unpin_all(index);
+    }
+
+    /// Marks the worker with the given id as running again after it's
+    /// restarted.
+    pub(crate) fn mark_alive(&self, id: usize) {
+        if let Some(dead) = id.checked_sub(1).and_then(|i| self.dead_workers.get(i)) {
+            dead.store(false, Ordering::SeqCst);
+        }
+    }
+
+    /// Moves the tasks pinned to the worker at `index` to the global queue.
+    fn unpin_all(&self, index: usize) {
+        let mut moved = false;
+        while let Some(mut t) = self.pop_pinned(index + 1) {
+            // No-steal tasks can be bound to another worker now.
+            t.task_cell.mut_extras().bound_worker = 0;
+            self.global_queue.push(t.task_cell);
+            moved = true;
+        }
+        if moved {
+            self.ensure_workers(0);
+        }
+    }
+
+    /// Requests the worker with the given id to park once it finishes its
+    /// current task, until `resume_worker` is called as many times.
+    pub(crate) fn quiesce_worker(&self, id: usize) {
+        if let Some(q) = self.quiesce_state(id) {
+            q.requests.fetch_add(1, Ordering::SeqCst);
+            self.unpark_worker(id, 0);
+        }
+    }
+
+    /// Cancels a request of `quiesce_worker`, and wakes up the worker if
+    /// there are no more requests.
+    pub(crate) fn resume_worker(&self, id: usize) {
+        let q = match self.quiesce_state(id) {
+            Some(q) => q,
+            None => return,
+        };
+        if q.requests.fetch_sub(1, Ordering::SeqCst) == 1 {
+            unsafe {
+                parking_lot_core::unpark_all(&q.requests as *const _ as usize, UnparkToken(0));
+            }
+        }
+    }
+
+    /// Checks whether the worker with the given id is requested to quiesce.
+    /// Burst workers are never quiesced.
+    #[inline]
+    pub(crate) fn is_quiesced(&self, id: usize) -> bool {
+        self.quiesce_state(id)
+            .is_some_and(|q| q.requests.load(Ordering::SeqCst) > 0)
+    }
+
+    /// Gets the quiesce state of the worker with the given id. Remotes and
+    /// burst workers have none.
+    fn quiesce_state(&self, id: usize) -> Option<&QuiesceState> {
+        id.checked_sub(1).and_then(|i| self.quiesce.get(i))
+    }
+
+    /// Waits until the worker with the given id has parked because it's
+    /// quiesced. Returns false if `timeout` elapses or the pool is shut down
+    /// first.
+    pub(crate) fn wait_quiesced_parked(&self, id: usize, timeout: Duration) -> bool {
+        let parked = match self.quiesce_state(id) {
+            Some(q) => &q.parked,
+            None => return false,
+        };
+        let deadline = Instant::now() + timeout;
+        loop {
+            if parked.load(Ordering::SeqCst) {
+                return true;
+            }
+            if self.is_shutdown() || Instant::now() >= deadline {
                 return false;
             }
+            unsafe {
+                parking_lot_core::park(
+                    parked as *const _ as usize,
+                    || !parked.load(Ordering::SeqCst) && !self.is_shutdown(),
+                    || {},
+                    |_, _| {},
+                    DEFAULT_PARK_TOKEN,
+                    Some(deadline),
+                );
+            }
+        }
+    }
+
+    /// Wakes up the worker with the given id if it's sleeping.
+    fn unpark_worker(&self, id: usize, source: usize) {
+        match &self.group {
+            Some(group) => group.unpark_worker(id, source),
+            None => unpark_worker(self as *const QueueCore<T> as usize, id, source),
+        }
+    }
+
+    /// The address workers of this core park on. Shared workers park on
+    /// their group, as they wait for the tasks of every core in it.
+    fn park_address(&self) -> usize {
+        match &self.group {
+            Some(group) => &**group as *const WorkerGroup as usize,
+            None => self as *const QueueCore<T> as usize,
+        }
+    }
+
+    /// Checks whether there are tasks pinned to the worker with the given id.
+    fn has_pinned(&self, id: usize) -> bool {
+        id.checked_sub(1)
+            .and_then(|i| self.pinned_queues.get(i))
+            .is_some_and(|q| !q.is_empty())
+    }
+
+    /// Pops a task pinned to the worker with the given id.
+    fn pop_pinned(&self, id: usize) -> Option<Pop<T>> {
+        let queue = self.pinned_queues.get(id.checked_sub(1)?)?;
+        let mut t = steal_pop(queue, TaskSource::Local)?;
+        self.global_queue.untrack(t.task_cell.mut_extras());
+        Some(t)
+    }
+
+    /// Pushes a task that is only handled when there are no other tasks.
+    fn push_idle(&self, mut task: T) {
+        task.mut_extras().schedule_time = Some(Instant::now());
+        self.record_spawn(0);
+        self.capture_context(task.mut_extras());
+        self.global_queue.track(task.mut_extras());
+        self.idle_queue.push(task);
+        self.ensure_workers(0);
+    }
+
+    /// Pops a task spawned by `Remote::spawn_idle`.
+    fn pop_idle(&self) -> Option<Pop<T>> {
+        let mut t = steal_pop(&self.idle_queue, TaskSource::Global)?;
+        self.global_queue.untrack(t.task_cell.mut_extras());
+        Some(t)
+    }
+
+    /// Returns the number of tasks in the global queue.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn global_queue_len(&self) -> usize {
+        self.global_queue.len()
+    }
+
+    /// Returns the lengths of the queue, including the tasks pinned to
+    /// workers and the idle tasks.
+    pub fn queue_stats(&self) -> QueueStats {
+        let mut stats = self.global_queue.stats();
+        stats.pinned_lens = self.pinned_queues.iter().map(Injector::len).collect();
+        stats.idle_len = self.idle_queue.len();
+        stats
+    }
+
+    /// Pushes the task to global queue.
+    ///
+    /// `source` is used to trace who triggers the action.
+    fn push(&self, source: usize, mut task: T) -> SpawnPlacement {
+        if let Some(index) = bound_queue(&mut task) {
+            return self.push_pinned(index, task);
+        }
+        if let Some(f) = self
+            .config
+            .partition_fn
+            .as_ref()
+            .filter(|_| self.config.max_thread_count > 0)
+        {
+            let index = f(task.mut_extras()) % self.config.max_thread_count;
+            return self.push_pinned(index, task);
+        }
+        self.push_global(source, task)
+    }
+
+    /// Pushes the task to the global queue, ignoring where it's bound to.
+    fn push_global(&self, source: usize, mut task: T) -> SpawnPlacement {
+        self.record_spawn(source);
+        self.capture_context(task.mut_extras());
+        self.global_queue.push(task);
+        self.ensure_workers(source);
+        if self.config.burst_thread_count > 0 {
+            self.unpark_burst_worker(source);
+            self.maybe_spawn_burst_worker();
+        }
+        SpawnPlacement::Global
+    }
+
+    /// Checks whether the worker with the given id is a burst worker.
+    fn is_burst_worker(&self, id: usize) -> bool {
+        id > self.config.max_thread_count
+    }
+
+    /// Wakes up one of the running burst workers if it's waiting for tasks.
+    fn unpark_burst_worker(&self, source: usize) {
+        if self.burst_workers.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        let mut unparked = false;
+        unsafe {
+            parking_lot_core::unpark_filter(
+                self.park_address(),
+                |p: ParkToken| {
+                    if !unparked && self.is_burst_worker(p.0) {
+                        unparked = true;
+                        FilterOp::Unpark
+                    } else {
+                        FilterOp::Skip
+                    }
+                },
+                |_| UnparkToken(source),
+            );
+        }
+    }
+
+    /// Spawns a burst worker if the global queue has been backlogged for
+    /// longer than `max_wait_time`.
+    fn maybe_spawn_burst_worker(&self) {
+        if self.global_queue.len() <= self.config.burst_queue_threshold {
+            if self.backlog_since.load(Ordering::Relaxed) != 0 {
+                self.backlog_since.store(0, Ordering::Relaxed);
+                self.alloc_backoff.reset();
+            }
+            return;
+        }
+        let now = self.created.elapsed().as_nanos() as u64 + 1;
+        let since = self.backlog_since.load(Ordering::Relaxed);
+        if since == 0 {
+            let _ =
+                self.backlog_since
+                    .compare_exchange(0, now, Ordering::Relaxed, Ordering::Relaxed);
+            return;
+        }
+        if now - since < self.config.max_wait_time.as_nanos() as u64 {
+            return;
+        }
+        // Later burst workers are spawned by `alloc_slot_backoff` as long as
+        // the queue stays backlogged.
+        if self.burst_workers.fetch_add(1, Ordering::SeqCst) >= self.config.burst_thread_count
+            || self.is_shutdown()
+            || self.alloc_backoff.try_acquire(now).is_err()
+        {
+            self.burst_workers.fetch_sub(1, Ordering::SeqCst);
+            return;
+        }
+        let spawned = match &*self.burst_spawner.lock().unwrap() {
+            Some(spawner) => spawner.spawn(),
+            None => false,
+        };
+        if !spawned {
+            self.burst_workers.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
 
-            match self.active_workers.compare_exchange_weak(
-                cnt,
-                cnt - WORKER_COUNT_BASE,
-                Ordering::SeqCst,
-                Ordering::SeqCst,
-            ) {
-                Ok(_) => return true,
-                Err(n) => cnt = n,
+    pub(crate) fn default_extras(&self) -> Extras {
+        self.global_queue.default_extras()
+    }
+
+    /// Captures the context of the spawning thread if the task doesn't have
+    /// one yet. A woken future keeps the context of the thread spawning it.
+    pub(crate) fn capture_context(&self, extras: &mut Extras) {
+        if let Some(hook) = &self.config.context_hook {
+            if extras.context.is_none() {
+                extras.context = Some(TaskContext(hook.capture()));
             }
         }
     }
 
-    /// Marks current thread as woken up states.
-    pub fn mark_woken(&self) {
-        let mut cnt = self.active_workers.load(Ordering::SeqCst);
-        loop {
-            match self.active_workers.compare_exchange_weak(
-                cnt,
-                cnt + WORKER_COUNT_BASE,
-                Ordering::SeqCst,
-                Ordering::SeqCst,
-            ) {
-                Ok(_) => return,
-                Err(n) => cnt = n,
+    /// Restores the context captured when the task was spawned. Returns the
+    /// context of the current thread, which should be reset by
+    /// `reset_context` after the task is handled.
+    pub(crate) fn restore_context(&self, extras: &Extras) -> Option<TaskContext> {
+        match (&self.config.context_hook, &extras.context) {
+            (Some(hook), Some(ctx)) => {
+                let prev = hook.capture();
+                hook.restore(&ctx.0);
+                Some(TaskContext(prev))
             }
+            _ => None,
         }
     }
 
-    /// Scale workers.
-    pub fn scale_workers(&self, mut new_thread_count: usize) {
-        if new_thread_count == 0 || new_thread_count > self.config.max_thread_count {
-            new_thread_count = self.config.max_thread_count;
-        } else if new_thread_count < self.config.min_thread_count {
-            new_thread_count = self.config.min_thread_count;
+    /// Resets the context of the current thread to the one returned by
+    /// `restore_context`.
+    pub(crate) fn reset_context(&self, prev: &TaskContext) {
+        if let Some(hook) = &self.config.context_hook {
+            hook.restore(&prev.0);
         }
-        self.config
-            .core_thread_count
-            .store(new_thread_count, Ordering::SeqCst);
     }
 
-    pub fn config(&self) -> &SchedConfig {
-        &self.config
+    /// Checks whether tasks run on the spawning thread because the pool has
+    /// no workers, see `Builder::inline_if_no_workers`.
+    pub(crate) fn runs_inline(&self) -> bool {
+        self.config.max_thread_count == 0 && self.config.inline_if_no_workers
     }
-}
 
-impl<T: TaskCell + Send> QueueCore<T> {
-    /// Pushes the task to global queue.
-    ///
-    /// `source` is used to trace who triggers the action.
-    fn push(&self, source: usize, task: T) {
-        self.global_queue.push(task);
-        self.ensure_workers(source);
+    /// Checks whether new tasks should be run on the spawning thread
+    /// according to the overflow policy, or because there are no workers.
+    fn should_run_on_caller(&self) -> bool {
+        if self.config.max_thread_count == 0 {
+            return self.runs_inline() && !self.is_shutdown();
+        }
+        match self.config.overflow_policy {
+            OverflowPolicy::Queue => false,
+            OverflowPolicy::RunOnCaller { queue_threshold } => {
+                let cnt = self.active_workers.load(Ordering::SeqCst);
+                !is_shutdown(cnt)
+                    && (cnt >> WORKER_COUNT_SHIFT)
+                        >= self.config.core_thread_count.load(Ordering::SeqCst)
+                    && self.global_queue.len() >= queue_threshold
+            }
+        }
     }
 
-    fn default_extras(&self) -> Extras {
-        self.global_queue.default_extras()
+    /// Checks whether the task should be rejected to relieve the overload.
+    fn should_shed(&self, task: &mut T) -> bool {
+        let policy = match self.config.shed_policy {
+            Some(p) => p,
+            None => return false,
+        };
+        if self.pending_tasks() <= policy.high_water {
+            return false;
+        }
+        self.global_queue.adjust_level(task);
+        task.mut_extras().current_level >= policy.min_level
+    }
+
+    /// Checks whether a thread that is marked sleep should stay awake to
+    /// keep the minimum number of awake threads under load.
+    fn should_keep_awake(&self) -> bool {
+        self.config.min_awake_under_load
+            && self.awake_workers() < self.config.min_thread_count
+            && !self.global_queue.is_empty()
     }
 }
 
@@ -181,19 +1675,266 @@ impl<T: TaskCell + Send> Remote<T> {
         Remote { core }
     }
 
+    /// Creates a remote from the global queue built by [`queue::build`].
+    ///
+    /// `config.max_thread_count` must be the number of local queues built
+    /// along with `injector`, and each of them should be turned into a
+    /// [`Local`] by [`Remote::local`] and driven by a thread, otherwise tasks
+    /// may never be picked.
+    ///
+    /// [`queue::build`]: crate::queue::build
+    pub fn from_injector(injector: TaskInjector<T>, config: SchedConfig) -> Remote<T> {
+        Remote::new(Arc::new(QueueCore::new(injector, config)))
+    }
+
+    /// Creates the `Local` of the worker with the given id, which is from 1
+    /// to `max_thread_count` in the order of the local queues.
+    pub fn local(&self, id: usize, local_queue: LocalQueue<T>) -> Local<T> {
+        Local::new(id, local_queue, self.core.clone())
+    }
+
     /// Submits a task to the thread pool.
+    ///
+    /// The task may be run on the current thread if the pool is overloaded
+    /// and `OverflowPolicy::RunOnCaller` is used, or if the pool has no
+    /// workers and `Builder::inline_if_no_workers` is set. The task is dropped if the
+    /// pool is being drained by `ThreadPool::shutdown_drain`.
     pub fn spawn(&self, task: impl WithExtras<T>) {
+        self.spawn_placed(task);
+    }
+
+    /// Submits a task like `spawn` and returns where it's placed.
+    pub(crate) fn spawn_placed(&self, task: impl WithExtras<T>) -> SpawnPlacement {
+        if self.core.is_draining() {
+            return SpawnPlacement::Rejected;
+        }
+        self.core.record_spawn_rate();
+        let t = task.with_extras(|| self.core.default_extras());
+        if self.core.should_run_on_caller() {
+            return match callback::run_on_caller(t, self) {
+                Ok(()) => SpawnPlacement::Rejected,
+                Err(t) => self.core.push(0, t),
+            };
+        }
+        self.core.push(0, t)
+    }
+
+    /// Submits a task like `spawn` unless it should be shed, in which case
+    /// the task is returned.
+    ///
+    /// A task is shed if the pool is overloaded and the task has a low
+    /// priority according to `Builder::shed_overload`, or if the pool is
+    /// being drained by `ThreadPool::shutdown_drain`.
+    pub fn try_spawn(&self, task: impl WithExtras<T>) -> Result<(), T> {
+        let mut t = task.with_extras(|| self.core.default_extras());
+        if self.core.is_draining() || self.core.should_shed(&mut t) {
+            return Err(t);
+        }
+        self.spawn(t);
+        Ok(())
+    }
+
+    /// Submits a task to the worker at the given index.
+    ///
+    /// The index wraps around the maximum number of threads. The task is
+    /// never stolen by other workers, so tasks sharing thread local states
+    /// can be co-located. However, a future is handled by whichever worker
+    /// wakes it up after it yields, so only its first poll is guaranteed to
+    /// happen on the given worker. The task is dropped if the pool is being
+    /// drained by `ThreadPool::shutdown_drain`. If the worker has exited
+    /// because its runner panicked on start, the task is pushed to the
+    /// global queue instead.
+    pub fn spawn_on(&self, worker: usize, task: impl WithExtras<T>) {
+        if self.core.is_draining() {
+            return;
+        }
+        let t = task.with_extras(|| self.core.default_extras());
+        if self.core.config.max_thread_count == 0 {
+            self.spawn(t);
+            return;
+        }
+        self.core.record_spawn_rate();
+        let index = worker % self.core.config.max_thread_count;
+        self.core.push_pinned(index, t);
+    }
+
+    /// Submits a task that is only handled when workers have nothing else to
+    /// do.
+    ///
+    /// It suits background maintenance like cleaning up caches. Unlike a low
+    /// level of the multilevel queue, it's never picked as long as there are
+    /// other tasks in the queues, so it may starve under steady load. The
+    /// task is dropped if the pool is being drained by
+    /// `ThreadPool::shutdown_drain`.
+    pub fn spawn_idle(&self, task: impl WithExtras<T>) {
+        if self.core.is_draining() {
+            return;
+        }
+        let t = task.with_extras(|| self.core.default_extras());
+        self.core.record_spawn_rate();
+        self.core.push_idle(t);
+    }
+
+    /// Submits a task that is never stolen once a worker picks it.
+    ///
+    /// The task may run on any worker at first, after that it's always
+    /// handled by the same worker. See [`Extras::set_no_steal`].
+    pub fn spawn_pinned(&self, task: impl WithExtras<T>) {
+        let mut t = task.with_extras(|| self.core.default_extras());
+        t.mut_extras().set_no_steal(true);
+        self.spawn(t);
+    }
+
+    /// Cancels all the tasks of the resource group set by
+    /// [`Extras::set_group_id`].
+    ///
+    /// Queued tasks of the group and tasks of the group spawned later are
+    /// dropped by workers instead of being handled. Tasks being handled are
+    /// not interrupted, a future is dropped the next time it's scheduled.
+    /// Only the latest 4096 cancelled groups are remembered, so tasks of a
+    /// group spawned after many other groups are cancelled may run again.
+    pub fn cancel_group(&self, group_id: u64) {
+        self.core.cancel_group(group_id);
+    }
+
+    /// Limits the number of tasks of the resource group set by
+    /// [`Extras::set_group_id`] that are handled at the same time.
+    ///
+    /// A task of the group popped while `limit` tasks of the group are in
+    /// flight is deferred, and pushed to the global queue again once one of
+    /// them finishes. A future is in flight from its first poll until it
+    /// completes, so a future that never completes keeps its permit. Setting
+    /// the limit to 0 removes it. Deferred tasks are dropped or drained like
+    /// queued tasks when the pool is shut down.
+    pub fn set_group_concurrency(&self, group_id: u64, limit: usize) {
+        self.core.set_group_concurrency(group_id, limit);
+    }
+
+    /// Submits a task with the given extras to the thread pool.
+    ///
+    /// It's the same as `spawn`, except that `extras` is used instead of the
+    /// default extras of the queue. `extras` can be either an [`Extras`] or
+    /// a closure producing one. If `task` is already a task cell, its extras
+    /// are replaced by `extras`.
+    pub fn spawn_with_extras(&self, task: impl WithExtras<T>, extras: impl IntoExtras) {
+        let mut extras = Some(extras);
+        let mut t = task.with_extras(|| extras.take().unwrap().into_extras());
+        // A task cell is returned as is without calling the closure.
+        if let Some(extras) = extras {
+            *t.mut_extras() = extras.into_extras();
+        }
+        self.spawn(t);
+    }
+
+    pub(crate) fn downgrade(&self) -> WeakRemote<T> {
+        WeakRemote {
+            core: Arc::downgrade(&self.core),
+        }
+    }
+
+    /// Submits a task to the queue of the thread pool regardless of the
+    /// overflow policy and whether the pool is being drained.
+    pub(crate) fn spawn_queued(&self, task: impl WithExtras<T>) {
         let t = task.with_extras(|| self.core.default_extras());
         self.core.push(0, t);
     }
 
+    /// Returns the approximate number of tasks spawned per second recently.
+    ///
+    /// Tasks spawned by remotes and by running tasks are counted, but tasks
+    /// scheduled again, like woken up futures and rescheduled callbacks, are
+    /// not.
+    ///
+    /// It's an exponentially weighted moving average with a time constant of
+    /// one second, which is updated when it's read. It's cheap enough for
+    /// quick control loops, but should not be treated as an accurate metric.
+    pub fn recent_spawn_rate(&self) -> f64 {
+        self.core.spawn_rate.get()
+    }
+
+    /// Returns the time spent on handling tasks of each level, or `None` if
+    /// the pool doesn't use the multilevel task queue.
+    ///
+    /// The counters are kept by the queue of this pool and updated when
+    /// workers flush their local metrics, roughly every 100ms of handling
+    /// tasks.
+    pub fn level_elapsed(&self) -> Option<[Duration; multilevel::LEVEL_NUM]> {
+        self.core.global_queue.level_elapsed()
+    }
+
+    /// Resets the statistics of the multilevel task queue, so
+    /// `level_elapsed` starts from zero and the chance of picking level 0
+    /// tasks goes back to its initial value. It does nothing if the pool
+    /// doesn't use the multilevel task queue.
+    ///
+    /// It's useful to analyze the time spent on levels within a window.
+    /// Only the states kept by the queue are reset, the Prometheus metrics
+    /// are monotonic and keep growing. Scheduling is not disrupted, the
+    /// chance is adapted again as tasks are handled.
+    pub fn reset_multilevel_stats(&self) {
+        self.core.global_queue.reset_multilevel_stats();
+    }
+
+    /// Replaces the running time thresholds that decide the levels of tasks
+    /// in a multilevel or priority task queue, like
+    /// `multilevel::Config::level_time_threshold`.
+    ///
+    /// There must be a threshold for every level but the last one, in
+    /// strictly increasing order. Tasks already in the queue keep their
+    /// levels, the new thresholds take effect the next time tasks are pushed.
+    pub fn set_level_thresholds(&self, thresholds: Vec<Duration>) -> Result<(), ConfigError> {
+        if thresholds.len() != multilevel::LEVEL_NUM - 1
+            || thresholds.windows(2).any(|w| w[0] >= w[1])
+        {
+            return Err(ConfigError::InvalidLevelThresholds);
+        }
+        let thresholds = std::array::from_fn(|i| thresholds[i]);
+        if self.core.global_queue.set_level_time_threshold(thresholds) {
+            Ok(())
+        } else {
+            Err(ConfigError::NoTaskLevels)
+        }
+    }
+
+    /// Moves the task with the given id to `level` if it's still in the
+    /// global queue of a multilevel pool, so it runs ahead of the tasks of
+    /// lower levels.
+    ///
+    /// It's a best-effort operation for manually expediting a known task.
+    /// Returns false if the task is running, finished, or already picked by a
+    /// worker. The level is only changed once, later pushes of the task
+    /// compute its level as usual. It goes through the whole global queue, so
+    /// don't call it on a hot path; use `LevelBooster` for boosting tasks
+    /// systematically.
+    pub fn boost_task(&self, task_id: u64, level: u8) -> bool {
+        let boosted = self.core.global_queue.boost_task(task_id, level);
+        // Workers may have seen the queue empty while it was being reordered.
+        self.core.ensure_workers(0);
+        boosted
+    }
+
+    /// Returns a copy of the extras of the tasks waiting in the pool, ordered
+    /// by level and then by the order they are pushed.
+    ///
+    /// It's meant for diagnostics. The tasks are not taken out of the queues:
+    /// a copy of the extras is kept aside when a task is pushed, so the
+    /// global queue, the local queues of workers and the tasks pinned to them
+    /// are all included. The snapshot is only a best-effort view of a point
+    /// in time, tasks being pushed or popped concurrently may be missed.
+    ///
+    /// It's always empty unless the pool is built with
+    /// `Builder::track_queued_tasks`.
+    pub fn snapshot_queue(&self) -> Vec<ExtrasSnapshot> {
+        self.core.global_queue.snapshot()
+    }
+
     /// Scales workers of the thread pool.
     pub fn scale_workers(&self, new_thread_count: usize) {
         self.core.scale_workers(new_thread_count)
     }
 
-    pub(crate) fn stop(&self) {
-        self.core.mark_shutdown(0);
+    pub(crate) fn stop(&self) -> bool {
+        self.core.mark_shutdown(0)
     }
 }
 
@@ -208,11 +1949,50 @@ impl<T> Clone for Remote<T> {
 /// Note that implements of Runner assumes `Remote` is `Sync` and `Send`.
 /// So we need to use assert trait to ensure the constraint at compile time
 /// to avoid future breaks.
+#[allow(dead_code)]
 trait AssertSync: Sync {}
 impl<T: Send> AssertSync for Remote<T> {}
+#[allow(dead_code)]
 trait AssertSend: Send {}
 impl<T: Send> AssertSend for Remote<T> {}
 
+/// A cheaply cloneable handle that can only spawn tasks to a thread pool.
+///
+/// Unlike [`Remote`], it can't reconfigure or shut down the pool, so it's
+/// safe to hand out to library code.
+///
+/// ```compile_fail
+/// let (_pool, handle) = yatp::Builder::new("example").build_future_pool_with_handle();
+/// handle.shutdown();
+/// ```
+pub struct SpawnHandle<T> {
+    remote: Remote<T>,
+}
+
+impl<T: TaskCell + Send> SpawnHandle<T> {
+    pub(crate) fn new(remote: Remote<T>) -> SpawnHandle<T> {
+        SpawnHandle { remote }
+    }
+
+    /// Submits a task to the thread pool, see [`Remote::spawn`].
+    pub fn spawn(&self, task: impl WithExtras<T>) {
+        self.remote.spawn(task)
+    }
+
+    /// Submits a task with the given extras, see [`Remote::spawn_with_extras`].
+    pub fn spawn_with_extras(&self, task: impl WithExtras<T>, extras: impl IntoExtras) {
+        self.remote.spawn_with_extras(task, extras)
+    }
+}
+
+impl<T> Clone for SpawnHandle<T> {
+    fn clone(&self) -> SpawnHandle<T> {
+        SpawnHandle {
+            remote: self.remote.clone(),
+        }
+    }
+}
+
 /// `WeakRemote` is a weak reference to the inner queue.
 pub(crate) struct WeakRemote<T> {
     core: Weak<QueueCore<T>>,
@@ -250,6 +2030,48 @@ pub struct Local<T> {
     id: usize,
     local_queue: LocalQueue<T>,
     core: Arc<QueueCore<T>>,
+    /// The wait time histogram and handled counter of each task kind.
+    kind_metrics: HashMap<&'static str, (Histogram, IntCounter)>,
+    /// Set by [`Unparker::unpark`], cleared when `park_until` consumes it.
+    unpark_requested: Arc<AtomicBool>,
+    /// Values reused by tasks handled by this worker, one for each type.
+    scratch: HashMap<TypeId, Box<dyn Any + Send>>,
+}
+
+/// Wakes up a specific worker parked by [`Local::park_until`].
+///
+/// An unpark issued while the worker is not parked is remembered, and the
+/// next `park_until` returns immediately.
+pub struct Unparker<T> {
+    id: usize,
+    requested: Arc<AtomicBool>,
+    core: Weak<QueueCore<T>>,
+}
+
+impl<T> Clone for Unparker<T> {
+    fn clone(&self) -> Self {
+        Unparker {
+            id: self.id,
+            requested: self.requested.clone(),
+            core: self.core.clone(),
+        }
+    }
+}
+
+impl<T: TaskCell + Send> Unparker<T> {
+    /// Wakes up the worker, does nothing if the pool is dropped.
+    pub fn unpark(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+        if let Some(core) = self.core.upgrade() {
+            core.unpark_worker(self.id, 0);
+        }
+    }
+}
+
+impl<T> Local<T> {
+    pub(crate) fn core(&self) -> &Arc<QueueCore<T>> {
+        &self.core
+    }
 }
 
 impl<T: TaskCell + Send> Local<T> {
@@ -258,13 +2080,51 @@ impl<T: TaskCell + Send> Local<T> {
             id,
             local_queue,
             core,
+            kind_metrics: HashMap::new(),
+            unpark_requested: Arc::new(AtomicBool::new(false)),
+            scratch: HashMap::new(),
         }
     }
 
     /// Spawns a task to the local queue.
     pub fn spawn(&mut self, task: impl WithExtras<T>) {
-        let t = task.with_extras(|| self.local_queue.default_extras());
+        self.spawn_placed(task);
+    }
+
+    /// Spawns a task like `spawn` and returns where it's placed.
+    pub(crate) fn spawn_placed(&mut self, task: impl WithExtras<T>) -> SpawnPlacement {
+        let mut t = task.with_extras(|| self.local_queue.default_extras());
+        if let Some(index) = bound_queue(&mut t) {
+            return self.core.push_pinned(index, t);
+        }
+        if self.is_full() {
+            return self.core.push(self.id, t);
+        }
+        self.core.record_spawn(self.id);
+        self.core.capture_context(t.mut_extras());
         self.local_queue.push(t);
+        SpawnPlacement::Local(self.id.saturating_sub(1))
+    }
+
+    /// Spawns a task to the front of the local queue, so it will be the next
+    /// task handled by this worker.
+    ///
+    /// It's an escape hatch for tasks that must run before anything else
+    /// already queued, like cancellation or cleanup. Tasks spawned this way are
+    /// never stolen by other workers, and keep spawning them starves the other
+    /// tasks of this worker.
+    pub fn spawn_urgent(&mut self, task: impl WithExtras<T>) {
+        let mut t = task.with_extras(|| self.local_queue.default_extras());
+        match bound_queue(&mut t) {
+            Some(index) if index + 1 != self.id => {
+                self.core.push_pinned(index, t);
+            }
+            _ => {
+                self.core.record_spawn(self.id);
+                self.core.capture_context(t.mut_extras());
+                self.local_queue.push_front(t);
+            }
+        }
     }
 
     /// Spawns a task to the remote queue.
@@ -274,22 +2134,178 @@ impl<T: TaskCell + Send> Local<T> {
     }
 
     /// Gets a remote so that tasks can be spawned from other threads.
+    ///
+    /// Runners can also keep it to spawn tasks to the global queue outside of
+    /// `Runner::handle`.
     pub fn remote(&self) -> Remote<T> {
         Remote::new(self.core.clone())
     }
 
+    /// Records that this worker starts to handle a task for the stall
+    /// detector.
+    pub(crate) fn begin_handle(&self, task_id: u64) {
+        self.core.record_handle_start(self.id, task_id);
+    }
+
+    /// Records that this worker finishes handling a task for the stall
+    /// detector.
+    pub(crate) fn end_handle(&self) {
+        self.core.record_handle_end(self.id);
+    }
+
+    /// Marks a popped task as handled by this worker.
+    pub(crate) fn task_done(&self) {
+        self.core.task_done(self.id);
+        self.core.record_handled(self.id);
+    }
+
+    /// Checks whether the local queue has reached its capacity, in which case
+    /// tasks spawned by `spawn` go to the global queue.
+    pub fn is_full(&self) -> bool {
+        let cap = self.core.config().local_queue_capacity;
+        cap > 0 && self.local_queue.len() >= cap
+    }
+
+    /// Returns the number of tasks in the local queue of this worker.
+    ///
+    /// Priority queues have no local queues, in which case it's the number
+    /// of tasks in the global queue.
+    pub fn len(&self) -> usize {
+        self.local_queue.len()
+    }
+
+    /// Checks whether the local queue of this worker is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the id of the worker, starting from 1.
+    pub(crate) fn id(&self) -> usize {
+        self.id
+    }
+
     pub(crate) fn weak_remote(&self) -> WeakRemote<T> {
         WeakRemote {
             core: Arc::downgrade(&self.core),
         }
     }
 
-    pub(crate) fn core(&self) -> &Arc<QueueCore<T>> {
-        &self.core
+    pub(crate) fn pop(&mut self) -> Option<Pop<T>> {
+        loop {
+            let mut t = match self.core.pop_pinned(self.id) {
+                Some(t) => t,
+                None => {
+                    let t = self.local_queue.pop();
+                    let retries = self.local_queue.take_contention();
+                    if retries > 0 {
+                        self.core.record_injector_contention(retries);
+                    }
+                    // Idle tasks are only handled when there is nothing else.
+                    let mut t = match t {
+                        Some(t) => t,
+                        None => self.core.pop_idle()?,
+                    };
+                    self.bind(&mut t.task_cell);
+                    t
+                }
+            };
+            // Tasks of cancelled groups are dropped without being handled.
+            let extras = t.task_cell.mut_extras();
+            if self.core.is_cancelled(extras) {
+                // A pending future of the group may hold a permit.
+                let permit = extras.group_id.filter(|_| extras.group_permit);
+                drop(t);
+                if let Some(group_id) = permit {
+                    self.core.release_group_permit(group_id);
+                }
+                self.core.task_done(self.id);
+                continue;
+            }
+            self.core.observe_wakeup_latency(t.task_cell.mut_extras());
+            self.core
+                .observe_first_poll_latency(t.task_cell.mut_extras());
+            let schedule_time = t.schedule_time;
+            self.observe_task_kind(t.task_cell.mut_extras(), schedule_time);
+            return Some(t);
+        }
     }
 
-    pub(crate) fn pop(&mut self) -> Option<Pop<T>> {
-        self.local_queue.pop()
+    /// Records the wait time of a task that has a kind and counts it as
+    /// handled.
+    fn observe_task_kind(&mut self, extras: &Extras, schedule_time: Instant) {
+        if !cfg!(feature = "metrics") {
+            return;
+        }
+        if let Some(kind) = extras.kind {
+            let name = &self.core.name;
+            let (wait, handled) = self.kind_metrics.entry(kind).or_insert_with(|| {
+                (
+                    crate::metrics::TASK_KIND_WAIT_DURATION.with_label_values(&[name, kind]),
+                    crate::metrics::TASK_KIND_HANDLED_TOTAL.with_label_values(&[name, kind]),
+                )
+            });
+            wait.observe(schedule_time.elapsed().as_secs_f64());
+            handled.inc();
+        }
+    }
+
+    /// Empties the queues when the worker exits.
+    ///
+    /// The tasks are dropped, unless the core keeps them for
+    /// `ThreadPool::drain_with`.
+    pub(crate) fn clear(&mut self) {
+        if !self.core.keep_leftovers.load(Ordering::SeqCst) {
+            // Drains the queues directly, as `pop` reports the tasks as
+            // handled. Cancelled tasks are not counted.
+            let mut dropped = 0;
+            while let Some(mut t) = self
+                .core
+                .pop_pinned(self.id)
+                .or_else(|| self.local_queue.pop())
+                .or_else(|| self.core.pop_idle())
+            {
+                let extras = t.task_cell.mut_extras();
+                if !self.core.is_cancelled(extras) {
+                    dropped += 1;
+                    continue;
+                }
+                let permit = extras.group_id.filter(|_| extras.group_permit);
+                drop(t);
+                if let Some(group_id) = permit {
+                    self.core.release_group_permit(group_id);
+                }
+                self.core.task_done(self.id);
+            }
+            dropped += self.core.take_all_deferred().len() as u64;
+            self.core.dropped_on_shutdown.inc_by(dropped);
+            return;
+        }
+        for t in self.core.take_all_deferred() {
+            self.core.leftovers.push(t);
+        }
+        while let Some(mut t) = self
+            .core
+            .pop_pinned(self.id)
+            .or_else(|| self.local_queue.pop())
+            .or_else(|| self.core.pop_idle())
+        {
+            if !self.core.is_cancelled(t.task_cell.mut_extras()) {
+                self.core.leftovers.push(t.task_cell);
+            }
+        }
+    }
+
+    /// Binds a no-steal task to this worker if it's not bound yet.
+    ///
+    /// Burst workers don't bind tasks as they may exit at any time.
+    fn bind(&self, task: &mut T) {
+        let extras = task.mut_extras();
+        if extras.no_steal
+            && extras.bound_worker == 0
+            && self.id <= self.core.config.max_thread_count
+        {
+            extras.bound_worker = self.id;
+        }
     }
 
     /// Pops a task from the queue.
@@ -308,8 +2324,8 @@ impl<T: TaskCell + Send> Local<T> {
                     if !self.core.mark_sleep() {
                         return false;
                     }
-                    task = self.local_queue.pop();
-                    task.is_none()
+                    task = self.pop();
+                    task.is_none() && !self.core.should_keep_awake() && !self.core.is_quiesced(id)
                 },
                 || {},
                 |_, _| {},
@@ -326,10 +2342,139 @@ impl<T: TaskCell + Send> Local<T> {
         }
     }
 
+    /// Parks the worker until it's no longer quiesced or the pool is shut
+    /// down.
+    ///
+    /// Tasks in the local queue are moved to the global queue first, so other
+    /// workers can handle them. Tasks pinned to the worker wait for it.
+    pub(crate) fn park_quiesced(&mut self) {
+        let mut moved = false;
+        // Tasks bound to this worker are in its pinned queue, so the ones in
+        // the local queue can be handled by any worker.
+        while let Some(t) = self.local_queue.take_local() {
+            self.core.global_queue.push(t);
+            moved = true;
+        }
+        if moved {
+            self.core.ensure_workers(self.id);
+        }
+        let (core, id) = (&self.core, self.id);
+        let state = match core.quiesce_state(id) {
+            Some(state) => state,
+            None => return,
+        };
+        if !core.mark_sleep() {
+            return;
+        }
+        state.parked.store(true, Ordering::SeqCst);
+        unsafe {
+            // Wake up the threads waiting in `WorkerGuard::wait_quiesced`.
+            parking_lot_core::unpark_all(&state.parked as *const _ as usize, UnparkToken(id));
+            parking_lot_core::park(
+                &state.requests as *const _ as usize,
+                || core.is_quiesced(id) && !core.is_shutdown(),
+                || {},
+                |_, _| {},
+                ParkToken(id),
+                None,
+            );
+        }
+        state.parked.store(false, Ordering::SeqCst);
+        self.core.mark_woken();
+    }
+
+    /// Calls `f` with the scratch value of type `S` of this worker, which is
+    /// created by `Default` on first use.
+    ///
+    /// Tasks handled by the same worker see the changes made by each other,
+    /// so it can be used to reuse buffers without allocating in every task.
+    pub fn with_scratch<S, R>(&mut self, f: impl FnOnce(&mut S) -> R) -> R
+    where
+        S: Default + Send + 'static,
+    {
+        let mut scratch = self.take_scratch();
+        let res = f(&mut scratch);
+        self.put_scratch(scratch);
+        res
+    }
+
+    /// Takes the scratch value of type `S` out, so it can be used without
+    /// borrowing the `Local`.
+    pub(crate) fn take_scratch<S: Default + Send + 'static>(&mut self) -> Box<S> {
+        match self.scratch.remove(&TypeId::of::<S>()) {
+            Some(s) => s.downcast().unwrap(),
+            None => Box::default(),
+        }
+    }
+
+    /// Puts back a scratch value taken by `take_scratch`.
+    pub(crate) fn put_scratch<S: Send + 'static>(&mut self, scratch: Box<S>) {
+        self.scratch.insert(TypeId::of::<S>(), scratch);
+    }
+
+    /// Returns a handle that wakes up this worker from [`Local::park_until`].
+    pub fn unparker(&self) -> Unparker<T> {
+        Unparker {
+            id: self.id,
+            requested: self.unpark_requested.clone(),
+            core: Arc::downgrade(&self.core),
+        }
+    }
+
+    /// Puts the current worker to sleep until `deadline`, a task arrives or
+    /// it's woken up by its [`Unparker`].
+    ///
+    /// Tasks are left in the queues. Returns false if it timed out.
+    pub fn park_until(&mut self, deadline: Instant) -> bool {
+        let address = self.core.park_address();
+        let id = self.id;
+        // Burst workers are not counted as awake workers.
+        let counted = !self.core.is_burst_worker(id);
+
+        let res = unsafe {
+            parking_lot_core::park(
+                address,
+                || {
+                    if counted && !self.core.mark_sleep() {
+                        return false;
+                    }
+                    !self.unpark_requested.swap(false, Ordering::SeqCst)
+                        && !self.core.has_pinned(id)
+                        && self.core.idle_queue.is_empty()
+                        && !self.local_queue.has_tasks_or_pull()
+                        && !self.core.should_keep_awake()
+                },
+                || {},
+                |_, _| {},
+                ParkToken(id),
+                Some(deadline),
+            )
+        };
+        if counted {
+            self.core.mark_woken();
+        }
+        match res {
+            ParkResult::Unparked(_) => {
+                self.unpark_requested.store(false, Ordering::SeqCst);
+                true
+            }
+            ParkResult::Invalid => true,
+            ParkResult::TimedOut => false,
+        }
+    }
+
+    /// Checks whether the local queue is a multilevel task queue.
+    pub(crate) fn is_multilevel(&self) -> bool {
+        self.local_queue.is_multilevel()
+    }
+
     /// Returns whether there are preemptive tasks to run.
     ///
-    /// If the pool is not busy, other tasks should not preempt the current running task.
-    pub(crate) fn need_preempt(&mut self) -> bool {
+    /// If the pool is not busy, other tasks should not preempt the current
+    /// running task. It may pull tasks from the global queue to the local
+    /// queue, so runners can use it to decide whether to reschedule a task
+    /// that can't be finished in one go.
+    pub fn need_preempt(&mut self) -> bool {
         fail_point!("need-preempt", |r| { r.unwrap().parse().unwrap() });
         self.local_queue.has_tasks_or_pull()
     }
@@ -347,13 +2492,17 @@ where
     T: TaskCell + Send,
 {
     let queue_type = queue_type.into();
-    let (global, locals) = crate::queue::build(queue_type, config.max_thread_count);
-    let core = Arc::new(QueueCore::new(global, config));
+    let (global, locals) = crate::queue::build_seeded(
+        queue_type,
+        config.max_thread_count,
+        config.injector_shards,
+        config.deterministic_seed,
+    );
+    let g = Remote::from_injector(global, config);
     let l = locals
         .into_iter()
         .enumerate()
-        .map(|(i, l)| Local::new(i + 1, l, core.clone()))
+        .map(|(i, l)| g.local(i + 1, l))
         .collect();
-    let g = Remote::new(core);
     (g, l)
 }
@@ -1,10 +1,13 @@
 // Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
 
 use crate::pool::*;
-use crate::task::callback::Handle;
+use crate::queue::{
+    multilevel, priority, Extras, ExtrasBuilder, QueueKind, QueueType, TaskCell, TaskSource,
+};
+use crate::task::callback::{self, Handle};
 use futures_timer::Delay;
 use rand::seq::SliceRandom;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 use std::time::*;
 
@@ -294,3 +297,2683 @@ fn test_scale_down_workers() {
 
     pool.shutdown();
 }
+
+#[test]
+fn test_empty_name() {
+    for name in &["", "  \t"] {
+        let builder = Builder::new(*name);
+        assert_eq!(builder.validate(), Err(ConfigError::EmptyName));
+        let rb = CloneRunnerBuilder(callback::Runner::default());
+        assert_eq!(
+            builder
+                .try_build_with_queue_and_runner(QueueType::SingleLevel, rb)
+                .err(),
+            Some(ConfigError::EmptyName)
+        );
+    }
+
+    let mut builder = Builder::new("test_empty_name");
+    assert_eq!(builder.validate(), Ok(()));
+    let rb = CloneRunnerBuilder(callback::Runner::default());
+    let pool = builder
+        .max_thread_count(1)
+        .try_build_with_queue_and_runner(QueueType::SingleLevel, rb)
+        .unwrap();
+    let (tx, rx) = mpsc::channel();
+    pool.spawn(move |_: &mut Handle<'_>| tx.send(1).unwrap());
+    assert_eq!(Ok(1), rx.recv_timeout(Duration::from_secs(1)));
+    pool.shutdown();
+
+    // Pools of different names never share metrics.
+    #[cfg(feature = "metrics")]
+    {
+        let names = ["test_empty_name_a", "test_empty_name_b"];
+        let pools: Vec<_> = names
+            .iter()
+            .zip([1, 2])
+            .map(|(name, threads)| {
+                Builder::new(*name)
+                    .max_thread_count(threads)
+                    .max_pop_spin(2)
+                    .max_pop_yield(3)
+                    .build_callback_pool()
+            })
+            .collect();
+        thread::sleep(Duration::from_millis(100));
+        // Every idle worker fails to pop 2 + 3 + 1 times before sleeping.
+        for (name, threads) in names.iter().zip([1, 2]) {
+            let spins = crate::metrics::WORKER_SPIN_TOTAL.with_label_values(&[name]);
+            assert_eq!(spins.get(), 6 * threads, "{}", name);
+        }
+        for pool in pools {
+            pool.shutdown();
+        }
+    }
+}
+
+#[test]
+fn test_shutdown_async() {
+    let pool = Builder::new("test_shutdown_async")
+        .max_thread_count(4)
+        .build_callback_pool();
+    let (tx, rx) = mpsc::channel();
+    let t = tx.clone();
+    pool.spawn(move |_: &mut Handle<'_>| {
+        thread::sleep(Duration::from_millis(100));
+        t.send(0).unwrap();
+    });
+    thread::sleep(Duration::from_millis(50));
+
+    // Awaits the shutdown in another pool.
+    let runtime = Builder::new("test_shutdown_async_runtime")
+        .max_thread_count(1)
+        .build_future_pool();
+    let shutdown = pool.shutdown_async();
+    runtime.spawn(async move {
+        shutdown.await;
+        tx.send(1).unwrap();
+    });
+    // The running task should finish before the future resolves.
+    assert_eq!(Ok(0), rx.recv_timeout(Duration::from_secs(1)));
+    assert_eq!(Ok(1), rx.recv_timeout(Duration::from_secs(1)));
+
+    // All workers have exited, awaiting again resolves immediately.
+    let (tx, rx) = mpsc::channel();
+    let shutdown = pool.shutdown_async();
+    runtime.spawn(async move {
+        shutdown.await;
+        tx.send(2).unwrap();
+    });
+    assert_eq!(Ok(2), rx.recv_timeout(Duration::from_secs(1)));
+    runtime.shutdown();
+}
+
+#[test]
+fn test_shutdown_async_repoll() {
+    use std::future::Future;
+    use std::task::{Context, Poll, Wake, Waker};
+
+    struct CountWake(AtomicUsize);
+
+    impl Wake for CountWake {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let pool = Builder::new("test_shutdown_async_repoll")
+        .max_thread_count(1)
+        .build_callback_pool();
+    let (block_tx, block_rx) = mpsc::channel::<()>();
+    let (tx, rx) = mpsc::channel();
+    pool.spawn(move |_: &mut Handle<'_>| {
+        tx.send(()).unwrap();
+        let _ = block_rx.recv();
+    });
+    rx.recv_timeout(Duration::from_secs(1)).unwrap();
+
+    let mut shutdown = Box::pin(pool.shutdown_async());
+    let wake = Arc::new(CountWake(AtomicUsize::new(0)));
+    let waker = Waker::from(wake.clone());
+    let other = Waker::from(Arc::new(CountWake(AtomicUsize::new(0))));
+    // The registered waker is replaced instead of piling up.
+    for w in [&waker, &waker, &other, &waker] {
+        let mut cx = Context::from_waker(w);
+        assert_eq!(shutdown.as_mut().poll(&mut cx), Poll::Pending);
+        assert_eq!(pool.live_workers.wakers.lock().unwrap().wakers.len(), 1);
+    }
+
+    drop(block_tx);
+    pool.shutdown();
+    assert_eq!(wake.0.load(Ordering::SeqCst), 1);
+    let mut cx = Context::from_waker(&waker);
+    assert_eq!(shutdown.as_mut().poll(&mut cx), Poll::Ready(()));
+
+    // Dropping a pending future unregisters its waker.
+    let pool = Builder::new("test_shutdown_async_repoll")
+        .max_thread_count(1)
+        .build_callback_pool();
+    let (block_tx, block_rx) = mpsc::channel::<()>();
+    pool.spawn(move |_: &mut Handle<'_>| {
+        let _ = block_rx.recv();
+    });
+    let mut shutdown = Box::pin(pool.shutdown_async());
+    let mut cx = Context::from_waker(&waker);
+    assert_eq!(shutdown.as_mut().poll(&mut cx), Poll::Pending);
+    drop(shutdown);
+    assert!(pool.live_workers.wakers.lock().unwrap().wakers.is_empty());
+    drop(block_tx);
+    pool.shutdown();
+}
+
+#[test]
+fn test_min_awake_under_load() {
+    let pool = Builder::new("test_min_awake_under_load")
+        .max_thread_count(4)
+        .min_thread_count(3)
+        .min_awake_under_load(true)
+        .build_callback_pool();
+    // Make sure all workers have run and gone to sleep before spawn new tasks
+    thread::sleep(Duration::from_millis(500));
+
+    let (tx, rx) = mpsc::channel();
+    for _ in 0..200 {
+        let t = tx.clone();
+        let core = pool.remote().core.clone();
+        pool.spawn(move |_: &mut Handle<'_>| {
+            // Keep the queue non-empty while sampling.
+            thread::sleep(Duration::from_millis(1));
+            t.send(core.awake_workers()).unwrap();
+        });
+    }
+    drop(tx);
+    let samples: Vec<_> = rx.iter().collect();
+    assert_eq!(samples.len(), 200);
+    // Workers may need some time to be woken up at the beginning.
+    for awake in &samples[20..180] {
+        assert!(*awake >= 3, "{:?}", samples);
+    }
+    pool.shutdown();
+}
+
+#[test]
+fn test_recent_spawn_rate() {
+    let pool = Builder::new("test_recent_spawn_rate")
+        .max_thread_count(2)
+        .build_callback_pool();
+    let remote = pool.remote();
+    assert_eq!(remote.recent_spawn_rate(), 0.0);
+
+    // Spawn about 200 tasks per second.
+    let begin = Instant::now();
+    while begin.elapsed() < Duration::from_millis(500) {
+        remote.spawn(move |_: &mut Handle<'_>| {});
+        thread::sleep(Duration::from_millis(5));
+    }
+    let rate = remote.recent_spawn_rate();
+    assert!(rate > 100.0 && rate < 250.0, "{}", rate);
+
+    // The estimate should decay when nothing is spawned.
+    thread::sleep(Duration::from_secs(1));
+    let rate = remote.recent_spawn_rate();
+    assert!(rate < 100.0, "{}", rate);
+    pool.shutdown();
+}
+
+#[test]
+fn test_spawn_rate_skips_wakeups() {
+    let pool = Builder::new("test_spawn_rate_skips_wakeups")
+        .max_thread_count(1)
+        .build_future_pool();
+    let (tx, rx) = mpsc::channel();
+    pool.spawn(async move {
+        // Woken up by the timer thread and pushed to the global queue.
+        for _ in 0..3 {
+            Delay::new(Duration::from_millis(1)).await;
+        }
+        crate::task::future::spawn(async move { tx.send(()).unwrap() });
+    });
+    rx.recv_timeout(Duration::from_secs(3)).unwrap();
+    // Only the future and its child are counted.
+    assert_eq!(pool.remote().core.spawned_tasks(), 2);
+    pool.shutdown();
+}
+
+#[test]
+fn test_run_on_caller() {
+    let pool = Builder::new("test_run_on_caller")
+        .max_thread_count(1)
+        .overflow_policy(OverflowPolicy::RunOnCaller { queue_threshold: 1 })
+        .build_callback_pool();
+    let caller = thread::current().id();
+
+    // Block the only worker.
+    let (block_tx, block_rx) = mpsc::channel::<()>();
+    let (tx, rx) = mpsc::channel();
+    let t = tx.clone();
+    pool.spawn(move |_: &mut Handle<'_>| {
+        t.send(thread::current().id()).unwrap();
+        block_rx.recv().unwrap();
+    });
+    assert_ne!(rx.recv_timeout(Duration::from_secs(1)).unwrap(), caller);
+
+    // The queue is not full yet, so the task is queued.
+    let t = tx.clone();
+    pool.spawn(move |_: &mut Handle<'_>| t.send(thread::current().id()).unwrap());
+    assert_eq!(
+        rx.recv_timeout(Duration::from_millis(200)),
+        Err(mpsc::RecvTimeoutError::Timeout)
+    );
+
+    // The task runs on the caller when the queue exceeds the threshold.
+    let t = tx.clone();
+    pool.spawn(move |h: &mut Handle<'_>| {
+        t.send(thread::current().id()).unwrap();
+        // Tasks spawned by it are queued.
+        let t = t.clone();
+        h.spawn(move |_: &mut Handle<'_>| t.send(thread::current().id()).unwrap());
+    });
+    assert_eq!(rx.try_recv(), Ok(caller));
+
+    block_tx.send(()).unwrap();
+    for _ in 0..2 {
+        assert_ne!(rx.recv_timeout(Duration::from_secs(1)).unwrap(), caller);
+    }
+    pool.shutdown();
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn test_active_workers_count() {
+    let name = "test_active_workers_count";
+    let hist = crate::metrics::ACTIVE_WORKERS_COUNT.with_label_values(&[name]);
+    let pool = Builder::new(name).max_thread_count(2).build_callback_pool();
+    let (tx, rx) = mpsc::channel();
+    for _ in 0..10 {
+        let t = tx.clone();
+        pool.spawn(move |_: &mut Handle<'_>| {
+            thread::sleep(Duration::from_millis(10));
+            t.send(()).unwrap();
+        });
+        thread::sleep(Duration::from_millis(20));
+    }
+    for _ in 0..10 {
+        rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    }
+    pool.shutdown();
+    assert!(hist.get_sample_count() > 0);
+    assert!(hist.get_sample_sum() > 0.0);
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn test_pop_backoff_counts() {
+    let name = "test_pop_backoff_counts";
+    let counter = crate::metrics::WORKER_SPIN_TOTAL.with_label_values(&[name]);
+    let pool = Builder::new(name)
+        .max_thread_count(1)
+        .max_pop_spin(2)
+        .max_pop_yield(3)
+        .build_callback_pool();
+    thread::sleep(Duration::from_millis(100));
+    // Every failed pop is counted, the last one sends the worker to sleep.
+    assert_eq!(counter.get(), 2 + 3 + 1);
+    pool.shutdown();
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn test_worker_spin_total() {
+    let name = "test_worker_spin_total";
+    let counter = crate::metrics::WORKER_SPIN_TOTAL.with_label_values(&[name]);
+    let pool = Builder::new(name).max_thread_count(2).build_callback_pool();
+    // Workers spin before going to sleep.
+    thread::sleep(Duration::from_millis(100));
+    let idle_spins = counter.get();
+    assert!(idle_spins > 0);
+
+    let (tx, rx) = mpsc::channel();
+    for _ in 0..10 {
+        let t = tx.clone();
+        pool.spawn(move |_: &mut Handle<'_>| t.send(()).unwrap());
+    }
+    for _ in 0..10 {
+        rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    }
+    thread::sleep(Duration::from_millis(100));
+    let spins = counter.get();
+    assert!(spins >= idle_spins);
+    // No more spins after workers go to sleep again.
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(counter.get(), spins);
+    pool.shutdown();
+}
+
+#[test]
+fn test_backoff_strategy() {
+    use crate::pool::spawn::Backoff;
+
+    // A mock clock in the format of `Backoff`.
+    let at = |ms: f64| (ms * 1_000_000.0) as u64 + 1;
+    let ms = Duration::from_millis;
+    let backoff = Backoff::new(BackoffStrategy::Exponential {
+        base: ms(1),
+        max: ms(8),
+    });
+    assert_eq!(backoff.try_acquire(at(0.0)), Ok(()));
+    assert_eq!(backoff.interval(), ms(1));
+    // Rapid wakes are throttled until the interval elapses, and the interval
+    // grows every time up to the maximum.
+    assert_eq!(backoff.try_acquire(at(0.5)), Err(at(1.0)));
+    assert_eq!(backoff.try_acquire(at(1.0)), Ok(()));
+    assert_eq!(backoff.interval(), ms(2));
+    assert_eq!(backoff.try_acquire(at(2.0)), Err(at(3.0)));
+    assert_eq!(backoff.try_acquire(at(3.0)), Ok(()));
+    assert_eq!(backoff.interval(), ms(4));
+    assert_eq!(backoff.try_acquire(at(7.0)), Ok(()));
+    assert_eq!(backoff.interval(), ms(8));
+    assert_eq!(backoff.try_acquire(at(15.0)), Ok(()));
+    assert_eq!(backoff.interval(), ms(8));
+    // It resets after being idle for twice the interval.
+    assert_eq!(backoff.try_acquire(at(31.0)), Ok(()));
+    assert_eq!(backoff.interval(), ms(1));
+    assert_eq!(backoff.try_acquire(at(31.5)), Err(at(32.0)));
+    backoff.reset();
+    assert_eq!(backoff.try_acquire(at(31.5)), Ok(()));
+
+    let backoff = Backoff::new(BackoffStrategy::Fixed(ms(2)));
+    for i in 0..4 {
+        let now = i as f64 * 2.0;
+        assert_eq!(backoff.try_acquire(at(now)), Ok(()));
+        assert_eq!(backoff.try_acquire(at(now + 1.0)), Err(at(now + 2.0)));
+        assert_eq!(backoff.interval(), ms(2));
+    }
+}
+
+#[test]
+fn test_wake_backoff() {
+    let pool = Builder::new("test_wake_backoff")
+        .max_thread_count(2)
+        .wake_backoff(Duration::from_millis(200))
+        .build_callback_pool();
+    // Let all workers go to sleep.
+    thread::sleep(Duration::from_millis(100));
+    let (block_tx, block_rx) = mpsc::channel::<()>();
+    let (tx, rx) = mpsc::channel();
+    let t = tx.clone();
+    pool.spawn(move |_: &mut Handle<'_>| {
+        t.send(()).unwrap();
+        block_rx.recv().unwrap();
+    });
+    rx.recv_timeout(Duration::from_secs(1)).unwrap();
+
+    // The wake is deferred while the only awake worker is busy, but not
+    // dropped.
+    let now = Instant::now();
+    pool.spawn(move |_: &mut Handle<'_>| tx.send(()).unwrap());
+    rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert!(
+        now.elapsed() >= Duration::from_millis(100),
+        "{:?}",
+        now.elapsed()
+    );
+
+    block_tx.send(()).unwrap();
+    pool.shutdown();
+}
+
+#[test]
+fn test_burst_workers() {
+    let pool = Builder::new("test_burst_workers")
+        .max_thread_count(1)
+        .burst_thread_count(2)
+        .burst_queue_threshold(2)
+        .max_wait_time(Duration::from_millis(10))
+        .max_idle_time(Duration::from_millis(100))
+        .build_callback_pool();
+    let core = pool.remote().core.clone();
+
+    // Block the only worker.
+    let (block_tx, block_rx) = mpsc::channel::<()>();
+    let (tx, rx) = mpsc::channel();
+    let t = tx.clone();
+    pool.spawn(move |_: &mut Handle<'_>| {
+        t.send(thread::current().name().unwrap().to_owned())
+            .unwrap();
+        block_rx.recv().unwrap();
+    });
+    assert_eq!(
+        rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+        "test_burst_workers-0"
+    );
+
+    // Keep the queue backlogged for a while.
+    let spawn = |n| {
+        for _ in 0..n {
+            let t = tx.clone();
+            pool.spawn(move |_: &mut Handle<'_>| {
+                t.send(thread::current().name().unwrap().to_owned())
+                    .unwrap()
+            });
+        }
+    };
+    spawn(3);
+    assert_eq!(core.burst_workers(), 0);
+    thread::sleep(Duration::from_millis(20));
+    spawn(1);
+    assert_eq!(core.burst_workers(), 1);
+    for _ in 0..4 {
+        let name = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(name.starts_with("test_burst_workers-"), "{}", name);
+        assert_ne!(name, "test_burst_workers-0");
+    }
+
+    // Idle burst workers are woken up by new tasks.
+    thread::sleep(Duration::from_millis(20));
+    assert_eq!(core.burst_workers(), 1);
+    let since = Instant::now();
+    spawn(1);
+    rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert!(since.elapsed() < Duration::from_millis(50));
+
+    // Burst workers exit after being idle.
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(core.burst_workers(), 0);
+
+    block_tx.send(()).unwrap();
+    pool.shutdown();
+}
+
+#[test]
+fn test_reconfigure_runner() {
+    #[derive(Clone)]
+    struct TagRunner {
+        tag: u32,
+        inner: callback::Runner,
+        tx: Arc<Mutex<mpsc::Sender<u32>>>,
+    }
+
+    impl Runner for TagRunner {
+        type TaskCell = callback::TaskCell;
+
+        fn handle(&mut self, local: &mut Local<Self::TaskCell>, t: Self::TaskCell) -> bool {
+            self.tx.lock().unwrap().send(self.tag).unwrap();
+            self.inner.handle(local, t)
+        }
+    }
+
+    let (tx, rx) = mpsc::channel();
+    let tx = Arc::new(Mutex::new(tx));
+    let runner = TagRunner {
+        tag: 1,
+        inner: callback::Runner::default(),
+        tx: tx.clone(),
+    };
+    let pool = Builder::new("test_reconfigure_runner")
+        .max_thread_count(2)
+        .build_with_queue_and_runner(QueueType::SingleLevel, CloneRunnerBuilder(runner));
+    let (done_tx, done_rx) = mpsc::channel();
+    let t = done_tx.clone();
+    pool.spawn(move |_: &mut Handle<'_>| t.send(()).unwrap());
+    done_rx.recv_timeout(Duration::from_secs(3)).unwrap();
+    assert_eq!(rx.recv().unwrap(), 1);
+
+    pool.reconfigure_runner(move || TagRunner {
+        tag: 2,
+        inner: callback::Runner::default(),
+        tx: tx.clone(),
+    });
+    for _ in 0..4 {
+        let t = done_tx.clone();
+        pool.spawn(move |_: &mut Handle<'_>| t.send(()).unwrap());
+        done_rx.recv_timeout(Duration::from_secs(3)).unwrap();
+        assert_eq!(rx.recv().unwrap(), 2);
+    }
+    pool.shutdown();
+}
+
+#[test]
+fn test_local_len() {
+    // Defers a task to the global queue if there are other tasks queued.
+    struct DeferRunner(callback::Runner);
+
+    impl Runner for DeferRunner {
+        type TaskCell = callback::TaskCell;
+
+        fn handle(&mut self, local: &mut Local<Self::TaskCell>, t: Self::TaskCell) -> bool {
+            if !local.is_empty() {
+                local.spawn_remote(t);
+                return false;
+            }
+            self.0.handle(local, t)
+        }
+    }
+
+    let (_, mut locals) = build_spawn(QueueType::SingleLevel, Default::default());
+    let local = &mut locals[0];
+    let mut runner = DeferRunner(callback::Runner::default());
+    let (tx, rx) = mpsc::channel();
+    for i in 0..2 {
+        let tx = tx.clone();
+        local.spawn(move |_: &mut Handle<'_>| tx.send(i).unwrap());
+    }
+    assert_eq!(local.len(), 2);
+
+    // The local queue is LIFO, so task 1 is popped first but deferred.
+    let t = local.pop().unwrap().task_cell;
+    assert!(!runner.handle(local, t));
+    assert_eq!(local.len(), 1);
+    assert!(rx.try_recv().is_err());
+    let t = local.pop().unwrap().task_cell;
+    assert!(runner.handle(local, t));
+    assert_eq!(rx.recv().unwrap(), 0);
+    assert!(local.is_empty());
+
+    // The deferred task is pulled back from the global queue.
+    let t = local.pop().unwrap().task_cell;
+    assert!(runner.handle(local, t));
+    assert_eq!(rx.recv().unwrap(), 1);
+}
+
+#[test]
+fn test_per_worker_handled() {
+    let pool = Builder::new("test_per_worker_handled")
+        .max_thread_count(4)
+        .build_callback_pool();
+    assert_eq!(pool.per_worker_handled(), vec![0; 4]);
+    let (tx, rx) = mpsc::channel();
+    for _ in 0..100 {
+        let t = tx.clone();
+        pool.spawn(move |_: &mut Handle<'_>| {
+            thread::sleep(Duration::from_millis(1));
+            t.send(()).unwrap();
+        });
+    }
+    for _ in 0..100 {
+        rx.recv_timeout(Duration::from_secs(3)).unwrap();
+    }
+    // Counters are updated after tasks return.
+    let deadline = Instant::now() + Duration::from_secs(3);
+    while pool.per_worker_handled().iter().sum::<u64>() < 100 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(1));
+    }
+    let handled = pool.per_worker_handled();
+    assert_eq!(handled.len(), 4);
+    assert_eq!(handled.iter().sum::<u64>(), 100);
+    pool.shutdown();
+}
+
+#[test]
+fn test_shared_pool() {
+    let mut builder = Builder::new("test_shared_pool");
+    builder.max_thread_count(2);
+    let pool = SharedPoolBuilder::new(&builder)
+        .queue(QueueType::SingleLevel)
+        .queue(QueueType::SingleLevel)
+        .build(CloneRunnerBuilder(callback::Runner::default()));
+    assert_eq!(pool.remotes().len(), 2);
+
+    let names = Arc::new(Mutex::new(std::collections::HashSet::new()));
+    let (tx, rx) = mpsc::channel();
+    // Keep the first queue busy, the second one should still be serviced.
+    for _ in 0..20 {
+        let (t, names) = (tx.clone(), names.clone());
+        pool.remote(0).spawn(move |_: &mut Handle<'_>| {
+            let name = thread::current().name().unwrap().to_owned();
+            names.lock().unwrap().insert(name);
+            thread::sleep(Duration::from_millis(5));
+            t.send(0).unwrap();
+        });
+    }
+    for _ in 0..5 {
+        let t = tx.clone();
+        pool.remote(1).spawn(move |h: &mut Handle<'_>| {
+            h.spawn(move |_: &mut Handle<'_>| t.send(1).unwrap());
+        });
+    }
+    let mut counts = [0; 2];
+    for _ in 0..25 {
+        counts[rx.recv_timeout(Duration::from_secs(3)).unwrap()] += 1;
+    }
+    assert_eq!(counts, [20, 5]);
+    // Only the shared threads handle tasks.
+    let names = names.lock().unwrap();
+    assert!(names.len() <= 2);
+    assert!(names.iter().all(|n| n.starts_with("test_shared_pool-")));
+    drop(names);
+
+    pool.shutdown();
+    pool.remote(1)
+        .spawn(move |_: &mut Handle<'_>| tx.send(1).unwrap());
+    assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+}
+
+#[test]
+fn test_shared_pool_spawn_on() {
+    let mut builder = Builder::new("test_shared_pool_spawn_on");
+    builder.max_thread_count(2);
+    let pool = SharedPoolBuilder::new(&builder)
+        .queue(QueueType::SingleLevel)
+        .queue(QueueType::SingleLevel)
+        .build(CloneRunnerBuilder(callback::Runner::default()));
+    let (tx, rx) = mpsc::channel();
+    for worker in 0..4 {
+        // Shared workers park on their group, pinned tasks must wake them
+        // up there.
+        let since = Instant::now();
+        while pool.group.awake_workers() > 0 {
+            assert!(since.elapsed() < Duration::from_secs(3));
+            thread::sleep(Duration::from_millis(1));
+        }
+        let t = tx.clone();
+        pool.remote(worker % 2)
+            .spawn_on(worker, move |_: &mut Handle<'_>| {
+                let name = thread::current().name().unwrap().to_owned();
+                t.send(name).unwrap();
+            });
+        let name = rx.recv_timeout(Duration::from_secs(3)).unwrap();
+        assert_eq!(name, format!("test_shared_pool_spawn_on-{}", worker % 2));
+    }
+    pool.shutdown();
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn test_shared_pool_pop_backoff() {
+    let name = "test_shared_pool_pop_backoff";
+    let counter = crate::metrics::WORKER_SPIN_TOTAL.with_label_values(&[name]);
+    let mut builder = Builder::new(name);
+    builder.max_thread_count(1).max_pop_spin(2).max_pop_yield(3);
+    let pool = SharedPoolBuilder::new(&builder)
+        .queue(QueueType::SingleLevel)
+        .queue(QueueType::SingleLevel)
+        .build(CloneRunnerBuilder(callback::Runner::default()));
+    thread::sleep(Duration::from_millis(100));
+    // Same as the workers of other pools.
+    assert_eq!(counter.get(), 2 + 3 + 1);
+    pool.shutdown();
+}
+
+#[test]
+fn test_stall_detector() {
+    let name = "test_stall_detector";
+    let stalls = crate::metrics::WORKER_STALL_TOTAL.with_label_values(&[name]);
+    let pool = Builder::new(name)
+        .max_thread_count(1)
+        .stall_detector(Duration::from_millis(50))
+        .build_callback_pool();
+    let (tx, rx) = mpsc::channel();
+    let t = tx.clone();
+    pool.spawn(move |_: &mut Handle<'_>| t.send(()).unwrap());
+    rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(stalls.get(), 0);
+
+    pool.spawn(move |_: &mut Handle<'_>| {
+        thread::sleep(Duration::from_millis(300));
+        tx.send(()).unwrap();
+    });
+    rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    // The stall is reported only once.
+    assert_eq!(stalls.get(), 1);
+    pool.shutdown();
+
+    // The detector is woken up and joined instead of finishing its sleep.
+    let pool = Builder::new("test_stall_detector_shutdown")
+        .max_thread_count(1)
+        .stall_detector(Duration::from_secs(60))
+        .build_callback_pool();
+    let now = Instant::now();
+    pool.shutdown();
+    assert!(
+        now.elapsed() < Duration::from_secs(10),
+        "{:?}",
+        now.elapsed()
+    );
+}
+
+#[test]
+fn test_queue_type() {
+    struct Fifo;
+
+    impl priority::TaskPriorityProvider for Fifo {
+        fn priority_of(&self, _: &crate::queue::Extras) -> u64 {
+            0
+        }
+    }
+
+    let mut builder = Builder::new("test_queue_type");
+    builder.max_thread_count(1);
+    let pool = builder.build_future_pool();
+    assert_eq!(pool.queue_type(), QueueKind::SingleLevel);
+    pool.shutdown();
+    let pool = builder.build_multilevel_future_pool();
+    assert_eq!(pool.queue_type(), QueueKind::Multilevel);
+    pool.shutdown();
+    let pool = builder.build_priority_future_pool(Arc::new(Fifo));
+    assert_eq!(pool.queue_type(), QueueKind::Priority);
+    pool.shutdown();
+    let queue_type = QueueType::StrictPriority { classes: 3 };
+    assert_eq!(queue_type.kind(), QueueKind::StrictPriority { classes: 3 });
+    let pool = builder
+        .build_with_queue_and_runner(queue_type, CloneRunnerBuilder(callback::Runner::default()));
+    assert_eq!(pool.queue_type(), QueueKind::StrictPriority { classes: 3 });
+    pool.shutdown();
+    assert_eq!(
+        builder
+            .try_build_with_queue_and_runner(
+                QueueType::StrictPriority { classes: 0 },
+                CloneRunnerBuilder(callback::Runner::default())
+            )
+            .err(),
+        Some(ConfigError::NoPriorityClasses)
+    );
+    assert_eq!(
+        QueueType::from(multilevel::Builder::new(Default::default())).kind(),
+        QueueKind::Multilevel
+    );
+}
+
+#[test]
+fn test_build_prewarmed() {
+    #[derive(Clone)]
+    struct SlowStartRunner {
+        inner: callback::Runner,
+        started: Arc<Mutex<usize>>,
+    }
+
+    impl Runner for SlowStartRunner {
+        type TaskCell = callback::TaskCell;
+
+        fn start(&mut self, local: &mut Local<Self::TaskCell>) {
+            thread::sleep(Duration::from_millis(50));
+            *self.started.lock().unwrap() += 1;
+            self.inner.start(local)
+        }
+
+        fn handle(&mut self, local: &mut Local<Self::TaskCell>, t: Self::TaskCell) -> bool {
+            self.inner.handle(local, t)
+        }
+    }
+
+    let started = Arc::new(Mutex::new(0));
+    let runner = SlowStartRunner {
+        inner: callback::Runner::default(),
+        started: started.clone(),
+    };
+    let (_, lazy) = Builder::new("test_build_prewarmed")
+        .max_thread_count(3)
+        .freeze_with_queue::<callback::TaskCell>(QueueType::SingleLevel);
+    let pool = lazy.build_prewarmed(CloneRunnerBuilder(runner));
+    // All workers have started when it returns.
+    assert_eq!(*started.lock().unwrap(), 3);
+    let (tx, rx) = mpsc::channel();
+    let now = Instant::now();
+    pool.spawn(move |_: &mut Handle<'_>| tx.send(()).unwrap());
+    rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    // Doesn't wait for the slow start.
+    assert!(now.elapsed() < Duration::from_millis(40));
+    pool.shutdown();
+}
+
+#[test]
+fn test_runner_start_panic() {
+    #[derive(Clone)]
+    struct PanicStartRunner {
+        inner: callback::Runner,
+        ended: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl Runner for PanicStartRunner {
+        type TaskCell = callback::TaskCell;
+
+        fn start(&mut self, local: &mut Local<Self::TaskCell>) {
+            if local.id() == 1 {
+                panic!("failed to start worker 1");
+            }
+            self.inner.start(local)
+        }
+
+        fn handle(&mut self, local: &mut Local<Self::TaskCell>, t: Self::TaskCell) -> bool {
+            self.inner.handle(local, t)
+        }
+
+        fn end(&mut self, local: &mut Local<Self::TaskCell>) {
+            self.ended.lock().unwrap().push(local.id());
+            self.inner.end(local)
+        }
+    }
+
+    let name = "test_runner_start_panic";
+    let counter = crate::metrics::WORKER_START_PANIC_TOTAL.with_label_values(&[name]);
+    let ended = Arc::new(Mutex::new(vec![]));
+    let runner = PanicStartRunner {
+        inner: callback::Runner::default(),
+        ended: ended.clone(),
+    };
+    let (remote, lazy) = Builder::new(name)
+        .max_thread_count(2)
+        .freeze_with_queue::<callback::TaskCell>(QueueType::SingleLevel);
+    let (tx, rx) = mpsc::channel();
+    remote.spawn_on(0, move |_: &mut Handle<'_>| tx.send(()).unwrap());
+    let pool = lazy.build_prewarmed(CloneRunnerBuilder(runner));
+    // The bad worker has been torn down when all workers are started.
+    assert_eq!(*ended.lock().unwrap(), vec![1]);
+    assert_eq!(counter.get(), 1);
+    // Tasks pinned to it are handled by the other worker.
+    rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    // New tasks pinned to it go to the global queue instead.
+    let (tx, rx) = mpsc::channel();
+    let t = tx.clone();
+    remote.spawn_on(0, move |_: &mut Handle<'_>| t.send(()).unwrap());
+    let mut extras = ExtrasBuilder::single_level().no_steal(true).build();
+    extras.bound_worker = 1;
+    remote.spawn(callback::TaskCell {
+        task: callback::Task::new_once(move |_| tx.send(()).unwrap()),
+        extras,
+    });
+    for _ in 0..2 {
+        rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    }
+
+    // The other worker still handles tasks.
+    let (tx, rx) = mpsc::channel();
+    for i in 0..10 {
+        let tx = tx.clone();
+        pool.spawn(move |_: &mut Handle<'_>| tx.send(i).unwrap());
+    }
+    let mut res: Vec<_> = (0..10)
+        .map(|_| rx.recv_timeout(Duration::from_secs(1)).unwrap())
+        .collect();
+    res.sort_unstable();
+    assert_eq!(res, (0..10).collect::<Vec<_>>());
+    pool.shutdown();
+    // The bad worker isn't ended twice.
+    assert_eq!(*ended.lock().unwrap(), vec![1, 2]);
+}
+
+#[test]
+fn test_restart_dead_workers() {
+    let name = "test_restart_dead_workers";
+    let pool = Builder::new(name)
+        .max_thread_count(1)
+        .restart_dead_workers(Duration::from_millis(200))
+        .build_callback_pool();
+    let (tx, rx) = mpsc::channel();
+    let tx1 = tx.clone();
+    pool.spawn(move |h: &mut Handle<'_>| {
+        for _ in 0..3 {
+            let tx = tx1.clone();
+            h.spawn(move |_: &mut Handle<'_>| {
+                tx.send(thread::current().name().unwrap().to_owned())
+                    .unwrap()
+            });
+        }
+        panic!("worker dies with tasks in its local queue");
+    });
+    // The tasks queued on the dead worker are handled by its replacement.
+    for _ in 0..3 {
+        let name = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(name, "test_restart_dead_workers-0");
+    }
+
+    // Dies again soon, the restart waits for the interval.
+    let now = Instant::now();
+    pool.spawn(|_: &mut Handle<'_>| panic!("worker dies again"));
+    pool.spawn(move |_: &mut Handle<'_>| tx.send(String::new()).unwrap());
+    rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert!(
+        now.elapsed() >= Duration::from_millis(100),
+        "{:?}",
+        now.elapsed()
+    );
+    pool.shutdown();
+}
+
+#[test]
+fn test_restart_with_new_runner() {
+    struct GenRunner {
+        inner: callback::Runner,
+        gen: usize,
+        handled: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl Runner for GenRunner {
+        type TaskCell = callback::TaskCell;
+
+        fn start(&mut self, local: &mut Local<Self::TaskCell>) {
+            if self.gen == 0 {
+                panic!("the first runner fails to start");
+            }
+            self.inner.start(local)
+        }
+
+        fn handle(&mut self, local: &mut Local<Self::TaskCell>, t: Self::TaskCell) -> bool {
+            self.handled.lock().unwrap().push(self.gen);
+            self.inner.handle(local, t)
+        }
+    }
+
+    struct GenRunnerBuilder {
+        gen: usize,
+        handled: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl RunnerBuilder for GenRunnerBuilder {
+        type Runner = GenRunner;
+
+        fn build(&mut self) -> GenRunner {
+            self.gen += 1;
+            GenRunner {
+                inner: callback::Runner::default(),
+                gen: self.gen - 1,
+                handled: self.handled.clone(),
+            }
+        }
+    }
+
+    let name = "test_restart_with_new_runner";
+    let handled = Arc::new(Mutex::new(vec![]));
+    let (remote, lazy) = Builder::new(name)
+        .max_thread_count(2)
+        .restart_dead_workers(Duration::from_millis(1))
+        .freeze::<callback::TaskCell>();
+    let pool = lazy.build_supervised(GenRunnerBuilder {
+        gen: 0,
+        handled: handled.clone(),
+    });
+    // Waits for worker 0 to be restarted with the third runner.
+    let (tx, rx) = mpsc::channel();
+    let now = Instant::now();
+    loop {
+        let tx = tx.clone();
+        remote.spawn(move |_: &mut Handle<'_>| {
+            tx.send(thread::current().name().unwrap().to_owned())
+                .unwrap()
+        });
+        if rx.recv_timeout(Duration::from_secs(1)).unwrap() == format!("{}-0", name) {
+            break;
+        }
+        assert!(now.elapsed() < Duration::from_secs(3));
+    }
+    // It's no longer dead, so pinned tasks are handled by it.
+    handled.lock().unwrap().clear();
+    for _ in 0..5 {
+        let tx = tx.clone();
+        remote.spawn_on(0, move |_: &mut Handle<'_>| {
+            tx.send(thread::current().name().unwrap().to_owned())
+                .unwrap()
+        });
+    }
+    for _ in 0..5 {
+        let name = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(name, "test_restart_with_new_runner-0");
+    }
+    assert_eq!(*handled.lock().unwrap(), vec![2; 5]);
+    // The supervisor is joined and drops the runner builder.
+    pool.shutdown();
+    assert_eq!(Arc::strong_count(&handled), 1);
+}
+
+#[test]
+fn test_build_with_local_runner_builder() {
+    // Only supervised pools need to send the runner builder.
+    struct LocalRunnerBuilder(std::marker::PhantomData<std::rc::Rc<()>>);
+
+    impl RunnerBuilder for LocalRunnerBuilder {
+        type Runner = callback::Runner;
+
+        fn build(&mut self) -> callback::Runner {
+            callback::Runner::default()
+        }
+    }
+
+    let (remote, lazy) = Builder::new("test_build_with_local_runner_builder")
+        .max_thread_count(1)
+        .restart_dead_workers(Duration::from_millis(1))
+        .freeze::<callback::TaskCell>();
+    let pool = lazy.build(LocalRunnerBuilder(Default::default()));
+    let (tx, rx) = mpsc::channel();
+    remote.spawn(move |_: &mut Handle<'_>| tx.send(()).unwrap());
+    rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    pool.shutdown();
+}
+
+#[test]
+fn test_panicking_task_done() {
+    let pool = Builder::new("test_panicking_task_done")
+        .max_thread_count(1)
+        .restart_dead_workers(Duration::from_millis(1))
+        .build_callback_pool();
+    let remote = pool.remote().clone();
+    remote.set_group_concurrency(3, 1);
+    let grouped = || ExtrasBuilder::single_level().group(3).build();
+    remote.spawn_with_extras(
+        |_: &mut Handle<'_>| panic!("task panics holding a group permit"),
+        grouped(),
+    );
+    // The permit is released although the task panics, and the restarted
+    // worker is counted as awake once.
+    let (tx, rx) = mpsc::channel();
+    let r = remote.clone();
+    remote.spawn_with_extras(
+        move |_: &mut Handle<'_>| tx.send(r.core.awake_workers()).unwrap(),
+        grouped(),
+    );
+    assert_eq!(rx.recv_timeout(Duration::from_secs(3)), Ok(1));
+    // Both tasks are marked done.
+    assert!(pool.wait_idle(Duration::from_secs(3)));
+    pool.shutdown_drain();
+}
+
+#[test]
+fn test_on_task_panic() {
+    #[inline(never)]
+    fn panics_in_task() {
+        panic!("task panics");
+    }
+
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+    let pool = Builder::new("test_on_task_panic")
+        .max_thread_count(1)
+        .on_task_panic(move |p: &TaskPanic| {
+            let bt = p.backtrace().map(|bt| bt.to_string());
+            tx.lock()
+                .unwrap()
+                .send((p.message().map(str::to_owned), bt))
+                .unwrap();
+        })
+        .build_callback_pool();
+    pool.spawn(|_: &mut Handle<'_>| panics_in_task());
+    let (msg, bt) = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(msg.as_deref(), Some("task panics"));
+    if cfg!(feature = "task-backtrace") {
+        // The backtrace is taken at the panic point, not where it's caught.
+        let bt = bt.unwrap();
+        assert!(bt.contains("panics_in_task"), "{}", bt);
+    } else {
+        assert!(bt.is_none());
+    }
+
+    // The worker survives the panic and the task is marked done.
+    let (tx, rx) = mpsc::channel();
+    pool.spawn(move |_: &mut Handle<'_>| tx.send(()).unwrap());
+    rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert!(pool.wait_idle(Duration::from_secs(1)));
+    pool.shutdown();
+}
+
+#[test]
+fn test_quiesce_worker() {
+    let name = "test_quiesce_worker";
+    let pool = Builder::new(name).max_thread_count(2).build_callback_pool();
+    let (tx, rx) = mpsc::channel();
+    let send_name = |tx: mpsc::Sender<String>| {
+        move |_: &mut Handle<'_>| {
+            tx.send(thread::current().name().unwrap().to_owned())
+                .unwrap()
+        }
+    };
+
+    // Worker 0 queues tasks locally and is quiesced before handling them.
+    let (quiesced_tx, quiesced_rx) = mpsc::channel();
+    let tx1 = tx.clone();
+    pool.remote().spawn_on(0, move |h: &mut Handle<'_>| {
+        for _ in 0..5 {
+            h.spawn(send_name(tx1.clone()));
+        }
+        quiesced_rx.recv().unwrap();
+    });
+    thread::sleep(Duration::from_millis(50));
+    let guard = pool.quiesce_worker(0);
+    quiesced_tx.send(()).unwrap();
+    assert!(guard.wait_quiesced(Duration::from_secs(1)));
+    for _ in 0..5 {
+        let name = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(name, "test_quiesce_worker-1");
+    }
+    for _ in 0..10 {
+        pool.spawn(send_name(tx.clone()));
+    }
+    for _ in 0..10 {
+        let name = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(name, "test_quiesce_worker-1");
+    }
+
+    // Tasks pinned to the worker wait until it resumes.
+    pool.remote().spawn_on(0, send_name(tx.clone()));
+    rx.recv_timeout(Duration::from_millis(100)).unwrap_err();
+    drop(guard);
+    let name = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(name, "test_quiesce_worker-0");
+    pool.shutdown();
+}
+
+#[test]
+fn test_tasks_dropped_on_shutdown() {
+    let name = "test_tasks_dropped_on_shutdown";
+    let counter = crate::metrics::TASKS_DROPPED_ON_SHUTDOWN.with_label_values(&[name]);
+    let pool = Builder::new(name).max_thread_count(1).build_callback_pool();
+    let (tx, rx) = mpsc::channel();
+    let (started_tx, started_rx) = mpsc::channel();
+    pool.spawn(move |_: &mut Handle<'_>| {
+        started_tx.send(()).unwrap();
+        rx.recv().unwrap();
+    });
+    started_rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    for _ in 0..10 {
+        pool.spawn(|_: &mut Handle<'_>| panic!("must not be handled"));
+    }
+    // Unblocks the worker after the pool is shut down.
+    let t = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        tx.send(()).unwrap();
+    });
+    pool.shutdown();
+    t.join().unwrap();
+    assert_eq!(counter.get(), 10);
+}
+
+#[test]
+fn test_with_initial_tasks() {
+    let (tx, rx) = mpsc::channel();
+    let tasks: Vec<_> = (0..10)
+        .map(|i| {
+            let tx = tx.clone();
+            move |_: &mut Handle<'_>| tx.send(i).unwrap()
+        })
+        .collect();
+    let (_, lazy) = Builder::new("test_with_initial_tasks")
+        .max_thread_count(1)
+        .freeze_with_queue::<callback::TaskCell>(QueueType::SingleLevel);
+    let lazy = lazy.with_initial_tasks(tasks);
+    // Nothing runs before the pool is built.
+    thread::sleep(Duration::from_millis(10));
+    assert!(rx.try_recv().is_err());
+
+    let pool = lazy.build(CloneRunnerBuilder(callback::Runner::default()));
+    let res: Vec<_> = (0..10)
+        .map(|_| rx.recv_timeout(Duration::from_secs(3)).unwrap())
+        .collect();
+    assert_eq!(res, (0..10).collect::<Vec<_>>());
+    pool.shutdown();
+}
+
+#[test]
+fn test_thread_name_fn() {
+    #[derive(Clone)]
+    struct NameRunner {
+        inner: callback::Runner,
+        tx: Arc<Mutex<mpsc::Sender<String>>>,
+    }
+
+    impl Runner for NameRunner {
+        type TaskCell = callback::TaskCell;
+
+        fn start(&mut self, local: &mut Local<Self::TaskCell>) {
+            let name = thread::current().name().unwrap().to_owned();
+            self.tx.lock().unwrap().send(name).unwrap();
+            self.inner.start(local)
+        }
+
+        fn handle(&mut self, local: &mut Local<Self::TaskCell>, t: Self::TaskCell) -> bool {
+            self.inner.handle(local, t)
+        }
+    }
+
+    let (tx, rx) = mpsc::channel();
+    let runner = NameRunner {
+        inner: callback::Runner::default(),
+        tx: Arc::new(Mutex::new(tx)),
+    };
+    let pool = Builder::new("test_thread_name_fn")
+        .max_thread_count(3)
+        .thread_name_fn(|i| format!("worker_{:03}", i))
+        .build_with_queue_and_runner(QueueType::SingleLevel, CloneRunnerBuilder(runner));
+    let mut names: Vec<_> = (0..3)
+        .map(|_| rx.recv_timeout(Duration::from_secs(3)).unwrap())
+        .collect();
+    names.sort();
+    assert_eq!(names, ["worker_000", "worker_001", "worker_002"]);
+    pool.shutdown();
+}
+
+#[test]
+fn test_stack_sizes() {
+    let mut builder = Builder::new("test_stack_sizes");
+    builder.stack_sizes(vec![1024 * 1024, 0]);
+    assert_eq!(builder.validate(), Err(ConfigError::ZeroStackSize));
+
+    fn recurse(depth: usize) -> usize {
+        let buf = [depth as u8; 1024];
+        if depth == 0 {
+            return buf[0] as usize;
+        }
+        std::hint::black_box(&buf);
+        recurse(depth - 1) + buf[1023] as usize
+    }
+
+    // Sizes are cycled, so the only worker uses the first size.
+    let pool = Builder::new("test_stack_sizes")
+        .max_thread_count(1)
+        .stack_sizes(vec![32 * 1024 * 1024, 64 * 1024])
+        .build_callback_pool();
+    let (tx, rx) = mpsc::channel();
+    // Requires more than 8MiB of stack.
+    pool.spawn(move |_: &mut Handle<'_>| tx.send(recurse(8 * 1024)).unwrap());
+    assert!(rx.recv_timeout(Duration::from_secs(10)).is_ok());
+    pool.shutdown();
+
+    // Workers 0 and 2 have big stacks, and deep tasks are pinned to them.
+    let pool = Builder::new("test_stack_sizes")
+        .max_thread_count(3)
+        .stack_sizes(vec![16 * 1024 * 1024, 1024 * 1024])
+        .build_callback_pool();
+    let (tx, rx) = mpsc::channel();
+    for worker in [0, 2, 0, 2] {
+        let tx = tx.clone();
+        pool.remote().spawn_on(worker, move |_: &mut Handle<'_>| {
+            // Requires more than 1MiB of stack.
+            let res = recurse(4 * 1024);
+            let name = thread::current().name().unwrap().to_owned();
+            tx.send((name, res)).unwrap();
+        });
+    }
+    let mut names: Vec<_> = (0..4)
+        .map(|_| rx.recv_timeout(Duration::from_secs(10)).unwrap().0)
+        .collect();
+    names.sort();
+    assert_eq!(
+        names,
+        [
+            "test_stack_sizes-0",
+            "test_stack_sizes-0",
+            "test_stack_sizes-2",
+            "test_stack_sizes-2"
+        ]
+    );
+    // Shallow tasks can run on any worker.
+    for i in 0..10 {
+        let tx = tx.clone();
+        pool.spawn(move |_: &mut Handle<'_>| {
+            let name = thread::current().name().unwrap().to_owned();
+            tx.send((name, recurse(i))).unwrap()
+        });
+    }
+    for _ in 0..10 {
+        rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    }
+    pool.shutdown();
+}
+
+#[test]
+#[should_panic(expected = "stack size should be greater than 0")]
+fn test_zero_stack_size() {
+    Builder::new("test_zero_stack_size")
+        .max_thread_count(1)
+        .stack_sizes(vec![1024 * 1024, 0])
+        .build_callback_pool();
+}
+
+#[test]
+fn test_shutdown_drain() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let pool = Builder::new("test_shutdown_drain")
+        .max_thread_count(2)
+        .build_callback_pool();
+    let handled = Arc::new(AtomicUsize::new(0));
+    for _ in 0..50 {
+        let handled = handled.clone();
+        pool.spawn(move |h: &mut Handle<'_>| {
+            thread::sleep(Duration::from_millis(1));
+            handled.fetch_add(1, Ordering::SeqCst);
+            // Children spawned while draining are handled too.
+            h.spawn(move |_: &mut Handle<'_>| {
+                handled.fetch_add(1, Ordering::SeqCst);
+            });
+        });
+    }
+    pool.shutdown_drain();
+    assert_eq!(handled.load(Ordering::SeqCst), 100);
+
+    // New tasks are rejected.
+    let (tx, rx) = mpsc::channel::<()>();
+    pool.spawn(move |_: &mut Handle<'_>| drop(tx));
+    assert!(rx.recv().is_err());
+}
+
+#[test]
+fn test_spawn_on() {
+    let pool = Builder::new("test_spawn_on")
+        .max_thread_count(4)
+        .build_callback_pool();
+    let (tx, rx) = mpsc::channel();
+    for i in 0..20 {
+        let tx = tx.clone();
+        // Indexes wrap around the thread count.
+        pool.remote()
+            .spawn_on(i % 2 * 4, move |_: &mut Handle<'_>| {
+                thread::sleep(Duration::from_millis(1));
+                tx.send(thread::current().name().unwrap().to_owned())
+                    .unwrap();
+            });
+    }
+    for _ in 0..20 {
+        let name = rx.recv_timeout(Duration::from_secs(3)).unwrap();
+        assert_eq!(name, "test_spawn_on-0");
+    }
+    pool.shutdown();
+}
+
+#[test]
+fn test_partition_fn() {
+    let pool = Builder::new("test_partition_fn")
+        .max_thread_count(4)
+        .partition_fn(|e: &Extras| e.group_id().unwrap_or(0) as usize)
+        .build_callback_pool();
+    let (tx, rx) = mpsc::channel();
+    for i in 0..40 {
+        let tx = tx.clone();
+        let key = i % 3;
+        let mut extras = Extras::single_level();
+        extras.set_group_id(key);
+        pool.remote().spawn_with_extras(
+            move |_: &mut Handle<'_>| {
+                thread::sleep(Duration::from_millis(1));
+                tx.send((key, thread::current().name().unwrap().to_owned()))
+                    .unwrap();
+            },
+            extras,
+        );
+    }
+    for _ in 0..40 {
+        let (key, name) = rx.recv_timeout(Duration::from_secs(3)).unwrap();
+        assert_eq!(name, format!("test_partition_fn-{}", key));
+    }
+
+    // Tasks of a dead worker go to the global queue.
+    pool.remote().core.mark_dead(1);
+    let mut extras = Extras::single_level();
+    extras.set_group_id(0);
+    pool.remote().spawn_with_extras(
+        move |_: &mut Handle<'_>| tx.send((0, String::new())).unwrap(),
+        extras,
+    );
+    rx.recv_timeout(Duration::from_secs(3)).unwrap();
+    pool.shutdown();
+}
+
+#[test]
+fn test_idle_time_per_tier() {
+    let pool = Builder::new("test_idle_time_per_tier")
+        .max_thread_count(1)
+        .burst_thread_count(1)
+        .burst_queue_threshold(1)
+        .max_wait_time(Duration::from_millis(10))
+        .core_idle_time(Duration::from_secs(2))
+        .extra_idle_time(Duration::from_millis(50))
+        .build_callback_pool();
+    let core = pool.remote().core.clone();
+
+    // Block the core worker and backlog the queue to start a burst worker.
+    let (block_tx, block_rx) = mpsc::channel::<()>();
+    let (tx, rx) = mpsc::channel();
+    let t = tx.clone();
+    pool.spawn(move |_: &mut Handle<'_>| {
+        t.send(()).unwrap();
+        block_rx.recv().unwrap();
+    });
+    rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    for _ in 0..2 {
+        let t = tx.clone();
+        pool.spawn(move |_: &mut Handle<'_>| t.send(()).unwrap());
+    }
+    thread::sleep(Duration::from_millis(20));
+    let t = tx.clone();
+    pool.spawn(move |_: &mut Handle<'_>| t.send(()).unwrap());
+    assert_eq!(core.burst_workers(), 1);
+    for _ in 0..3 {
+        rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    }
+    block_tx.send(()).unwrap();
+
+    // The burst worker exits soon while the core worker keeps waiting.
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(core.burst_workers(), 0);
+
+    // The core worker picks up new tasks before its idle time runs out.
+    let t = tx.clone();
+    pool.spawn(move |_: &mut Handle<'_>| t.send(()).unwrap());
+    rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    pool.shutdown();
+}
+
+#[test]
+fn test_spawn_pinned() {
+    let pool = Builder::new("test_spawn_pinned")
+        .max_thread_count(4)
+        .build_future_pool();
+    let (tx, rx) = mpsc::channel();
+    pool.remote().spawn_pinned(async move {
+        for _ in 0..10 {
+            tx.send(thread::current().name().unwrap().to_owned())
+                .unwrap();
+            // Keep the current worker busy, so the rescheduled task would be
+            // stolen by the other workers from the global queue.
+            crate::task::future::spawn_urgent(async {
+                thread::sleep(Duration::from_millis(20));
+            });
+            crate::task::future::reschedule().await;
+        }
+    });
+    let first = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    for _ in 1..10 {
+        assert_eq!(rx.recv_timeout(Duration::from_secs(1)).unwrap(), first);
+    }
+    pool.shutdown();
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn test_wakeup_latency() {
+    let name = "test_wakeup_latency";
+    let hist = crate::metrics::WAKEUP_LATENCY.with_label_values(&[name]);
+    let pool = Builder::new(name).max_thread_count(2).build_future_pool();
+    let (tx, rx) = mpsc::channel();
+    pool.spawn(async move {
+        Delay::new(Duration::from_millis(10)).await;
+        tx.send(()).unwrap();
+    });
+    rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    pool.shutdown();
+    // Only the wake up by the timer is observed, the first run isn't.
+    assert_eq!(hist.get_sample_count(), 1);
+    assert!(hist.get_sample_sum() > 0.0);
+    assert!(hist.get_sample_sum() < 1.0);
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn test_first_poll_latency() {
+    let name = "test_first_poll_latency";
+    let hist = crate::metrics::FIRST_POLL_LATENCY.with_label_values(&[name]);
+    let pool = Builder::new(name).max_thread_count(2).build_future_pool();
+    let (tx, rx) = mpsc::channel();
+    for _ in 0..20 {
+        let tx = tx.clone();
+        pool.spawn(async move {
+            // Keeps workers busy so later tasks wait in queue, and wakes up
+            // once more which must not be observed again.
+            Delay::new(Duration::from_millis(5)).await;
+            thread::sleep(Duration::from_millis(1));
+            tx.send(()).unwrap();
+        });
+    }
+    for _ in 0..20 {
+        rx.recv_timeout(Duration::from_secs(3)).unwrap();
+    }
+    pool.shutdown();
+    assert_eq!(hist.get_sample_count(), 20);
+    assert!(hist.get_sample_sum() > 0.0);
+}
+
+#[test]
+fn test_cancel_group() {
+    use crate::queue::Extras;
+
+    let pool = Builder::new("test_cancel_group")
+        .max_thread_count(1)
+        .build_callback_pool();
+    let remote = pool.remote();
+    let extras = |group| {
+        let mut extras = Extras::single_level();
+        extras.set_group_id(group);
+        extras
+    };
+
+    // Block the only worker so tasks stay queued.
+    let (block_tx, block_rx) = mpsc::channel::<()>();
+    pool.spawn(move |_: &mut Handle<'_>| block_rx.recv().unwrap());
+    let (tx, rx) = mpsc::channel();
+    for i in 0..10 {
+        let t = tx.clone();
+        remote.spawn_with_extras(move |_: &mut Handle<'_>| t.send(i).unwrap(), extras(i % 2));
+    }
+    remote.cancel_group(1);
+    // Tasks spawned after cancelling are dropped too.
+    let t = tx.clone();
+    remote.spawn_with_extras(move |_: &mut Handle<'_>| t.send(11).unwrap(), extras(1));
+    block_tx.send(()).unwrap();
+
+    let t = tx.clone();
+    remote.spawn_with_extras(move |_: &mut Handle<'_>| t.send(10).unwrap(), extras(0));
+    let res: Vec<_> = (0..6)
+        .map(|_| rx.recv_timeout(Duration::from_secs(1)).unwrap())
+        .collect();
+    assert_eq!(res, [0, 2, 4, 6, 8, 10]);
+    assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    assert_eq!(remote.core.pending_tasks(), 0);
+
+    // Old groups are forgotten, but the latest ones are still cancelled.
+    for group in 100..20100 {
+        remote.cancel_group(group);
+    }
+    assert!(remote.core.cancelled_groups() <= 8192);
+    remote.spawn_with_extras(move |_: &mut Handle<'_>| tx.send(1).unwrap(), extras(20099));
+    assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    pool.shutdown();
+}
+
+#[test]
+fn test_try_shutdown() {
+    use std::sync::atomic::Ordering;
+
+    let pool = Builder::new("test_try_shutdown")
+        .max_thread_count(2)
+        .build_callback_pool();
+    let (block_tx, block_rx) = mpsc::channel::<()>();
+    let (tx, rx) = mpsc::channel();
+    let t = tx.clone();
+    pool.spawn(move |_: &mut Handle<'_>| {
+        t.send(0).unwrap();
+        block_rx.recv().unwrap();
+    });
+    rx.recv_timeout(Duration::from_secs(1)).unwrap();
+
+    // It doesn't wait for the blocked worker.
+    let now = Instant::now();
+    assert!(pool.try_shutdown());
+    assert!(now.elapsed() < Duration::from_millis(100));
+    assert!(!pool.try_shutdown());
+
+    // The idle worker exits while the blocked one is still running.
+    let live_workers = &pool.live_workers.count;
+    for _ in 0..100 {
+        if live_workers.load(Ordering::SeqCst) == 1 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(live_workers.load(Ordering::SeqCst), 1);
+
+    block_tx.send(()).unwrap();
+    pool.shutdown();
+    assert!(pool.threads.lock().unwrap().is_empty());
+    assert_eq!(live_workers.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn test_broadcast() {
+    let pool = Builder::new("test_broadcast")
+        .max_thread_count(4)
+        .build_callback_pool();
+    let ran = Arc::new(Mutex::new(Vec::new()));
+    let r = ran.clone();
+    pool.broadcast(move |i| {
+        r.lock()
+            .unwrap()
+            .push((i, thread::current().name().unwrap().to_owned()));
+    });
+    let mut ran = ran.lock().unwrap().clone();
+    ran.sort();
+    let expected: Vec<_> = (0..4)
+        .map(|i| (i, format!("test_broadcast-{}", i)))
+        .collect();
+    assert_eq!(ran, expected);
+
+    let future_pool = Builder::new("test_broadcast_future")
+        .max_thread_count(2)
+        .build_future_pool();
+    let (tx, rx) = mpsc::channel();
+    let tx = Arc::new(Mutex::new(tx));
+    future_pool.broadcast(move |i| tx.lock().unwrap().send(i).unwrap());
+    let mut ran: Vec<_> = rx.try_iter().collect();
+    ran.sort_unstable();
+    assert_eq!(ran, [0, 1]);
+
+    pool.shutdown();
+    future_pool.shutdown();
+
+    // Returns without running `f` after the pool is shut down.
+    let (tx, rx) = mpsc::channel();
+    let tx = Arc::new(Mutex::new(tx));
+    pool.broadcast(move |i| tx.lock().unwrap().send(i).unwrap());
+    assert!(rx.recv().is_err());
+    // Tasks pinned to exited workers are dropped.
+    let (tx, rx) = mpsc::channel::<()>();
+    pool.remote()
+        .spawn_on(1, move |_: &mut Handle<'_>| drop(tx));
+    assert!(rx.recv().is_err());
+}
+
+#[cfg_attr(not(feature = "failpoints"), ignore)]
+#[test]
+fn test_pinned_tasks_rejected_on_shutdown() {
+    let _guard = fail::FailScenario::setup();
+    let config = SchedConfig {
+        max_thread_count: 2,
+        ..Default::default()
+    };
+    let (remote, _locals) = build_spawn::<callback::TaskCell>(QueueType::SingleLevel, config);
+    remote.spawn_on(0, |_: &mut Handle<'_>| {});
+    assert_eq!(remote.core.pending_tasks(), 1);
+
+    // The pool is shut down right after the task is pushed.
+    let core = remote.core.clone();
+    fail::cfg_callback("push-pinned-before-check", move || {
+        core.mark_shutdown(0);
+    })
+    .unwrap();
+    remote.spawn_on(0, |_: &mut Handle<'_>| {});
+    fail::remove("push-pinned-before-check");
+    assert_eq!(remote.core.pending_tasks(), 0);
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn test_injector_contention() {
+    use crate::queue::FORCE_STEAL_RETRY;
+
+    let config = SchedConfig {
+        max_thread_count: 1,
+        ..Default::default()
+    };
+    let (remote, mut locals) = build_spawn::<callback::TaskCell>(QueueType::SingleLevel, config);
+    remote.spawn(|_: &mut Handle<'_>| {});
+    // The steal is retried twice before it succeeds, and both retries are
+    // recorded by the worker.
+    FORCE_STEAL_RETRY.with(|r| r.set(2));
+    assert!(locals[0].pop().is_some());
+    assert_eq!(FORCE_STEAL_RETRY.with(|r| r.get()), 0);
+    assert_eq!(remote.core.injector_contention_count(), 2);
+    // Nothing is recorded without contention.
+    remote.spawn(|_: &mut Handle<'_>| {});
+    assert!(locals[0].pop().is_some());
+    assert_eq!(remote.core.injector_contention_count(), 2);
+}
+
+#[test]
+fn test_injector_shards() {
+    let pool = Builder::new("test_injector_shards")
+        .max_thread_count(4)
+        .injector_shards(4)
+        .build_callback_pool();
+    let (tx, rx) = mpsc::channel();
+    let spawners: Vec<_> = (0..8)
+        .map(|i| {
+            let remote = pool.remote().clone();
+            let tx = tx.clone();
+            thread::spawn(move || {
+                for j in 0..5000 {
+                    let t = tx.clone();
+                    remote.spawn(move |_: &mut Handle<'_>| t.send((i, j)).unwrap());
+                }
+            })
+        })
+        .collect();
+    for s in spawners {
+        s.join().unwrap();
+    }
+    let mut res: Vec<_> = (0..40000)
+        .map(|_| rx.recv_timeout(Duration::from_secs(5)).unwrap())
+        .collect();
+    res.sort_unstable();
+    res.dedup();
+    assert_eq!(res.len(), 40000);
+    pool.shutdown();
+}
+
+#[test]
+fn test_task_source() {
+    #[derive(Clone)]
+    struct SourceRunner {
+        inner: callback::Runner,
+        sources: Arc<Mutex<Vec<TaskSource>>>,
+    }
+
+    impl Runner for SourceRunner {
+        type TaskCell = callback::TaskCell;
+
+        fn on_task_source(&mut self, _: &mut Local<Self::TaskCell>, source: TaskSource) {
+            self.sources.lock().unwrap().push(source);
+        }
+
+        fn handle(&mut self, local: &mut Local<Self::TaskCell>, t: Self::TaskCell) -> bool {
+            self.inner.handle(local, t)
+        }
+    }
+
+    let sources = Arc::new(Mutex::new(Vec::new()));
+    let runner = SourceRunner {
+        inner: callback::Runner::default(),
+        sources: sources.clone(),
+    };
+    let pool = Builder::new("test_task_source")
+        .max_thread_count(1)
+        .build_with_queue_and_runner(QueueType::SingleLevel, CloneRunnerBuilder(runner));
+    let (tx, rx) = mpsc::channel();
+    pool.spawn(move |h: &mut Handle<'_>| {
+        h.spawn(move |_: &mut Handle<'_>| tx.send(()).unwrap());
+    });
+    rx.recv_timeout(Duration::from_secs(3)).unwrap();
+    pool.shutdown();
+    assert_eq!(
+        *sources.lock().unwrap(),
+        vec![TaskSource::Global, TaskSource::Local]
+    );
+}
+
+#[test]
+fn test_wait_idle() {
+    fn chain(h: &mut Handle<'_>, left: usize, done: Arc<Mutex<usize>>) {
+        thread::sleep(Duration::from_millis(10));
+        *done.lock().unwrap() += 1;
+        if left > 0 {
+            h.spawn(move |h: &mut Handle<'_>| chain(h, left - 1, done));
+        }
+    }
+
+    let pool = Builder::new("test_wait_idle")
+        .max_thread_count(2)
+        .build_callback_pool();
+    assert!(pool.wait_idle(Duration::from_millis(10)));
+
+    let done = Arc::new(Mutex::new(0));
+    let d = done.clone();
+    pool.spawn(move |h: &mut Handle<'_>| chain(h, 9, d));
+    assert!(!pool.wait_idle(Duration::from_millis(20)));
+    assert!(*done.lock().unwrap() < 10);
+    assert!(pool.wait_idle(Duration::from_secs(3)));
+    assert_eq!(*done.lock().unwrap(), 10);
+    pool.shutdown();
+}
+
+#[cfg(not(feature = "metrics"))]
+#[test]
+fn test_no_metrics() {
+    use crate::metrics::*;
+
+    let name = "test_no_metrics";
+    let pool = Builder::new(name).max_thread_count(2).build_future_pool();
+    let (tx, rx) = mpsc::channel();
+    for i in 0..10 {
+        let tx = tx.clone();
+        pool.spawn(async move {
+            Delay::new(Duration::from_millis(1)).await;
+            tx.send(i).unwrap();
+        });
+    }
+    let mut res: Vec<_> = (0..10)
+        .map(|_| rx.recv_timeout(Duration::from_secs(3)).unwrap())
+        .collect();
+    res.sort_unstable();
+    assert_eq!(res, (0..10).collect::<Vec<_>>());
+    pool.shutdown();
+
+    // Workers spun and tasks were woken up, but nothing is recorded.
+    let spin = WORKER_SPIN_TOTAL.with_label_values(&[name]);
+    assert_eq!(spin.get(), 0);
+    let wakeup = WAKEUP_LATENCY.with_label_values(&[name]);
+    assert_eq!(wakeup.get_sample_count(), 0);
+    let active = ACTIVE_WORKERS_COUNT.with_label_values(&[name]);
+    assert_eq!(active.get_sample_count(), 0);
+}
+
+#[test]
+fn test_remote_from_injector() {
+    let config = SchedConfig {
+        max_thread_count: 2,
+        ..Default::default()
+    };
+    let (injector, locals) = crate::queue::build(QueueType::SingleLevel, 2, 1);
+    let remote = Remote::from_injector(injector, config);
+    let mut locals: Vec<_> = locals
+        .into_iter()
+        .enumerate()
+        .map(|(i, l)| remote.local(i + 1, l))
+        .collect();
+
+    let mut runner = callback::Runner::default();
+    let (tx, rx) = mpsc::channel();
+    remote.spawn(move |h: &mut Handle<'_>| {
+        tx.send(1).unwrap();
+        h.spawn(move |_: &mut Handle<'_>| tx.send(2).unwrap());
+    });
+    assert!(locals[1]
+        .pop()
+        .is_some_and(|t| runner.handle(&mut locals[1], t.task_cell)));
+    assert_eq!(rx.recv().unwrap(), 1);
+    // The child is spawned to the local queue of the worker.
+    assert_eq!(locals[1].len(), 1);
+    let t = locals[1].pop().unwrap().task_cell;
+    assert!(runner.handle(&mut locals[1], t));
+    assert_eq!(rx.recv().unwrap(), 2);
+    assert!(locals.iter_mut().all(|l| l.pop().is_none()));
+}
+
+#[test]
+fn test_local_with_id_zero() {
+    let config = SchedConfig {
+        max_thread_count: 1,
+        ..Default::default()
+    };
+    let (injector, locals) = crate::queue::build(QueueType::SingleLevel, 1, 1);
+    let remote = Remote::from_injector(injector, config);
+    let mut local = remote.local(0, locals.into_iter().next().unwrap());
+
+    // Id 0 is not a worker, so there is nothing pinned, dead or quiesced.
+    let core = &remote.core;
+    core.mark_dead(0);
+    core.mark_alive(0);
+    core.quiesce_worker(0);
+    core.resume_worker(0);
+    assert!(!core.is_quiesced(0));
+    assert!(!core.wait_quiesced_parked(0, Duration::from_millis(10)));
+
+    let (tx, rx) = mpsc::channel();
+    remote.spawn(move |_: &mut Handle<'_>| tx.send(()).unwrap());
+    let t = local.pop().unwrap().task_cell;
+    assert!(callback::Runner::default().handle(&mut local, t));
+    rx.recv().unwrap();
+    assert!(local.pop().is_none());
+}
+
+#[test]
+fn test_is_healthy() {
+    let pool = Builder::new("test_is_healthy")
+        .max_thread_count(2)
+        .health_check(true)
+        .build_callback_pool();
+    let threshold = Duration::from_millis(50);
+    assert!(pool.is_healthy(threshold));
+
+    let (started_tx, started_rx) = mpsc::channel();
+    let (finished_tx, finished_rx) = mpsc::channel();
+    let mut blockers = vec![];
+    for i in 0..2 {
+        let (tx, rx) = mpsc::channel::<()>();
+        blockers.push(tx);
+        let started_tx = started_tx.clone();
+        let finished_tx = finished_tx.clone();
+        pool.spawn(move |_: &mut Handle<'_>| {
+            started_tx.send(()).unwrap();
+            // Blocks until the sender is dropped.
+            let _ = rx.recv();
+            finished_tx.send(()).unwrap();
+        });
+        started_rx.recv_timeout(Duration::from_secs(3)).unwrap();
+        thread::sleep(threshold * 2);
+        // The pool is only unhealthy when all workers are blocked.
+        assert_eq!(pool.is_healthy(threshold), i == 0);
+    }
+
+    drop(blockers);
+    for _ in 0..2 {
+        finished_rx.recv_timeout(Duration::from_secs(3)).unwrap();
+    }
+    assert!(pool.wait_idle(Duration::from_secs(3)));
+    assert!(pool.is_healthy(threshold));
+    pool.shutdown();
+}
+
+#[test]
+fn test_snapshot_queue() {
+    let queue_types = vec![
+        QueueType::SingleLevel,
+        QueueType::Multilevel(multilevel::Builder::new(multilevel::Config::default())),
+    ];
+    for queue_type in queue_types {
+        let (injector, locals) = crate::queue::build(queue_type, 1, 1);
+        let config = SchedConfig {
+            track_queued_tasks: true,
+            ..Default::default()
+        };
+        let remote = Remote::from_injector(injector, config);
+        let mut local = remote.local(1, locals.into_iter().next().unwrap());
+        let extras = |id: u64, level: u8| {
+            ExtrasBuilder::multilevel()
+                .task_id(id)
+                .fixed_level(level)
+                .build()
+        };
+
+        for i in 0..3u8 {
+            remote.spawn_with_extras(|_: &mut Handle<'_>| {}, extras(10 + i as u64, 2 - i));
+        }
+        // Tasks in the local queue and pinned to the worker are included.
+        local.spawn(callback::TaskCell {
+            task: callback::Task::new_once(|_| {}),
+            extras: extras(13, 1),
+        });
+        remote.spawn_on(
+            0,
+            callback::TaskCell {
+                task: callback::Task::new_once(|_| {}),
+                extras: extras(14, 0),
+            },
+        );
+        let snapshot = remote.snapshot_queue();
+        let ids: Vec<_> = snapshot.iter().map(|s| (s.task_id, s.level)).collect();
+        assert_eq!(ids, [(12, 0), (14, 0), (11, 1), (13, 1), (10, 2)]);
+        assert!(snapshot.iter().all(|s| s.schedule_time.is_some()));
+        // Tasks are left in the queue.
+        assert_eq!(remote.snapshot_queue(), snapshot);
+        let mut popped = vec![];
+        while let Some(mut t) = local.pop() {
+            popped.push(t.task_cell.mut_extras().task_id());
+        }
+        popped.sort_unstable();
+        assert_eq!(popped, [10, 11, 12, 13, 14]);
+        assert!(remote.snapshot_queue().is_empty());
+    }
+
+    // Nothing is tracked by default.
+    let (injector, _locals) = crate::queue::build(QueueType::SingleLevel, 1, 1);
+    let remote = Remote::from_injector(injector, SchedConfig::default());
+    remote.spawn(|_: &mut Handle<'_>| {});
+    assert!(remote.snapshot_queue().is_empty());
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn test_task_kind_metrics() {
+    use crate::metrics::{TASK_KIND_HANDLED_TOTAL, TASK_KIND_WAIT_DURATION};
+
+    let name = "test_task_kind_metrics";
+    let pool = Builder::new(name).max_thread_count(2).build_callback_pool();
+    let (tx, rx) = mpsc::channel();
+    for kind in ["read", "read", "read", "write", "write", ""] {
+        let mut builder = ExtrasBuilder::single_level();
+        if !kind.is_empty() {
+            builder = builder.kind(kind);
+        }
+        let tx = tx.clone();
+        pool.remote()
+            .spawn_with_extras(move |_: &mut Handle<'_>| tx.send(()).unwrap(), builder);
+    }
+    for _ in 0..6 {
+        rx.recv_timeout(Duration::from_secs(3)).unwrap();
+    }
+    pool.shutdown();
+    for (kind, count) in [("read", 3), ("write", 2)] {
+        let handled = TASK_KIND_HANDLED_TOTAL.with_label_values(&[name, kind]);
+        assert_eq!(handled.get(), count);
+        let wait = TASK_KIND_WAIT_DURATION.with_label_values(&[name, kind]);
+        assert_eq!(wait.get_sample_count(), count);
+    }
+    // Tasks without a kind are not reported.
+    assert_eq!(
+        TASK_KIND_HANDLED_TOTAL.with_label_values(&[name, ""]).get(),
+        0
+    );
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn test_clear_not_handled() {
+    use crate::metrics::{FIRST_POLL_LATENCY, TASK_KIND_HANDLED_TOTAL, WAKEUP_LATENCY};
+
+    let name = "test_clear_not_handled";
+    let handled = TASK_KIND_HANDLED_TOTAL.with_label_values(&[name, "read"]);
+    let wakeup = WAKEUP_LATENCY.with_label_values(&[name]);
+    let first_poll = FIRST_POLL_LATENCY.with_label_values(&[name]);
+    let pool = Builder::new(name).max_thread_count(1).build_callback_pool();
+    let (tx, rx) = mpsc::channel();
+    let (started_tx, started_rx) = mpsc::channel();
+    pool.spawn(move |_: &mut Handle<'_>| {
+        started_tx.send(()).unwrap();
+        rx.recv().unwrap();
+    });
+    started_rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    let wakeup_count = wakeup.get_sample_count();
+    let first_poll_count = first_poll.get_sample_count();
+    for _ in 0..10 {
+        pool.remote().spawn_with_extras(
+            |_: &mut Handle<'_>| panic!("must not be handled"),
+            ExtrasBuilder::single_level().kind("read"),
+        );
+    }
+    let t = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        tx.send(()).unwrap();
+    });
+    pool.shutdown();
+    t.join().unwrap();
+    // The tasks cleared on shutdown are not reported as handled.
+    assert_eq!(handled.get(), 0);
+    assert_eq!(wakeup.get_sample_count(), wakeup_count);
+    assert_eq!(first_poll.get_sample_count(), first_poll_count);
+}
+
+#[test]
+fn test_drain_with() {
+    let pool = Builder::new("test_drain_with")
+        .max_thread_count(1)
+        .build_callback_pool();
+    let (tx, rx) = mpsc::channel();
+    let (block_tx, block_rx) = mpsc::channel::<()>();
+    pool.spawn(move |_: &mut Handle<'_>| {
+        tx.send(()).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        drop(block_tx);
+    });
+    rx.recv_timeout(Duration::from_secs(3)).unwrap();
+    let handled = Arc::new(AtomicUsize::new(0));
+    for _ in 0..10 {
+        let handled = handled.clone();
+        pool.spawn(move |_: &mut Handle<'_>| {
+            handled.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+    let mut leftovers = 0;
+    pool.drain_with(|_| leftovers += 1);
+    // The blocking task finished before the worker exited.
+    assert!(block_rx.recv().is_err());
+    assert_eq!(leftovers, 10);
+    assert_eq!(handled.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn test_drain_with_attached() {
+    let (remote, builder) = Builder::new("test_drain_with_attached")
+        .max_thread_count(1)
+        .freeze::<callback::TaskCell>();
+    let (pool, workers) = builder.build_attached(CloneRunnerBuilder(callback::Runner::default()));
+    let worker = workers.into_iter().next().unwrap();
+    let t = thread::spawn(move || worker.run());
+    let (tx, rx) = mpsc::channel();
+    remote.spawn(move |_: &mut Handle<'_>| {
+        tx.send(()).unwrap();
+        thread::sleep(Duration::from_millis(100));
+    });
+    rx.recv_timeout(Duration::from_secs(3)).unwrap();
+    // Pinned tasks are only collected by the worker when it exits.
+    for _ in 0..10 {
+        remote.spawn_on(0, move |_: &mut Handle<'_>| {});
+    }
+    let mut leftovers = 0;
+    pool.drain_with(|_| leftovers += 1);
+    // The attached worker has exited before `drain_with` returns.
+    assert_eq!(leftovers, 10);
+    t.join().unwrap();
+}
+
+#[test]
+fn test_group_concurrency() {
+    let pool = Builder::new("test_group_concurrency")
+        .max_thread_count(4)
+        .build_callback_pool();
+    let remote = pool.remote();
+    remote.set_group_concurrency(7, 2);
+    let running = Arc::new(AtomicUsize::new(0));
+    let max_running = Arc::new(AtomicUsize::new(0));
+    let (tx, rx) = mpsc::channel();
+    for _ in 0..10 {
+        let (running, max_running, tx) = (running.clone(), max_running.clone(), tx.clone());
+        let mut extras = Extras::single_level();
+        extras.set_group_id(7);
+        remote.spawn_with_extras(
+            move |_: &mut Handle<'_>| {
+                let n = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max_running.fetch_max(n, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(20));
+                running.fetch_sub(1, Ordering::SeqCst);
+                tx.send(()).unwrap();
+            },
+            extras,
+        );
+    }
+    for _ in 0..10 {
+        rx.recv_timeout(Duration::from_secs(3)).unwrap();
+    }
+    assert!(max_running.load(Ordering::SeqCst) <= 2);
+    assert!(pool.wait_idle(Duration::from_secs(3)));
+
+    // Removing the limit schedules the deferred tasks.
+    remote.set_group_concurrency(7, 1);
+    let group_task = |f: Box<dyn FnOnce() + Send>| {
+        let mut extras = Extras::single_level();
+        extras.set_group_id(7);
+        remote.spawn_with_extras(move |_: &mut Handle<'_>| f(), extras);
+    };
+    let (block_tx, block_rx) = mpsc::channel::<()>();
+    let t = tx.clone();
+    group_task(Box::new(move || {
+        t.send(()).unwrap();
+        let _ = block_rx.recv();
+    }));
+    rx.recv_timeout(Duration::from_secs(3)).unwrap();
+    for _ in 0..2 {
+        let t = tx.clone();
+        group_task(Box::new(move || t.send(()).unwrap()));
+    }
+    assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    remote.set_group_concurrency(7, 0);
+    for _ in 0..2 {
+        rx.recv_timeout(Duration::from_secs(3)).unwrap();
+    }
+    drop(block_tx);
+    pool.shutdown();
+}
+
+#[test]
+fn test_group_concurrency_in_flight() {
+    let pool = Builder::new("test_group_concurrency_in_flight")
+        .max_thread_count(2)
+        .build_future_pool();
+    let remote = pool.remote();
+    remote.set_group_concurrency(7, 1);
+    let grouped = || {
+        let mut extras = Extras::single_level();
+        extras.set_group_id(7);
+        extras
+    };
+    let (wake_tx, wake_rx) = tokio::sync::oneshot::channel::<()>();
+    let (tx, rx) = mpsc::channel();
+    let t = tx.clone();
+    remote.spawn_with_extras(
+        async move {
+            t.send(1).unwrap();
+            let _ = wake_rx.await;
+            t.send(2).unwrap();
+        },
+        grouped(),
+    );
+    assert_eq!(rx.recv_timeout(Duration::from_secs(3)), Ok(1));
+    remote.spawn_with_extras(async move { tx.send(3).unwrap() }, grouped());
+    // The pending future still holds the permit.
+    assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    wake_tx.send(()).unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(3)), Ok(2));
+    assert_eq!(rx.recv_timeout(Duration::from_secs(3)), Ok(3));
+    pool.shutdown();
+}
+
+#[test]
+fn test_drain_deferred_group_tasks() {
+    let pool = Builder::new("test_drain_deferred_group_tasks")
+        .max_thread_count(2)
+        .build_callback_pool();
+    let remote = pool.remote().clone();
+    remote.set_group_concurrency(7, 1);
+    let group_task = |f: Box<dyn FnOnce() + Send>| {
+        let mut extras = Extras::single_level();
+        extras.set_group_id(7);
+        remote.spawn_with_extras(move |_: &mut Handle<'_>| f(), extras);
+    };
+    let (tx, rx) = mpsc::channel();
+    let (block_tx, block_rx) = mpsc::channel::<()>();
+    group_task(Box::new(move || {
+        tx.send(()).unwrap();
+        let _ = block_rx.recv();
+    }));
+    rx.recv_timeout(Duration::from_secs(3)).unwrap();
+    for _ in 0..3 {
+        group_task(Box::new(|| {}));
+    }
+    // Waits for the other worker to defer them.
+    thread::sleep(Duration::from_millis(100));
+    let unblock = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        drop(block_tx);
+    });
+    // Deferred tasks are drained like queued ones.
+    let mut leftovers = 0;
+    pool.drain_with(|_| leftovers += 1);
+    assert_eq!(leftovers, 3);
+    unblock.join().unwrap();
+}
+
+#[test]
+fn test_park_until() {
+    let config = SchedConfig {
+        max_thread_count: 1,
+        core_thread_count: std::sync::atomic::AtomicUsize::new(1),
+        ..Default::default()
+    };
+    let (remote, mut locals) = build_spawn::<callback::TaskCell>(QueueType::SingleLevel, config);
+    let mut local = locals.remove(0);
+    let unparker = local.unparker();
+
+    // Times out without any events.
+    let now = Instant::now();
+    assert!(!local.park_until(now + Duration::from_millis(50)));
+    assert!(now.elapsed() >= Duration::from_millis(50));
+
+    // An unpark before parking is not lost.
+    unparker.unpark();
+    assert!(local.park_until(Instant::now() + Duration::from_secs(10)));
+
+    let (tx, rx) = mpsc::channel();
+    let handle = thread::spawn(move || {
+        for _ in 0..2 {
+            let woken = local.park_until(Instant::now() + Duration::from_secs(10));
+            tx.send(woken).unwrap();
+        }
+        local
+    });
+
+    // Woken up by another thread.
+    assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    unparker.unpark();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(3)), Ok(true));
+
+    // Woken up by task arrival, the task is left in the queue.
+    thread::sleep(Duration::from_millis(50));
+    remote.spawn(|_: &mut Handle<'_>| {});
+    assert_eq!(rx.recv_timeout(Duration::from_secs(3)), Ok(true));
+    let mut local = handle.join().unwrap();
+    assert!(local.pop().is_some());
+}
+
+#[test]
+fn test_inline_if_no_workers() {
+    let pool = Builder::new("test_inline_if_no_workers")
+        .max_thread_count(0)
+        .inline_if_no_workers(true)
+        .build_callback_pool();
+    assert_eq!(pool.thread_count_limit().1, 0);
+    let caller = thread::current().id();
+    let (tx, rx) = mpsc::channel();
+    for i in 0..3 {
+        let tx = tx.clone();
+        pool.remote().spawn_on(i, move |h: &mut Handle<'_>| {
+            let tx1 = tx.clone();
+            h.spawn(move |_: &mut Handle<'_>| tx1.send(thread::current().id()).unwrap());
+            tx.send(thread::current().id()).unwrap();
+        });
+    }
+    for _ in 0..6 {
+        assert_eq!(rx.try_recv(), Ok(caller));
+    }
+    pool.shutdown();
+
+    // Without the option, zero falls back to the default thread count.
+    let pool = Builder::new("test_inline_if_no_workers")
+        .max_thread_count(0)
+        .build_callback_pool();
+    assert!(pool.thread_count_limit().1 > 0);
+    pool.shutdown();
+}
+
+#[test]
+fn test_context_hook() {
+    thread_local! {
+        static CONTEXT: std::cell::Cell<u32> = const { std::cell::Cell::new(0) };
+    }
+    let pool = Builder::new("test_context_hook")
+        .max_thread_count(2)
+        .context_hook(
+            || CONTEXT.with(|c| c.get()),
+            |ctx: &u32| CONTEXT.with(|c| c.set(*ctx)),
+        )
+        .build_callback_pool();
+    let (tx, rx) = mpsc::channel();
+    for ctx in 1..=2 {
+        CONTEXT.with(|c| c.set(ctx));
+        let tx = tx.clone();
+        pool.spawn(move |h: &mut Handle<'_>| {
+            tx.send(CONTEXT.with(|c| c.get())).unwrap();
+            // Tasks spawned by the task inherit the restored context.
+            h.spawn(move |_: &mut Handle<'_>| tx.send(CONTEXT.with(|c| c.get())).unwrap());
+        });
+        for _ in 0..2 {
+            assert_eq!(rx.recv_timeout(Duration::from_secs(3)), Ok(ctx));
+        }
+    }
+    pool.shutdown();
+
+    // The context of the worker is reset after each task.
+    let restored = Arc::new(Mutex::new(vec![]));
+    let r = restored.clone();
+    let pool = Builder::new("test_context_hook")
+        .max_thread_count(1)
+        .context_hook(
+            || CONTEXT.with(|c| c.get()),
+            move |ctx: &u32| {
+                r.lock().unwrap().push(*ctx);
+                CONTEXT.with(|c| c.set(*ctx));
+            },
+        )
+        .build_callback_pool();
+    for ctx in 3..=4 {
+        CONTEXT.with(|c| c.set(ctx));
+        pool.spawn(move |_: &mut Handle<'_>| assert_eq!(CONTEXT.with(|c| c.get()), ctx));
+    }
+    assert!(pool.wait_idle(Duration::from_secs(3)));
+    pool.shutdown();
+    assert_eq!(*restored.lock().unwrap(), vec![3, 0, 4, 0]);
+}
+
+#[test]
+fn test_current_task_id() {
+    // What an allocator wrapper would do on each allocation.
+    fn on_alloc(accounted: &Mutex<Vec<(Option<u64>, Option<u64>)>>) {
+        accounted
+            .lock()
+            .unwrap()
+            .push((current_task_id(), current_group_id()));
+    }
+
+    let accounted = Arc::new(Mutex::new(vec![]));
+    on_alloc(&accounted);
+    let pool = Builder::new("test_current_task_id")
+        .max_thread_count(1)
+        .build_callback_pool();
+    let (tx, rx) = mpsc::channel();
+    let extras = ExtrasBuilder::single_level().task_id(42).group(7).build();
+    let a = accounted.clone();
+    pool.remote().spawn_with_extras(
+        move |h: &mut Handle<'_>| {
+            on_alloc(&a);
+            let extras = ExtrasBuilder::single_level().task_id(43).build();
+            h.spawn_once(
+                move |_: &mut Handle<'_>| {
+                    on_alloc(&a);
+                    tx.send(()).unwrap();
+                },
+                extras,
+            );
+        },
+        extras,
+    );
+    rx.recv_timeout(Duration::from_secs(3)).unwrap();
+    pool.shutdown();
+
+    // Tasks run on the spawning thread are published as well.
+    let pool = Builder::new("test_current_task_id")
+        .max_thread_count(0)
+        .inline_if_no_workers(true)
+        .build_callback_pool();
+    let extras = ExtrasBuilder::single_level().task_id(44).group(8).build();
+    let a = accounted.clone();
+    pool.remote()
+        .spawn_with_extras(move |_: &mut Handle<'_>| on_alloc(&a), extras);
+    on_alloc(&accounted);
+    pool.shutdown();
+    assert_eq!(
+        *accounted.lock().unwrap(),
+        vec![
+            (None, None),
+            (Some(42), Some(7)),
+            (Some(43), None),
+            (Some(44), Some(8)),
+            (None, None)
+        ]
+    );
+}
+
+#[test]
+fn test_spawn_idle() {
+    fn foreground(h: &mut Handle<'_>, order: Arc<Mutex<Vec<i32>>>, i: i32) {
+        order.lock().unwrap().push(i);
+        if i < 20 {
+            // Keeps the worker busy by spawning the next one.
+            h.spawn(move |h: &mut Handle<'_>| foreground(h, order, i + 1));
+        }
+    }
+
+    let pool = Builder::new("test_spawn_idle")
+        .max_thread_count(1)
+        .build_callback_pool();
+    let order = Arc::new(Mutex::new(vec![]));
+    let (tx, rx) = mpsc::channel();
+    let (block_tx, block_rx) = mpsc::channel::<()>();
+    pool.spawn(move |_: &mut Handle<'_>| {
+        let _ = block_rx.recv();
+    });
+    let o = order.clone();
+    pool.remote().spawn_idle(move |_: &mut Handle<'_>| {
+        o.lock().unwrap().push(-1);
+        tx.send(()).unwrap();
+    });
+    let o = order.clone();
+    pool.spawn(move |h: &mut Handle<'_>| foreground(h, o, 0));
+    drop(block_tx);
+
+    rx.recv_timeout(Duration::from_secs(3)).unwrap();
+    let mut expected: Vec<_> = (0..=20).collect();
+    expected.push(-1);
+    assert_eq!(*order.lock().unwrap(), expected);
+    pool.shutdown();
+}
+
+#[test]
+fn test_build_attached() {
+    let (remote, builder) = Builder::new("test_build_attached")
+        .max_thread_count(2)
+        .freeze::<callback::TaskCell>();
+    let (pool, workers) = builder.build_attached(CloneRunnerBuilder(callback::Runner::default()));
+    assert_eq!(
+        workers.iter().map(|w| w.index()).collect::<Vec<_>>(),
+        [0, 1]
+    );
+
+    // Threads managed by the embedder.
+    let threads: Vec<_> = workers
+        .into_iter()
+        .map(|w| {
+            thread::Builder::new()
+                .name(format!("embedder-{}", w.index()))
+                .spawn(move || w.run())
+                .unwrap()
+        })
+        .collect();
+    let (tx, rx) = mpsc::channel();
+    for _ in 0..10 {
+        let tx = tx.clone();
+        remote.spawn(move |_: &mut Handle<'_>| {
+            tx.send(thread::current().name().unwrap().to_owned())
+                .unwrap()
+        });
+    }
+    for _ in 0..10 {
+        let name = rx.recv_timeout(Duration::from_secs(3)).unwrap();
+        assert!(name.starts_with("embedder-"), "{}", name);
+    }
+
+    // The workers return after the pool is shut down.
+    pool.shutdown();
+    for t in threads {
+        t.join().unwrap();
+    }
+}
+
+#[test]
+fn test_read_extras() {
+    // Logs the level and wait time of each handled task.
+    struct LogRunner {
+        inner: callback::Runner,
+        log: Vec<(u64, u8, Duration)>,
+    }
+
+    impl Runner for LogRunner {
+        type TaskCell = callback::TaskCell;
+
+        fn handle(&mut self, local: &mut Local<Self::TaskCell>, t: Self::TaskCell) -> bool {
+            let extras = &t.extras;
+            let wait = extras.schedule_time().unwrap().elapsed();
+            self.log
+                .push((extras.task_id(), extras.current_level(), wait));
+            self.inner.handle(local, t)
+        }
+    }
+
+    let queue_type = QueueType::Multilevel(multilevel::Builder::new(multilevel::Config::default()));
+    let (injector, locals) = crate::queue::build(queue_type, 1, 1);
+    let remote = Remote::from_injector(injector, SchedConfig::default());
+    let mut local = remote.local(1, locals.into_iter().next().unwrap());
+    let mut runner = LogRunner {
+        inner: callback::Runner::default(),
+        log: vec![],
+    };
+    for i in 0..3u8 {
+        let extras = ExtrasBuilder::multilevel()
+            .task_id(10 + i as u64)
+            .fixed_level(i)
+            .build();
+        remote.spawn_with_extras(|_: &mut Handle<'_>| {}, extras);
+    }
+    thread::sleep(Duration::from_millis(20));
+
+    while let Some(t) = local.pop() {
+        assert!((10..13).contains(&t.task_id()));
+        assert_eq!(t.level() as u64, t.task_id() - 10);
+        assert!(t.wait_time() >= Duration::from_millis(20));
+        assert_eq!(t.task_cell.extras.schedule_time(), Some(t.schedule_time));
+        runner.handle(&mut local, t.task_cell);
+    }
+    runner.log.sort_by_key(|(id, ..)| *id);
+    let levels: Vec<_> = runner.log.iter().map(|(id, l, _)| (*id, *l)).collect();
+    assert_eq!(levels, [(10, 0), (11, 1), (12, 2)]);
+    assert!(runner
+        .log
+        .iter()
+        .all(|(.., wait)| *wait >= Duration::from_millis(20)));
+}
+
+#[test]
+fn test_queue_stats() {
+    let queue_type = QueueType::Multilevel(multilevel::Builder::new(multilevel::Config::default()));
+    let pool = Builder::new("test_queue_stats")
+        .max_thread_count(1)
+        .build_with_queue_and_runner(queue_type, CloneRunnerBuilder(callback::Runner::default()));
+    assert_eq!(
+        pool.queue_stats(),
+        QueueStats {
+            injector_len: 0,
+            local_lens: vec![0],
+            pinned_lens: vec![0],
+            idle_len: 0,
+            level_lens: vec![0, 0, 0],
+        }
+    );
+
+    // Keeps the only worker busy with two tasks queued in its local queue.
+    let (started_tx, started_rx) = mpsc::channel();
+    let (block_tx, block_rx) = mpsc::channel::<()>();
+    let (done_tx, done_rx) = mpsc::channel();
+    let done = done_tx.clone();
+    pool.spawn(move |h: &mut Handle<'_>| {
+        for level in 1..3 {
+            let done = done.clone();
+            let extras = ExtrasBuilder::multilevel().fixed_level(level).build();
+            h.spawn_once(move |_: &mut Handle<'_>| done.send(()).unwrap(), extras);
+        }
+        started_tx.send(()).unwrap();
+        block_rx.recv().unwrap();
+    });
+    started_rx.recv_timeout(Duration::from_secs(3)).unwrap();
+    for _ in 0..3 {
+        let done = done_tx.clone();
+        let extras = ExtrasBuilder::multilevel().fixed_level(0).build();
+        pool.remote()
+            .spawn_with_extras(move |_: &mut Handle<'_>| done.send(()).unwrap(), extras);
+    }
+    let done = done_tx.clone();
+    pool.remote()
+        .spawn_on(0, move |_: &mut Handle<'_>| done.send(()).unwrap());
+    let done = done_tx.clone();
+    pool.remote()
+        .spawn_idle(move |_: &mut Handle<'_>| done.send(()).unwrap());
+    assert_eq!(
+        pool.queue_stats(),
+        QueueStats {
+            injector_len: 3,
+            local_lens: vec![2],
+            pinned_lens: vec![1],
+            idle_len: 1,
+            level_lens: vec![3, 1, 1],
+        }
+    );
+
+    block_tx.send(()).unwrap();
+    for _ in 0..7 {
+        done_rx.recv_timeout(Duration::from_secs(3)).unwrap();
+    }
+    let stats = pool.queue_stats();
+    assert_eq!(stats.injector_len, 0);
+    assert_eq!(stats.local_lens, [0]);
+    assert_eq!(stats.pinned_lens, [0]);
+    assert_eq!(stats.idle_len, 0);
+    pool.shutdown();
+
+    let pool = Builder::new("test_queue_stats")
+        .max_thread_count(1)
+        .build_callback_pool();
+    assert!(pool.queue_stats().level_lens.is_empty());
+    pool.shutdown();
+}
+
+#[test]
+fn test_deterministic_seed() {
+    // Drives a multilevel queue on the current thread and returns the order
+    // the tasks are popped.
+    fn pop_order(seed: u64) -> Vec<u64> {
+        let config = SchedConfig {
+            max_thread_count: 1,
+            deterministic_seed: Some(seed),
+            ..SchedConfig::default()
+        };
+        let queue_type = multilevel::Builder::new(multilevel::Config::default());
+        let (remote, mut locals) = build_spawn(queue_type, config);
+        for i in 0..60 {
+            let extras = ExtrasBuilder::multilevel()
+                .task_id(i)
+                .fixed_level((i % 3) as u8)
+                .build();
+            remote.spawn_with_extras(|_: &mut Handle<'_>| {}, extras);
+        }
+        let mut order = vec![];
+        while let Some(t) = locals[0].pop() {
+            order.push(t.task_id());
+        }
+        assert_eq!(order.len(), 60);
+        order
+    }
+
+    let order = pop_order(42);
+    // Levels are interleaved by the random choices.
+    assert_ne!(order, (0..60).collect::<Vec<_>>());
+    for _ in 0..3 {
+        assert_eq!(pop_order(42), order);
+    }
+
+    let mut builder = Builder::new("test_deterministic_seed");
+    builder.max_thread_count(1).deterministic_seed(42);
+    let pool = builder.build_multilevel_future_pool();
+    let (tx, rx) = mpsc::channel();
+    pool.spawn(async move { tx.send(()).unwrap() });
+    rx.recv_timeout(Duration::from_secs(3)).unwrap();
+    pool.shutdown();
+}
+
+#[test]
+fn test_pool_group() {
+    let group = PoolGroup::new("test_group");
+    let callback_pool = group.build("cb", |b| b.max_thread_count(1).build_callback_pool());
+    let future_pool = group.build("fut", |b| b.max_thread_count(2).build_future_pool());
+    assert_eq!(group.names(), ["test_group-cb", "test_group-fut"]);
+
+    let (tx, rx) = mpsc::channel();
+    let t = tx.clone();
+    callback_pool.spawn(move |_: &mut Handle<'_>| {
+        t.send(thread::current().name().unwrap().to_owned())
+            .unwrap()
+    });
+    let t = tx.clone();
+    future_pool.spawn(async move {
+        t.send(thread::current().name().unwrap().to_owned())
+            .unwrap()
+    });
+    let mut names: Vec<_> = (0..2)
+        .map(|_| rx.recv_timeout(Duration::from_secs(3)).unwrap())
+        .collect();
+    names.sort();
+    assert!(names[0].starts_with("test_group-cb-"), "{:?}", names);
+    assert!(names[1].starts_with("test_group-fut-"), "{:?}", names);
+
+    // Names can't collide in a group.
+    let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        group.build("cb", |b| b.build_callback_pool())
+    }));
+    assert!(res.is_err());
+    assert_eq!(group.names().len(), 2);
+
+    group.shutdown_all();
+    assert!(callback_pool.remote().core.is_shutdown());
+    assert!(future_pool.remote().core.is_shutdown());
+    future_pool.spawn(async move { tx.send(String::new()).unwrap() });
+    assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+}
+
+#[test]
+fn test_default_max_thread_count() {
+    // `num_cpus` reads the CPU quota of cgroups, so the pool follows the
+    // quota of the container it runs in.
+    let pool = Builder::new("test_default_max_thread_count").build_callback_pool();
+    assert_eq!(pool.thread_count_limit().1, num_cpus::get());
+    pool.shutdown();
+}
@@ -1,56 +1,676 @@
 // Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
 
-use crate::pool::{Local, Runner};
-use crate::queue::{Pop, TaskCell};
-use parking_lot_core::SpinWait;
+use crate::pool::spawn::WorkerGroup;
+use crate::pool::{Local, Runner, SchedConfig, TaskPanicHandler};
+use crate::queue::{Pop, TaskCell, TaskContext, TaskSource};
+use crate::timer;
+use parking_lot_core::{DEFAULT_PARK_TOKEN, DEFAULT_UNPARK_TOKEN};
+use prometheus::IntCounter;
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::cell::Cell;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Barrier};
+use std::task::{Wake, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The maximum times to back off when the runner refuses to accept tasks.
+const MAX_ACCEPT_RETRIES: usize = 10;
+/// The initial interval to back off when the runner refuses to accept tasks.
+const ACCEPT_BACKOFF: Duration = Duration::from_micros(100);
+/// The maximum interval to back off when the runner refuses to accept tasks.
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_millis(1);
+
+/// Wakes up a worker waiting for its runner to accept tasks when the backoff
+/// timer fires.
+#[derive(Default)]
+struct AcceptTimer {
+    fired: AtomicBool,
+}
+
+impl AcceptTimer {
+    /// Parks the current thread until the timer fires.
+    fn wait(&self) {
+        let addr = &self.fired as *const AtomicBool as usize;
+        while !self.fired.load(Ordering::SeqCst) {
+            unsafe {
+                parking_lot_core::park(
+                    addr,
+                    || !self.fired.load(Ordering::SeqCst),
+                    || {},
+                    |_, _| {},
+                    DEFAULT_PARK_TOKEN,
+                    None,
+                );
+            }
+        }
+    }
+}
+
+impl Wake for AcceptTimer {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.fired.store(true, Ordering::SeqCst);
+        let addr = &self.fired as *const AtomicBool as usize;
+        unsafe {
+            parking_lot_core::unpark_all(addr, DEFAULT_UNPARK_TOKEN);
+        }
+    }
+}
+
+/// Waits until `runner` can accept more tasks or the retries are exhausted.
+///
+/// Every retry is scheduled by the shared timer, which wakes up the parked
+/// worker when the backoff elapses.
+fn wait_for_accept<T, R: Runner<TaskCell = T>>(runner: &R, local: &Local<T>) {
+    let mut backoff = ACCEPT_BACKOFF;
+    for _ in 0..MAX_ACCEPT_RETRIES {
+        if runner.can_accept(local) || local.core().is_shutdown() {
+            return;
+        }
+        let fired = Arc::new(AcceptTimer::default());
+        let _timer = timer::register(Instant::now() + backoff, Waker::from(fired.clone()));
+        fired.wait();
+        backoff = std::cmp::min(backoff * 2, MAX_ACCEPT_BACKOFF);
+    }
+}
+
+/// The maximum exponent of busy loops in a spin of `PopBackoff`.
+const MAX_SPIN_SHIFT: usize = 10;
+
+/// Spins and then yields before a worker goes to sleep, like `SpinWait` but
+/// with the counts taken from the config.
+struct PopBackoff {
+    counter: usize,
+    max_spin: usize,
+    max_yield: usize,
+}
+
+impl PopBackoff {
+    fn new(config: &SchedConfig) -> PopBackoff {
+        PopBackoff {
+            counter: 0,
+            max_spin: config.max_pop_spin,
+            max_yield: config.max_pop_yield,
+        }
+    }
+
+    /// Backs off once, returns false if the worker should go to sleep
+    /// instead.
+    fn spin(&mut self) -> bool {
+        if self.counter >= self.max_spin + self.max_yield {
+            return false;
+        }
+        self.counter += 1;
+        if self.counter <= self.max_spin {
+            for _ in 0..1 << self.counter.min(MAX_SPIN_SHIFT) {
+                std::hint::spin_loop();
+            }
+        } else {
+            thread::yield_now();
+        }
+        true
+    }
+}
+
+thread_local! {
+    /// The id and group of the task being handled by the current thread. It's
+    /// const initialized, so reading it never allocates.
+    static CURRENT_TASK: Cell<Option<(u64, Option<u64>)>> = const { Cell::new(None) };
+}
+
+/// Returns the id of the task being run by the current thread, or `None` if
+/// it's not called inside a task.
+///
+/// Besides worker threads, it covers tasks run on the spawning thread, like
+/// callbacks run by `OverflowPolicy::RunOnCaller` and the first poll of
+/// `Remote::spawn_eager`. It's a single thread local read, cheap enough to be
+/// called on every allocation by an allocator wrapper that accounts memory
+/// per task.
+pub fn current_task_id() -> Option<u64> {
+    CURRENT_TASK.with(|t| t.get()).map(|(id, _)| id)
+}
+
+/// Returns the group of the task being run by the current thread, or `None`
+/// if it's not called inside a task or the task has no group.
+///
+/// Like `current_task_id`, it's cheap enough for allocator wrappers.
+pub fn current_group_id() -> Option<u64> {
+    CURRENT_TASK.with(|t| t.get()).and_then(|(_, group)| group)
+}
+
+/// Publishes the task being run to `CURRENT_TASK` until dropped, then
+/// restores the outer one, as a task may run another one on its thread.
+pub(crate) struct CurrentTaskGuard {
+    prev: Option<(u64, Option<u64>)>,
+}
+
+impl CurrentTaskGuard {
+    pub(crate) fn new<T: TaskCell>(task_cell: &mut T) -> CurrentTaskGuard {
+        let extras = task_cell.mut_extras();
+        let task = (extras.task_id(), extras.group_id());
+        CurrentTaskGuard {
+            prev: CURRENT_TASK.with(|t| t.replace(Some(task))),
+        }
+    }
+}
+
+impl Drop for CurrentTaskGuard {
+    fn drop(&mut self) {
+        CURRENT_TASK.with(|t| t.set(self.prev));
+    }
+}
+
+/// A panic of a task caught by its worker, see
+/// [`Builder::on_task_panic`](crate::Builder::on_task_panic).
+pub struct TaskPanic {
+    payload: Box<dyn Any + Send>,
+    backtrace: Option<Backtrace>,
+}
+
+impl TaskPanic {
+    /// Returns the payload the task panicked with.
+    pub fn payload(&self) -> &(dyn Any + Send) {
+        &*self.payload
+    }
+
+    /// Returns the message of the panic if the payload is a string, which is
+    /// the case for `panic!`.
+    pub fn message(&self) -> Option<&str> {
+        match self.payload.downcast_ref::<&str>() {
+            Some(s) => Some(s),
+            None => self.payload.downcast_ref::<String>().map(String::as_str),
+        }
+    }
+
+    /// Returns the backtrace captured at the panic point. It's only captured
+    /// with the `task-backtrace` feature, regardless of `RUST_BACKTRACE`.
+    pub fn backtrace(&self) -> Option<&Backtrace> {
+        self.backtrace.as_ref()
+    }
+}
+
+impl fmt::Debug for TaskPanic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TaskPanic")
+            .field("message", &self.message())
+            .field("backtrace", &self.backtrace)
+            .finish()
+    }
+}
+
+#[cfg(feature = "task-backtrace")]
+thread_local! {
+    /// Whether the panic hook should capture a backtrace, that is the current
+    /// thread is handling a task whose panic is caught.
+    static CAPTURE_BACKTRACE: Cell<bool> = const { Cell::new(false) };
+    /// The backtrace captured by the panic hook.
+    static PANIC_BACKTRACE: std::cell::RefCell<Option<Backtrace>> =
+        const { std::cell::RefCell::new(None) };
+}
+
+/// Installs a panic hook that captures the backtrace of a panicking task
+/// before calling the hook set before it. It's installed once per process.
+#[cfg(feature = "task-backtrace")]
+fn install_backtrace_hook() {
+    static HOOK: std::sync::Once = std::sync::Once::new();
+    HOOK.call_once(|| {
+        let prev = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let _ = CAPTURE_BACKTRACE.try_with(|capture| {
+                if capture.get() {
+                    let bt = Backtrace::force_capture();
+                    PANIC_BACKTRACE.with(|b| *b.borrow_mut() = Some(bt));
+                }
+            });
+            prev(info);
+        }));
+    });
+}
+
+/// Handles a task with the runner. If the pool has a task panic handler, a
+/// panic of the task is caught and passed to it instead of unwinding the
+/// worker. Returns whether the task is finished, which is true if it panics.
+fn handle_task<R: Runner>(
+    runner: &mut R,
+    local: &mut Local<R::TaskCell>,
+    task_cell: R::TaskCell,
+    on_panic: Option<&TaskPanicHandler>,
+) -> bool {
+    let on_panic = match on_panic {
+        Some(f) => f,
+        None => return runner.handle(local, task_cell),
+    };
+    #[cfg(feature = "task-backtrace")]
+    {
+        install_backtrace_hook();
+        CAPTURE_BACKTRACE.with(|c| c.set(true));
+    }
+    let res = panic::catch_unwind(AssertUnwindSafe(|| runner.handle(local, task_cell)));
+    #[cfg(feature = "task-backtrace")]
+    let backtrace = {
+        CAPTURE_BACKTRACE.with(|c| c.set(false));
+        // Also clears the backtrace of a panic the task has recovered from.
+        PANIC_BACKTRACE.with(|b| b.borrow_mut().take())
+    };
+    #[cfg(not(feature = "task-backtrace"))]
+    let backtrace = None;
+    match res {
+        Ok(finished) => finished,
+        Err(payload) => {
+            on_panic(&TaskPanic { payload, backtrace });
+            true
+        }
+    }
+}
+
+/// Finishes the bookkeeping of a task being handled when dropped, so it's
+/// done even if the runner panics.
+struct HandleGuard<'a, T: TaskCell + Send> {
+    local: &'a mut Local<T>,
+    /// Whether the task is tracked by the stall detector.
+    track: bool,
+    /// The group permit held by the task, released unless the task is not
+    /// finished yet.
+    group: Option<u64>,
+    /// The context of the worker before the task's one is restored.
+    context: Option<TaskContext>,
+}
+
+impl<T: TaskCell + Send> Drop for HandleGuard<'_, T> {
+    fn drop(&mut self) {
+        if let Some(ctx) = &self.context {
+            self.local.core().reset_context(ctx);
+        }
+        if self.track {
+            self.local.end_handle();
+        }
+        if let Some(group_id) = self.group {
+            self.local.core().release_group_permit(group_id);
+        }
+        self.local.task_done();
+    }
+}
+
+/// A worker that died of a panic, sent to the supervisor to be restarted.
+pub(crate) struct DeadWorker<T, R> {
+    pub thd: WorkerThread<T, R>,
+    /// Given to the restarted worker, so it can be restarted again.
+    pub tx: mpsc::Sender<DeadWorker<T, R>>,
+}
 
 pub(crate) struct WorkerThread<T, R> {
     local: Local<T>,
     runner: R,
+    spin_counter: IntCounter,
+    /// The runner epoch of the queue core the runner is built for.
+    runner_epoch: usize,
+    /// Waited on after the runner is started.
+    start_barrier: Option<Arc<Barrier>>,
 }
 
 impl<T, R> WorkerThread<T, R> {
-    pub fn new(local: Local<T>, runner: R) -> WorkerThread<T, R> {
-        WorkerThread { local, runner }
+    pub fn new(local: Local<T>, runner: R, spin_counter: IntCounter) -> WorkerThread<T, R> {
+        WorkerThread {
+            local,
+            runner,
+            spin_counter,
+            runner_epoch: 0,
+            start_barrier: None,
+        }
+    }
+
+    /// Replaces the runner with a freshly built one, which is rebuilt again
+    /// if the runner factory of the pool has been replaced.
+    pub fn reset_runner(&mut self, runner: R) {
+        self.runner = runner;
+        self.runner_epoch = 0;
+    }
+
+    /// Makes the worker wait on `barrier` after its runner is started.
+    pub fn with_start_barrier(mut self, barrier: Arc<Barrier>) -> WorkerThread<T, R> {
+        self.start_barrier = Some(barrier);
+        self
+    }
+
+    #[inline]
+    fn record_spin(&self) {
+        if cfg!(feature = "metrics") {
+            self.spin_counter.inc();
+        }
     }
 }
 
 impl<T, R> WorkerThread<T, R>
 where
     T: TaskCell + Send,
-    R: Runner<TaskCell = T>,
+    R: Runner<TaskCell = T> + 'static,
 {
     #[inline]
     fn pop(&mut self) -> Option<Pop<T>> {
         // Wait some time before going to sleep, which is more expensive.
-        let mut spin = SpinWait::new();
+        let mut spin = PopBackoff::new(self.local.core().config());
         loop {
             if let Some(t) = self.local.pop() {
                 return Some(t);
             }
+            self.record_spin();
             if !spin.spin() {
                 break;
             }
         }
+        if let Some(t) = self.wait_as_core() {
+            return Some(t);
+        }
         self.runner.pause(&mut self.local);
         let t = self.local.pop_or_sleep();
         self.runner.resume(&mut self.local);
         t
     }
 
+    /// Keeps waiting for tasks for `core_idle_time` if the worker is one of
+    /// the first `min_thread_count` workers.
+    ///
+    /// The worker parks with a timeout in the meantime, so it's woken up as
+    /// soon as a task arrives.
+    fn wait_as_core(&mut self) -> Option<Pop<T>> {
+        let config = self.local.core().config();
+        if self.local.id() > config.min_thread_count || config.core_idle_time == Duration::ZERO {
+            return None;
+        }
+        let deadline = Instant::now() + config.core_idle_time;
+        loop {
+            if let Some(t) = self.local.pop() {
+                return Some(t);
+            }
+            if self.local.core().is_shutdown() || !self.local.park_until(deadline) {
+                return None;
+            }
+        }
+    }
+
+    /// Replaces the runner if the pool is reconfigured. It must not be called
+    /// when the runner is handling a task.
+    fn maybe_rebuild_runner(&mut self) {
+        let core = self.local.core().clone();
+        if let Some(runner) = core.rebuild_runner(&mut self.runner_epoch) {
+            self.runner.end(&mut self.local);
+            self.runner = runner;
+            self.runner.start(&mut self.local);
+        }
+    }
+
+    fn handle(&mut self, task_cell: T, source: TaskSource) {
+        self.maybe_rebuild_runner();
+        let (mut task_cell, group) = match self.local.core().acquire_group_permit(task_cell) {
+            Some(t) => t,
+            None => return,
+        };
+        self.runner.on_task_source(&mut self.local, source);
+        let context = self.local.core().restore_context(task_cell.mut_extras());
+        let config = self.local.core().config();
+        let (track, on_panic) = (config.track_handling(), config.on_task_panic.clone());
+        if track {
+            self.local.begin_handle(task_cell.mut_extras().task_id());
+        }
+        let mut guard = HandleGuard {
+            local: &mut self.local,
+            track,
+            group,
+            context,
+        };
+        let _current = CurrentTaskGuard::new(&mut task_cell);
+        if !handle_task(&mut self.runner, guard.local, task_cell, on_panic.as_ref()) {
+            // A pending future keeps its permit until it finishes.
+            guard.group = None;
+        }
+    }
+
+    /// Starts the runner and returns whether it succeeds.
+    ///
+    /// If `start` panics, the runner is ended to release what it may have
+    /// acquired, and the worker is no longer counted as awake since it's
+    /// going to exit. Tasks pinned to it are handed to other workers.
+    fn start_runner(&mut self) -> bool {
+        let (runner, local) = (&mut self.runner, &mut self.local);
+        if panic::catch_unwind(AssertUnwindSafe(|| runner.start(local))).is_ok() {
+            return true;
+        }
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| runner.end(local)));
+        let core = self.local.core();
+        core.record_start_panic(self.local.id());
+        core.mark_dead(self.local.id());
+        core.mark_sleep();
+        false
+    }
+
     pub fn run(mut self) {
-        self.runner.start(&mut self.local);
+        self.run_in_place();
+    }
+
+    /// Runs the worker like `run`, but sends it to `dead` instead of
+    /// unwinding if it panics, so it can be restarted with its local queue.
+    ///
+    /// The runner of a dead worker is ended and must not be used again, and
+    /// the worker is marked dead and no longer counted as awake until it's
+    /// restarted. `dead` is sent along, so all senders are dropped once the
+    /// workers have exited for good.
+    pub fn run_supervised(mut self, dead: mpsc::Sender<DeadWorker<T, R>>) {
+        match panic::catch_unwind(AssertUnwindSafe(|| self.run_in_place())) {
+            Ok(true) => return,
+            // `start_runner` has cleaned up already.
+            Ok(false) => {}
+            Err(_) => {
+                let (runner, local) = (&mut self.runner, &mut self.local);
+                let _ = panic::catch_unwind(AssertUnwindSafe(|| runner.end(local)));
+                let core = self.local.core();
+                core.mark_dead(self.local.id());
+                core.mark_sleep();
+            }
+        }
+        let _ = dead.send(DeadWorker {
+            thd: self,
+            tx: dead.clone(),
+        });
+    }
+
+    /// Returns false if the runner panics on start.
+    fn run_in_place(&mut self) -> bool {
+        let started = self.start_runner();
+        if let Some(barrier) = self.start_barrier.take() {
+            barrier.wait();
+        }
+        if !started {
+            return false;
+        }
         while !self.local.core().is_shutdown() {
+            if self.local.core().is_quiesced(self.local.id()) {
+                self.runner.pause(&mut self.local);
+                self.local.park_quiesced();
+                self.runner.resume(&mut self.local);
+                continue;
+            }
+            wait_for_accept(&self.runner, &self.local);
             let task = match self.pop() {
                 Some(t) => t,
                 None => continue,
             };
-            self.runner.handle(&mut self.local, task.task_cell);
+            self.handle(task.task_cell, task.source);
         }
         self.runner.end(&mut self.local);
 
         // Drain all futures in the queue
-        while self.local.pop().is_some() {}
+        self.local.clear();
+        true
+    }
+
+    /// Runs the worker until it has been idle for `idle_time` or the pool is
+    /// shut down.
+    ///
+    /// It's used by burst workers, which exit the thread instead of going to
+    /// sleep when idle. `resume` indicates whether the worker has been run
+    /// before. Returns false if the pool is shut down, in which case the
+    /// runner has been ended.
+    pub fn run_until_idle(&mut self, idle_time: Duration, resume: bool) -> bool {
+        if resume {
+            self.runner.resume(&mut self.local);
+        } else {
+            self.runner.start(&mut self.local);
+        }
+        let mut last_active = Instant::now();
+        while !self.local.core().is_shutdown() {
+            wait_for_accept(&self.runner, &self.local);
+            match self.local.pop() {
+                Some(t) => {
+                    self.handle(t.task_cell, t.source);
+                    last_active = Instant::now();
+                }
+                None => {
+                    if !self.local.park_until(last_active + idle_time) {
+                        self.runner.pause(&mut self.local);
+                        return true;
+                    }
+                }
+            }
+        }
+        self.runner.end(&mut self.local);
+        self.local.clear();
+        false
+    }
+
+    /// Ends a worker that has been paused by `run_until_idle`.
+    pub fn end(&mut self) {
+        self.runner.end(&mut self.local);
+        self.local.clear();
+    }
+
+    /// Drops the tasks left in the local queue of a dead worker, whose runner
+    /// has been ended.
+    pub fn clear(&mut self) {
+        self.local.clear();
+    }
+
+    pub fn local(&self) -> &Local<T> {
+        &self.local
+    }
+}
+
+/// A worker thread shared by several queues.
+///
+/// It holds a local queue and a runner for each queue, and polls the queues in
+/// a round-robin way so none of them is starved.
+pub(crate) struct SharedWorkerThread<T, R> {
+    id: usize,
+    queues: Vec<(Local<T>, R)>,
+    group: Arc<WorkerGroup>,
+    spin_counter: IntCounter,
+    /// The queue to poll first next time.
+    next: usize,
+}
+
+impl<T, R> SharedWorkerThread<T, R>
+where
+    T: TaskCell + Send,
+    R: Runner<TaskCell = T>,
+{
+    pub fn new(
+        id: usize,
+        queues: Vec<(Local<T>, R)>,
+        group: Arc<WorkerGroup>,
+        spin_counter: IntCounter,
+    ) -> Self {
+        SharedWorkerThread {
+            id,
+            queues,
+            group,
+            spin_counter,
+            next: 0,
+        }
+    }
+
+    /// Pops a task from the queues starting from `next`, returns the index of
+    /// the queue along with the task.
+    fn try_pop(queues: &mut [(Local<T>, R)], next: usize) -> Option<(usize, Pop<T>)> {
+        let n = queues.len();
+        (0..n).map(|i| (next + i) % n).find_map(|i| {
+            let t = queues[i].0.pop()?;
+            Some((i, t))
+        })
+    }
+
+    fn pop(&mut self) -> Option<(usize, Pop<T>)> {
+        // The queues share the same scheduling configurations.
+        let mut spin = PopBackoff::new(self.queues[0].0.core().config());
+        loop {
+            if let Some(t) = Self::try_pop(&mut self.queues, self.next) {
+                return Some(t);
+            }
+            if cfg!(feature = "metrics") {
+                self.spin_counter.inc();
+            }
+            if !spin.spin() {
+                break;
+            }
+        }
+        for (local, runner) in &mut self.queues {
+            runner.pause(local);
+        }
+        let (queues, next) = (&mut self.queues, self.next);
+        let t = self
+            .group
+            .pop_or_sleep(self.id, || Self::try_pop(queues, next));
+        for (local, runner) in &mut self.queues {
+            runner.resume(local);
+        }
+        t
+    }
+
+    pub fn run(mut self) {
+        for (local, runner) in &mut self.queues {
+            runner.start(local);
+        }
+        while !self.group.is_shutdown() {
+            let (i, task) = match self.pop() {
+                Some(t) => t,
+                None => continue,
+            };
+            self.next = (i + 1) % self.queues.len();
+            let (local, runner) = &mut self.queues[i];
+            // The queue of a task is only known once it's popped, so the
+            // runner is waited for afterwards.
+            wait_for_accept(runner, local);
+            let (mut task_cell, group) = match local.core().acquire_group_permit(task.task_cell) {
+                Some(t) => t,
+                None => continue,
+            };
+            runner.on_task_source(local, task.source);
+            let context = local.core().restore_context(task_cell.mut_extras());
+            let config = local.core().config();
+            let (track, on_panic) = (config.track_handling(), config.on_task_panic.clone());
+            if track {
+                local.begin_handle(task_cell.mut_extras().task_id());
+            }
+            let mut guard = HandleGuard {
+                local,
+                track,
+                group,
+                context,
+            };
+            let _current = CurrentTaskGuard::new(&mut task_cell);
+            if !handle_task(runner, guard.local, task_cell, on_panic.as_ref()) {
+                guard.group = None;
+            }
+        }
+        for (local, runner) in &mut self.queues {
+            runner.end(local);
+            local.clear();
+        }
     }
 }
 
@@ -61,9 +681,8 @@ mod tests {
     use crate::pool::SchedConfig;
     use crate::queue::QueueType;
     use crate::task::callback;
-    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::sync::*;
-    use std::time::*;
 
     #[derive(Default, PartialEq, Debug)]
     struct Metrics {
@@ -116,6 +735,30 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_pop_backoff() {
+        let mut config = SchedConfig::default();
+        // Same as `SpinWait` by default.
+        let mut spin = PopBackoff::new(&config);
+        assert_eq!((0..).take_while(|_| spin.spin()).count(), 10);
+
+        config.max_pop_spin = 2;
+        config.max_pop_yield = 5;
+        let mut spin = PopBackoff::new(&config);
+        assert_eq!((0..).take_while(|_| spin.spin()).count(), 7);
+        assert!(!spin.spin());
+
+        // Large spin counts don't overflow.
+        config.max_pop_spin = 100;
+        config.max_pop_yield = 0;
+        let mut spin = PopBackoff::new(&config);
+        assert_eq!((0..).take_while(|_| spin.spin()).count(), 100);
+
+        config.max_pop_spin = 0;
+        let mut spin = PopBackoff::new(&config);
+        assert!(!spin.spin());
+    }
+
     #[test]
     fn test_hooks() {
         let (tx, rx) = mpsc::channel();
@@ -129,7 +772,7 @@ mod tests {
         let mut config: SchedConfig = Default::default();
         config.core_thread_count = AtomicUsize::new(config.max_thread_count);
         let (injector, mut locals) = build_spawn(QueueType::SingleLevel, config);
-        let th = WorkerThread::new(locals.remove(0), r);
+        let th = WorkerThread::new(locals.remove(0), r, IntCounter::new("_", "_").unwrap());
         let handle = std::thread::spawn(move || {
             th.run();
         });
@@ -151,4 +794,69 @@ mod tests {
         expected_metrics.end = 1;
         assert_eq!(expected_metrics, *metrics.lock().unwrap());
     }
+
+    struct RefusingRunner {
+        runner: callback::Runner,
+        // Refuses every call if true, otherwise refuses every other call.
+        refuse_all: bool,
+        accepted: std::cell::Cell<bool>,
+        refused: Arc<AtomicUsize>,
+    }
+
+    impl crate::pool::Runner for RefusingRunner {
+        type TaskCell = callback::TaskCell;
+
+        fn can_accept(&self, _: &Local<Self::TaskCell>) -> bool {
+            let accept = !self.refuse_all && !self.accepted.get();
+            self.accepted.set(accept);
+            if !accept {
+                self.refused.fetch_add(1, Ordering::SeqCst);
+                return false;
+            }
+            true
+        }
+
+        fn handle(&mut self, local: &mut Local<Self::TaskCell>, t: Self::TaskCell) -> bool {
+            self.runner.handle(local, t)
+        }
+    }
+
+    #[test]
+    fn test_can_accept() {
+        for refuse_all in [false, true] {
+            let refused = Arc::new(AtomicUsize::new(0));
+            let r = RefusingRunner {
+                runner: callback::Runner::default(),
+                refuse_all,
+                accepted: Default::default(),
+                refused: refused.clone(),
+            };
+            let mut config: SchedConfig = Default::default();
+            config.core_thread_count = AtomicUsize::new(config.max_thread_count);
+            let (remote, mut locals) = build_spawn(QueueType::SingleLevel, config);
+            let th = WorkerThread::new(locals.remove(0), r, IntCounter::new("_", "_").unwrap());
+            let handle = std::thread::spawn(move || {
+                th.run();
+            });
+
+            let (tx, rx) = mpsc::channel();
+            let now = Instant::now();
+            for i in 0..20 {
+                let tx = tx.clone();
+                remote.spawn(move |_: &mut callback::Handle<'_>| tx.send(i).unwrap());
+            }
+            // All tasks should be handled in order even if the runner keeps
+            // refusing.
+            for i in 0..20 {
+                assert_eq!(rx.recv_timeout(Duration::from_secs(3)), Ok(i));
+            }
+            // Every task except the first one has been delayed by at least
+            // one backoff.
+            assert!(refused.load(Ordering::SeqCst) >= 19);
+            assert!(now.elapsed() >= ACCEPT_BACKOFF * 19);
+
+            remote.stop();
+            handle.join().unwrap();
+        }
+    }
 }
@@ -13,10 +13,18 @@ pub mod priority;
 
 mod extras;
 mod single_level;
+mod strict_priority;
 
-pub use self::extras::Extras;
+pub(crate) use self::extras::TaskContext;
+pub use self::extras::{Extras, ExtrasBuilder, ExtrasSnapshot};
+#[cfg(all(test, feature = "metrics"))]
+pub(crate) use self::single_level::FORCE_STEAL_RETRY;
 
-use std::time::Instant;
+use dashmap::{mapref::entry::Entry, DashMap};
+use rand::prelude::*;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 /// A cell containing a task and needed extra information.
 pub trait TaskCell: 'static {
@@ -37,28 +45,184 @@ impl<F: TaskCell> WithExtras<F> for F {
     }
 }
 
+/// A source of [`Extras`], either an `Extras` value or a closure producing
+/// one.
+pub trait IntoExtras {
+    /// Returns the extras.
+    fn into_extras(self) -> Extras;
+}
+
+impl IntoExtras for Extras {
+    fn into_extras(self) -> Extras {
+        self
+    }
+}
+
+impl IntoExtras for ExtrasBuilder {
+    fn into_extras(self) -> Extras {
+        self.build()
+    }
+}
+
+impl<F: FnOnce() -> Extras> IntoExtras for F {
+    fn into_extras(self) -> Extras {
+        self()
+    }
+}
+
 /// The injector of a task queue.
-pub(crate) struct TaskInjector<T>(InjectorInner<T>);
+///
+/// It's only a handle to be passed to [`Remote::from_injector`], tasks are
+/// pushed through the remote.
+pub struct TaskInjector<T>(InjectorInner<T>, Arc<QueuedTasks>);
 
 enum InjectorInner<T> {
     SingleLevel(single_level::TaskInjector<T>),
     Multilevel(multilevel::TaskInjector<T>),
     Priority(priority::TaskInjector<T>),
+    StrictPriority(strict_priority::TaskInjector<T>),
+}
+
+impl<T> TaskInjector<T> {
+    /// Returns the kind of the queue.
+    pub(crate) fn kind(&self) -> QueueKind {
+        match &self.0 {
+            InjectorInner::SingleLevel(_) => QueueKind::SingleLevel,
+            InjectorInner::Multilevel(_) => QueueKind::Multilevel,
+            InjectorInner::Priority(_) => QueueKind::Priority,
+            InjectorInner::StrictPriority(q) => QueueKind::StrictPriority {
+                classes: q.classes(),
+            },
+        }
+    }
+
+    /// Sets whether the queued tasks are kept track of for `snapshot`. It
+    /// must be set before any task is pushed.
+    pub(crate) fn set_tracking(&mut self, enable: bool) {
+        self.1.enabled.store(enable, Ordering::Relaxed);
+    }
 }
 
 impl<T: TaskCell + Send> TaskInjector<T> {
-    /// Pushes a task to the queue.
-    pub fn push(&self, task_cell: T) {
+    /// Returns the time spent on each level if it's a multilevel task queue.
+    pub(crate) fn level_elapsed(&self) -> Option<[Duration; multilevel::LEVEL_NUM]> {
+        match &self.0 {
+            InjectorInner::Multilevel(q) => Some(q.level_elapsed()),
+            _ => None,
+        }
+    }
+
+    /// Resets the statistics of levels if it's a multilevel task queue.
+    pub(crate) fn reset_multilevel_stats(&self) {
+        if let InjectorInner::Multilevel(q) = &self.0 {
+            q.reset_stats();
+        }
+    }
+
+    /// Replaces the running time thresholds of task levels. Returns false if
+    /// the queue has no levels.
+    pub(crate) fn set_level_time_threshold(
+        &self,
+        value: [Duration; multilevel::LEVEL_NUM - 1],
+    ) -> bool {
+        match &self.0 {
+            InjectorInner::Multilevel(q) => q.set_level_time_threshold(value),
+            InjectorInner::Priority(q) => q.set_level_time_threshold(value),
+            _ => return false,
+        }
+        true
+    }
+
+    /// Updates the level of the task like pushing it, does nothing if the
+    /// queue has no levels.
+    pub(crate) fn adjust_level(&self, task_cell: &mut T) {
+        match &self.0 {
+            InjectorInner::Multilevel(q) => q.adjust_level(task_cell),
+            InjectorInner::Priority(q) => q.adjust_level(task_cell),
+            _ => {}
+        }
+    }
+
+    /// Moves the queued tasks with the given id to `level` if it's a
+    /// multilevel task queue.
+    pub(crate) fn boost_task(&self, task_id: u64, level: u8) -> bool {
         match &self.0 {
+            InjectorInner::Multilevel(q) => q.boost_task(task_id, level, &self.1),
+            _ => false,
+        }
+    }
+
+    /// Returns a copy of the extras of the tasks in the global queue and the
+    /// local queues, ordered by level and then by the order they are pushed.
+    pub(crate) fn snapshot(&self) -> Vec<ExtrasSnapshot> {
+        self.1.snapshot()
+    }
+
+    /// Pushes a task to the queue.
+    pub(crate) fn push(&self, mut task_cell: T) {
+        let key = self.1.next_key(task_cell.mut_extras());
+        let snapshot = match &self.0 {
             InjectorInner::SingleLevel(q) => q.push(task_cell),
             InjectorInner::Multilevel(q) => q.push(task_cell),
             InjectorInner::Priority(q) => q.push(task_cell),
+            InjectorInner::StrictPriority(q) => q.push(task_cell),
+        };
+        self.1.insert(key, snapshot);
+    }
+
+    /// Adds a task kept out of the queue, like a task pinned to a worker, to
+    /// the snapshot of the queue. The schedule time must be assigned.
+    pub(crate) fn track(&self, extras: &mut Extras) {
+        if let Some(key) = self.1.next_key(extras) {
+            self.1.insert(Some(key), ExtrasSnapshot::new(extras));
+        }
+    }
+
+    /// Removes a task added by `track` from the snapshot of the queue.
+    pub(crate) fn untrack(&self, extras: &Extras) {
+        self.1.remove(extras.queue_key);
+    }
+
+    /// Checks whether there are no tasks in the global queue.
+    pub(crate) fn is_empty(&self) -> bool {
+        match &self.0 {
+            InjectorInner::SingleLevel(q) => q.is_empty(),
+            InjectorInner::Multilevel(q) => q.is_empty(),
+            InjectorInner::Priority(q) => q.is_empty(),
+            InjectorInner::StrictPriority(q) => q.is_empty(),
+        }
+    }
+
+    /// Returns the number of tasks in the global queue.
+    pub(crate) fn len(&self) -> usize {
+        match &self.0 {
+            InjectorInner::SingleLevel(q) => q.len(),
+            InjectorInner::Multilevel(q) => q.len(),
+            InjectorInner::Priority(q) => q.len(),
+            InjectorInner::StrictPriority(q) => q.len(),
         }
     }
 
-    pub fn default_extras(&self) -> Extras {
+    /// Returns the lengths of the queue at this point in time.
+    pub(crate) fn stats(&self) -> QueueStats {
+        let (local_lens, level_lens) = match &self.0 {
+            InjectorInner::SingleLevel(q) => (q.local_lens(), vec![]),
+            InjectorInner::Multilevel(q) => (q.local_lens(), q.level_lens().to_vec()),
+            InjectorInner::Priority(_) | InjectorInner::StrictPriority(_) => (vec![], vec![]),
+        };
+        QueueStats {
+            injector_len: self.len(),
+            local_lens,
+            level_lens,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn default_extras(&self) -> Extras {
         match self.0 {
-            InjectorInner::SingleLevel(_) => Extras::single_level(),
+            InjectorInner::SingleLevel(_) | InjectorInner::StrictPriority(_) => {
+                Extras::single_level()
+            }
             InjectorInner::Multilevel(_) | InjectorInner::Priority(_) => {
                 Extras::multilevel_default()
             }
@@ -66,6 +230,127 @@ impl<T: TaskCell + Send> TaskInjector<T> {
     }
 }
 
+/// The lengths of a task queue at a point in time, returned by
+/// [`ThreadPool::queue_stats`](crate::ThreadPool::queue_stats).
+///
+/// The lengths are read one by one while tasks keep moving, so they may not
+/// add up exactly. Some tasks are not counted at all: the ones pushed to the
+/// front of a local queue, which are only visible to their worker, and the
+/// ones deferred by `Remote::set_group_concurrency`.
+///
+/// The per worker lengths are indexed by the worker index. The regular
+/// workers come first, followed by the burst workers if
+/// `Builder::burst_thread_count` is set, whose entries stay 0 while they are
+/// not running.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    /// The number of tasks in the global queue.
+    pub injector_len: usize,
+    /// The number of tasks in the local queue of each worker. It's empty for
+    /// the priority and strict priority queues, whose tasks are all kept in
+    /// the global queue.
+    pub local_lens: Vec<usize>,
+    /// The number of tasks pinned to each worker, by `Remote::spawn_on` or
+    /// `Builder::partition_fn` for example.
+    pub pinned_lens: Vec<usize>,
+    /// The number of tasks spawned by `Remote::spawn_idle` that are waiting
+    /// for workers to become idle.
+    pub idle_len: usize,
+    /// The number of tasks at each level, indexed by the level, including
+    /// the ones in the local queues but not the pinned or idle ones. It's
+    /// empty unless the queue is a multilevel queue.
+    pub level_lens: Vec<usize>,
+}
+
+/// The tasks in a task queue, kept aside when they are pushed so they can be
+/// read without taking them out of the queue.
+#[derive(Default)]
+pub(crate) struct QueuedTasks {
+    /// Whether the tasks are kept track of. Nothing is recorded otherwise.
+    enabled: AtomicBool,
+    next_key: AtomicU64,
+    /// The snapshots of the queued tasks by their keys. `None` marks a task
+    /// that is popped before its snapshot is inserted.
+    tasks: DashMap<u64, Option<ExtrasSnapshot>>,
+}
+
+impl QueuedTasks {
+    /// Assigns a new key to a task that is about to be pushed, `None` if
+    /// tracking is disabled.
+    fn next_key(&self, extras: &mut Extras) -> Option<u64> {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return None;
+        }
+        let key = self.next_key.fetch_add(1, Ordering::Relaxed) + 1;
+        extras.queue_key = key;
+        Some(key)
+    }
+
+    /// Inserts the snapshot of a pushed task.
+    fn insert(&self, key: Option<u64>, snapshot: ExtrasSnapshot) {
+        let key = match key {
+            Some(key) => key,
+            None => return,
+        };
+        match self.tasks.entry(key) {
+            // The task is popped by another thread already.
+            Entry::Occupied(e) => {
+                e.remove();
+            }
+            Entry::Vacant(e) => {
+                e.insert(Some(snapshot));
+            }
+        }
+    }
+
+    /// Removes the snapshot of a popped task.
+    fn remove(&self, key: u64) {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return;
+        }
+        match self.tasks.entry(key) {
+            Entry::Occupied(e) => {
+                e.remove();
+            }
+            Entry::Vacant(e) => {
+                e.insert(None);
+            }
+        }
+    }
+
+    /// Updates the level of a task moved to another level in the queue.
+    fn set_level(&self, key: u64, level: u8) {
+        if let Some(mut s) = self.tasks.get_mut(&key) {
+            if let Some(s) = s.as_mut() {
+                s.level = level;
+            }
+        }
+    }
+
+    fn snapshot(&self) -> Vec<ExtrasSnapshot> {
+        let mut tasks: Vec<_> = self
+            .tasks
+            .iter()
+            .filter_map(|e| Some((*e.key(), e.value().clone()?)))
+            .collect();
+        tasks.sort_by_key(|(key, s)| (s.level, *key));
+        tasks.into_iter().map(|(_, s)| s).collect()
+    }
+}
+
+/// Wires the parts of a task queue together with the tasks they share.
+fn wrap<T>(
+    injector: InjectorInner<T>,
+    locals: impl IntoIterator<Item = LocalQueueInner<T>>,
+) -> (TaskInjector<T>, Vec<LocalQueue<T>>) {
+    let queued = Arc::new(QueuedTasks::default());
+    let locals = locals
+        .into_iter()
+        .map(|l| LocalQueue(l, queued.clone()))
+        .collect();
+    (TaskInjector(injector, queued), locals)
+}
+
 /// Popped task cell from a task queue.
 pub struct Pop<T> {
     /// The task cell
@@ -77,52 +362,174 @@ pub struct Pop<T> {
     /// Whether the task comes from the current [`LocalQueue`] instead of being
     /// just stolen from the injector or other [`LocalQueue`]s.
     pub from_local: bool,
+
+    /// Where the task comes from.
+    pub(crate) source: TaskSource,
+    /// The id and level of the task when it's popped, so they can be read
+    /// without mutable access to the task cell.
+    task_id: u64,
+    level: u8,
+}
+
+impl<T: TaskCell> Pop<T> {
+    /// Creates a popped task, with the `from_local` flag derived from
+    /// `source`.
+    ///
+    /// `Pop` has private fields, so it can only be built by this function.
+    pub fn new(mut task_cell: T, schedule_time: Instant, source: TaskSource) -> Pop<T> {
+        let extras = task_cell.mut_extras();
+        let (task_id, level) = (extras.task_id(), extras.current_level());
+        Pop {
+            task_cell,
+            schedule_time,
+            from_local: source == TaskSource::Local,
+            source,
+            task_id,
+            level,
+        }
+    }
+
+    /// Gets where the task comes from.
+    pub fn source(&self) -> TaskSource {
+        self.source
+    }
+
+    /// Gets the identifier of the popped task.
+    pub fn task_id(&self) -> u64 {
+        self.task_id
+    }
+
+    /// Gets the level of queue which the popped task comes from.
+    pub fn level(&self) -> u8 {
+        self.level
+    }
+
+    /// Gets how long the task has waited since it was pushed to the queue.
+    pub fn wait_time(&self) -> Duration {
+        Instant::now().saturating_duration_since(self.schedule_time)
+    }
+}
+
+/// Where a popped task comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskSource {
+    /// The local queue of the current worker, including tasks pinned to it.
+    Local,
+    /// The local queue of another worker.
+    Steal,
+    /// The global queue.
+    Global,
 }
 
 /// The local queue of a task queue.
-pub(crate) struct LocalQueue<T>(LocalQueueInner<T>);
+///
+/// It's only a handle to be passed to [`Remote::local`].
+pub struct LocalQueue<T>(LocalQueueInner<T>, Arc<QueuedTasks>);
 
 enum LocalQueueInner<T> {
     SingleLevel(single_level::LocalQueue<T>),
-    Multilevel(multilevel::LocalQueue<T>),
+    // Boxed as its rng makes it much larger than the other variants.
+    Multilevel(Box<multilevel::LocalQueue<T>>),
     Priority(priority::LocalQueue<T>),
+    StrictPriority(strict_priority::LocalQueue<T>),
 }
 
 impl<T: TaskCell + Send> LocalQueue<T> {
     /// Pushes a task to the local queue.
-    pub fn push(&mut self, task_cell: T) {
-        match &mut self.0 {
+    pub(crate) fn push(&mut self, mut task_cell: T) {
+        let key = self.1.next_key(task_cell.mut_extras());
+        let snapshot = match &mut self.0 {
             LocalQueueInner::SingleLevel(q) => q.push(task_cell),
             LocalQueueInner::Multilevel(q) => q.push(task_cell),
             LocalQueueInner::Priority(q) => q.push(task_cell),
+            LocalQueueInner::StrictPriority(q) => q.push(task_cell),
+        };
+        self.1.insert(key, snapshot);
+    }
+
+    /// Pushes a task to the front of the local queue, so it will be the next
+    /// task popped from this queue.
+    pub(crate) fn push_front(&mut self, mut task_cell: T) {
+        let key = self.1.next_key(task_cell.mut_extras());
+        let snapshot = match &mut self.0 {
+            LocalQueueInner::SingleLevel(q) => q.push_front(task_cell),
+            LocalQueueInner::Multilevel(q) => q.push_front(task_cell),
+            LocalQueueInner::Priority(q) => q.push_front(task_cell),
+            LocalQueueInner::StrictPriority(q) => q.push_front(task_cell),
+        };
+        self.1.insert(key, snapshot);
+    }
+
+    /// Returns the number of tasks in the local queue.
+    pub(crate) fn len(&self) -> usize {
+        match &self.0 {
+            LocalQueueInner::SingleLevel(q) => q.len(),
+            LocalQueueInner::Multilevel(q) => q.len(),
+            LocalQueueInner::Priority(q) => q.local_len(),
+            LocalQueueInner::StrictPriority(q) => q.len(),
         }
     }
 
+    /// Takes a task that is only in this local queue, including the ones
+    /// pushed to the front, without pulling from the global queue.
+    pub(crate) fn take_local(&mut self) -> Option<T> {
+        let mut t = match &mut self.0 {
+            LocalQueueInner::SingleLevel(q) => q.take_local(),
+            LocalQueueInner::Multilevel(q) => q.take_local(),
+            LocalQueueInner::Priority(_) => None,
+            LocalQueueInner::StrictPriority(q) => q.take_local(),
+        }?;
+        self.1.remove(t.mut_extras().queue_key);
+        Some(t)
+    }
+
     /// Gets a task cell from the queue. Returns `None` if there is no task cell
     /// available.
-    pub fn pop(&mut self) -> Option<Pop<T>> {
-        match &mut self.0 {
+    pub(crate) fn pop(&mut self) -> Option<Pop<T>> {
+        let mut p = match &mut self.0 {
             LocalQueueInner::SingleLevel(q) => q.pop(),
             LocalQueueInner::Multilevel(q) => q.pop(),
             LocalQueueInner::Priority(q) => q.pop(),
-        }
+            LocalQueueInner::StrictPriority(q) => q.pop(),
+        }?;
+        self.1.remove(p.task_cell.mut_extras().queue_key);
+        Some(p)
     }
 
-    pub fn default_extras(&self) -> Extras {
+    pub(crate) fn default_extras(&self) -> Extras {
         match self.0 {
             LocalQueueInner::SingleLevel(_) => Extras::single_level(),
             LocalQueueInner::Multilevel(_) => Extras::multilevel_default(),
-            LocalQueueInner::Priority(_) => Extras::single_level(),
+            LocalQueueInner::Priority(_) | LocalQueueInner::StrictPriority(_) => {
+                Extras::single_level()
+            }
         }
     }
 
+    /// Returns and resets the number of times stealing from the global queue
+    /// had to retry because of concurrent accesses.
+    pub(crate) fn take_contention(&mut self) -> u64 {
+        match &mut self.0 {
+            LocalQueueInner::SingleLevel(q) => q.take_contention(),
+            LocalQueueInner::Multilevel(q) => q.take_contention(),
+            LocalQueueInner::Priority(_) => 0,
+            LocalQueueInner::StrictPriority(q) => q.take_contention(),
+        }
+    }
+
+    /// Checks whether it's a multilevel task queue.
+    pub(crate) fn is_multilevel(&self) -> bool {
+        matches!(self.0, LocalQueueInner::Multilevel(_))
+    }
+
     /// If there are tasks in the local queue, returns true. Otherwise, pulls
     /// tasks from the global queue and returns whether it succeeds.
-    pub fn has_tasks_or_pull(&mut self) -> bool {
+    pub(crate) fn has_tasks_or_pull(&mut self) -> bool {
         match &mut self.0 {
             LocalQueueInner::SingleLevel(q) => q.has_tasks_or_pull(),
             LocalQueueInner::Multilevel(q) => q.has_tasks_or_pull(),
             LocalQueueInner::Priority(q) => q.has_tasks_or_pull(),
+            LocalQueueInner::StrictPriority(q) => q.has_tasks_or_pull(),
         }
     }
 }
@@ -137,8 +544,48 @@ pub enum QueueType {
     Multilevel(multilevel::Builder),
     /// A concurrent prioirty queue.
     Priority(priority::Builder),
+    /// A queue with a fixed number of strict priority classes.
+    ///
+    /// Tasks of a class only run when all the classes before it are empty,
+    /// which is set by [`Extras::set_priority_class`]. There is no aging, so
+    /// low classes can be starved.
+    StrictPriority {
+        /// The number of classes, must be greater than 0.
+        classes: usize,
+    },
+}
+
+impl QueueType {
+    /// Returns the kind of the queue.
+    pub fn kind(&self) -> QueueKind {
+        match self {
+            QueueType::SingleLevel => QueueKind::SingleLevel,
+            QueueType::Multilevel(_) => QueueKind::Multilevel,
+            QueueType::Priority(_) => QueueKind::Priority,
+            QueueType::StrictPriority { classes } => {
+                QueueKind::StrictPriority { classes: *classes }
+            }
+        }
+    }
+}
+
+/// The kind of a [`QueueType`] without its configurations.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueueKind {
+    /// A single level work stealing queue.
+    SingleLevel,
+    /// A multilevel feedback queue.
+    Multilevel,
+    /// A concurrent prioirty queue.
+    Priority,
+    /// A queue with a fixed number of strict priority classes.
+    StrictPriority {
+        /// The number of classes.
+        classes: usize,
+    },
 }
 
+#[allow(clippy::derivable_impls)]
 impl Default for QueueType {
     fn default() -> QueueType {
         QueueType::SingleLevel
@@ -157,22 +604,64 @@ impl From<priority::Builder> for QueueType {
     }
 }
 
-pub(crate) fn build<T>(ty: QueueType, local_num: usize) -> (TaskInjector<T>, Vec<LocalQueue<T>>) {
+/// Builds a task queue. `shards` is the number of shards of the global queue,
+/// which is only supported by the single level queue.
+///
+/// The parts are wired together by [`Remote::from_injector`] and
+/// [`Remote::local`].
+pub fn build<T>(
+    ty: QueueType,
+    local_num: usize,
+    shards: usize,
+) -> (TaskInjector<T>, Vec<LocalQueue<T>>) {
+    build_seeded(ty, local_num, shards, None)
+}
+
+/// Builds a task queue like [`build`], with the random decisions of the local
+/// queues seeded by `seed`, see [`SchedConfig::deterministic_seed`].
+///
+/// [`SchedConfig::deterministic_seed`]: crate::pool::SchedConfig::deterministic_seed
+pub fn build_seeded<T>(
+    ty: QueueType,
+    local_num: usize,
+    shards: usize,
+    seed: Option<u64>,
+) -> (TaskInjector<T>, Vec<LocalQueue<T>>) {
     match ty {
-        QueueType::SingleLevel => single_level(local_num),
-        QueueType::Multilevel(b) => b.build(local_num),
+        QueueType::SingleLevel => single_level(local_num, shards, seed),
+        QueueType::Multilevel(b) => b.build(local_num, seed),
         QueueType::Priority(b) => b.build(local_num),
+        QueueType::StrictPriority { classes } => strict_priority(classes, local_num),
+    }
+}
+
+/// Creates the random number generator of the local queue at `index`, which
+/// is seeded by `seed` if it's given.
+fn local_rng(seed: Option<u64>, index: usize) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed.wrapping_add(index as u64)),
+        None => StdRng::from_entropy(),
     }
 }
 
 /// Creates a task queue that allows given number consumers.
-fn single_level<T>(local_num: usize) -> (TaskInjector<T>, Vec<LocalQueue<T>>) {
-    let (injector, locals) = single_level::create(local_num);
-    (
-        TaskInjector(InjectorInner::SingleLevel(injector)),
-        locals
-            .into_iter()
-            .map(|i| LocalQueue(LocalQueueInner::SingleLevel(i)))
-            .collect(),
+fn single_level<T>(
+    local_num: usize,
+    shards: usize,
+    seed: Option<u64>,
+) -> (TaskInjector<T>, Vec<LocalQueue<T>>) {
+    let (injector, locals) = single_level::create_sharded(local_num, shards, seed);
+    wrap(
+        InjectorInner::SingleLevel(injector),
+        locals.into_iter().map(LocalQueueInner::SingleLevel),
+    )
+}
+
+/// Creates a strict priority task queue that allows given number consumers.
+fn strict_priority<T>(classes: usize, local_num: usize) -> (TaskInjector<T>, Vec<LocalQueue<T>>) {
+    let (injector, locals) = strict_priority::create(classes, local_num);
+    wrap(
+        InjectorInner::StrictPriority(injector),
+        locals.into_iter().map(LocalQueueInner::StrictPriority),
     )
 }
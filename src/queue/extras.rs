@@ -3,6 +3,8 @@
 use super::multilevel::ElapsedTime;
 
 use rand::prelude::*;
+use std::any::Any;
+use std::fmt;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
@@ -27,11 +29,52 @@ pub struct Extras {
     /// If `fixed_level` is `Some`, this task is always pushed to the given
     /// level. Only used in the multilevel task queue.
     pub(crate) fixed_level: Option<u8>,
+    /// The class of this task. Only used in the strict priority task queue.
+    pub(crate) priority_class: u8,
+    /// Whether the task must keep running on the worker that first picks it.
+    pub(crate) no_steal: bool,
+    /// The id of the worker a no-steal task is bound to, 0 if no worker has
+    /// picked it yet.
+    pub(crate) bound_worker: usize,
+    /// The resource group of the task, used to cancel tasks of a group.
+    pub(crate) group_id: Option<u64>,
+    /// Whether the task holds a permit of its group, see
+    /// `Remote::set_group_concurrency`.
+    pub(crate) group_permit: bool,
+    /// The kind of the task, used as a label of the per kind metrics.
+    pub(crate) kind: Option<&'static str>,
+    /// The instant when the task was woken up. It's taken when a worker picks
+    /// the task.
+    pub(crate) wake_time: Option<Instant>,
+    /// Whether a worker has picked the task, used to observe the latency
+    /// until its first poll only once.
+    pub(crate) polled: bool,
     /// Number of execute times
     pub(crate) exec_times: u32,
+    /// Overrides the repoll limit of the future runner for this task.
+    pub(crate) repoll_limit: Option<usize>,
+    /// The number of slices the task can run every time it's picked by a
+    /// worker, 1 by default.
+    pub(crate) weight: u32,
     /// Extra metadata of this task. User can use this field to store arbitrary data. It is useful
     /// in some case to implement more complext `TaskPriorityProvider` in the priority task queue.
     pub(crate) metadata: Vec<u8>,
+    /// The context captured on the spawning thread by the context hook of the
+    /// pool.
+    pub(crate) context: Option<TaskContext>,
+    /// The key of the task in the queued tasks of the queue it's pushed to,
+    /// assigned every time it's pushed.
+    pub(crate) queue_key: u64,
+}
+
+/// A context captured by `Builder::context_hook` when a task is spawned.
+#[derive(Clone)]
+pub(crate) struct TaskContext(pub(crate) Arc<dyn Any + Send + Sync>);
+
+impl fmt::Debug for TaskContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TaskContext")
+    }
 }
 
 impl Extras {
@@ -46,8 +89,20 @@ impl Extras {
             total_running_time: None,
             current_level: 0,
             fixed_level: None,
+            priority_class: 0,
+            no_steal: false,
+            bound_worker: 0,
+            group_id: None,
+            group_permit: false,
+            kind: None,
+            wake_time: None,
+            polled: false,
             exec_times: 0,
+            repoll_limit: None,
+            weight: 1,
             metadata: Vec::new(),
+            context: None,
+            queue_key: 0,
         }
     }
 
@@ -69,8 +124,20 @@ impl Extras {
             total_running_time: None,
             current_level: fixed_level.unwrap_or(0),
             fixed_level,
+            priority_class: 0,
+            no_steal: false,
+            bound_worker: 0,
+            group_id: None,
+            group_permit: false,
+            kind: None,
+            wake_time: None,
+            polled: false,
             exec_times: 0,
+            repoll_limit: None,
+            weight: 1,
             metadata: Vec::new(),
+            context: None,
+            queue_key: 0,
         }
     }
 
@@ -96,6 +163,87 @@ impl Extras {
         self.current_level
     }
 
+    /// Gets the priority class of this task.
+    pub fn priority_class(&self) -> u8 {
+        self.priority_class
+    }
+
+    /// Sets the priority class of this task. Class 0 is the most urgent one,
+    /// classes out of range are treated as the last class of the queue.
+    pub fn set_priority_class(&mut self, class: u8) {
+        self.priority_class = class;
+    }
+
+    /// Checks whether the task is bound to the worker that first picks it.
+    pub fn no_steal(&self) -> bool {
+        self.no_steal
+    }
+
+    /// Sets whether the task is bound to the worker that first picks it.
+    ///
+    /// Once a worker picks a no-steal task, the task is always pushed to the
+    /// pinned queue of that worker, so other workers never steal it. It's
+    /// needed by tasks holding thread local or `!Sync` resources acquired on
+    /// the first run.
+    pub fn set_no_steal(&mut self, no_steal: bool) {
+        self.no_steal = no_steal;
+    }
+
+    /// Gets the resource group of this task.
+    pub fn group_id(&self) -> Option<u64> {
+        self.group_id
+    }
+
+    /// Sets the resource group of this task, so it can be cancelled along
+    /// with other tasks of the group by `Remote::cancel_group`.
+    pub fn set_group_id(&mut self, group_id: u64) {
+        self.group_id = Some(group_id);
+    }
+
+    /// Gets the kind of this task.
+    pub fn kind(&self) -> Option<&'static str> {
+        self.kind
+    }
+
+    /// Sets the kind of this task. Tasks with a kind are also reported in
+    /// metrics labeled with it, so the latency of different kinds of tasks
+    /// in the same pool can be told apart. It's a static string to keep the
+    /// number of labels bounded.
+    pub fn set_kind(&mut self, kind: &'static str) {
+        self.kind = Some(kind);
+    }
+
+    /// Gets the repoll limit of this task, `None` if it uses the default of
+    /// the runner.
+    pub fn repoll_limit(&self) -> Option<usize> {
+        self.repoll_limit
+    }
+
+    /// Sets how many times the future runner can poll this task again in
+    /// place when it's woken up during polling, overriding the runner's
+    /// limit. 0 makes the task yield whenever it's woken up while other tasks
+    /// are waiting.
+    pub fn set_repoll_limit(&mut self, limit: usize) {
+        self.repoll_limit = Some(limit);
+    }
+
+    /// Gets the weight of this task.
+    pub fn weight(&self) -> u32 {
+        self.weight
+    }
+
+    /// Sets the weight of this task, 1 by default, 0 is treated as 1.
+    ///
+    /// Every time a worker picks the task, it can run `weight` slices before
+    /// being pushed back to the queue, like a repoll limit of the future
+    /// runner or the reruns of a callback. Among tasks competing for workers
+    /// at the same level, a task of weight 3 gets roughly 3 times the
+    /// service of a task of weight 1. It's a lightweight alternative to
+    /// resource groups for proportional sharing.
+    pub fn set_weight(&mut self, weight: u32) {
+        self.weight = weight;
+    }
+
     /// Gets the metadata of this task.
     pub fn metadata(&self) -> &[u8] {
         &self.metadata
@@ -111,3 +259,122 @@ impl Extras {
         self.metadata = metadata;
     }
 }
+
+/// A copy of the extras of a queued task, taken by
+/// [`Remote::snapshot_queue`](crate::Remote::snapshot_queue).
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtrasSnapshot {
+    /// The identifier of the task.
+    pub task_id: u64,
+    /// The level of queue which the task is in.
+    pub level: u8,
+    /// The instant when the task was pushed to the queue.
+    pub schedule_time: Option<Instant>,
+}
+
+impl ExtrasSnapshot {
+    pub(crate) fn new(extras: &Extras) -> ExtrasSnapshot {
+        ExtrasSnapshot {
+            task_id: extras.task_id,
+            level: extras.current_level,
+            schedule_time: extras.schedule_time,
+        }
+    }
+}
+
+/// A builder of [`Extras`] with chained setters.
+///
+/// ```
+/// use yatp::queue::ExtrasBuilder;
+///
+/// let extras = ExtrasBuilder::multilevel()
+///     .task_id(42)
+///     .fixed_level(1)
+///     .group(7)
+///     .build();
+/// assert_eq!(extras.task_id(), 42);
+/// assert_eq!(extras.current_level(), 1);
+/// assert_eq!(extras.group_id(), Some(7));
+/// ```
+#[derive(Debug, Clone)]
+pub struct ExtrasBuilder {
+    extras: Extras,
+}
+
+impl ExtrasBuilder {
+    /// Starts from the default extras of a single level task queue.
+    pub fn single_level() -> ExtrasBuilder {
+        ExtrasBuilder {
+            extras: Extras::single_level(),
+        }
+    }
+
+    /// Starts from the default extras of a multilevel task queue.
+    pub fn multilevel() -> ExtrasBuilder {
+        ExtrasBuilder {
+            extras: Extras::multilevel_default(),
+        }
+    }
+
+    /// Sets the identifier of the task.
+    pub fn task_id(mut self, task_id: u64) -> ExtrasBuilder {
+        self.extras.task_id = task_id;
+        self
+    }
+
+    /// Always pushes the task to the given level of a multilevel task queue.
+    pub fn fixed_level(mut self, level: u8) -> ExtrasBuilder {
+        self.extras.fixed_level = Some(level);
+        self.extras.current_level = level;
+        self
+    }
+
+    /// Sets the priority class of the task, see
+    /// [`Extras::set_priority_class`].
+    pub fn priority_class(mut self, class: u8) -> ExtrasBuilder {
+        self.extras.priority_class = class;
+        self
+    }
+
+    /// Sets the resource group of the task, see [`Extras::set_group_id`].
+    pub fn group(mut self, group_id: u64) -> ExtrasBuilder {
+        self.extras.group_id = Some(group_id);
+        self
+    }
+
+    /// Sets the kind of the task, see [`Extras::set_kind`].
+    pub fn kind(mut self, kind: &'static str) -> ExtrasBuilder {
+        self.extras.kind = Some(kind);
+        self
+    }
+
+    /// Sets whether the task is bound to the worker that first picks it, see
+    /// [`Extras::set_no_steal`].
+    pub fn no_steal(mut self, no_steal: bool) -> ExtrasBuilder {
+        self.extras.no_steal = no_steal;
+        self
+    }
+
+    /// Sets the repoll limit of the task, see [`Extras::set_repoll_limit`].
+    pub fn repoll_limit(mut self, limit: usize) -> ExtrasBuilder {
+        self.extras.repoll_limit = Some(limit);
+        self
+    }
+
+    /// Sets the weight of the task, see [`Extras::set_weight`].
+    pub fn weight(mut self, weight: u32) -> ExtrasBuilder {
+        self.extras.weight = weight;
+        self
+    }
+
+    /// Sets the metadata of the task.
+    pub fn metadata(mut self, metadata: Vec<u8>) -> ExtrasBuilder {
+        self.extras.metadata = metadata;
+        self
+    }
+
+    /// Builds the extras.
+    pub fn build(self) -> Extras {
+        self.extras
+    }
+}
@@ -6,18 +6,20 @@
 //! The task queue requires that the accompanying [`MultilevelRunner`] must be
 //! used to collect necessary information.
 
-use super::{Pop, TaskCell};
+use super::{ExtrasSnapshot, Pop, QueuedTasks, TaskCell, TaskSource};
 use crate::metrics::*;
-use crate::pool::{Local, Runner, RunnerBuilder};
+use crate::pool::{Local, Runner, RunnerBuilder, Stopwatch};
 
 use crossbeam_deque::{Injector, Steal, Stealer, Worker};
+use crossbeam_utils::atomic::AtomicCell;
 use dashmap::{try_result::TryResult::Present, DashMap};
 use fail::fail_point;
 use prometheus::local::{LocalHistogram, LocalIntCounter};
 use prometheus::{Gauge, Histogram, HistogramOpts, IntCounter};
 use rand::prelude::*;
 use std::array;
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
 use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering::*};
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
@@ -67,6 +69,8 @@ const ADJUST_LEVEL_STEAL_SIZE_THRESHOLD: usize = 100;
 /// The injector of a multilevel task queue.
 pub(crate) struct TaskInjector<T> {
     level_injectors: Arc<[Injector<T>; LEVEL_NUM]>,
+    /// The stealers of all local queues, only used to read their lengths.
+    local_stealers: Arc<[[Stealer<T>; LEVEL_NUM]]>,
     manager: Arc<LevelManager>,
 }
 
@@ -74,6 +78,7 @@ impl<T> Clone for TaskInjector<T> {
     fn clone(&self) -> Self {
         Self {
             level_injectors: self.level_injectors.clone(),
+            local_stealers: self.local_stealers.clone(),
             manager: self.manager.clone(),
         }
     }
@@ -83,85 +88,201 @@ impl<T> TaskInjector<T>
 where
     T: TaskCell + Send,
 {
-    pub(super) fn push(&self, mut task_cell: T) {
-        self.manager.prepare_before_push(&mut task_cell);
-        let level = task_cell.mut_extras().current_level as usize;
-        self.level_injectors[level].push(task_cell);
+    pub(super) fn push(&self, mut task_cell: T) -> ExtrasSnapshot {
+        let snapshot = self.manager.prepare_before_push(&mut task_cell);
+        self.level_injectors[snapshot.level as usize].push(task_cell);
+        snapshot
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.level_injectors.iter().all(|q| q.is_empty())
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.level_injectors.iter().map(|q| q.len()).sum()
+    }
+
+    /// Returns the number of tasks in each local queue, not including the ones
+    /// pushed to the front.
+    pub(super) fn local_lens(&self) -> Vec<usize> {
+        self.local_stealers
+            .iter()
+            .map(|s| s.iter().map(Stealer::len).sum())
+            .collect()
+    }
+
+    /// Returns the number of tasks at each level, in both the global queue and
+    /// the local queues.
+    pub(super) fn level_lens(&self) -> [usize; LEVEL_NUM] {
+        array::from_fn(|level| {
+            self.level_injectors[level].len()
+                + self
+                    .local_stealers
+                    .iter()
+                    .map(|s| s[level].len())
+                    .sum::<usize>()
+        })
+    }
+
+    pub(super) fn level_elapsed(&self) -> [Duration; LEVEL_NUM] {
+        self.manager.level_elapsed()
+    }
+
+    pub(super) fn reset_stats(&self) {
+        self.manager.reset_stats();
+    }
+
+    pub(super) fn set_level_time_threshold(&self, value: [Duration; LEVEL_NUM - 1]) {
+        self.manager.task_level_mgr.set_level_time_threshold(value);
+    }
+
+    /// Updates the level of the task like pushing it.
+    pub(super) fn adjust_level(&self, task_cell: &mut T) {
+        self.manager.task_level_mgr.adjust_task_level(task_cell);
+    }
+
+    /// Moves the queued tasks with the given id from lower levels to `level`.
+    ///
+    /// Every lower level is drained and the other tasks are pushed back in
+    /// order, so it's only meant for rare manual operations.
+    pub(super) fn boost_task(&self, task_id: u64, level: u8, queued: &QueuedTasks) -> bool {
+        let level = (level as usize).min(LEVEL_NUM - 1);
+        let mut boosted = false;
+        for injector in &self.level_injectors[level + 1..] {
+            let mut rest = Vec::with_capacity(injector.len());
+            loop {
+                match injector.steal() {
+                    Steal::Success(mut t) => {
+                        let extras = t.mut_extras();
+                        if extras.task_id == task_id {
+                            extras.current_level = level as u8;
+                            queued.set_level(extras.queue_key, level as u8);
+                            self.level_injectors[level].push(t);
+                            boosted = true;
+                        } else {
+                            rest.push(t);
+                        }
+                    }
+                    Steal::Empty => break,
+                    Steal::Retry => {}
+                }
+            }
+            for t in rest {
+                injector.push(t);
+            }
+        }
+        boosted
     }
 }
 
 /// The local queue of a multilevel task queue.
 pub(crate) struct LocalQueue<T> {
-    local_queue: Worker<T>,
+    /// Tasks of each level, so other workers can steal the ones of higher
+    /// priority first.
+    local_queues: [Worker<T>; LEVEL_NUM],
+    /// Tasks pushed by `push_front`. They are popped before any other task and
+    /// can't be stolen.
+    front: VecDeque<T>,
     level_injectors: Arc<[Injector<T>; LEVEL_NUM]>,
-    stealers: Vec<Stealer<T>>,
+    /// The stealers of the local queues of other workers.
+    stealers: Vec<[Stealer<T>; LEVEL_NUM]>,
+    /// Makes the random choices of the level to pop.
+    rng: StdRng,
     manager: Arc<LevelManager>,
+    /// The deficit counters of levels used by `Selector::Wfq`.
+    deficits: [i64; LEVEL_NUM],
+    /// The number of times stealing from the global queue had to retry
+    /// because of concurrent accesses, since the last `take_contention`.
+    contention: u64,
 }
 
 impl<T> LocalQueue<T>
 where
     T: TaskCell,
 {
-    pub(super) fn push(&mut self, mut task_cell: T) {
-        self.manager.prepare_before_push(&mut task_cell);
-        self.local_queue.push(task_cell);
+    pub(super) fn push(&mut self, mut task_cell: T) -> ExtrasSnapshot {
+        let snapshot = self.manager.prepare_before_push(&mut task_cell);
+        self.local_queues[snapshot.level as usize].push(task_cell);
+        snapshot
+    }
+
+    pub(super) fn push_front(&mut self, mut task_cell: T) -> ExtrasSnapshot {
+        let snapshot = self.manager.prepare_before_push(&mut task_cell);
+        self.front.push_back(task_cell);
+        snapshot
+    }
+
+    /// Returns the number of tasks in the local queue.
+    pub(super) fn len(&self) -> usize {
+        self.local_queues.iter().map(|q| q.len()).sum::<usize>() + self.front.len()
+    }
+
+    /// Takes a task pushed to this local queue.
+    pub(super) fn take_local(&mut self) -> Option<T> {
+        self.front.pop_front().or_else(|| self.pop_local())
+    }
+
+    /// Pops a local task, checking the levels in priority order.
+    fn pop_local(&self) -> Option<T> {
+        self.local_queues.iter().find_map(|q| q.pop())
+    }
+
+    /// Steals tasks from other workers, scanning the levels in priority
+    /// order so the most important pending tasks are helped first.
+    fn steal_from_others(&mut self, need_retry: &mut bool) -> Option<T> {
+        for level in 0..LEVEL_NUM {
+            for (idx, stealers) in self.stealers.iter().enumerate() {
+                match stealers[level].steal_batch_and_pop(&self.local_queues[level]) {
+                    Steal::Success(t) => {
+                        let last_pos = self.stealers.len() - 1;
+                        self.stealers.swap(idx, last_pos);
+                        return Some(t);
+                    }
+                    Steal::Retry => *need_retry = true,
+                    Steal::Empty => {}
+                }
+            }
+        }
+        None
     }
 
     pub(super) fn pop(&mut self) -> Option<Pop<T>> {
-        fn into_pop<T>(mut t: T, from_local: bool) -> Pop<T>
+        fn into_pop<T>(mut t: T, source: TaskSource) -> Pop<T>
         where
             T: TaskCell,
         {
             let schedule_time = t.mut_extras().schedule_time.unwrap();
-            Pop {
-                task_cell: t,
-                schedule_time,
-                from_local,
-            }
+            Pop::new(t, schedule_time, source)
         }
 
-        if let Some(t) = self.local_queue.pop() {
-            return Some(into_pop(t, true));
+        if let Some(t) = self.front.pop_front() {
+            return Some(into_pop(t, TaskSource::Local));
+        }
+        if let Some(t) = self.pop_local() {
+            return Some(into_pop(t, TaskSource::Local));
         }
-        let mut rng = thread_rng();
         let mut need_retry = true;
-        let level0_chance = self.manager.level0_chance.get();
         while need_retry {
             need_retry = false;
-            let expected_level = if rng.gen::<f64>() < level0_chance {
-                0
-            } else {
-                (1..LEVEL_NUM - 1)
-                    .find(|_| rng.gen_ratio(CHANCE_RATIO, CHANCE_RATIO + 1))
-                    .unwrap_or(LEVEL_NUM - 1)
-            };
+            let expected_level = self.expected_level();
             match self.steal_from_injector(expected_level) {
-                Steal::Success(t) => return Some(into_pop(t, false)),
-                Steal::Retry => need_retry = true,
+                Steal::Success(t) => return Some(into_pop(t, TaskSource::Global)),
+                Steal::Retry => {
+                    self.contention += 1;
+                    need_retry = true;
+                }
                 _ => {}
             }
-            if !self.stealers.is_empty() {
-                let mut found = None;
-                for (idx, stealer) in self.stealers.iter().enumerate() {
-                    match stealer.steal_batch_and_pop(&self.local_queue) {
-                        Steal::Success(t) => {
-                            found = Some((idx, into_pop(t, false)));
-                            break;
-                        }
-                        Steal::Retry => need_retry = true,
-                        _ => {}
-                    }
-                }
-                if let Some((idx, task)) = found {
-                    let last_pos = self.stealers.len() - 1;
-                    self.stealers.swap(idx, last_pos);
-                    return Some(task);
-                }
+            if let Some(t) = self.steal_from_others(&mut need_retry) {
+                return Some(into_pop(t, TaskSource::Steal));
             }
             for l in expected_level + 1..expected_level + LEVEL_NUM {
                 match self.steal_from_injector(l % LEVEL_NUM) {
-                    Steal::Success(t) => return Some(into_pop(t, false)),
-                    Steal::Retry => need_retry = true,
+                    Steal::Success(t) => return Some(into_pop(t, TaskSource::Global)),
+                    Steal::Retry => {
+                        self.contention += 1;
+                        need_retry = true;
+                    }
                     _ => {}
                 }
             }
@@ -169,6 +290,36 @@ where
         None
     }
 
+    /// Chooses the level to steal tasks from the global queue first.
+    fn expected_level(&mut self) -> usize {
+        match self.manager.selector {
+            Selector::Chance => {
+                let rng = &mut self.rng;
+                if rng.gen::<f64>() < self.manager.level0_chance.get() {
+                    0
+                } else {
+                    (1..LEVEL_NUM - 1)
+                        .find(|_| rng.gen_ratio(CHANCE_RATIO, CHANCE_RATIO + 1))
+                        .unwrap_or(LEVEL_NUM - 1)
+                }
+            }
+            Selector::Wfq { weights } => {
+                // Every level earns credits by its weight, and the level with
+                // the most credits pays for being chosen. Ties go to the
+                // higher priority level.
+                let mut level = 0;
+                for (i, w) in weights.iter().enumerate() {
+                    self.deficits[i] += *w as i64;
+                    if self.deficits[i] > self.deficits[level] {
+                        level = i;
+                    }
+                }
+                self.deficits[level] -= weights.iter().map(|w| *w as i64).sum::<i64>();
+                level
+            }
+        }
+    }
+
     #[inline]
     fn steal_from_injector(&self, level: usize) -> Steal<T> {
         // steal one task from level injector, for all level except the max level, we use a different
@@ -180,31 +331,32 @@ where
         } else {
             self.manager.max_level_queue_steal_size.load(Relaxed)
         };
-        self.level_injectors[level].steal_batch_with_limit_and_pop(&self.local_queue, steal_limit)
+        self.level_injectors[level]
+            .steal_batch_with_limit_and_pop(&self.local_queues[level], steal_limit)
     }
 
     pub fn has_tasks_or_pull(&mut self) -> bool {
-        if !self.local_queue.is_empty() {
+        if !self.front.is_empty() || self.local_queues.iter().any(|q| !q.is_empty()) {
             return true;
         }
 
-        let mut rng = thread_rng();
-        let level0_chance = self.manager.level0_chance.get();
         loop {
-            let expected_level = if rng.gen::<f64>() < level0_chance {
-                0
-            } else {
-                (1..LEVEL_NUM - 1)
-                    .find(|_| rng.gen_ratio(CHANCE_RATIO, CHANCE_RATIO + 1))
-                    .unwrap_or(LEVEL_NUM - 1)
-            };
-            match self.level_injectors[expected_level].steal_batch(&self.local_queue) {
+            let expected_level = self.expected_level();
+            match self.level_injectors[expected_level]
+                .steal_batch(&self.local_queues[expected_level])
+            {
                 Steal::Success(()) => return true,
                 Steal::Empty => return false,
-                Steal::Retry => {}
+                Steal::Retry => self.contention += 1,
             }
         }
     }
+
+    /// Returns and resets the number of retries of stealing from the global
+    /// queue.
+    pub fn take_contention(&mut self) -> u64 {
+        std::mem::take(&mut self.contention)
+    }
 }
 
 /// The runner builder for multilevel task queues.
@@ -231,6 +383,44 @@ where
     }
 }
 
+/// The levels the task being handled by a `TrackedRunner` runs at, so the
+/// time of every poll is accounted to the level it runs at even if the task
+/// moves to another level in the middle of `handle`.
+struct LevelSpans {
+    stopwatch: Stopwatch,
+    /// The level the task runs at now.
+    level: usize,
+    /// When the task moves to `level`, measured by `stopwatch`.
+    since: Duration,
+    /// The time spent at each level before `since`.
+    elapsed: [Duration; LEVEL_NUM],
+}
+
+impl LevelSpans {
+    fn switch(&mut self, level: usize) {
+        let now = self.stopwatch.elapsed();
+        self.elapsed[self.level] += now.saturating_sub(self.since);
+        self.level = level;
+        self.since = now;
+    }
+}
+
+thread_local! {
+    static LEVEL_SPANS: RefCell<Option<LevelSpans>> = const { RefCell::new(None) };
+}
+
+/// Accounts the time the task being handled runs from now on to `level`.
+///
+/// It does nothing if the current thread is not handling a task of a
+/// multilevel queue.
+pub(crate) fn switch_level(level: u8) {
+    LEVEL_SPANS.with(|s| {
+        if let Some(spans) = &mut *s.borrow_mut() {
+            spans.switch(level as usize);
+        }
+    });
+}
+
 /// `TrackedRunner` wraps a runner with some metrics.
 pub struct TrackedRunner<R> {
     inner: R,
@@ -240,6 +430,8 @@ pub struct TrackedRunner<R> {
     task_execute_duration: LocalHistogram,
     task_poll_duration: [LocalHistogram; LEVEL_NUM],
     task_execute_times: LocalHistogram,
+    local_level_elapsed_us: [LocalIntCounter; LEVEL_NUM],
+    local_level_exec_tasks: [LocalIntCounter; LEVEL_NUM],
     // whether to trigger local metrics flush.
     auto_flush_metrics: bool,
 }
@@ -254,6 +446,12 @@ impl<R> TrackedRunner<R> {
         for h in &self.task_poll_duration {
             h.flush();
         }
+        for c in &self.local_level_elapsed_us {
+            c.flush();
+        }
+        for c in &self.local_level_exec_tasks {
+            c.flush();
+        }
     }
 
     #[inline]
@@ -273,6 +471,10 @@ where
         self.inner.start(local)
     }
 
+    fn on_task_source(&mut self, local: &mut Local<T>, source: TaskSource) {
+        self.inner.on_task_source(local, source)
+    }
+
     fn handle(&mut self, local: &mut Local<T>, mut task_cell: T) -> bool {
         let extras = task_cell.mut_extras();
         let total_running_time = extras.total_running_time.clone();
@@ -281,21 +483,38 @@ where
         let level = extras.current_level as usize;
         extras.exec_times += 1;
         let exec_times = extras.exec_times;
-        let begin = Instant::now();
+        let spans = LevelSpans {
+            stopwatch: local.core().config().time_source.start(),
+            level,
+            since: Duration::ZERO,
+            elapsed: [Duration::ZERO; LEVEL_NUM],
+        };
+        LEVEL_SPANS.with(|s| *s.borrow_mut() = Some(spans));
         let res = self.inner.handle(local, task_cell);
-        let elapsed = begin.elapsed();
+        let mut spans = LEVEL_SPANS.with(|s| s.borrow_mut().take()).unwrap();
+        spans.switch(spans.level);
+        let elapsed = spans.elapsed.iter().sum::<Duration>();
 
         task_running_time.inc_by(elapsed);
         if let Some(ref running_time) = total_running_time {
             running_time.inc_by(elapsed);
         }
-        self.task_poll_duration[level].observe(elapsed.as_secs_f64());
-        let elapsed_us = elapsed.as_micros() as u64;
-        if level == 0 {
-            self.local_level0_elapsed_us.inc_by(elapsed_us);
+        if cfg!(feature = "metrics") {
+            self.task_poll_duration[level].observe(elapsed.as_secs_f64());
+        }
+        // The task is counted at the level it's picked from, but its time
+        // goes to the levels it runs at.
+        for (l, e) in spans.elapsed.iter().enumerate() {
+            if *e > Duration::ZERO {
+                self.local_level_elapsed_us[l].inc_by(e.as_micros() as u64);
+            }
         }
+        self.local_level_exec_tasks[level].inc();
+        self.local_level0_elapsed_us
+            .inc_by(spans.elapsed[0].as_micros() as u64);
+        let elapsed_us = elapsed.as_micros() as u64;
         // set task execute time metrics
-        if res {
+        if cfg!(feature = "metrics") && res {
             let exec_time = task_running_time.as_duration();
             let wait_time = start_time.elapsed().saturating_sub(exec_time);
             self.task_wait_duration.observe(wait_time.as_secs_f64());
@@ -330,6 +549,10 @@ pub(super) struct MultiLevelMetrics {
     task_execute_duration: Histogram,
     task_poll_duration: [Histogram; LEVEL_NUM],
     task_execute_times: Histogram,
+    // Not exported, backs `level_elapsed` of this queue.
+    level_elapsed_us: [IntCounter; LEVEL_NUM],
+    // Not exported, the level manager uses them to adjust the steal size.
+    level_exec_tasks: [IntCounter; LEVEL_NUM],
 }
 
 impl MultiLevelMetrics {
@@ -381,6 +604,8 @@ impl MultiLevelMetrics {
             task_execute_duration,
             task_execute_times,
             task_poll_duration,
+            level_elapsed_us: array::from_fn(|_| IntCounter::new("_", "_").unwrap()),
+            level_exec_tasks: array::from_fn(|_| IntCounter::new("_", "_").unwrap()),
         }
     }
 }
@@ -419,6 +644,8 @@ where
             task_wait_duration: self.metrics.task_wait_duration.local(),
             task_poll_duration: array::from_fn(|i| self.metrics.task_poll_duration[i].local()),
             task_execute_times: self.metrics.task_execute_times.local(),
+            local_level_elapsed_us: array::from_fn(|i| self.metrics.level_elapsed_us[i].local()),
+            local_level_exec_tasks: array::from_fn(|i| self.metrics.level_exec_tasks[i].local()),
             auto_flush_metrics: self.auto_flush_metrics,
         }
     }
@@ -443,6 +670,10 @@ where
         self.inner.start(local)
     }
 
+    fn on_task_source(&mut self, local: &mut Local<T>, source: TaskSource) {
+        self.inner.on_task_source(local, source)
+    }
+
     fn handle(&mut self, local: &mut Local<T>, task_cell: T) -> bool {
         let res = self.inner.handle(local, task_cell);
         if self.inner.should_flush() {
@@ -471,10 +702,14 @@ struct LevelManager {
     task_level_mgr: TaskLevelManager,
     level0_chance: Gauge,
     level0_proportion_target: f64,
+    selector: Selector,
     adjusting: AtomicBool,
     last_level0_elapsed_us: Cell<u64>,
     last_total_elapsed_us: Cell<u64>,
-    task_poll_duration: [Histogram; LEVEL_NUM],
+    level_elapsed_us: [IntCounter; LEVEL_NUM],
+    /// The values of `level_elapsed_us` when the statistics were reset.
+    elapsed_base: AtomicCell<[u64; LEVEL_NUM]>,
+    level_exec_tasks: [IntCounter; LEVEL_NUM],
     last_exec_tasks_per_level: [Cell<u64>; LEVEL_NUM],
     max_level_queue_steal_size: AtomicUsize,
 }
@@ -485,12 +720,52 @@ struct LevelManager {
 unsafe impl Sync for LevelManager {}
 
 impl LevelManager {
-    fn prepare_before_push<T>(&self, task_cell: &mut T)
+    fn prepare_before_push<T>(&self, task_cell: &mut T) -> ExtrasSnapshot
     where
         T: TaskCell,
     {
         self.task_level_mgr.adjust_task_level(task_cell);
-        task_cell.mut_extras().schedule_time = Some(now());
+        let extras = task_cell.mut_extras();
+        extras.schedule_time = Some(now());
+        ExtrasSnapshot::new(extras)
+    }
+
+    /// Returns the time spent on handling tasks of each level, which is
+    /// updated when workers flush their local metrics.
+    fn level_elapsed(&self) -> [Duration; LEVEL_NUM] {
+        let base = self.elapsed_base.load();
+        array::from_fn(|i| {
+            let us = self.level_elapsed_us[i].get();
+            Duration::from_micros(us.saturating_sub(base[i]))
+        })
+    }
+
+    /// Starts the statistics over, as if no task has been handled.
+    ///
+    /// The metrics are monotonic and left untouched, the manager only
+    /// remembers their current values and counts from there.
+    fn reset_stats(&self) {
+        self.elapsed_base
+            .store(array::from_fn(|i| self.level_elapsed_us[i].get()));
+        // Waits for a concurrent `maybe_adjust_chance` so the window it
+        // records doesn't overwrite the new one.
+        while self
+            .adjusting
+            .compare_exchange_weak(false, true, SeqCst, SeqCst)
+            .is_err()
+        {
+            std::hint::spin_loop();
+        }
+        self.last_total_elapsed_us.set(self.total_elapsed_us.get());
+        self.last_level0_elapsed_us
+            .set(self.level0_elapsed_us.get());
+        for (i, c) in self.last_exec_tasks_per_level.iter().enumerate() {
+            c.set(self.level_exec_tasks[i].get());
+        }
+        self.level0_chance.set(INIT_LEVEL0_CHANCE);
+        self.max_level_queue_steal_size
+            .store(DEFAULT_STEAL_LIMIT_PER_LEVEL[LEVEL_NUM - 1], SeqCst);
+        self.adjusting.store(false, SeqCst);
     }
 
     fn maybe_adjust_chance(&self) {
@@ -528,7 +803,7 @@ impl LevelManager {
         self.level0_chance.set(new_chance);
 
         let cur_total_tasks_per_level: [u64; LEVEL_NUM] =
-            array::from_fn(|i| self.task_poll_duration[i].get_sample_count());
+            array::from_fn(|i| self.level_exec_tasks[i].get());
         let cur_total_tasks = cur_total_tasks_per_level.iter().sum::<u64>();
         let last_level0_total_tasks = self.last_exec_tasks_per_level[0].get();
         let last_total_tasks: u64 = self.last_exec_tasks_per_level.iter().map(|c| c.get()).sum();
@@ -537,6 +812,7 @@ impl LevelManager {
         let total_tasks = (cur_total_tasks - last_total_tasks) as usize;
         // adjust the batch size after meeting enough tasks.
         if total_tasks > ADJUST_LEVEL_STEAL_SIZE_THRESHOLD {
+            #[allow(clippy::manual_checked_ops)]
             let new_steal_count = if level_0_tasks == 0 {
                 // level 0 has no tasks, that means the current workloads are all low-priority tasks.
                 LEVEL_MAX_QUEUE_MAX_STEAL_SIZE
@@ -559,7 +835,10 @@ impl LevelManager {
 
 pub(super) struct TaskLevelManager {
     task_elapsed_map: TaskElapsedMap,
-    level_time_threshold: [Duration; LEVEL_NUM - 1],
+    /// It can be replaced at runtime by `Remote::set_level_thresholds`.
+    level_time_threshold: AtomicCell<[Duration; LEVEL_NUM - 1]>,
+    /// The number of active boosts to each level for every boosted task.
+    boosted: DashMap<u64, [usize; LEVEL_NUM]>,
 }
 
 impl TaskLevelManager {
@@ -569,7 +848,8 @@ impl TaskLevelManager {
     ) -> Self {
         Self {
             task_elapsed_map: TaskElapsedMap::new(cleanup_interval),
-            level_time_threshold,
+            level_time_threshold: AtomicCell::new(level_time_threshold),
+            boosted: DashMap::new(),
         }
     }
 
@@ -587,6 +867,7 @@ impl TaskLevelManager {
                     .get_or_insert_with(|| self.get_elapsed(task_id));
                 let running_time = running_time.as_duration();
                 self.level_time_threshold
+                    .load()
                     .iter()
                     .enumerate()
                     .find(|(_, &threshold)| running_time < threshold)
@@ -594,7 +875,34 @@ impl TaskLevelManager {
                     .unwrap_or(LEVEL_NUM - 1) as u8
             }
         };
-        extras.current_level = current_level;
+        extras.current_level = match self.boosted_level(task_id) {
+            Some(level) => level.min(current_level),
+            None => current_level,
+        };
+    }
+
+    pub(super) fn set_level_time_threshold(&self, value: [Duration; LEVEL_NUM - 1]) {
+        self.level_time_threshold.store(value);
+    }
+
+    fn boosted_level(&self, task_id: u64) -> Option<u8> {
+        if self.boosted.is_empty() {
+            return None;
+        }
+        let counts = self.boosted.get(&task_id)?;
+        counts.iter().position(|c| *c > 0).map(|l| l as u8)
+    }
+
+    fn boost(&self, task_id: u64, level: u8) {
+        self.boosted.entry(task_id).or_default()[level as usize] += 1;
+    }
+
+    fn unboost(&self, task_id: u64, level: u8) {
+        if let Some(mut counts) = self.boosted.get_mut(&task_id) {
+            counts[level as usize] -= 1;
+        }
+        self.boosted
+            .remove_if(&task_id, |_, counts| counts.iter().all(|c| *c == 0));
     }
 
     pub(super) fn try_cleanup(&self) -> Option<Instant> {
@@ -725,12 +1033,61 @@ impl TaskElapsedMap {
     }
 }
 
+/// Boosts the levels of tasks to avoid priority inversion.
+///
+/// When a task at a high level waits for a task that has been demoted to a
+/// lower level, for example through a shared channel, the high level task
+/// is effectively blocked by the low level one. The waiting task can boost
+/// the task it depends on, so the dependency is scheduled at least at the
+/// level of the waiter until the boost is released.
+///
+/// It can be created by [`Builder::level_booster`].
+#[derive(Clone)]
+pub struct LevelBooster {
+    manager: Arc<LevelManager>,
+}
+
+impl LevelBooster {
+    /// Boosts the task with the given task id to be scheduled at `level` or
+    /// a higher level until the returned guard is dropped.
+    ///
+    /// The boost takes effect the next time the task is pushed to the queue,
+    /// a task that is already in the queue is not moved. Generally the waiter
+    /// should hold the guard until it's woken up by the dependency.
+    pub fn boost(&self, task_id: u64, level: u8) -> BoostGuard {
+        let level = level.min(LEVEL_NUM as u8 - 1);
+        self.manager.task_level_mgr.boost(task_id, level);
+        BoostGuard {
+            manager: self.manager.clone(),
+            task_id,
+            level,
+        }
+    }
+}
+
+/// Releases the boost created by [`LevelBooster::boost`] when dropped.
+#[must_use = "the boost is released immediately if the guard is not held"]
+pub struct BoostGuard {
+    manager: Arc<LevelManager>,
+    task_id: u64,
+    level: u8,
+}
+
+impl Drop for BoostGuard {
+    fn drop(&mut self) {
+        self.manager
+            .task_level_mgr
+            .unboost(self.task_id, self.level);
+    }
+}
+
 /// The configurations of multilevel task queues.
 pub struct Config {
     name: Option<String>,
     cleanup_interval: Option<Duration>,
     level_time_threshold: [Duration; LEVEL_NUM - 1],
     level0_proportion_target: f64,
+    selector: Selector,
 }
 
 impl Config {
@@ -761,6 +1118,22 @@ impl Config {
         self
     }
 
+    /// Sets how workers choose the level to take tasks from.
+    ///
+    /// The default value is `Selector::Chance`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if all the weights of `Selector::Wfq` are 0.
+    #[inline]
+    pub fn selector(mut self, value: Selector) -> Self {
+        if let Selector::Wfq { weights } = value {
+            assert!(weights.iter().any(|w| *w > 0), "all weights are 0");
+        }
+        self.selector = value;
+        self
+    }
+
     /// Sets the interval of cleaning up task elapsed map.
     ///
     /// The pool tries to cleanup task elapsed map for every given interval. However, it may introduce tail latency on
@@ -782,10 +1155,32 @@ impl Default for Config {
             cleanup_interval: Some(DEFAULT_CLEANUP_OLD_MAP_INTERVAL),
             level_time_threshold: [Duration::from_millis(5), Duration::from_millis(100)],
             level0_proportion_target: 0.8,
+            selector: Selector::Chance,
         }
     }
 }
 
+/// The strategy for workers to choose the level to take tasks from.
+///
+/// Workers take tasks from their local queues first, the selector only
+/// decides which level of the global queue to steal from. If the chosen level
+/// is empty, other levels are tried.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Selector {
+    /// Chooses level 0 by a chance, which is adjusted to reach
+    /// `level0_proportion_target`.
+    Chance,
+    /// Weighted fair queuing across levels.
+    ///
+    /// Every worker chooses each level in proportion to its weight, by deficit
+    /// round robin instead of random draws. It has less latency variance than
+    /// `Chance`, but doesn't adapt to the running time of levels.
+    Wfq {
+        /// The weights of levels.
+        weights: [u32; LEVEL_NUM],
+    },
+}
+
 /// The builder of a multilevel task queue.
 pub struct Builder {
     metrics: MultiLevelMetrics,
@@ -814,11 +1209,14 @@ impl Builder {
             ),
             level0_chance,
             level0_proportion_target: config.level0_proportion_target,
+            selector: config.selector,
             adjusting: AtomicBool::new(false),
             last_level0_elapsed_us: Cell::new(0),
             last_total_elapsed_us: Cell::new(0),
+            level_exec_tasks: metrics.level_exec_tasks.clone(),
             last_exec_tasks_per_level: array::from_fn(|_| Cell::new(0)),
-            task_poll_duration: metrics.task_poll_duration.clone(),
+            level_elapsed_us: metrics.level_elapsed_us.clone(),
+            elapsed_base: AtomicCell::new([0; LEVEL_NUM]),
             max_level_queue_steal_size: AtomicUsize::new(
                 DEFAULT_STEAL_LIMIT_PER_LEVEL[LEVEL_NUM - 1],
             ),
@@ -844,30 +1242,51 @@ impl Builder {
         move || m.task_level_mgr.try_cleanup()
     }
 
-    fn build_raw<T>(self, local_num: usize) -> (TaskInjector<T>, Vec<LocalQueue<T>>) {
+    /// Returns a booster for raising the levels of tasks that higher level
+    /// tasks depend on.
+    pub fn level_booster(&self) -> LevelBooster {
+        LevelBooster {
+            manager: self.manager.clone(),
+        }
+    }
+
+    fn build_raw<T>(
+        self,
+        local_num: usize,
+        seed: Option<u64>,
+    ) -> (TaskInjector<T>, Vec<LocalQueue<T>>) {
         let level_injectors: Arc<[Injector<T>; LEVEL_NUM]> =
             Arc::new([Injector::new(), Injector::new(), Injector::new()]);
-        let workers: Vec<_> = iter::repeat_with(Worker::new_lifo)
-            .take(local_num)
+        let workers: Vec<[Worker<T>; LEVEL_NUM]> =
+            iter::repeat_with(|| array::from_fn(|_| Worker::new_lifo()))
+                .take(local_num)
+                .collect();
+        let stealers: Vec<[Stealer<T>; LEVEL_NUM]> = workers
+            .iter()
+            .map(|w| array::from_fn(|level| w[level].stealer()))
             .collect();
-        let stealers: Vec<_> = workers.iter().map(Worker::stealer).collect();
         let locals = workers
             .into_iter()
             .enumerate()
-            .map(|(self_index, local_queue)| {
+            .map(|(self_index, local_queues)| {
                 let mut stealers: Vec<_> = stealers
                     .iter()
                     .enumerate()
                     .filter(|(index, _)| *index != self_index)
                     .map(|(_, stealer)| stealer.clone())
                     .collect();
+                let mut rng = super::local_rng(seed, self_index);
                 // Steal with a random start to avoid imbalance.
-                stealers.shuffle(&mut thread_rng());
+                stealers.shuffle(&mut rng);
                 LocalQueue {
-                    local_queue,
+                    local_queues,
+                    front: VecDeque::new(),
                     level_injectors: level_injectors.clone(),
                     stealers,
+                    rng,
                     manager: self.manager.clone(),
+                    deficits: [0; LEVEL_NUM],
+                    contention: 0,
                 }
             })
             .collect();
@@ -875,6 +1294,7 @@ impl Builder {
         (
             TaskInjector {
                 level_injectors,
+                local_stealers: stealers.into(),
                 manager: self.manager,
             },
             locals,
@@ -885,15 +1305,14 @@ impl Builder {
     pub(crate) fn build<T>(
         self,
         local_num: usize,
+        seed: Option<u64>,
     ) -> (super::TaskInjector<T>, Vec<super::LocalQueue<T>>) {
-        let (injector, locals) = self.build_raw(local_num);
-        let local_queues = locals
-            .into_iter()
-            .map(|local| super::LocalQueue(super::LocalQueueInner::Multilevel(local)))
-            .collect();
-        (
-            super::TaskInjector(super::InjectorInner::Multilevel(injector)),
-            local_queues,
+        let (injector, locals) = self.build_raw(local_num, seed);
+        super::wrap(
+            super::InjectorInner::Multilevel(injector),
+            locals
+                .into_iter()
+                .map(|local| super::LocalQueueInner::Multilevel(Box::new(local))),
         )
     }
 }
@@ -919,8 +1338,10 @@ pub(super) fn recent() -> Instant {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::pool::build_spawn;
-    use crate::queue::Extras;
+    use crate::pool::{
+        build_spawn, CloneRunnerBuilder, ConfigError, Remote, SchedConfig, ShedPolicy, TimeSource,
+    };
+    use crate::queue::{Extras, QueueType};
 
     use std::sync::atomic::AtomicU64;
     use std::sync::mpsc;
@@ -1020,7 +1441,7 @@ mod tests {
         const SLEEP_DUR: Duration = Duration::from_millis(5);
 
         let builder = Builder::new(Config::default());
-        let (injector, mut locals) = builder.build(1);
+        let (injector, mut locals) = builder.build(1, None);
         injector.push(MockTask::new(0, Extras::multilevel_default()));
         thread::sleep(SLEEP_DUR);
         let schedule_time = locals[0].pop().unwrap().schedule_time;
@@ -1033,7 +1454,7 @@ mod tests {
             Config::default()
                 .level_time_threshold([Duration::from_millis(1), Duration::from_millis(100)]),
         );
-        let (injector, _) = builder.build_raw(1);
+        let (injector, _) = builder.build_raw(1, None);
 
         // Running time is 50us. It should be pushed to level 0.
         let extras = Extras {
@@ -1106,7 +1527,7 @@ mod tests {
         // auto cleanup will be triggered only when tls_recent_now - tls_last_cleanup_time > cleanup_interval, thus we'd
         // better make sure that tls_recent always gets updated after pushing task.
         let builder = Builder::new(Config::default());
-        let (injector, _) = builder.build::<MockTask>(1);
+        let (injector, _) = builder.build::<MockTask>(1, None);
         let time_before_push = now();
         injector.push(MockTask::new(0, Extras::multilevel_default()));
         assert!(recent() > time_before_push);
@@ -1115,7 +1536,7 @@ mod tests {
     #[test]
     fn test_pop_by_stealing_injector() {
         let builder = Builder::new(Config::default());
-        let (injector, mut locals) = builder.build(3);
+        let (injector, mut locals) = builder.build(3, None);
         for i in 0..100 {
             injector.push(MockTask::new(i, Extras::multilevel_default()));
         }
@@ -1129,18 +1550,18 @@ mod tests {
     #[test]
     fn test_pop_by_steal_others() {
         let builder = Builder::new(Config::default());
-        let (injector, mut locals) = builder.build_raw(3);
+        let (injector, mut locals) = builder.build_raw(3, None);
         for i in 0..50 {
             injector.push(MockTask::new(i, Extras::multilevel_default()));
         }
         assert!(injector.level_injectors[0]
-            .steal_batch(&locals[0].local_queue)
+            .steal_batch(&locals[0].local_queues[0])
             .is_success());
         for i in 50..100 {
             injector.push(MockTask::new(i, Extras::multilevel_default()));
         }
         assert!(injector.level_injectors[0]
-            .steal_batch(&locals[1].local_queue)
+            .steal_batch(&locals[1].local_queues[0])
             .is_success());
         let sum: u64 = (0..100)
             .map(|_| locals[2].pop().unwrap().task_cell.sleep_ms)
@@ -1149,10 +1570,40 @@ mod tests {
         assert!(locals.iter_mut().all(|c| c.pop().is_none()));
     }
 
+    #[test]
+    fn test_steal_higher_priority_first() {
+        let builder = Builder::new(Config::default());
+        let (_, mut locals) = builder.build_raw(2, None);
+        // The busy worker has queued low priority tasks before a high
+        // priority one.
+        for i in 0..10 {
+            locals[0].push(MockTask::new(i, Extras::new_multilevel(i, Some(2))));
+        }
+        locals[0].push(MockTask::new(100, Extras::new_multilevel(100, Some(0))));
+        for i in 10..20 {
+            locals[0].push(MockTask::new(i, Extras::new_multilevel(i, Some(1))));
+        }
+
+        // The idle worker helps with the high priority task first.
+        let mut t = locals[1].pop().unwrap();
+        assert_eq!(t.source, TaskSource::Steal);
+        assert_eq!(t.task_cell.sleep_ms, 100);
+        assert_eq!(t.task_cell.mut_extras().current_level(), 0);
+        // Then the tasks of the next level.
+        let mut t = locals[1].pop().unwrap();
+        assert_eq!(t.task_cell.mut_extras().current_level(), 1);
+
+        let mut levels = vec![];
+        while let Some(mut t) = locals[1].pop().or_else(|| locals[0].pop()) {
+            levels.push(t.task_cell.mut_extras().current_level());
+        }
+        assert_eq!(levels.len(), 19);
+    }
+
     #[test]
     fn test_pop_concurrently() {
         let builder = Builder::new(Config::default());
-        let (injector, locals) = builder.build(3);
+        let (injector, locals) = builder.build(3, None);
         for i in 0..10_000 {
             injector.push(MockTask::new(i, Extras::multilevel_default()));
         }
@@ -1196,6 +1647,157 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_runner_records_cpu_time() {
+        let builder = Builder::new(Config::default());
+        let mut runner_builder = builder.runner_builder(MockRunnerBuilder);
+        let manager = builder.manager.clone();
+        let config = SchedConfig {
+            time_source: TimeSource::ThreadCpu,
+            ..Default::default()
+        };
+        let (remote, mut locals) = build_spawn(builder, config);
+        let mut runner = runner_builder.build();
+
+        remote.spawn(MockTask::new(100, Extras::new_multilevel(1, None)));
+        let Pop { task_cell, .. } = locals[0].pop().unwrap();
+        assert!(runner.handle(&mut locals[0], task_cell));
+        let elapsed = manager
+            .task_level_mgr
+            .task_elapsed_map
+            .get_elapsed(1)
+            .as_duration();
+        if cfg!(any(target_os = "linux", target_os = "macos")) {
+            // Sleeping doesn't consume CPU time, so the task is not demoted.
+            assert!(elapsed < Duration::from_millis(5), "{:?}", elapsed);
+            remote.spawn(MockTask::new(0, Extras::new_multilevel(1, None)));
+            let mut t = locals[0].pop().unwrap().task_cell;
+            assert_eq!(t.mut_extras().current_level(), 0);
+        } else {
+            assert!(elapsed >= Duration::from_millis(100));
+        }
+    }
+
+    #[test]
+    fn test_runner_records_level_before_demotion() {
+        #[derive(Clone)]
+        struct DemoteRunner;
+
+        impl Runner for DemoteRunner {
+            type TaskCell = MockTask;
+
+            fn handle(&mut self, local: &mut Local<MockTask>, mut task_cell: MockTask) -> bool {
+                thread::sleep(Duration::from_millis(task_cell.sleep_ms));
+                // Demotes itself and is pushed back before the handle returns.
+                task_cell.extras.fixed_level = Some(2);
+                local.spawn(task_cell);
+                false
+            }
+        }
+
+        let builder = Builder::new(Config::default());
+        let mut runner_builder = builder.runner_builder(CloneRunnerBuilder(DemoteRunner));
+        let manager = builder.manager.clone();
+        let (remote, mut locals) = build_spawn(builder, Default::default());
+        let mut runner = runner_builder.build();
+
+        remote.spawn(MockTask::new(20, Extras::new_multilevel(1, None)));
+        let Pop { task_cell, .. } = locals[0].pop().unwrap();
+        assert!(!runner.handle(&mut locals[0], task_cell));
+        runner.inner.flush();
+        // The time is accounted to the level the task is handled at.
+        let elapsed = manager.level_elapsed();
+        assert!(elapsed[0] >= Duration::from_millis(20), "{:?}", elapsed);
+        assert_eq!(elapsed[2], Duration::ZERO);
+        let mut t = locals[0].pop().unwrap().task_cell;
+        assert_eq!(t.mut_extras().current_level(), 2);
+    }
+
+    #[test]
+    fn test_level_switch_accounted_across_repolls() {
+        use crate::pool::MockClock;
+        use crate::task::future::{self, reschedule_with_priority};
+
+        let _clock = MockClock::install();
+        let builder = Builder::new(Config::default());
+        let mut runner_builder =
+            builder.runner_builder(CloneRunnerBuilder(future::Runner::default()));
+        let manager = builder.manager.clone();
+        let (remote, mut locals) = build_spawn(builder, Default::default());
+        let mut runner = runner_builder.build();
+
+        remote.spawn(async {
+            MockClock::advance(Duration::from_millis(10));
+            reschedule_with_priority(2).await;
+            MockClock::advance(Duration::from_millis(30));
+        });
+        let Pop { task_cell, .. } = locals[0].pop().unwrap();
+        // Nothing else to run, so the task is polled again in the same
+        // handle after moving to level 2.
+        assert!(runner.handle(&mut locals[0], task_cell));
+        runner.inner.flush();
+        let ms = Duration::from_millis;
+        assert_eq!(manager.level_elapsed(), [ms(10), ms(0), ms(30)]);
+
+        // With other tasks to run, it's pushed back at the new level.
+        remote.spawn(async {
+            reschedule_with_priority(1).await;
+        });
+        let Pop { task_cell, .. } = locals[0].pop().unwrap();
+        locals[0].spawn(async {});
+        assert!(!runner.handle(&mut locals[0], task_cell));
+        let mut levels = vec![];
+        while let Some(mut t) = locals[0].pop() {
+            levels.push(t.task_cell.mut_extras().current_level());
+        }
+        assert!(levels.contains(&1), "{:?}", levels);
+    }
+
+    #[test]
+    fn test_reset_stats() {
+        #[derive(Clone)]
+        struct SleepRunner;
+
+        impl Runner for SleepRunner {
+            type TaskCell = MockTask;
+
+            fn handle(&mut self, _: &mut Local<MockTask>, task_cell: MockTask) -> bool {
+                thread::sleep(Duration::from_millis(task_cell.sleep_ms));
+                true
+            }
+        }
+
+        let builder = Builder::new(Config::default());
+        let mut runner_builder = builder.runner_builder(CloneRunnerBuilder(SleepRunner));
+        let manager = builder.manager.clone();
+        let (remote, mut locals) = build_spawn(builder, Default::default());
+        let mut runner = runner_builder.build();
+        let mut run = |id| {
+            remote.spawn(MockTask::new(10, Extras::new_multilevel(id, None)));
+            let Pop { task_cell, .. } = locals[0].pop().unwrap();
+            runner.handle(&mut locals[0], task_cell);
+            runner.inner.flush();
+        };
+
+        run(1);
+        assert!(manager.level_elapsed()[0] >= Duration::from_millis(10));
+        manager.level0_chance.set(MIN_LEVEL0_CHANCE);
+        let elapsed_us = manager.level_elapsed_us[0].get();
+
+        manager.reset_stats();
+        assert_eq!(manager.level_elapsed(), [Duration::ZERO; LEVEL_NUM]);
+        assert_eq!(manager.level0_chance.get(), INIT_LEVEL0_CHANCE);
+        // The counter is left untouched.
+        assert_eq!(manager.level_elapsed_us[0].get(), elapsed_us);
+
+        // Counts from the reset.
+        run(2);
+        let elapsed = manager.level_elapsed()[0];
+        assert!(elapsed >= Duration::from_millis(10), "{:?}", elapsed);
+        let us = manager.level_elapsed_us[0].get();
+        assert_eq!(elapsed, Duration::from_micros(us - elapsed_us));
+    }
+
     #[test]
     fn test_adjust_level_chance() {
         // Default level 0 target is 0.8
@@ -1297,4 +1899,200 @@ mod tests {
         cleanup();
         assert_eq!(mgr.get_elapsed(1).as_duration(), Duration::from_secs(0));
     }
+
+    #[test]
+    fn test_level_booster() {
+        let builder = Builder::new(Config::default());
+        let booster = builder.level_booster();
+        let manager = builder.manager.clone();
+        // Always try level 0 first, so lower levels are only popped when
+        // level 0 is empty.
+        manager.level0_chance.set(1.0);
+        let (injector, mut locals) = builder.build_raw(1, None);
+        // The dependency has been demoted to the last level.
+        let dep_id = 42;
+        manager
+            .task_level_mgr
+            .get_elapsed(dep_id)
+            .inc_by(Duration::from_secs(1));
+
+        let mut pop_order = |boost: bool| {
+            let _guard = if boost {
+                Some(booster.boost(dep_id, 0))
+            } else {
+                None
+            };
+            injector.push(MockTask::new(0, Extras::new_multilevel(dep_id, None)));
+            for i in 1..=100 {
+                injector.push(MockTask::new(i, Extras::new_multilevel(dep_id + i, None)));
+            }
+            let order: Vec<_> = (0..101)
+                .map(|_| locals[0].pop().unwrap().task_cell.sleep_ms)
+                .collect();
+            order.iter().position(|id| *id == 0).unwrap()
+        };
+        // Without boosting, the dependency runs after all level 0 tasks.
+        assert_eq!(pop_order(false), 100);
+        // The boosted dependency runs before tasks pushed after it.
+        assert_eq!(pop_order(true), 0);
+        // The boost is released after the guard is dropped.
+        assert!(manager.task_level_mgr.boosted.is_empty());
+        assert_eq!(pop_order(false), 100);
+    }
+
+    #[test]
+    fn test_boost_task() {
+        let builder = Builder::new(Config::default());
+        let manager = builder.manager.clone();
+        // Always try level 0 first, so lower levels are only popped when
+        // level 0 is empty.
+        manager.level0_chance.set(1.0);
+        let (remote, mut locals) = build_spawn(builder, Default::default());
+
+        for i in 0..10 {
+            remote.spawn(MockTask::new(i, Extras::new_multilevel(100 + i, Some(2))));
+        }
+        assert!(remote.boost_task(105, 0));
+        // Unknown tasks and tasks already at the target level are not moved.
+        assert!(!remote.boost_task(200, 0));
+        assert!(!remote.boost_task(105, 0));
+
+        let order: Vec<_> = (0..10)
+            .map(|_| {
+                let mut task_cell = locals[0].pop().unwrap().task_cell;
+                assert_eq!(
+                    task_cell.mut_extras().current_level(),
+                    if task_cell.sleep_ms == 5 { 0 } else { 2 }
+                );
+                task_cell.sleep_ms
+            })
+            .collect();
+        assert_eq!(order, [5, 0, 1, 2, 3, 4, 6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn test_set_level_thresholds() {
+        let builder = Builder::new(Config::default());
+        let manager = builder.manager.clone();
+        let (remote, mut locals) = build_spawn(builder, Default::default());
+        manager
+            .task_level_mgr
+            .get_elapsed(1)
+            .inc_by(Duration::from_millis(50));
+
+        let mut level_after_push = || {
+            remote.spawn(MockTask::new(0, Extras::new_multilevel(1, None)));
+            let mut t = locals[0].pop().unwrap().task_cell;
+            t.mut_extras().current_level()
+        };
+        // Demoted by the default thresholds of 5ms and 100ms.
+        assert_eq!(level_after_push(), 1);
+        remote
+            .set_level_thresholds(vec![Duration::from_millis(100), Duration::from_secs(1)])
+            .unwrap();
+        assert_eq!(level_after_push(), 0);
+        remote
+            .set_level_thresholds(vec![Duration::from_millis(1), Duration::from_millis(10)])
+            .unwrap();
+        assert_eq!(level_after_push(), 2);
+
+        for invalid in [
+            vec![],
+            vec![Duration::from_millis(1)],
+            vec![Duration::from_millis(10), Duration::from_millis(10)],
+            vec![Duration::from_millis(10), Duration::from_millis(1)],
+            vec![Duration::from_millis(1); LEVEL_NUM],
+        ] {
+            assert_eq!(
+                remote.set_level_thresholds(invalid),
+                Err(ConfigError::InvalidLevelThresholds)
+            );
+        }
+        // Invalid thresholds are not applied.
+        assert_eq!(level_after_push(), 2);
+
+        let (remote, _) = build_spawn(QueueType::SingleLevel, Default::default());
+        let remote: Remote<MockTask> = remote;
+        assert_eq!(
+            remote.set_level_thresholds(vec![Duration::from_millis(1), Duration::from_secs(1)]),
+            Err(ConfigError::NoTaskLevels)
+        );
+    }
+
+    #[test]
+    fn test_try_spawn_sheds_low_priority() {
+        let builder = Builder::new(Config::default());
+        let config = SchedConfig {
+            shed_policy: Some(ShedPolicy {
+                high_water: 2,
+                min_level: 1,
+            }),
+            ..Default::default()
+        };
+        let (remote, mut locals) = build_spawn(builder, config);
+        let low = |id| MockTask::new(0, Extras::new_multilevel(id, Some(2)));
+        let high = |id| MockTask::new(0, Extras::new_multilevel(id, None));
+
+        // Not overloaded yet, low priority tasks are accepted.
+        assert!(remote.try_spawn(low(1)).is_ok());
+        for id in 2..4 {
+            assert!(remote.try_spawn(high(id)).is_ok());
+        }
+        let mut rejected = remote.try_spawn(low(4)).unwrap_err();
+        assert_eq!(rejected.mut_extras().task_id(), 4);
+        assert!(remote.try_spawn(high(5)).is_ok());
+        assert_eq!(remote.core.pending_tasks(), 4);
+
+        // Accepted again once the queue drains below the mark.
+        for _ in 0..2 {
+            locals[0].pop().unwrap();
+            locals[0].task_done();
+        }
+        assert!(remote.try_spawn(low(6)).is_ok());
+    }
+
+    #[test]
+    fn test_wfq_selector() {
+        let weights = [6, 3, 1];
+        let builder = Builder::new(Config::default().selector(Selector::Wfq { weights }));
+        let (injector, mut locals) = builder.build_raw(1, None);
+        let mut counts = [0; LEVEL_NUM];
+        let mut level0_gap = 0;
+        let mut max_level0_gap = 0;
+        for _ in 0..1000 {
+            let level = locals[0].expected_level();
+            counts[level] += 1;
+            level0_gap = if level == 0 { 0 } else { level0_gap + 1 };
+            max_level0_gap = max_level0_gap.max(level0_gap);
+        }
+        assert_eq!(counts, [600, 300, 100]);
+        // Level 0 is chosen regularly instead of by chance.
+        assert!(max_level0_gap <= 2, "{}", max_level0_gap);
+
+        // Drive load through the queue. Every level keeps backlogged, so the
+        // steals from the global queue follow the weights.
+        for level in 0..LEVEL_NUM {
+            for _ in 0..5000 {
+                let extras = Extras::new_multilevel(0, Some(level as u8));
+                injector.push(MockTask::new(level as u64, extras));
+            }
+        }
+        let mut steals = [0; LEVEL_NUM];
+        for _ in 0..100 {
+            let t = locals[0].pop().unwrap();
+            assert!(!t.from_local);
+            steals[t.task_cell.sleep_ms as usize] += 1;
+            // Drop the rest of the stolen batch to count steals only.
+            while locals[0].take_local().is_some() {}
+        }
+        assert_eq!(steals, [60, 30, 10]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_wfq_zero_weights() {
+        Config::default().selector(Selector::Wfq {
+            weights: [0; LEVEL_NUM],
+        });
+    }
 }
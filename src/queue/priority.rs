@@ -24,7 +24,7 @@ use crate::queue::{
         now, MultiLevelMetrics, TaskLevelManager, TrackedRunnerBuilder,
         DEFAULT_CLEANUP_OLD_MAP_INTERVAL, LEVEL_NUM,
     },
-    Extras, Pop, TaskCell,
+    Extras, ExtrasSnapshot, Pop, TaskCell, TaskSource,
 };
 
 // a wrapper of u64 with an extra sequence number to avoid duplicate value.
@@ -44,9 +44,31 @@ where
 {
     /// Pushes the task cell to the queue. The schedule time in the extras is
     /// assigned to be now.
-    pub fn push(&self, mut task_cell: T) {
-        let priority = self.task_manager.prepare_before_push(&mut task_cell);
+    pub fn push(&self, mut task_cell: T) -> ExtrasSnapshot {
+        let (priority, snapshot) = self.task_manager.prepare_before_push(&mut task_cell);
         self.queue.push(task_cell, priority);
+        snapshot
+    }
+
+    /// Checks whether there are no tasks in the queue.
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Returns the number of tasks in the queue.
+    pub fn len(&self) -> usize {
+        self.queue.pq.len()
+    }
+
+    pub(super) fn set_level_time_threshold(&self, value: [Duration; LEVEL_NUM - 1]) {
+        self.task_manager
+            .level_manager
+            .set_level_time_threshold(value);
+    }
+
+    /// Updates the level of the task like pushing it.
+    pub(super) fn adjust_level(&self, task_cell: &mut T) {
+        self.task_manager.level_manager.adjust_task_level(task_cell);
     }
 }
 
@@ -61,9 +83,24 @@ where
         self.queue.pop()
     }
 
+    /// There is no local queue in a priority queue, so the task is pushed with
+    /// the highest priority instead. It runs before all other tasks except the
+    /// ones that also have the highest priority and are pushed earlier.
+    pub(super) fn push_front(&mut self, mut task_cell: T) -> ExtrasSnapshot {
+        let (_, snapshot) = self.task_manager.prepare_before_push(&mut task_cell);
+        self.queue.push(task_cell, 0);
+        snapshot
+    }
+
     pub(super) fn has_tasks_or_pull(&mut self) -> bool {
         !self.queue.is_empty()
     }
+
+    /// There is no local queue in a priority queue, so it's the number of
+    /// tasks in the global queue.
+    pub(super) fn local_len(&self) -> usize {
+        self.len()
+    }
 }
 
 /// A trait used to generate priority value for each task.
@@ -80,13 +117,15 @@ struct PriorityTaskManager {
 }
 
 impl PriorityTaskManager {
-    fn prepare_before_push<T>(&self, task_cell: &mut T) -> u64
+    fn prepare_before_push<T>(&self, task_cell: &mut T) -> (u64, ExtrasSnapshot)
     where
         T: TaskCell,
     {
         self.level_manager.adjust_task_level(task_cell);
-        task_cell.mut_extras().schedule_time = Some(now());
-        self.priority_manager.priority_of(task_cell.mut_extras())
+        let extras = task_cell.mut_extras();
+        extras.schedule_time = Some(now());
+        let priority = self.priority_manager.priority_of(extras);
+        (priority, ExtrasSnapshot::new(extras))
     }
 }
 
@@ -122,11 +161,7 @@ impl<T: TaskCell + Send + 'static> QueueCore<T> {
             T: TaskCell,
         {
             let schedule_time = t.mut_extras().schedule_time.unwrap();
-            Pop {
-                task_cell: t,
-                schedule_time,
-                from_local: false,
-            }
+            Pop::new(t, schedule_time, TaskSource::Global)
         }
 
         self.pq
@@ -256,12 +291,9 @@ impl Builder {
         local_num: usize,
     ) -> (super::TaskInjector<T>, Vec<super::LocalQueue<T>>) {
         let (injector, locals) = self.build_raw(local_num);
-        (
-            super::TaskInjector(super::InjectorInner::Priority(injector)),
-            locals
-                .into_iter()
-                .map(|i| super::LocalQueue(super::LocalQueueInner::Priority(i)))
-                .collect(),
+        super::wrap(
+            super::InjectorInner::Priority(injector),
+            locals.into_iter().map(super::LocalQueueInner::Priority),
         )
     }
 }
@@ -323,6 +355,7 @@ mod tests {
     struct OrderByIdProvider;
 
     impl TaskPriorityProvider for OrderByIdProvider {
+        #[allow(clippy::needless_return)]
         fn priority_of(&self, extras: &Extras) -> u64 {
             return extras.task_id();
         }
@@ -454,54 +487,59 @@ mod tests {
                 .get()
                 > 100_000
         );
-        assert!(
-            TASK_WAIT_DURATION
-                .get_metric_with_label_values(&[name])
-                .unwrap()
-                .get_sample_count()
-                >= 3
-        );
-        assert!(
-            TASK_EXEC_DURATION
-                .get_metric_with_label_values(&[name])
-                .unwrap()
-                .get_sample_count()
-                >= 3
-        );
-        assert!(
-            TASK_EXEC_DURATION
-                .get_metric_with_label_values(&[name])
-                .unwrap()
-                .get_sample_sum()
-                >= 0.1
-        );
-        assert!(
-            TASK_POLL_DURATION
-                .get_metric_with_label_values(&[name, "0"])
-                .unwrap()
-                .get_sample_count()
-                >= 3
-        );
-        assert!(
-            TASK_POLL_DURATION
-                .get_metric_with_label_values(&[name, "0"])
-                .unwrap()
-                .get_sample_sum()
-                >= 0.1
-        );
-        assert!(
-            TASK_EXEC_TIMES
-                .get_metric_with_label_values(&[name])
-                .unwrap()
-                .get_sample_count()
-                >= 3
-        );
-        assert!(
-            TASK_EXEC_TIMES
-                .get_metric_with_label_values(&[name])
-                .unwrap()
-                .get_sample_sum()
-                >= 3.0
-        );
+        // The histograms are compiled out along with the other metrics on the
+        // hot path.
+        #[cfg(feature = "metrics")]
+        {
+            assert!(
+                TASK_WAIT_DURATION
+                    .get_metric_with_label_values(&[name])
+                    .unwrap()
+                    .get_sample_count()
+                    >= 3
+            );
+            assert!(
+                TASK_EXEC_DURATION
+                    .get_metric_with_label_values(&[name])
+                    .unwrap()
+                    .get_sample_count()
+                    >= 3
+            );
+            assert!(
+                TASK_EXEC_DURATION
+                    .get_metric_with_label_values(&[name])
+                    .unwrap()
+                    .get_sample_sum()
+                    >= 0.1
+            );
+            assert!(
+                TASK_POLL_DURATION
+                    .get_metric_with_label_values(&[name, "0"])
+                    .unwrap()
+                    .get_sample_count()
+                    >= 3
+            );
+            assert!(
+                TASK_POLL_DURATION
+                    .get_metric_with_label_values(&[name, "0"])
+                    .unwrap()
+                    .get_sample_sum()
+                    >= 0.1
+            );
+            assert!(
+                TASK_EXEC_TIMES
+                    .get_metric_with_label_values(&[name])
+                    .unwrap()
+                    .get_sample_count()
+                    >= 3
+            );
+            assert!(
+                TASK_EXEC_TIMES
+                    .get_metric_with_label_values(&[name])
+                    .unwrap()
+                    .get_sample_sum()
+                    >= 3.0
+            );
+        }
     }
 }
@@ -4,29 +4,62 @@
 //!
 //! The instant when the task cell is pushed into the queue is recorded
 //! in the extras.
+//!
+//! The global queue can be split into several shards to reduce contention
+//! when many threads spawn tasks at the same time. Every spawning thread
+//! sticks to one shard, so tasks spawned by the same thread are still popped
+//! in order, while tasks spawned by different threads are not strictly FIFO.
 
-use super::{Pop, TaskCell};
+use super::{ExtrasSnapshot, Pop, TaskCell, TaskSource};
 
 use crossbeam_deque::{Injector, Steal, Stealer, Worker};
 use rand::prelude::*;
+use std::collections::VecDeque;
 use std::iter;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Instant;
 
 /// The injector of a single level work stealing task queue.
-pub struct TaskInjector<T>(Arc<Injector<T>>);
+pub struct TaskInjector<T>(Arc<[Injector<T>]>, Arc<[Stealer<T>]>);
+
+static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(test)]
+thread_local! {
+    /// The number of the next steals from the global queue on the current
+    /// thread that fail with `Steal::Retry` as if they raced with others.
+    pub(crate) static FORCE_STEAL_RETRY: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Steals a batch of tasks from a shard of the global queue and pops one.
+fn steal_shard<T>(shard: &Injector<T>, dest: &Worker<T>) -> Steal<T> {
+    #[cfg(test)]
+    if FORCE_STEAL_RETRY.with(|r| r.replace(r.get().saturating_sub(1))) > 0 {
+        return Steal::Retry;
+    }
+    shard.steal_batch_and_pop(dest)
+}
+
+thread_local! {
+    /// Spawning threads are assigned to shards in a round-robin way.
+    static SHARD_HINT: usize = NEXT_SHARD.fetch_add(1, Ordering::Relaxed);
+}
 
-impl<T: TaskCell> Clone for TaskInjector<T> {
+impl<T> Clone for TaskInjector<T> {
     fn clone(&self) -> Self {
-        TaskInjector(self.0.clone())
+        TaskInjector(self.0.clone(), self.1.clone())
     }
 }
 
-fn set_schedule_time<T>(task_cell: &mut T)
+/// Assigns the schedule time and returns a snapshot of the extras.
+fn set_schedule_time<T>(task_cell: &mut T) -> ExtrasSnapshot
 where
     T: TaskCell,
 {
-    task_cell.mut_extras().schedule_time = Some(Instant::now());
+    let extras = task_cell.mut_extras();
+    extras.schedule_time = Some(Instant::now());
+    ExtrasSnapshot::new(extras)
 }
 
 impl<T> TaskInjector<T>
@@ -35,58 +68,111 @@ where
 {
     /// Pushes the task cell to the queue. The schedule time in the extras is
     /// assigned to be now.
-    pub fn push(&self, mut task_cell: T) {
-        set_schedule_time(&mut task_cell);
-        self.0.push(task_cell);
+    pub fn push(&self, mut task_cell: T) -> ExtrasSnapshot {
+        let snapshot = set_schedule_time(&mut task_cell);
+        let shard = if self.0.len() == 1 {
+            0
+        } else {
+            SHARD_HINT.with(|s| *s % self.0.len())
+        };
+        self.0[shard].push(task_cell);
+        snapshot
+    }
+
+    /// Checks whether there are no tasks in the queue.
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(Injector::is_empty)
+    }
+
+    /// Returns the number of tasks in the queue.
+    pub fn len(&self) -> usize {
+        self.0.iter().map(Injector::len).sum()
+    }
+
+    /// Returns the number of tasks in each local queue, not including the ones
+    /// pushed to the front.
+    pub(super) fn local_lens(&self) -> Vec<usize> {
+        self.1.iter().map(Stealer::len).collect()
     }
 }
 
 /// The local queue of a single level work stealing task queue.
 pub struct LocalQueue<T> {
     local_queue: Worker<T>,
-    injector: Arc<Injector<T>>,
+    /// Tasks pushed by `push_front`. They are popped before any other task and
+    /// can't be stolen.
+    front: VecDeque<T>,
+    injector: Arc<[Injector<T>]>,
+    /// The shard of the global queue to steal from first.
+    shard: usize,
     stealers: Vec<Stealer<T>>,
+    /// The number of times stealing from the global queue had to retry
+    /// because of concurrent accesses, since the last `take_contention`.
+    contention: u64,
 }
 
 impl<T> LocalQueue<T>
 where
     T: TaskCell,
 {
-    pub fn push(&mut self, mut task_cell: T) {
-        set_schedule_time(&mut task_cell);
+    pub fn push(&mut self, mut task_cell: T) -> ExtrasSnapshot {
+        let snapshot = set_schedule_time(&mut task_cell);
         self.local_queue.push(task_cell);
+        snapshot
+    }
+
+    pub fn push_front(&mut self, mut task_cell: T) -> ExtrasSnapshot {
+        let snapshot = set_schedule_time(&mut task_cell);
+        self.front.push_back(task_cell);
+        snapshot
+    }
+
+    /// Returns the number of tasks in the local queue.
+    pub fn len(&self) -> usize {
+        self.local_queue.len() + self.front.len()
+    }
+
+    /// Takes a task pushed to this local queue.
+    pub fn take_local(&mut self) -> Option<T> {
+        self.front.pop_front().or_else(|| self.local_queue.pop())
     }
 
     pub fn pop(&mut self) -> Option<Pop<T>> {
-        fn into_pop<T>(mut t: T, from_local: bool) -> Pop<T>
+        fn into_pop<T>(mut t: T, source: TaskSource) -> Pop<T>
         where
             T: TaskCell,
         {
             let schedule_time = t.mut_extras().schedule_time.unwrap();
-            Pop {
-                task_cell: t,
-                schedule_time,
-                from_local,
-            }
+            Pop::new(t, schedule_time, source)
         }
 
+        if let Some(t) = self.front.pop_front() {
+            return Some(into_pop(t, TaskSource::Local));
+        }
         if let Some(t) = self.local_queue.pop() {
-            return Some(into_pop(t, true));
+            return Some(into_pop(t, TaskSource::Local));
         }
         let mut need_retry = true;
         while need_retry {
             need_retry = false;
-            match self.injector.steal_batch_and_pop(&self.local_queue) {
-                Steal::Success(t) => return Some(into_pop(t, false)),
-                Steal::Retry => need_retry = true,
-                _ => {}
+            let shards = self.injector.len();
+            for i in 0..shards {
+                let shard = &self.injector[(self.shard + i) % shards];
+                match steal_shard(shard, &self.local_queue) {
+                    Steal::Success(t) => return Some(into_pop(t, TaskSource::Global)),
+                    Steal::Retry => {
+                        self.contention += 1;
+                        need_retry = true;
+                    }
+                    _ => {}
+                }
             }
             if !self.stealers.is_empty() {
                 let mut found = None;
                 for (idx, stealer) in self.stealers.iter().enumerate() {
                     match stealer.steal_batch_and_pop(&self.local_queue) {
                         Steal::Success(t) => {
-                            found = Some((idx, into_pop(t, false)));
+                            found = Some((idx, into_pop(t, TaskSource::Steal)));
                             break;
                         }
                         Steal::Retry => need_retry = true,
@@ -104,22 +190,45 @@ where
     }
 
     pub fn has_tasks_or_pull(&mut self) -> bool {
-        if !self.local_queue.is_empty() {
+        if !self.front.is_empty() || !self.local_queue.is_empty() {
             return true;
         }
-        loop {
-            match self.injector.steal_batch(&self.local_queue) {
-                Steal::Success(()) => return true,
-                Steal::Empty => return false,
-                Steal::Retry => {}
+        let shards = self.injector.len();
+        for i in 0..shards {
+            let shard = &self.injector[(self.shard + i) % shards];
+            loop {
+                match shard.steal_batch(&self.local_queue) {
+                    Steal::Success(()) => return true,
+                    Steal::Empty => break,
+                    Steal::Retry => self.contention += 1,
+                }
             }
         }
+        false
+    }
+
+    /// Returns and resets the number of retries of stealing from the global
+    /// queue.
+    pub fn take_contention(&mut self) -> u64 {
+        std::mem::take(&mut self.contention)
     }
 }
 
 /// Creates a single level work stealing task queue with `local_num` local queues.
+#[cfg(test)]
 pub fn create<T>(local_num: usize) -> (TaskInjector<T>, Vec<LocalQueue<T>>) {
-    let injector = Arc::new(Injector::new());
+    create_sharded(local_num, 1, None)
+}
+
+/// Creates a single level work stealing task queue with `local_num` local
+/// queues, whose global queue is split into `shards` shards. The steal order
+/// of the local queues is seeded by `seed` if it's given.
+pub fn create_sharded<T>(
+    local_num: usize,
+    shards: usize,
+    seed: Option<u64>,
+) -> (TaskInjector<T>, Vec<LocalQueue<T>>) {
+    let injector: Arc<[Injector<T>]> = (0..shards.max(1)).map(|_| Injector::new()).collect();
     let workers: Vec<_> = iter::repeat_with(Worker::new_lifo)
         .take(local_num)
         .collect();
@@ -135,16 +244,19 @@ pub fn create<T>(local_num: usize) -> (TaskInjector<T>, Vec<LocalQueue<T>>) {
                 .map(|(_, stealer)| stealer.clone())
                 .collect();
             // Steal with a random start to avoid imbalance.
-            stealers.shuffle(&mut thread_rng());
+            stealers.shuffle(&mut super::local_rng(seed, self_index));
             LocalQueue {
                 local_queue,
+                front: VecDeque::new(),
+                shard: self_index % injector.len(),
                 injector: injector.clone(),
                 stealers,
+                contention: 0,
             }
         })
         .collect();
 
-    (TaskInjector(injector), local_queues)
+    (TaskInjector(injector, stealers.into()), local_queues)
 }
 
 #[cfg(test)]
@@ -207,11 +319,15 @@ mod tests {
         for i in 0..50 {
             injector.push(MockCell::new(i));
         }
-        assert!(injector.0.steal_batch(&locals[0].local_queue).is_success());
+        assert!(injector.0[0]
+            .steal_batch(&locals[0].local_queue)
+            .is_success());
         for i in 50..100 {
             injector.push(MockCell::new(i));
         }
-        assert!(injector.0.steal_batch(&locals[1].local_queue).is_success());
+        assert!(injector.0[0]
+            .steal_batch(&locals[1].local_queue)
+            .is_success());
         let sum: i32 = (0..100)
             .map(|_| locals[2].pop().unwrap().task_cell.value)
             .sum();
@@ -219,6 +335,23 @@ mod tests {
         assert!(locals.iter_mut().all(|c| c.pop().is_none()));
     }
 
+    #[test]
+    fn test_push_front() {
+        let (injector, mut locals) = super::create(2);
+        injector.push(MockCell::new(0));
+        locals[0].push(MockCell::new(1));
+        locals[0].push_front(MockCell::new(2));
+        locals[0].push_front(MockCell::new(3));
+        assert_eq!(locals[0].pop().unwrap().task_cell.value, 2);
+        assert_eq!(locals[0].pop().unwrap().task_cell.value, 3);
+        assert_eq!(locals[0].pop().unwrap().task_cell.value, 1);
+        locals[0].push_front(MockCell::new(4));
+        // Tasks pushed to the front can't be stolen.
+        assert_eq!(locals[1].pop().unwrap().task_cell.value, 0);
+        assert!(locals[1].pop().is_none());
+        assert_eq!(locals[0].pop().unwrap().task_cell.value, 4);
+    }
+
     #[test]
     fn test_pop_concurrently() {
         let (injector, locals) = super::create(3);
@@ -242,4 +375,32 @@ mod tests {
         }
         assert_eq!(sum.load(Ordering::SeqCst), (0..10_000).sum());
     }
+
+    #[test]
+    fn test_sharded() {
+        let (injector, mut locals) = super::create_sharded(2, 3, None);
+        assert_eq!(injector.0.len(), 3);
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let injector = injector.clone();
+                thread::spawn(move || {
+                    for j in 0..100 {
+                        injector.push(MockCell::new(i * 100 + j));
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(injector.len(), 400);
+        // Stealing checks all the shards.
+        let mut values: Vec<_> = (0..400)
+            .map(|_| locals[1].pop().unwrap().task_cell.value)
+            .collect();
+        assert!(injector.is_empty());
+        assert!(locals.iter_mut().all(|c| c.pop().is_none()));
+        values.sort_unstable();
+        assert_eq!(values, (0..400).collect::<Vec<_>>());
+    }
 }
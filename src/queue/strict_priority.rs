@@ -0,0 +1,228 @@
+// Copyright 2024 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! A task queue with a fixed number of strict priority classes.
+//!
+//! Tasks are pushed to the class set by [`Extras::set_priority_class`], class
+//! 0 being the most urgent one. A task is only popped when all classes before
+//! its own are empty, and tasks are never promoted or demoted, so a busy
+//! class can starve all the classes after it. That's intended: use the
+//! multilevel task queue if tasks should share the workers fairly.
+//!
+//! [`Extras::set_priority_class`]: crate::queue::Extras::set_priority_class
+
+use super::{ExtrasSnapshot, Pop, TaskCell, TaskSource};
+
+use crossbeam_deque::{Injector, Steal};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// The injector of a strict priority task queue.
+pub struct TaskInjector<T>(Arc<[Injector<T>]>);
+
+impl<T> TaskInjector<T> {
+    /// Returns the number of classes.
+    pub fn classes(&self) -> usize {
+        self.0.len()
+    }
+}
+
+impl<T> Clone for TaskInjector<T> {
+    fn clone(&self) -> Self {
+        TaskInjector(self.0.clone())
+    }
+}
+
+/// Assigns the schedule time and returns the class the task belongs to, with
+/// a snapshot of the extras.
+fn prepare_before_push<T>(task_cell: &mut T, classes: usize) -> (usize, ExtrasSnapshot)
+where
+    T: TaskCell,
+{
+    let extras = task_cell.mut_extras();
+    extras.schedule_time = Some(Instant::now());
+    let class = (extras.priority_class as usize).min(classes - 1);
+    (class, ExtrasSnapshot::new(extras))
+}
+
+impl<T> TaskInjector<T>
+where
+    T: TaskCell + Send,
+{
+    /// Pushes the task cell to the queue of its class. The schedule time in
+    /// the extras is assigned to be now.
+    pub fn push(&self, mut task_cell: T) -> ExtrasSnapshot {
+        let (class, snapshot) = prepare_before_push(&mut task_cell, self.0.len());
+        self.0[class].push(task_cell);
+        snapshot
+    }
+
+    /// Checks whether there are no tasks in the queue.
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(Injector::is_empty)
+    }
+
+    /// Returns the number of tasks in the queue.
+    pub fn len(&self) -> usize {
+        self.0.iter().map(Injector::len).sum()
+    }
+}
+
+/// The local queue of a strict priority task queue.
+///
+/// Tasks are always pushed to the shared queues, otherwise a worker could
+/// run its own tasks of a low class while tasks of a higher class wait.
+pub struct LocalQueue<T> {
+    /// Tasks pushed by `push_front`. They are popped before any other task and
+    /// can't be stolen.
+    front: VecDeque<T>,
+    injector: TaskInjector<T>,
+    /// The number of times stealing from the global queue had to retry
+    /// because of concurrent accesses, since the last `take_contention`.
+    contention: u64,
+}
+
+impl<T> LocalQueue<T>
+where
+    T: TaskCell + Send,
+{
+    pub fn push(&mut self, task_cell: T) -> ExtrasSnapshot {
+        self.injector.push(task_cell)
+    }
+
+    pub fn push_front(&mut self, mut task_cell: T) -> ExtrasSnapshot {
+        let (_, snapshot) = prepare_before_push(&mut task_cell, self.injector.0.len());
+        self.front.push_back(task_cell);
+        snapshot
+    }
+
+    /// There is no local queue in a strict priority queue, so it's the number
+    /// of tasks in the global queue plus the ones pushed to the front.
+    pub fn len(&self) -> usize {
+        self.front.len() + self.injector.len()
+    }
+
+    /// Takes a task pushed to the front, the others are in the global queue.
+    pub fn take_local(&mut self) -> Option<T> {
+        self.front.pop_front()
+    }
+
+    pub fn pop(&mut self) -> Option<Pop<T>> {
+        fn into_pop<T>(mut t: T, source: TaskSource) -> Pop<T>
+        where
+            T: TaskCell,
+        {
+            let schedule_time = t.mut_extras().schedule_time.unwrap();
+            Pop::new(t, schedule_time, source)
+        }
+
+        if let Some(t) = self.front.pop_front() {
+            return Some(into_pop(t, TaskSource::Local));
+        }
+        for injector in self.injector.0.iter() {
+            loop {
+                match injector.steal() {
+                    Steal::Success(t) => return Some(into_pop(t, TaskSource::Global)),
+                    Steal::Empty => break,
+                    Steal::Retry => self.contention += 1,
+                }
+            }
+        }
+        None
+    }
+
+    pub fn has_tasks_or_pull(&mut self) -> bool {
+        !self.front.is_empty() || !self.injector.is_empty()
+    }
+
+    /// Returns and resets the number of retries of stealing from the global
+    /// queue.
+    pub fn take_contention(&mut self) -> u64 {
+        std::mem::take(&mut self.contention)
+    }
+}
+
+/// Creates a strict priority task queue with `classes` classes and
+/// `local_num` local queues.
+///
+/// # Panics
+///
+/// Panics if `classes` is 0.
+pub fn create<T>(classes: usize, local_num: usize) -> (TaskInjector<T>, Vec<LocalQueue<T>>) {
+    assert!(
+        classes > 0,
+        "strict priority queue needs at least one class"
+    );
+    let injector = TaskInjector((0..classes).map(|_| Injector::new()).collect());
+    let local_queues = (0..local_num)
+        .map(|_| LocalQueue {
+            front: VecDeque::new(),
+            injector: injector.clone(),
+            contention: 0,
+        })
+        .collect();
+    (injector, local_queues)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::queue::Extras;
+
+    #[derive(Debug)]
+    struct MockCell {
+        value: i32,
+        extras: Extras,
+    }
+
+    impl MockCell {
+        fn new(value: i32, class: u8) -> Self {
+            let mut extras = Extras::single_level();
+            extras.set_priority_class(class);
+            MockCell { value, extras }
+        }
+    }
+
+    impl TaskCell for MockCell {
+        fn mut_extras(&mut self) -> &mut Extras {
+            &mut self.extras
+        }
+    }
+
+    #[test]
+    fn test_strict_priority() {
+        let (injector, mut locals) = super::create(3, 2);
+        for i in 0..10 {
+            injector.push(MockCell::new(i, 2));
+        }
+        locals[0].push(MockCell::new(10, 1));
+        injector.push(MockCell::new(11, 0));
+        // Classes out of range fall into the last class.
+        injector.push(MockCell::new(12, 8));
+        assert_eq!(injector.len(), 13);
+
+        assert_eq!(locals[1].pop().unwrap().task_cell.value, 11);
+        assert_eq!(locals[1].pop().unwrap().task_cell.value, 10);
+        let rest: Vec<_> = (0..11)
+            .map(|_| locals[0].pop().unwrap().task_cell.value)
+            .collect();
+        assert_eq!(rest, (0..10).chain(Some(12)).collect::<Vec<_>>());
+        assert!(locals.iter_mut().all(|l| l.pop().is_none()));
+    }
+
+    #[test]
+    fn test_push_front() {
+        let (injector, mut locals) = super::create(2, 2);
+        injector.push(MockCell::new(0, 0));
+        locals[0].push_front(MockCell::new(1, 1));
+        assert_eq!(locals[0].len(), 2);
+        assert_eq!(locals[0].pop().unwrap().task_cell.value, 1);
+        assert_eq!(locals[0].pop().unwrap().task_cell.value, 0);
+        locals[0].push_front(MockCell::new(2, 1));
+        // Tasks pushed to the front can't be stolen.
+        assert!(locals[1].pop().is_none());
+        assert!(!locals[1].has_tasks_or_pull());
+        assert!(locals[0].has_tasks_or_pull());
+    }
+}
@@ -0,0 +1,238 @@
+// Copyright 2026 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Synchronization primitives for futures running in the pool.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll, Waker};
+
+/// Notifies a single task to wake up.
+///
+/// It's a lightweight replacement for `tokio::sync::Notify`. Tasks waiting on
+/// [`notified`](Notify::notified) yield their worker, and are rescheduled
+/// through the pool when [`notify_one`](Notify::notify_one) is called.
+///
+/// If `notify_one` is called when no task is waiting, a permit is stored and
+/// the next call to `notified` completes immediately. At most one permit is
+/// stored.
+///
+/// ```
+/// use std::sync::Arc;
+/// use yatp::sync::Notify;
+///
+/// let pool = yatp::Builder::new("notify").build_future_pool();
+/// let notify = Arc::new(Notify::new());
+/// let (tx, rx) = std::sync::mpsc::channel();
+/// let n = notify.clone();
+/// pool.spawn(async move {
+///     n.notified().await;
+///     tx.send(()).unwrap();
+/// });
+/// notify.notify_one();
+/// rx.recv().unwrap();
+/// ```
+#[derive(Default)]
+pub struct Notify {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    permit: bool,
+    /// Waiting tasks in the order they start to wait.
+    waiters: VecDeque<(u64, Waker)>,
+    next_id: u64,
+}
+
+impl Notify {
+    /// Creates a notify without any permit.
+    pub fn new() -> Notify {
+        Notify::default()
+    }
+
+    /// Waits for a notification.
+    ///
+    /// The returned future starts waiting when it's first polled. If it's
+    /// dropped after being notified but before completing, the notification
+    /// is passed on to the next waiting task.
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            id: None,
+        }
+    }
+
+    /// Wakes up the task that has waited the longest, or stores a permit if
+    /// no task is waiting.
+    pub fn notify_one(&self) {
+        let waiter = {
+            let mut state = self.state.lock().unwrap();
+            match state.waiters.pop_front() {
+                Some((_, waker)) => waker,
+                None => {
+                    state.permit = true;
+                    return;
+                }
+            }
+        };
+        waiter.wake();
+    }
+}
+
+/// A future that completes when a [`Notify`] is notified.
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    /// The id of the waiter once it starts waiting.
+    id: Option<u64>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.notify.state.lock().unwrap();
+        match self.id {
+            Some(id) => match state.waiters.iter_mut().find(|(i, _)| *i == id) {
+                Some((_, waker)) => {
+                    if !waker.will_wake(cx.waker()) {
+                        *waker = cx.waker().clone();
+                    }
+                    Poll::Pending
+                }
+                None => {
+                    // Removed by `notify_one`.
+                    drop(state);
+                    self.id = None;
+                    Poll::Ready(())
+                }
+            },
+            None => {
+                if state.permit {
+                    state.permit = false;
+                    return Poll::Ready(());
+                }
+                let id = state.next_id;
+                state.next_id += 1;
+                state.waiters.push_back((id, cx.waker().clone()));
+                drop(state);
+                self.id = Some(id);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        let id = match self.id {
+            Some(id) => id,
+            None => return,
+        };
+        let mut state = self.notify.state.lock().unwrap();
+        match state.waiters.iter().position(|(i, _)| *i == id) {
+            Some(pos) => {
+                state.waiters.remove(pos);
+            }
+            None => {
+                // Notified but never completed, pass the notification on.
+                drop(state);
+                self.notify.notify_one();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Builder;
+    use std::sync::{mpsc, Arc};
+    use std::time::Duration;
+
+    #[test]
+    fn test_notify_handoff() {
+        let pool = Builder::new("test_notify_handoff")
+            .max_thread_count(2)
+            .build_future_pool();
+        let notify = Arc::new(Notify::new());
+        let (tx, rx) = mpsc::channel();
+        let n = notify.clone();
+        pool.spawn(async move {
+            n.notified().await;
+            tx.send(()).unwrap();
+        });
+        // The waiting task doesn't occupy a worker.
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        let (done_tx, done_rx) = mpsc::channel();
+        pool.spawn(async move {
+            done_tx.send(()).unwrap();
+        });
+        done_rx.recv_timeout(Duration::from_secs(3)).unwrap();
+        assert!(rx.try_recv().is_err());
+
+        let n = notify.clone();
+        pool.spawn(async move { n.notify_one() });
+        rx.recv_timeout(Duration::from_secs(3)).unwrap();
+        assert!(notify.state.lock().unwrap().waiters.is_empty());
+        assert!(!notify.state.lock().unwrap().permit);
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_notify_permit() {
+        let pool = Builder::new("test_notify_permit")
+            .max_thread_count(1)
+            .build_future_pool();
+        let notify = Arc::new(Notify::new());
+        // Only one permit is stored.
+        notify.notify_one();
+        notify.notify_one();
+        let (tx, rx) = mpsc::channel();
+        let n = notify.clone();
+        pool.spawn(async move {
+            n.notified().await;
+            tx.send(1).unwrap();
+            n.notified().await;
+            tx.send(2).unwrap();
+        });
+        assert_eq!(rx.recv_timeout(Duration::from_secs(3)).unwrap(), 1);
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        notify.notify_one();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(3)).unwrap(), 2);
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_notify_dropped_waiter() {
+        struct NoopWaker;
+
+        impl std::task::Wake for NoopWaker {
+            fn wake(self: Arc<Self>) {}
+        }
+
+        let waker = Waker::from(Arc::new(NoopWaker));
+        let mut cx = Context::from_waker(&waker);
+        let notify = Notify::new();
+        let mut first = Box::pin(notify.notified());
+        let mut second = Box::pin(notify.notified());
+        assert!(first.as_mut().poll(&mut cx).is_pending());
+        assert!(second.as_mut().poll(&mut cx).is_pending());
+
+        // The first waiter is notified but dropped, so the second one takes
+        // over the notification.
+        notify.notify_one();
+        drop(first);
+        assert!(second.as_mut().poll(&mut cx).is_ready());
+        drop(second);
+        assert!(!notify.state.lock().unwrap().permit);
+
+        // Without other waiters, it's stored as a permit.
+        let mut third = Box::pin(notify.notified());
+        assert!(third.as_mut().poll(&mut cx).is_pending());
+        notify.notify_one();
+        drop(third);
+        assert!(notify.state.lock().unwrap().permit);
+    }
+}
@@ -2,8 +2,16 @@
 
 //! A [`FnOnce`] or [`FnMut`] closure.
 
-use crate::pool::Local;
+use crate::pool::{CurrentTaskGuard, Local, Remote, ThreadPool};
 use crate::queue::{Extras, WithExtras};
+use lazy_static::lazy_static;
+use std::alloc::{self, Layout};
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::mem::{self, MaybeUninit};
+use std::ptr;
+use std::sync::mpsc::{self, Receiver};
 
 /// A callback task, which is either a [`FnOnce`] or a [`FnMut`].
 pub enum Task {
@@ -15,8 +23,16 @@ pub enum Task {
 
 impl Task {
     /// Creates a [`FnOnce`] task.
+    ///
+    /// The task reuses the memory of a finished task if it's created on a
+    /// worker with `callback_recycle_cap` set. Recycling is enabled by
+    /// [`Runner`]'s `start`, so it's disabled for runners wrapping it that
+    /// don't forward `start`.
     pub fn new_once(t: impl FnOnce(&mut Handle<'_>) + Send + 'static) -> Self {
-        Task::Once(Box::new(t))
+        match Slotted::new_boxed(t) {
+            Ok(t) => Task::Once(t),
+            Err(t) => Task::Once(Box::new(t)),
+        }
     }
 
     /// Creates a [`FnMut`] task.
@@ -25,6 +41,214 @@ impl Task {
     }
 }
 
+type OnceFn = dyn FnOnce(&mut Handle<'_>) + Send;
+
+const SLOT_WORDS: usize = 8;
+
+/// A small [`FnOnce`] closure stored inline, so the boxes holding it have the
+/// same layout whatever the closure is and can be reused.
+struct Slotted {
+    data: MaybeUninit<[usize; SLOT_WORDS]>,
+    call: unsafe fn(*mut u8, &mut Handle<'_>),
+    /// `None` once the closure is moved out.
+    drop: Option<unsafe fn(*mut u8)>,
+}
+
+// The closure stored in the slot is `Send`.
+unsafe impl Send for Slotted {}
+
+impl Slotted {
+    /// Boxes the closure in a slot of the current worker. The closure is
+    /// given back if it's too large or recycling is disabled on this thread.
+    fn new_boxed<F>(f: F) -> Result<Box<OnceFn>, F>
+    where
+        F: FnOnce(&mut Handle<'_>) + Send + 'static,
+    {
+        unsafe fn call<F: FnOnce(&mut Handle<'_>)>(p: *mut u8, handle: &mut Handle<'_>) {
+            ptr::read(p as *mut F)(handle)
+        }
+
+        unsafe fn drop<F>(p: *mut u8) {
+            ptr::drop_in_place(p as *mut F)
+        }
+
+        if mem::size_of::<F>() > mem::size_of::<[usize; SLOT_WORDS]>()
+            || mem::align_of::<F>() > mem::align_of::<usize>()
+        {
+            return Err(f);
+        }
+        let p = match alloc_slot() {
+            Some(p) => p,
+            None => return Err(f),
+        };
+        let mut slotted = Slotted {
+            data: MaybeUninit::uninit(),
+            call: call::<F>,
+            drop: Some(drop::<F>),
+        };
+        unsafe {
+            ptr::write(slotted.data.as_mut_ptr() as *mut F, f);
+            place(boxed_slotted, p, boxed_slotted(slotted));
+            Ok(Box::from_raw(SLOTTED.at(p)))
+        }
+    }
+
+    fn run(mut self, handle: &mut Handle<'_>) {
+        // The closure is moved out before being called, so it's not dropped
+        // again if it panics.
+        self.drop = None;
+        unsafe { (self.call)(self.data.as_mut_ptr() as *mut u8, handle) }
+    }
+}
+
+impl Drop for Slotted {
+    fn drop(&mut self) {
+        if let Some(drop) = self.drop.take() {
+            unsafe { drop(self.data.as_mut_ptr() as *mut u8) }
+        }
+    }
+}
+
+/// The closure boxed as a `Task::Once` for a [`Slotted`]. It's only ever
+/// named through this function.
+fn boxed_slotted(s: Slotted) -> impl FnOnce(&mut Handle<'_>) + Send + 'static {
+    move |handle: &mut Handle<'_>| s.run(handle)
+}
+
+unsafe fn place<C>(_: fn(Slotted) -> C, p: *mut u8, c: C) {
+    ptr::write(p as *mut C, c)
+}
+
+unsafe fn take<C>(_: fn(Slotted) -> C, p: *mut u8) -> C {
+    ptr::read(p as *mut C)
+}
+
+/// The layout and the vtable of the boxed closure of [`Slotted`].
+struct SlottedBox {
+    layout: Layout,
+    /// A dangling pointer to the closure. Pointers to all recycled boxes are
+    /// derived from it, so they share its vtable and can be told apart from
+    /// other boxes.
+    template: *mut OnceFn,
+}
+
+unsafe impl Send for SlottedBox {}
+unsafe impl Sync for SlottedBox {}
+
+impl SlottedBox {
+    fn new<C: FnOnce(&mut Handle<'_>) + Send + 'static>(_: fn(Slotted) -> C) -> SlottedBox {
+        SlottedBox {
+            layout: Layout::new::<C>(),
+            template: ptr::NonNull::<C>::dangling().as_ptr() as *mut OnceFn,
+        }
+    }
+
+    /// Returns the pointer to a boxed closure of [`Slotted`] at `p`.
+    fn at(&self, p: *mut u8) -> *mut OnceFn {
+        let offset = (p as isize).wrapping_sub(self.template as *mut u8 as isize);
+        self.template.wrapping_byte_offset(offset)
+    }
+}
+
+lazy_static! {
+    static ref SLOTTED: SlottedBox = SlottedBox::new(boxed_slotted);
+}
+
+/// Runs a `Task::Once`. If it's stored in a slot, the slot is given back to
+/// the current worker.
+fn run_once(task: Box<OnceFn>, handle: &mut Handle<'_>) {
+    let p = Box::into_raw(task);
+    if !ptr::eq(p, SLOTTED.at(p as *mut u8)) {
+        return unsafe { Box::from_raw(p) }(handle);
+    }
+    let c = unsafe { take(boxed_slotted, p as *mut u8) };
+    let _slot = SlotGuard(p as *mut u8);
+    c(handle)
+}
+
+/// Gives back the slot after the closure moved out of it finishes, so the
+/// tasks it spawns don't take the slot.
+struct SlotGuard(*mut u8);
+
+impl Drop for SlotGuard {
+    fn drop(&mut self) {
+        recycle_slot(self.0);
+    }
+}
+
+struct Recycler {
+    slots: Vec<*mut u8>,
+    cap: usize,
+}
+
+impl Recycler {
+    fn truncate(&mut self, len: usize) {
+        while self.slots.len() > len {
+            let p = self.slots.pop().unwrap();
+            unsafe { alloc::dealloc(p, SLOTTED.layout) };
+        }
+    }
+}
+
+impl Drop for Recycler {
+    fn drop(&mut self) {
+        self.truncate(0);
+    }
+}
+
+thread_local! {
+    /// Freed slots of the current worker. `cap` is 0 on non-worker threads.
+    static RECYCLER: RefCell<Recycler> = const {
+        RefCell::new(Recycler {
+            slots: Vec::new(),
+            cap: 0,
+        })
+    };
+}
+
+fn set_recycle_cap(cap: usize) {
+    RECYCLER.with(|r| {
+        let mut r = r.borrow_mut();
+        r.cap = cap;
+        r.truncate(cap);
+    })
+}
+
+fn alloc_slot() -> Option<*mut u8> {
+    RECYCLER.with(|r| {
+        let mut r = r.borrow_mut();
+        if r.cap == 0 {
+            return None;
+        }
+        Some(r.slots.pop().unwrap_or_else(|| {
+            let p = unsafe { alloc::alloc(SLOTTED.layout) };
+            if p.is_null() {
+                alloc::handle_alloc_error(SLOTTED.layout);
+            }
+            p
+        }))
+    })
+}
+
+fn recycle_slot(p: *mut u8) {
+    // The thread local may have been destroyed if the task is run when the
+    // thread exits.
+    let kept = RECYCLER
+        .try_with(|r| {
+            let mut r = r.borrow_mut();
+            if r.slots.len() < r.cap {
+                r.slots.push(p);
+                true
+            } else {
+                false
+            }
+        })
+        .unwrap_or(false);
+    if !kept {
+        unsafe { alloc::dealloc(p, SLOTTED.layout) };
+    }
+}
+
 /// The task cell for callback tasks.
 pub struct TaskCell {
     /// The callback task.
@@ -39,6 +263,49 @@ impl crate::queue::TaskCell for TaskCell {
     }
 }
 
+impl TaskCell {
+    /// Runs the task on the spawning thread instead of a worker thread.
+    /// Tasks spawned by the task are pushed to `remote`.
+    fn run_on_caller(mut self, remote: &Remote<Self>) {
+        if remote.core.runs_inline() {
+            run_inline(self, remote);
+            return;
+        }
+        let _current = CurrentTaskGuard::new(&mut self);
+        let mut handle = Handle {
+            spawner: Spawner::Remote(remote),
+            rerun: false,
+            next: None,
+        };
+        match self.task {
+            Task::Mut(ref mut r) => {
+                r(&mut handle);
+                // Don't occupy the caller any longer.
+                if handle.rerun {
+                    remote.spawn_queued(self);
+                }
+            }
+            Task::Once(r) => run_once(r, &mut handle),
+        }
+    }
+}
+
+/// Runs `task` on the spawning thread if it's a callback task.
+///
+/// It's called when the pool is overloaded and the overflow policy is
+/// `OverflowPolicy::RunOnCaller`. Other tasks can't be run in place, so they
+/// are returned back to be queued as usual.
+pub(crate) fn run_on_caller<T: 'static>(task: T, remote: &Remote<T>) -> Result<(), T> {
+    let mut task = Some(task);
+    let cell = (&mut task as &mut dyn Any).downcast_mut::<Option<TaskCell>>();
+    let remote = (remote as &dyn Any).downcast_ref::<Remote<TaskCell>>();
+    if let (Some(cell), Some(remote)) = (cell, remote) {
+        cell.take().unwrap().run_on_caller(remote);
+        return Ok(());
+    }
+    Err(task.unwrap())
+}
+
 impl<F> WithExtras<TaskCell> for F
 where
     F: FnOnce(&mut Handle<'_>) + Send + 'static,
@@ -51,19 +318,97 @@ where
     }
 }
 
+/// Runs the task and all the tasks it spawns on the calling thread, as the
+/// pool has no workers to run them.
+fn run_inline(task_cell: TaskCell, remote: &Remote<TaskCell>) {
+    let mut pending = VecDeque::new();
+    pending.push_back(task_cell);
+    while let Some(mut task_cell) = pending.pop_front() {
+        let _current = CurrentTaskGuard::new(&mut task_cell);
+        let mut handle = Handle {
+            spawner: Spawner::Inline {
+                remote,
+                pending: &mut pending,
+            },
+            rerun: false,
+            next: None,
+        };
+        match task_cell.task {
+            Task::Mut(ref mut r) => {
+                r(&mut handle);
+                if handle.rerun {
+                    drop(handle);
+                    pending.push_back(task_cell);
+                }
+            }
+            Task::Once(r) => run_once(r, &mut handle),
+        }
+    }
+}
+
+/// Where the tasks spawned by a [`Handle`] go.
+enum Spawner<'a> {
+    Local(&'a mut Local<TaskCell>),
+    /// The task is run on the spawning thread instead of a worker thread.
+    Remote(&'a Remote<TaskCell>),
+    /// The pool has no workers, spawned tasks are run on the spawning thread
+    /// after the current one.
+    Inline {
+        remote: &'a Remote<TaskCell>,
+        pending: &'a mut VecDeque<TaskCell>,
+    },
+}
+
+impl ThreadPool<TaskCell> {
+    /// Runs `f` once on every worker thread with the index of the worker,
+    /// and blocks until all of them finish.
+    ///
+    /// It's useful to initialize per worker resources after the pool starts.
+    /// It must not be called from a worker of the pool, which would wait for
+    /// itself.
+    pub fn broadcast(&self, f: impl Fn(usize) + Send + Sync + Clone + 'static) {
+        self.broadcast_by(f, |g| move |_: &mut Handle<'_>| g());
+    }
+}
+
+impl Spawner<'_> {
+    fn spawn(&mut self, t: impl WithExtras<TaskCell>) {
+        match self {
+            Spawner::Local(local) => local.spawn(t),
+            Spawner::Remote(remote) => remote.spawn_queued(t),
+            Spawner::Inline { remote, pending } => {
+                pending.push_back(t.with_extras(|| remote.core.default_extras()))
+            }
+        }
+    }
+
+    /// Spawns a new task, which is counted by `Remote::recent_spawn_rate`.
+    fn spawn_new(&mut self, t: impl WithExtras<TaskCell>) {
+        match self {
+            Spawner::Local(local) => local.core().record_spawn_rate(),
+            Spawner::Remote(remote) | Spawner::Inline { remote, .. } => {
+                remote.core.record_spawn_rate()
+            }
+        }
+        self.spawn(t);
+    }
+}
+
 /// Handle passed to the task closure.
 ///
 /// It can be used to spawn new tasks or control whether this task should be
 /// rerun.
 pub struct Handle<'a> {
-    local: &'a mut Local<TaskCell>,
+    spawner: Spawner<'a>,
     rerun: bool,
+    /// The continuation to run after the current callback returns.
+    next: Option<Task>,
 }
 
 impl<'a> Handle<'a> {
     /// Spawns a [`FnOnce`] to the thread pool.
     pub fn spawn_once(&mut self, t: impl FnOnce(&mut Handle<'_>) + Send + 'static, extras: Extras) {
-        self.local.spawn(TaskCell {
+        self.spawner.spawn_new(TaskCell {
             task: Task::new_once(t),
             extras,
         });
@@ -71,7 +416,7 @@ impl<'a> Handle<'a> {
 
     /// Spawns a [`FnMut`] to the thread pool.
     pub fn spawn_mut(&mut self, t: impl FnMut(&mut Handle<'_>) + Send + 'static, extras: Extras) {
-        self.local.spawn(TaskCell {
+        self.spawner.spawn_new(TaskCell {
             task: Task::new_mut(t),
             extras,
         });
@@ -79,7 +424,90 @@ impl<'a> Handle<'a> {
 
     /// Spawns a task to the thread pool.
     pub fn spawn(&mut self, t: impl WithExtras<TaskCell>) {
-        self.local.spawn(t)
+        self.spawner.spawn_new(t)
+    }
+
+    /// Spawns a [`FnOnce`] to the thread pool and returns a receiver for its
+    /// result.
+    ///
+    /// If the task is dropped without being run, for example because the pool
+    /// is shut down, receiving from the receiver returns an error.
+    pub fn spawn_with_result<R>(
+        &mut self,
+        t: impl FnOnce(&mut Handle<'_>) -> R + Send + 'static,
+    ) -> Receiver<R>
+    where
+        R: Send + 'static,
+    {
+        let (tx, rx) = mpsc::sync_channel(1);
+        self.spawner.spawn_new(move |handle: &mut Handle<'_>| {
+            // The receiver may have been dropped if the result is not needed.
+            let _ = tx.send(t(handle));
+        });
+        rx
+    }
+
+    /// Yields the worker to other tasks and runs `continuation` later.
+    ///
+    /// The continuation is pushed to the global queue like a rescheduled
+    /// future, so tasks queued before it can run in between. Long running
+    /// callbacks can process their work in chunks this way instead of
+    /// occupying the worker. The continuation uses the default extras of the
+    /// queue.
+    pub fn yield_now(&mut self, continuation: impl FnOnce(&mut Handle<'_>) + Send + 'static) {
+        match &mut self.spawner {
+            Spawner::Local(local) => local.spawn_remote(continuation),
+            Spawner::Remote(remote) => remote.spawn_queued(continuation),
+            Spawner::Inline { .. } => self.spawner.spawn(continuation),
+        }
+    }
+
+    /// Runs `continuation` on this worker, or pushes it to the global queue
+    /// if other tasks are waiting for the worker.
+    ///
+    /// It's the callback counterpart of `future::reschedule`. Long running
+    /// callbacks can process their work in chunks this way and only give up
+    /// the worker when other tasks need it, unlike `yield_now` which always
+    /// does. A continuation that isn't rescheduled runs right after the
+    /// current callback returns. Returns whether it's rescheduled.
+    pub fn reschedule(
+        &mut self,
+        continuation: impl FnOnce(&mut Handle<'_>) + Send + 'static,
+    ) -> bool {
+        let local = match &mut self.spawner {
+            Spawner::Local(local) => local,
+            // Don't occupy the caller any longer.
+            Spawner::Remote(remote) => {
+                remote.spawn_queued(continuation);
+                return true;
+            }
+            Spawner::Inline { .. } => {
+                self.spawner.spawn(continuation);
+                return true;
+            }
+        };
+        if local.need_preempt() {
+            local.core().record_migration();
+            local.spawn_remote(continuation);
+            return true;
+        }
+        if self.next.is_some() {
+            // Only one continuation is kept, later ones run after it.
+            local.spawn(continuation);
+        } else {
+            self.next = Some(Task::new_once(continuation));
+        }
+        false
+    }
+
+    /// Runs the continuations set by `reschedule`.
+    fn run_next(&mut self) {
+        while let Some(task) = self.next.take() {
+            match task {
+                Task::Once(r) => run_once(r, self),
+                Task::Mut(mut r) => r(self),
+            }
+        }
     }
 
     /// Sets whether this task should be rerun later.
@@ -128,20 +556,29 @@ impl Clone for Runner {
 impl crate::pool::Runner for Runner {
     type TaskCell = TaskCell;
 
+    fn start(&mut self, local: &mut Local<TaskCell>) {
+        set_recycle_cap(local.core().config().callback_recycle_cap);
+    }
+
     fn handle(&mut self, local: &mut Local<TaskCell>, mut task_cell: TaskCell) -> bool {
         let mut handle = Handle {
-            local,
+            spawner: Spawner::Local(local),
             rerun: false,
+            next: None,
         };
         match task_cell.task {
             Task::Mut(ref mut r) => {
+                // A weighted task reruns more times every time it's picked.
+                let weight = task_cell.extras.weight.max(1) as usize;
+                let max_rerun = (self.max_inplace_spin + 1) * weight - 1;
                 let mut rerun_times = 0;
                 loop {
                     r(&mut handle);
+                    handle.run_next();
                     if !handle.rerun {
                         return true;
                     }
-                    if rerun_times >= self.max_inplace_spin {
+                    if rerun_times >= max_rerun {
                         break;
                     }
                     rerun_times += 1;
@@ -149,7 +586,8 @@ impl crate::pool::Runner for Runner {
                 }
             }
             Task::Once(r) => {
-                r(&mut handle);
+                run_once(r, &mut handle);
+                handle.run_next();
                 return true;
             }
         }
@@ -161,9 +599,23 @@ impl crate::pool::Runner for Runner {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::pool::{build_spawn, Runner as _};
+    use crate::pool::{build_spawn, Runner as _, SchedConfig};
     use crate::queue::QueueType;
-    use std::sync::mpsc;
+    use std::sync::{mpsc, Arc, Mutex};
+
+    fn recycled_slots() -> usize {
+        RECYCLER.with(|r| r.borrow().slots.len())
+    }
+
+    fn in_slot(task: &Task) -> bool {
+        match task {
+            Task::Once(r) => {
+                let p = &**r as *const OnceFn as *mut OnceFn;
+                ptr::eq(p, SLOTTED.at(p as *mut u8))
+            }
+            Task::Mut(_) => false,
+        }
+    }
 
     #[test]
     fn test_once() {
@@ -233,4 +685,185 @@ mod tests {
         assert!(locals[0].pop().is_some());
         assert!(rx.recv().is_err());
     }
+
+    #[test]
+    fn test_spawn_with_result() {
+        let (_, mut locals) = build_spawn(QueueType::SingleLevel, Default::default());
+        let mut runner = Runner::default();
+        let (tx, rx) = mpsc::channel();
+        runner.handle(
+            &mut locals[0],
+            TaskCell {
+                task: Task::new_once(move |handle| {
+                    tx.send(handle.spawn_with_result(|_| (1..=10).sum::<u32>()))
+                        .unwrap();
+                    tx.send(handle.spawn_with_result(|_| 0)).unwrap();
+                }),
+                extras: Extras::single_level(),
+            },
+        );
+        let res1 = rx.recv().unwrap();
+        let res2 = rx.recv().unwrap();
+        // The local queue is LIFO, so the second task is handled first.
+        // Dropping it without running closes the result channel.
+        drop(locals[0].pop().unwrap());
+        assert!(res2.recv().is_err());
+        let t = locals[0].pop().unwrap().task_cell;
+        runner.handle(&mut locals[0], t);
+        assert_eq!(res1.recv().unwrap(), 55);
+    }
+
+    #[test]
+    fn test_recycle() {
+        let config = SchedConfig {
+            callback_recycle_cap: 2,
+            ..Default::default()
+        };
+        let (_, mut locals) = build_spawn(QueueType::SingleLevel, config);
+        let mut runner = Runner::default();
+        runner.start(&mut locals[0]);
+        assert_eq!(recycled_slots(), 0);
+
+        let (tx, rx) = mpsc::channel();
+        let tx1 = tx.clone();
+        let task = Task::new_once(move |handle| {
+            let tx2 = tx1.clone();
+            handle.spawn_once(move |_| tx2.send(2).unwrap(), Extras::single_level());
+            tx1.send(1).unwrap();
+        });
+        assert!(in_slot(&task));
+        runner.handle(
+            &mut locals[0],
+            TaskCell {
+                task,
+                extras: Extras::single_level(),
+            },
+        );
+        assert_eq!(rx.recv().unwrap(), 1);
+        assert_eq!(recycled_slots(), 1);
+        let t = locals[0].pop().unwrap().task_cell;
+        runner.handle(&mut locals[0], t);
+        assert_eq!(rx.recv().unwrap(), 2);
+        assert_eq!(recycled_slots(), 2);
+
+        // The number of kept slots is limited by the cap.
+        let tasks: Vec<_> = (3..6)
+            .map(|i| {
+                let tx = tx.clone();
+                Task::new_once(move |_| tx.send(i).unwrap())
+            })
+            .collect();
+        assert!(tasks.iter().all(in_slot));
+        assert_eq!(recycled_slots(), 0);
+        for task in tasks {
+            let extras = Extras::single_level();
+            runner.handle(&mut locals[0], TaskCell { task, extras });
+        }
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![3, 4, 5]);
+        assert_eq!(recycled_slots(), 2);
+
+        // Dropping a task without running it drops the closure, and its slot
+        // is freed.
+        let t = tx.clone();
+        let task = Task::new_once(move |_| t.send(6).unwrap());
+        assert!(in_slot(&task));
+        drop(task);
+        assert_eq!(recycled_slots(), 1);
+        drop(tx);
+        assert!(rx.recv().is_err());
+
+        // Large closures are boxed as usual.
+        let large = [0u64; SLOT_WORDS + 1];
+        let task = Task::new_once(move |_| assert_eq!(large.len(), SLOT_WORDS + 1));
+        assert!(!in_slot(&task));
+    }
+
+    #[test]
+    fn test_yield_now() {
+        fn process(
+            start: usize,
+            end: usize,
+            log: Arc<Mutex<Vec<usize>>>,
+        ) -> impl FnOnce(&mut Handle<'_>) + Send + 'static {
+            move |handle| {
+                let chunk_end = end.min(start + 10);
+                log.lock().unwrap().extend(start..chunk_end);
+                if chunk_end < end {
+                    handle.yield_now(process(chunk_end, end, log));
+                }
+            }
+        }
+
+        let (remote, mut locals) = build_spawn(QueueType::SingleLevel, Default::default());
+        let mut runner = Runner::default();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        remote.spawn(process(0, 30, log.clone()));
+        let l = log.clone();
+        remote.spawn(move |_: &mut Handle<'_>| l.lock().unwrap().push(100));
+        let l = log.clone();
+        remote.spawn(move |_: &mut Handle<'_>| l.lock().unwrap().push(200));
+        while let Some(t) = locals[0].pop() {
+            runner.handle(&mut locals[0], t.task_cell);
+        }
+
+        // Other tasks run between the chunks.
+        let expected: Vec<_> = (0..10)
+            .chain(Some(100))
+            .chain(Some(200))
+            .chain(10..30)
+            .collect();
+        assert_eq!(*log.lock().unwrap(), expected);
+    }
+
+    #[cfg_attr(not(feature = "failpoints"), ignore)]
+    #[test]
+    fn test_reschedule() {
+        fn process(
+            start: usize,
+            end: usize,
+            log: Arc<Mutex<Vec<usize>>>,
+        ) -> impl FnOnce(&mut Handle<'_>) + Send + 'static {
+            move |handle| {
+                let chunk_end = end.min(start + 10);
+                log.lock().unwrap().extend(start..chunk_end);
+                if chunk_end < end {
+                    handle.reschedule(process(chunk_end, end, log));
+                }
+            }
+        }
+
+        let _guard = fail::FailScenario::setup();
+        let (remote, mut locals) = build_spawn(QueueType::SingleLevel, Default::default());
+        let mut runner = Runner::default();
+        let run = |preempt: bool, locals: &mut Vec<Local<TaskCell>>, runner: &mut Runner| {
+            fail::cfg("need-preempt", &format!("return({})", preempt)).unwrap();
+            let log = Arc::new(Mutex::new(Vec::new()));
+            remote.spawn(process(0, 30, log.clone()));
+            let l = log.clone();
+            remote.spawn(move |_: &mut Handle<'_>| l.lock().unwrap().push(100));
+            let mut handled = 0;
+            while let Some(t) = locals[0].pop() {
+                runner.handle(&mut locals[0], t.task_cell);
+                handled += 1;
+            }
+            let log = log.lock().unwrap().clone();
+            (log, handled)
+        };
+
+        // Without other tasks to run, all chunks run in one go.
+        let (log, handled) = run(false, &mut locals, &mut runner);
+        let expected: Vec<_> = (0..30).chain(Some(100)).collect();
+        assert_eq!(log, expected);
+        assert_eq!(handled, 2);
+        #[cfg(feature = "metrics")]
+        assert_eq!(remote.core.migration_count(), 0);
+
+        // Otherwise the chunks are pushed to the global queue.
+        let (log, handled) = run(true, &mut locals, &mut runner);
+        let expected: Vec<_> = (0..10).chain(Some(100)).chain(10..30).collect();
+        assert_eq!(log, expected);
+        assert_eq!(handled, 4);
+        #[cfg(feature = "metrics")]
+        assert_eq!(remote.core.migration_count(), 2);
+    }
 }
@@ -1,9 +1,16 @@
 // Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
 
 //! A [`Future`].
+//!
+//! Wakers of the futures can be used from any thread, like the threads of an
+//! external reactor. A future woken up outside the workers of its pool is
+//! pushed to the global queue, and is dropped if the pool is gone.
 
-use crate::pool::{Local, WeakRemote};
-use crate::queue::{Extras, WithExtras};
+use crate::pool::{
+    CurrentTaskGuard, Local, Remote, SpawnPlacement, ThreadPool, WeakRemote, WokenTaskOrder,
+};
+use crate::queue::{multilevel, Extras, WithExtras};
+use crate::timer::{self, TimerHandle};
 
 use std::borrow::Cow;
 use std::cell::{Cell, UnsafeCell};
@@ -13,12 +20,14 @@ use std::mem::ManuallyDrop;
 use std::pin::Pin;
 use std::ptr::NonNull;
 use std::sync::atomic::{
-    AtomicU8, AtomicUsize,
+    AtomicBool, AtomicU8, AtomicUsize,
     Ordering::{Acquire, Relaxed, Release, SeqCst},
 };
-use std::sync::{atomic, Arc};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{atomic, Arc, Mutex};
 use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
-use std::{fmt, mem, ptr};
+use std::time::{Duration, Instant};
+use std::{error, fmt, mem, ptr, thread};
 
 /// The default repoll limit for a future runner. See `Runner::new` for
 /// details.
@@ -93,7 +102,7 @@ impl TaskCell {
         unsafe { &self.0.as_ref().status }
     }
 
-    fn extras(&self) -> &UnsafeCell<TaskExtras> {
+    fn task_extras(&self) -> &UnsafeCell<TaskExtras> {
         unsafe { &self.0.as_ref().extras }
     }
 
@@ -193,6 +202,12 @@ impl TaskCell {
         });
         unsafe { TaskCell(NonNull::new_unchecked(Box::into_raw(inner) as _)) }
     }
+
+    /// Gets the extra information of the task without mutable access, so
+    /// runners can inspect it before handling the task.
+    pub fn extras(&self) -> &Extras {
+        unsafe { &(*self.task_extras().get()).extras }
+    }
 }
 
 impl crate::queue::TaskCell for TaskCell {
@@ -287,7 +302,7 @@ unsafe fn wake_ref_raw(this: *const ()) {
 #[inline]
 unsafe fn clone_task(task: *const ()) -> TaskCell {
     let task_cell = TaskCell::from_raw(task);
-    let extras = &mut *task_cell.extras().get();
+    let extras = &mut *task_cell.task_extras().get();
     if extras.remote.is_none() {
         LOCAL.with(|l| {
             extras.remote = Some((*l.get()).weak_remote());
@@ -299,6 +314,7 @@ unsafe fn clone_task(task: *const ()) -> TaskCell {
 
 thread_local! {
     /// Local queue reference that is set before polling and unset after polled.
+    #[allow(clippy::missing_const_for_thread_local)]
     static LOCAL: Cell<*mut Local<TaskCell>> = Cell::new(std::ptr::null_mut());
 }
 
@@ -307,34 +323,67 @@ unsafe fn wake_task(task: Cow<'_, TaskCell>, reschedule: bool) {
         // `wake_task` is only called when the status of the task is IDLE. Before the
         // status is set to IDLE, the runtime will set `remote` in `TaskExtras`. So we
         // can make sure `remote` is not None.
-        let task_remote = (*task.extras().get())
+        let task_remote = (*task.task_extras().get())
             .remote
             .as_ref()
             .expect("core should exist!!!");
         let out_of_polling = ptr.get().is_null()
             || !ptr::eq(Arc::as_ptr((*ptr.get()).core()), task_remote.as_core_ptr());
         if out_of_polling {
-            // It's out of polling process, has to be spawn to global queue.
+            // It's out of polling process, for example woken up by a reactor
+            // thread or a worker of another pool, has to be spawn to global queue.
             // It needs to clone to make it safe as it's unclear whether `self`
             // is still used inside method `spawn` after `TaskCell` is dropped.
             if let Some(remote) = task_remote.upgrade() {
-                remote.spawn(task.clone().into_owned());
+                remote.core.record_migration();
+                remote.spawn_queued(woken(task.clone().into_owned()));
             }
         } else if reschedule {
-            // It's requested explicitly to schedule to global queue.
-            (*ptr.get()).spawn_remote(task.into_owned());
+            // It's requested explicitly to schedule to global queue. Pushing
+            // to its tail keeps the task behind the ones already queued.
+            let local = &*ptr.get();
+            local.core().record_migration();
+            local.spawn_remote(woken(task.into_owned()));
+        } else if (*ptr.get()).is_full() {
+            // The local queue is full, falls back to the global queue so the
+            // task is neither lost nor blocks the worker.
+            let local = &*ptr.get();
+            local.core().record_migration();
+            local.spawn_remote(woken(task.into_owned()));
         } else {
             // Otherwise spawns to local queue for best locality.
-            (*ptr.get()).spawn(task.into_owned());
+            let local = &mut *ptr.get();
+            let task = woken(task.into_owned());
+            match local.core().config().woken_task_order {
+                WokenTaskOrder::Spawned => local.spawn(task),
+                WokenTaskOrder::Front => local.spawn_urgent(task),
+                WokenTaskOrder::Back => local.spawn_remote(task),
+            }
         }
     })
 }
 
+/// Records when the task is woken up for the wakeup latency metric.
+fn woken(mut task: TaskCell) -> TaskCell {
+    if cfg!(feature = "metrics") {
+        use crate::queue::TaskCell as _;
+
+        task.mut_extras().wake_time = Some(Instant::now());
+    }
+    task
+}
+
+thread_local! {
+    /// The task being polled, it's set and unset along with `LOCAL`.
+    static CURRENT_TASK: Cell<*const ()> = const { Cell::new(std::ptr::null()) };
+}
+
 struct Scope<'a>(&'a mut Local<TaskCell>);
 
 impl<'a> Scope<'a> {
-    fn new(l: &'a mut Local<TaskCell>) -> Scope<'a> {
+    fn new(l: &'a mut Local<TaskCell>, task_cell: &TaskCell) -> Scope<'a> {
         LOCAL.with(|c| c.set(l));
+        CURRENT_TASK.with(|c| c.set(task_cell.as_raw()));
         Scope(l)
     }
 }
@@ -342,6 +391,30 @@ impl<'a> Scope<'a> {
 impl<'a> Drop for Scope<'a> {
     fn drop(&mut self) {
         LOCAL.with(|c| c.set(std::ptr::null_mut()));
+        CURRENT_TASK.with(|c| c.set(std::ptr::null()));
+    }
+}
+
+/// Makes the task polled by `Remote::spawn_eager` the current task, and
+/// restores the one being polled by the worker, if any, when dropped.
+struct EagerScope {
+    prev_task: *const (),
+    prev_reschedule: bool,
+}
+
+impl EagerScope {
+    fn new(task_cell: &TaskCell) -> EagerScope {
+        EagerScope {
+            prev_task: CURRENT_TASK.with(|c| c.replace(task_cell.as_raw())),
+            prev_reschedule: NEED_RESCHEDULE.with(|r| r.replace(false)),
+        }
+    }
+}
+
+impl Drop for EagerScope {
+    fn drop(&mut self) {
+        CURRENT_TASK.with(|c| c.set(self.prev_task));
+        NEED_RESCHEDULE.with(|r| r.set(self.prev_reschedule));
     }
 }
 
@@ -364,12 +437,14 @@ impl Runner {
     ///
     /// `repoll_limit` is the maximum times a [`Future`] is polled again
     /// immediately after polling because of being waken up during polling.
+    /// Tasks can override it by [`Extras::set_repoll_limit`].
     pub fn new(repoll_limit: usize) -> Self {
         Self { repoll_limit }
     }
 }
 
 thread_local! {
+    #[allow(clippy::missing_const_for_thread_local)]
     static NEED_RESCHEDULE: Cell<bool> = Cell::new(false);
 }
 
@@ -377,18 +452,24 @@ impl crate::pool::Runner for Runner {
     type TaskCell = TaskCell;
 
     fn handle(&mut self, local: &mut Local<TaskCell>, task_cell: TaskCell) -> bool {
-        let scope = Scope::new(local);
+        let scope = Scope::new(local, &task_cell);
         unsafe {
             let waker_ref = WakerRef::new(&task_cell);
             let mut cx = waker_ref.to_context();
+            let (repoll_limit, weight) = {
+                let extras = &(*task_cell.task_extras().get()).extras;
+                let limit = extras.repoll_limit.unwrap_or(self.repoll_limit);
+                (limit, extras.weight.max(1))
+            };
             let mut repoll_times = 0;
+            let mut slices = 1;
             loop {
                 task_cell.status().store(POLLING, SeqCst);
                 if task_cell.poll(&mut cx).is_ready() {
                     task_cell.status().store(COMPLETED, SeqCst);
                     return true;
                 }
-                let extras = { &mut *task_cell.extras().get() };
+                let extras = { &mut *task_cell.task_extras().get() };
                 if extras.remote.is_none() {
                     // It's possible to avoid assigning remote in some cases, but it requires
                     // at least one atomic load to detect such situation. So here just assign
@@ -404,9 +485,17 @@ impl crate::pool::Runner for Runner {
                     Ok(_) => return false,
                     Err(NOTIFIED) => {
                         let need_reschedule = NEED_RESCHEDULE.with(|r| r.replace(false));
-                        if (repoll_times >= self.repoll_limit || need_reschedule)
-                            && scope.0.need_preempt()
-                        {
+                        let limit_reached = repoll_times >= repoll_limit;
+                        let yielding = (limit_reached || need_reschedule) && scope.0.need_preempt();
+                        if yielding && slices < weight {
+                            // A weighted task runs more slices every time
+                            // it's picked.
+                            slices += 1;
+                            repoll_times = 0;
+                        } else if yielding {
+                            if limit_reached {
+                                scope.0.core().record_repoll_limit_reached();
+                            }
                             wake_task(Cow::Owned(task_cell), need_reschedule);
                             return false;
                         } else {
@@ -420,13 +509,148 @@ impl crate::pool::Runner for Runner {
     }
 }
 
+/// Spawns a future to the current worker's local queue.
+///
+/// It's the fast path for spawning children inside a future running in yatp:
+/// the future is pushed to the local queue directly without going through
+/// the global queue, which is cheaper than `Remote::spawn` and keeps the
+/// child on the same worker for best locality. The local queue can still be
+/// stolen by idle workers. Callback tasks get the same with
+/// [`Handle::spawn`](crate::task::callback::Handle::spawn).
+///
+/// # Panics
+///
+/// Panics if it's not called inside a future running in yatp.
+pub fn spawn(f: impl Future<Output = ()> + Send + 'static) {
+    LOCAL.with(|l| {
+        let local = l.get();
+        assert!(
+            !local.is_null(),
+            "spawn must be called inside a future running in yatp"
+        );
+        unsafe {
+            (*local).core().record_spawn_rate();
+            (*local).spawn(f)
+        }
+    })
+}
+
+/// Spawns a future to the front of the current worker's local queue, so it
+/// will be the next task handled by the worker.
+///
+/// It's an escape hatch for cancellation or cleanup work that must not wait
+/// behind tasks already queued. Keep spawning urgent tasks can starve other
+/// tasks of the worker, so use it sparingly.
+///
+/// # Panics
+///
+/// Panics if it's not called inside a future running in yatp.
+pub fn spawn_urgent(f: impl Future<Output = ()> + Send + 'static) {
+    LOCAL.with(|l| {
+        let local = l.get();
+        assert!(
+            !local.is_null(),
+            "spawn_urgent must be called inside a future running in yatp"
+        );
+        unsafe {
+            (*local).core().record_spawn_rate();
+            (*local).spawn_urgent(f)
+        }
+    })
+}
+
+/// Calls `f` with the scratch value of type `S` of the current worker, see
+/// [`Local::with_scratch`].
+///
+/// Only one task runs on a worker at a time, so the value is never shared.
+/// A nested call for the same type gets a separate value, and the outer one
+/// wins when both are put back.
+///
+/// # Panics
+///
+/// Panics if it's not called inside a future running in yatp.
+pub fn with_worker_scratch<S, R>(f: impl FnOnce(&mut S) -> R) -> R
+where
+    S: Default + Send + 'static,
+{
+    let local = LOCAL.with(|l| l.get());
+    assert!(
+        !local.is_null(),
+        "with_worker_scratch must be called inside a future running in yatp"
+    );
+    // The `Local` is not borrowed while `f` runs, so `f` can use it again.
+    let mut scratch = unsafe { (*local).take_scratch::<S>() };
+    let res = f(&mut scratch);
+    unsafe { (*local).put_scratch(scratch) };
+    res
+}
+
+/// Checks whether the current thread is a yatp worker polling a future.
+///
+/// Blocking on a future from a worker may deadlock the pool, helpers like
+/// `block_on` can assert on this to panic early instead.
+pub fn in_worker_thread() -> bool {
+    LOCAL.with(|l| !l.get().is_null())
+}
+
+/// Returns the level of the multilevel queue the current task is scheduled
+/// from.
+///
+/// The level is updated every time the task is pushed to the queue, so it's
+/// useful for checking whether the task has been demoted. Returns `None` if
+/// it's not called inside a future running in a multilevel pool.
+pub fn current_task_level() -> Option<u8> {
+    let local = LOCAL.with(|l| l.get());
+    if local.is_null() || !unsafe { (*local).is_multilevel() } {
+        return None;
+    }
+    let task = CURRENT_TASK.with(|t| t.get());
+    // The task is alive as it's being polled.
+    let task_cell = ManuallyDrop::new(unsafe { TaskCell::from_raw(task) });
+    Some(unsafe { (*task_cell.task_extras().get()).extras.current_level })
+}
+
 /// Gives up a time slice to the task scheduler.
 ///
+/// If there are other tasks to run, the task is pushed to the tail of the
+/// global queue, so it runs after all the tasks spawned before it yields.
+/// Tasks that keep rescheduling hence take turns with new tasks instead of
+/// starving them. With nothing else to run, the task is polled again in
+/// place.
+///
 /// It is only guaranteed to work in yatp.
 pub async fn reschedule() {
     Reschedule { first_poll: true }.await
 }
 
+/// Moves the current task to `level` of the multilevel queue, then gives up a
+/// time slice like [`reschedule`].
+///
+/// The task stays at the level from then on, as if it's spawned with the
+/// level fixed. If it's polled again in place as there is nothing else to
+/// run, the time it runs from then on is accounted to the new level. Levels
+/// beyond the last one are clamped to it. The level is ignored if it's not
+/// called inside a future running in a multilevel pool.
+pub async fn reschedule_with_priority(level: u8) {
+    set_current_level(level);
+    reschedule().await
+}
+
+fn set_current_level(level: u8) {
+    let local = LOCAL.with(|l| l.get());
+    if local.is_null() || !unsafe { (*local).is_multilevel() } {
+        return;
+    }
+    let level = level.min(multilevel::LEVEL_NUM as u8 - 1);
+    let task = CURRENT_TASK.with(|t| t.get());
+    // The task is alive as it's being polled.
+    let task_cell = ManuallyDrop::new(unsafe { TaskCell::from_raw(task) });
+    let extras = unsafe { &mut (*task_cell.task_extras().get()).extras };
+    extras.fixed_level = Some(level);
+    extras.current_level = level;
+    multilevel::switch_level(level);
+}
+
 struct Reschedule {
     first_poll: bool,
 }
@@ -448,10 +672,500 @@ impl Future for Reschedule {
     }
 }
 
+/// The error returned when a future spawned by [`Remote::spawn_timeout`] is
+/// cancelled because it doesn't finish in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimedOut;
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        "future timed out".fmt(f)
+    }
+}
+
+impl error::Error for TimedOut {}
+
+/// A future that is dropped once its deadline is exceeded.
+struct Timeout<F> {
+    fut: Option<Pin<Box<F>>>,
+    deadline: Instant,
+    timer: Option<TimerHandle>,
+    tx: SyncSender<Result<(), TimedOut>>,
+}
+
+impl<F: Future<Output = ()>> Future for Timeout<F> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = &mut *self;
+        let fut = match this.fut.as_mut() {
+            Some(fut) => fut,
+            None => return Poll::Ready(()),
+        };
+        let res = if Instant::now() >= this.deadline {
+            Err(TimedOut)
+        } else if fut.as_mut().poll(cx).is_ready() {
+            Ok(())
+        } else {
+            if this.timer.is_none() {
+                this.timer = Some(timer::register(this.deadline, cx.waker().clone()));
+            }
+            return Poll::Pending;
+        };
+        this.fut = None;
+        this.timer = None;
+        // The receiver may have been dropped if the result is not needed.
+        let _ = this.tx.send(res);
+        Poll::Ready(())
+    }
+}
+
+/// Shared state of a batch of futures spawned by [`Remote::spawn_many_join`].
+struct JoinState {
+    /// The number of unfinished futures, plus 1 while the batch is being
+    /// spawned.
+    remaining: AtomicUsize,
+    wakers: Mutex<Vec<Waker>>,
+}
+
+impl JoinState {
+    fn done(&self) {
+        if self.remaining.fetch_sub(1, SeqCst) == 1 {
+            for w in mem::take(&mut *self.wakers.lock().unwrap()) {
+                w.wake();
+            }
+        }
+    }
+}
+
+/// Marks a future of the batch as finished when dropped, so a future dropped
+/// without finishing doesn't block the batch forever.
+struct JoinGuard(Arc<JoinState>);
+
+impl Drop for JoinGuard {
+    fn drop(&mut self) {
+        self.0.done();
+    }
+}
+
+/// A future that resolves once all futures of a batch spawned by
+/// [`Remote::spawn_many_join`] finish.
+///
+/// Dropping it doesn't cancel the futures.
+pub struct Join {
+    state: Arc<JoinState>,
+}
+
+impl Future for Join {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.state.remaining.load(SeqCst) == 0 {
+            return Poll::Ready(());
+        }
+        self.state.wakers.lock().unwrap().push(cx.waker().clone());
+        // Check again in case the last future finishes before the waker is
+        // registered.
+        if self.state.remaining.load(SeqCst) == 0 {
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
+
+/// The error returned by a [`JoinHandle`] when its future is aborted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Aborted;
+
+impl fmt::Display for Aborted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        "future aborted".fmt(f)
+    }
+}
+
+impl error::Error for Aborted {}
+
+/// How a future spawned by [`Remote::spawn_with_finalizer`] ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Completion {
+    /// The future ran to completion.
+    Done,
+    /// The future panicked when being polled.
+    Panicked,
+    /// The future was dropped without finishing, because it was aborted or
+    /// the pool was shut down.
+    Cancelled,
+}
+
+/// Runs the finalizer when dropped if the future hasn't finished.
+struct Finalizer<G: FnOnce(Completion)>(Option<G>);
+
+impl<G: FnOnce(Completion)> Finalizer<G> {
+    fn finish(&mut self, completion: Completion) {
+        if let Some(g) = self.0.take() {
+            g(completion);
+        }
+    }
+}
+
+impl<G: FnOnce(Completion)> Drop for Finalizer<G> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.finish(Completion::Panicked);
+        } else {
+            self.finish(Completion::Cancelled);
+        }
+    }
+}
+
+struct AbortState {
+    aborted: AtomicBool,
+    /// The waker of the task, woken up to drop the future once aborted.
+    waker: Mutex<Option<Waker>>,
+}
+
+/// Aborts a future spawned by [`Remote::spawn_with_abort`].
+#[derive(Clone)]
+pub struct AbortHandle(Arc<AbortState>);
+
+impl AbortHandle {
+    /// Aborts the future. It's dropped the next time it's polled, and the
+    /// task is woken up so that happens soon.
+    ///
+    /// It's a no-op if the future has finished.
+    pub fn abort(&self) {
+        self.0.aborted.store(true, SeqCst);
+        if let Some(w) = self.0.waker.lock().unwrap().take() {
+            w.wake();
+        }
+    }
+}
+
+struct JoinHandleState<R> {
+    result: Option<Result<R, Aborted>>,
+    waker: Option<Waker>,
+}
+
+/// A future that resolves to the output of a future spawned by
+/// [`Remote::spawn_with_abort`].
+///
+/// Dropping it doesn't cancel the future.
+pub struct JoinHandle<R>(Arc<Mutex<JoinHandleState<R>>>);
+
+impl<R> Future for JoinHandle<R> {
+    type Output = Result<R, Aborted>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut state = self.0.lock().unwrap();
+        match state.result.take() {
+            Some(res) => Poll::Ready(res),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// A future that is dropped once aborted by its [`AbortHandle`].
+struct Abortable<F: Future> {
+    fut: Option<Pin<Box<F>>>,
+    abort: Arc<AbortState>,
+    join: Arc<Mutex<JoinHandleState<F::Output>>>,
+}
+
+impl<F: Future> Abortable<F> {
+    fn finish(&mut self, res: Result<F::Output, Aborted>) {
+        self.fut = None;
+        self.abort.waker.lock().unwrap().take();
+        let mut state = self.join.lock().unwrap();
+        state.result = Some(res);
+        if let Some(w) = state.waker.take() {
+            w.wake();
+        }
+    }
+}
+
+impl<F: Future> Future for Abortable<F> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = &mut *self;
+        let fut = match this.fut.as_mut() {
+            Some(fut) => fut,
+            None => return Poll::Ready(()),
+        };
+        // Registers the waker before checking the flag, so an abort either is
+        // seen here or wakes up the task again.
+        *this.abort.waker.lock().unwrap() = Some(cx.waker().clone());
+        let res = if this.abort.aborted.load(SeqCst) {
+            Err(Aborted)
+        } else if let Poll::Ready(v) = fut.as_mut().poll(cx) {
+            Ok(v)
+        } else {
+            return Poll::Pending;
+        };
+        this.finish(res);
+        Poll::Ready(())
+    }
+}
+
+impl<F: Future> Drop for Abortable<F> {
+    fn drop(&mut self) {
+        // Dropped without finishing, for example because the pool is shut
+        // down.
+        if self.fut.is_some() {
+            self.finish(Err(Aborted));
+        }
+    }
+}
+
+/// A future that resolves to the output of the first future spawned by
+/// [`Remote::spawn_race`] to finish.
+///
+/// Dropping it aborts the futures that are still running.
+pub struct Race<R> {
+    racers: Vec<(JoinHandle<R>, AbortHandle)>,
+}
+
+impl<R> Future for Race<R> {
+    type Output = Result<R, Aborted>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut i = 0;
+        while i < self.racers.len() {
+            match Pin::new(&mut self.racers[i].0).poll(cx) {
+                Poll::Ready(Ok(v)) => {
+                    for (_, abort) in self.racers.drain(..) {
+                        abort.abort();
+                    }
+                    return Poll::Ready(Ok(v));
+                }
+                Poll::Ready(Err(Aborted)) => {
+                    self.racers.swap_remove(i);
+                }
+                Poll::Pending => i += 1,
+            }
+        }
+        if self.racers.is_empty() {
+            Poll::Ready(Err(Aborted))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<R> Drop for Race<R> {
+    fn drop(&mut self) {
+        for (_, abort) in &self.racers {
+            abort.abort();
+        }
+    }
+}
+
+/// A boxed future that can be spawned by [`Remote::spawn_boxed`].
+pub type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+impl ThreadPool<TaskCell> {
+    /// Runs `f` once on every worker thread with the index of the worker,
+    /// and blocks until all of them finish.
+    ///
+    /// It's useful to initialize per worker resources after the pool starts.
+    /// It must not be called from a worker of the pool, which would wait for
+    /// itself.
+    pub fn broadcast(&self, f: impl Fn(usize) + Send + Sync + Clone + 'static) {
+        self.broadcast_by(f, |g| async move { g() });
+    }
+}
+
+impl Remote<TaskCell> {
+    /// Spawns a future and returns where it's placed.
+    ///
+    /// Unlike `spawn`, which always goes through the global queue, it spawns
+    /// the future to the local queue of the current worker when it's called
+    /// inside a future running in the same pool, the same way woken up tasks
+    /// are scheduled. It's useful for checking the locality of tasks.
+    pub fn spawn_reporting(&self, f: impl Future<Output = ()> + Send + 'static) -> SpawnPlacement {
+        let local = LOCAL.with(|l| l.get());
+        if !local.is_null() {
+            let local = unsafe { &mut *local };
+            if Arc::ptr_eq(local.core(), &self.core) {
+                local.core().record_spawn_rate();
+                return local.spawn_placed(f);
+            }
+        }
+        self.spawn_placed(f)
+    }
+
+    /// Spawns a future after polling it once on the current thread.
+    ///
+    /// If the future is ready in the first poll, it never goes through the
+    /// queue, which saves the round trip of scheduling futures that usually
+    /// finish immediately, like the ones reading a cached value. Otherwise
+    /// the pending task is scheduled as usual: it's queued right away if it
+    /// woke itself up during the poll, or by its waker later.
+    ///
+    /// The first poll runs on the calling thread, which may not be a worker
+    /// of the pool, so it should be cheap.
+    pub fn spawn_eager(&self, f: impl Future<Output = ()> + Send + 'static) {
+        if self.core.is_draining() {
+            return;
+        }
+        let mut task_cell = f.with_extras(|| self.core.default_extras());
+        // Wakers cloned during the poll can't take the remote from the
+        // current worker, which may not exist or belong to another pool.
+        unsafe { (*task_cell.task_extras().get()).remote = Some(self.downgrade()) };
+        let ready = {
+            let _current = CurrentTaskGuard::new(&mut task_cell);
+            let _scope = EagerScope::new(&task_cell);
+            let waker_ref = WakerRef::new(&task_cell);
+            let mut cx = waker_ref.to_context();
+            task_cell.status().store(POLLING, SeqCst);
+            unsafe { task_cell.poll(&mut cx) }.is_ready()
+        };
+        if ready {
+            task_cell.status().store(COMPLETED, SeqCst);
+            return;
+        }
+        match task_cell
+            .status()
+            .compare_exchange(POLLING, IDLE, SeqCst, SeqCst)
+        {
+            Ok(_) => {}
+            Err(NOTIFIED) => self.spawn_queued(task_cell),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Spawns a boxed future.
+    ///
+    /// The box is moved into the task as is, so the future is not boxed
+    /// again. It's convenient for executors dispatching futures dynamically.
+    pub fn spawn_boxed(&self, fut: BoxFuture) {
+        self.spawn(fut);
+    }
+
+    /// Spawns a batch of futures and returns a future that resolves once all
+    /// of them finish.
+    ///
+    /// Futures that are dropped without finishing, for example because the
+    /// pool is shut down, are also considered finished.
+    pub fn spawn_many_join<I, F>(&self, futures: I) -> Join
+    where
+        I: IntoIterator<Item = F>,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let state = Arc::new(JoinState {
+            remaining: AtomicUsize::new(1),
+            wakers: Mutex::new(Vec::new()),
+        });
+        for f in futures {
+            state.remaining.fetch_add(1, SeqCst);
+            let guard = JoinGuard(state.clone());
+            self.spawn(async move {
+                let _guard = guard;
+                f.await
+            });
+        }
+        state.done();
+        Join { state }
+    }
+
+    /// Spawns a future that is cancelled if it doesn't finish within `dur`,
+    /// and returns a receiver for whether it finished in time.
+    ///
+    /// A timed out future is dropped the next time it's polled, which happens
+    /// soon after the deadline as the task is woken up then. If the task is
+    /// dropped without finishing, for example because the pool is shut down,
+    /// receiving from the receiver returns an error.
+    pub fn spawn_timeout(
+        &self,
+        dur: Duration,
+        f: impl Future<Output = ()> + Send + 'static,
+    ) -> Receiver<Result<(), TimedOut>> {
+        let (tx, rx) = mpsc::sync_channel(1);
+        self.spawn(Timeout {
+            fut: Some(Box::pin(f)),
+            deadline: Instant::now() + dur,
+            timer: None,
+            tx,
+        });
+        rx
+    }
+
+    /// Spawns a future and returns a handle to its output along with a handle
+    /// to abort it.
+    ///
+    /// An aborted future is dropped the next time it's polled and the join
+    /// handle resolves to `Err(Aborted)`. So does it if the future is dropped
+    /// without finishing, for example because the pool is shut down.
+    pub fn spawn_with_abort<F>(&self, f: F) -> (JoinHandle<F::Output>, AbortHandle)
+    where
+        F: Future + Send + 'static,
+        F::Output: Send,
+    {
+        let abort = Arc::new(AbortState {
+            aborted: AtomicBool::new(false),
+            waker: Mutex::new(None),
+        });
+        let join = Arc::new(Mutex::new(JoinHandleState {
+            result: None,
+            waker: None,
+        }));
+        self.spawn(Abortable {
+            fut: Some(Box::pin(f)),
+            abort: abort.clone(),
+            join: join.clone(),
+        });
+        (JoinHandle(join), AbortHandle(abort))
+    }
+
+    /// Spawns a future along with a finalizer that runs exactly once after
+    /// the future ends, and returns a handle to abort the future.
+    ///
+    /// The finalizer is told how the future ended. A panicking future is
+    /// dropped while unwinding, the finalizer runs then on the worker thread
+    /// before the panic goes on. An aborted future, or one dropped because
+    /// the pool is shut down, is reported as `Completion::Cancelled`.
+    pub fn spawn_with_finalizer<F, G>(&self, f: F, finalizer: G) -> AbortHandle
+    where
+        F: Future<Output = ()> + Send + 'static,
+        G: FnOnce(Completion) + Send + 'static,
+    {
+        let mut guard = Finalizer(Some(finalizer));
+        let (_, abort) = self.spawn_with_abort(async move {
+            f.await;
+            guard.finish(Completion::Done);
+        });
+        abort
+    }
+
+    /// Spawns the futures and returns a future that resolves to the output of
+    /// the first one to finish. The others are aborted then, as if by
+    /// [`AbortHandle::abort`].
+    ///
+    /// It resolves to `Err(Aborted)` if there is no future or all of them are
+    /// dropped without finishing.
+    pub fn spawn_race<I, F>(&self, futures: I) -> Race<F::Output>
+    where
+        I: IntoIterator<Item = F>,
+        F: Future + Send + 'static,
+        F::Output: Send,
+    {
+        let racers = futures
+            .into_iter()
+            .map(|f| self.spawn_with_abort(f))
+            .collect();
+        Race { racers }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::pool::{build_spawn, Builder, Remote, Runner as _};
+    use crate::pool::{
+        build_spawn, Builder, Remote, Runner as _, SchedConfig, SpawnPlacement, WokenTaskOrder,
+    };
     use crate::queue::QueueType;
 
     use std::sync::mpsc;
@@ -535,8 +1249,12 @@ mod tests {
         assert!(res_rx.try_recv().is_err());
 
         let waker = waker_rx.recv().unwrap();
+        assert_eq!(local.remote.core.migration_count(), 0);
         f(waker);
         assert!(res_rx.try_recv().is_err());
+        // Woken up out of polling, so it's pushed to the global queue.
+        #[cfg(feature = "metrics")]
+        assert_eq!(local.remote.core.migration_count(), 1);
         local.handle_once();
         assert_eq!(res_rx.recv().unwrap(), 2);
     }
@@ -552,10 +1270,46 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::waker_clone_wake)]
     fn test_waker_clone() {
         test_wake_impl(|waker| waker.clone().wake());
     }
 
+    #[test]
+    fn test_wake_from_foreign_thread() {
+        let pool = Builder::new("test_wake_from_foreign_thread")
+            .max_thread_count(1)
+            .build_future_pool();
+        let (res_tx, res_rx) = mpsc::channel();
+        let (waker_tx, waker_rx) = mpsc::sync_channel(10);
+        pool.spawn(async move {
+            WakeLater::new(waker_tx).await;
+            res_tx
+                .send(thread::current().name().map(String::from))
+                .unwrap();
+        });
+        let waker = waker_rx.recv_timeout(Duration::from_secs(3)).unwrap();
+        assert!(res_rx.try_recv().is_err());
+
+        // Acts like a reactor thread that never runs any tasks of the pool.
+        thread::spawn(move || {
+            assert!(!in_worker_thread());
+            waker.wake();
+        })
+        .join()
+        .unwrap();
+        let name = res_rx
+            .recv_timeout(Duration::from_secs(3))
+            .unwrap()
+            .unwrap();
+        assert!(
+            name.starts_with("test_wake_from_foreign_thread"),
+            "{}",
+            name
+        );
+        pool.shutdown();
+    }
+
     struct PendingOnce {
         first_poll: bool,
     }
@@ -579,6 +1333,361 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_spawn_boxed() {
+        let pool = Builder::new("test_spawn_boxed")
+            .max_thread_count(1)
+            .build_future_pool();
+        let (tx, rx) = mpsc::channel();
+        let futures: Vec<BoxFuture> = (0..3)
+            .map(|i| {
+                let tx = tx.clone();
+                if i % 2 == 0 {
+                    Box::pin(async move { tx.send(i).unwrap() }) as BoxFuture
+                } else {
+                    Box::pin(async move {
+                        futures_timer::Delay::new(Duration::from_millis(10)).await;
+                        tx.send(i).unwrap()
+                    })
+                }
+            })
+            .collect();
+        for f in futures {
+            pool.remote().spawn_boxed(f);
+        }
+        let mut res: Vec<_> = (0..3)
+            .map(|_| rx.recv_timeout(Duration::from_secs(1)).unwrap())
+            .collect();
+        res.sort_unstable();
+        assert_eq!(res, [0, 1, 2]);
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_spawn_with_abort() {
+        struct DropGuard(Arc<AtomicUsize>);
+
+        impl Drop for DropGuard {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, SeqCst);
+            }
+        }
+
+        let pool = Builder::new("test_spawn_with_abort")
+            .max_thread_count(2)
+            .build_future_pool();
+        let remote = pool.remote();
+        let (tx, rx) = mpsc::channel();
+
+        let (join, abort) = remote.spawn_with_abort(async { 1 });
+        let t = tx.clone();
+        pool.spawn(async move { t.send(join.await).unwrap() });
+        assert_eq!(rx.recv_timeout(Duration::from_secs(3)).unwrap(), Ok(1));
+        // Aborting a finished future is a no-op.
+        abort.abort();
+
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let guard = DropGuard(dropped.clone());
+        let (join, abort) = remote.spawn_with_abort(async move {
+            let _guard = guard;
+            std::future::pending::<()>().await;
+            0
+        });
+        pool.spawn(async move { tx.send(join.await).unwrap() });
+        thread::sleep(Duration::from_millis(50));
+        assert!(rx.try_recv().is_err());
+        assert_eq!(dropped.load(SeqCst), 0);
+        let a = abort.clone();
+        thread::spawn(move || a.abort()).join().unwrap();
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(3)).unwrap(),
+            Err(Aborted)
+        );
+        assert_eq!(dropped.load(SeqCst), 1);
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_spawn_with_finalizer() {
+        let mut local = MockLocal::default();
+        let (tx, rx) = mpsc::channel();
+
+        let t = tx.clone();
+        local
+            .remote
+            .spawn_with_finalizer(async {}, move |c| t.send(c).unwrap());
+        assert!(rx.try_recv().is_err());
+        local.handle_once();
+        assert_eq!(rx.try_recv(), Ok(Completion::Done));
+        assert!(rx.try_recv().is_err());
+
+        let t = tx.clone();
+        local
+            .remote
+            .spawn_with_finalizer(async { panic!("task panics") }, move |c| t.send(c).unwrap());
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| local.handle_once()));
+        assert!(res.is_err());
+        assert_eq!(rx.try_recv(), Ok(Completion::Panicked));
+
+        let abort = local
+            .remote
+            .spawn_with_finalizer(std::future::pending(), move |c| tx.send(c).unwrap());
+        local.handle_once();
+        assert!(rx.try_recv().is_err());
+        abort.abort();
+        local.handle_once();
+        assert_eq!(rx.try_recv(), Ok(Completion::Cancelled));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_weight() {
+        let pool = Builder::new("test_weight")
+            .max_thread_count(1)
+            .build_future_pool();
+        let counts = Arc::new([AtomicUsize::new(0), AtomicUsize::new(0)]);
+        let (tx, rx) = mpsc::channel();
+        for (i, weight) in [1, 3].iter().copied().enumerate() {
+            let (counts, tx) = (counts.clone(), tx.clone());
+            let extras = crate::queue::ExtrasBuilder::single_level().weight(weight);
+            pool.remote().spawn_with_extras(
+                async move {
+                    // Both tasks keep yielding until they have done enough
+                    // work together.
+                    while counts.iter().map(|c| c.load(SeqCst)).sum::<usize>() < 400 {
+                        counts[i].fetch_add(1, SeqCst);
+                        reschedule().await;
+                    }
+                    tx.send(()).unwrap();
+                },
+                extras,
+            );
+        }
+        for _ in 0..2 {
+            rx.recv_timeout(Duration::from_secs(3)).unwrap();
+        }
+        pool.shutdown();
+        let (light, heavy) = (counts[0].load(SeqCst), counts[1].load(SeqCst));
+        let ratio = heavy as f64 / light as f64;
+        assert!((2.5..3.5).contains(&ratio), "{} {}", light, heavy);
+    }
+
+    #[test]
+    fn test_with_worker_scratch() {
+        let pool = Builder::new("test_with_worker_scratch")
+            .max_thread_count(1)
+            .build_future_pool();
+        let (tx, rx) = mpsc::channel();
+        for i in 0..2 {
+            let tx = tx.clone();
+            pool.spawn(async move {
+                let buf = with_worker_scratch(|buf: &mut Vec<u32>| {
+                    buf.push(i);
+                    buf.clone()
+                });
+                tx.send(buf).unwrap();
+            });
+            let buf = rx.recv_timeout(Duration::from_secs(3)).unwrap();
+            assert_eq!(buf, (0..=i).collect::<Vec<_>>());
+        }
+        pool.shutdown();
+
+        let res = std::panic::catch_unwind(|| with_worker_scratch(|_: &mut Vec<u32>| {}));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_spawn_handle() {
+        let (pool, handle) = Builder::new("test_spawn_handle")
+            .max_thread_count(1)
+            .build_future_pool_with_handle();
+        let (tx, rx) = mpsc::channel();
+        let h = handle.clone();
+        let t = tx.clone();
+        handle.spawn(async move {
+            t.send(1).unwrap();
+            h.spawn(async move { t.send(2).unwrap() });
+        });
+        handle.spawn_with_extras(async move { tx.send(3).unwrap() }, Extras::single_level());
+        let mut res: Vec<_> = (0..3)
+            .map(|_| rx.recv_timeout(Duration::from_secs(3)).unwrap())
+            .collect();
+        res.sort_unstable();
+        assert_eq!(res, vec![1, 2, 3]);
+
+        // Only the owner can shut the pool down, the handle can outlive it.
+        pool.shutdown();
+        handle.spawn(async {});
+    }
+
+    #[test]
+    fn test_spawn_reporting() {
+        let pool = Builder::new("test_spawn_reporting")
+            .max_thread_count(2)
+            .build_future_pool();
+        let remote = pool.remote().clone();
+        let (tx, rx) = mpsc::channel();
+
+        assert_eq!(remote.spawn_reporting(async {}), SpawnPlacement::Global);
+        let r = remote.clone();
+        pool.spawn(async move {
+            let placement = r.spawn_reporting(async {});
+            tx.send(placement).unwrap();
+        });
+        match rx.recv_timeout(Duration::from_secs(3)).unwrap() {
+            SpawnPlacement::Local(index) => assert!(index < 2),
+            placement => panic!("unexpected placement {:?}", placement),
+        }
+        // Futures of other pools are spawned to their own queues.
+        let other = Builder::new("test_spawn_reporting_other")
+            .max_thread_count(1)
+            .build_future_pool();
+        let (tx, rx) = mpsc::channel();
+        other.spawn(async move {
+            tx.send(remote.spawn_reporting(async {})).unwrap();
+        });
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(3)).unwrap(),
+            SpawnPlacement::Global
+        );
+        other.shutdown();
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_spawn_race() {
+        struct DropGuard(mpsc::Sender<()>);
+
+        impl Drop for DropGuard {
+            fn drop(&mut self) {
+                let _ = self.0.send(());
+            }
+        }
+
+        let pool = Builder::new("test_spawn_race")
+            .max_thread_count(2)
+            .build_future_pool();
+        let remote = pool.remote();
+        let (tx, rx) = mpsc::channel();
+        let (drop_tx, drop_rx) = mpsc::channel();
+
+        let guard = DropGuard(drop_tx);
+        let slow = async move {
+            let _guard = guard;
+            futures_timer::Delay::new(Duration::from_secs(10)).await;
+            0
+        };
+        let fast = async {
+            futures_timer::Delay::new(Duration::from_millis(10)).await;
+            1
+        };
+        let futures: Vec<Pin<Box<dyn Future<Output = i32> + Send>>> =
+            vec![Box::pin(slow), Box::pin(fast)];
+        let race = remote.spawn_race(futures);
+        pool.spawn(async move { tx.send(race.await).unwrap() });
+        assert_eq!(rx.recv_timeout(Duration::from_secs(3)).unwrap(), Ok(1));
+        // The slow future is cancelled instead of running to the end.
+        drop_rx.recv_timeout(Duration::from_secs(3)).unwrap();
+
+        let race = remote.spawn_race(Vec::<futures_timer::Delay>::new());
+        let (tx, rx) = mpsc::channel();
+        pool.spawn(async move { tx.send(race.await).unwrap() });
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(3)).unwrap(),
+            Err(Aborted)
+        );
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_spawn_many_join() {
+        let pool = Builder::new("test_spawn_many_join")
+            .max_thread_count(2)
+            .build_future_pool();
+        let count = Arc::new(AtomicUsize::new(0));
+        let futures: Vec<_> = (0..10)
+            .map(|i| {
+                let count = count.clone();
+                async move {
+                    futures_timer::Delay::new(Duration::from_millis(i * 2)).await;
+                    count.fetch_add(1, SeqCst);
+                }
+            })
+            .collect();
+        let join = pool.remote().spawn_many_join(futures);
+        let (tx, rx) = mpsc::channel();
+        let c = count.clone();
+        pool.spawn(async move {
+            join.await;
+            tx.send(c.load(SeqCst)).unwrap();
+        });
+        assert_eq!(rx.recv_timeout(Duration::from_secs(3)).unwrap(), 10);
+
+        // An empty batch resolves immediately, and dropping the join future
+        // doesn't cancel the batch.
+        let (tx, rx) = mpsc::channel();
+        let join = pool
+            .remote()
+            .spawn_many_join(Vec::<futures_timer::Delay>::new());
+        pool.spawn(async move {
+            join.await;
+            tx.send(()).unwrap();
+        });
+        rx.recv_timeout(Duration::from_secs(3)).unwrap();
+        let c = count.clone();
+        drop(pool.remote().spawn_many_join(vec![async move {
+            c.fetch_add(1, SeqCst);
+        }]));
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(count.load(SeqCst), 11);
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_wake_with_full_local_queue() {
+        let config = SchedConfig {
+            local_queue_capacity: 1,
+            ..Default::default()
+        };
+        let (remote, locals) = build_spawn(QueueType::SingleLevel, config);
+        let mut local = MockLocal {
+            runner: Default::default(),
+            remote,
+            locals,
+        };
+        let (res_tx, res_rx) = mpsc::channel();
+        let (waker_tx, waker_rx) = mpsc::sync_channel(10);
+        for i in 0..2 {
+            let (res_tx, waker_tx) = (res_tx.clone(), waker_tx.clone());
+            local.remote.spawn(async move {
+                WakeLater::new(waker_tx).await;
+                res_tx.send(i).unwrap();
+            });
+        }
+        local.handle_once();
+        local.handle_once();
+        let wakers: Vec<_> = waker_rx.try_iter().collect();
+        assert_eq!(wakers.len(), 2);
+
+        local.remote.spawn(async move {
+            for w in wakers {
+                w.wake();
+            }
+        });
+        local.handle_once();
+        // Only one task fits in the local queue, the other one falls back to
+        // the global queue.
+        assert_eq!(local.locals[0].len(), 1);
+        assert_eq!(local.remote.core.global_queue_len(), 1);
+        #[cfg(feature = "metrics")]
+        assert_eq!(local.remote.core.migration_count(), 1);
+        local.handle_once();
+        local.handle_once();
+        let mut res: Vec<_> = res_rx.try_iter().collect();
+        res.sort_unstable();
+        assert_eq!(res, [0, 1]);
+    }
+
     #[test]
     fn test_wake_by_self() {
         let mut local = MockLocal::default();
@@ -594,6 +1703,112 @@ mod tests {
         local.handle_once();
         assert_eq!(res_rx.recv().unwrap(), 1);
         assert_eq!(res_rx.recv().unwrap(), 2);
+        assert_eq!(local.remote.core.migration_count(), 0);
+    }
+
+    #[test]
+    fn test_spawn_urgent() {
+        let mut local = MockLocal::default();
+        let (res_tx, res_rx) = mpsc::channel();
+
+        let tx = res_tx.clone();
+        let fut = async move {
+            let tx1 = tx.clone();
+            LOCAL.with(|l| unsafe {
+                (*l.get()).spawn(async move {
+                    tx1.send(2).unwrap();
+                })
+            });
+            let tx2 = tx.clone();
+            spawn_urgent(async move {
+                tx2.send(1).unwrap();
+            });
+        };
+        local.remote.spawn(fut);
+
+        local.handle_once();
+        assert!(res_rx.try_recv().is_err());
+        local.handle_once();
+        assert_eq!(res_rx.recv().unwrap(), 1);
+        local.handle_once();
+        assert_eq!(res_rx.recv().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_spawn_with_extras() {
+        let (remote, mut locals) = build_spawn(QueueType::SingleLevel, Default::default());
+        let (res_tx, res_rx) = mpsc::channel();
+        let tx = res_tx.clone();
+        remote.spawn_with_extras(
+            async move { tx.send(1).unwrap() },
+            Extras::new_multilevel(42, Some(1)),
+        );
+        let tx = res_tx.clone();
+        remote.spawn_with_extras(async move { tx.send(2).unwrap() }, || {
+            Extras::new_multilevel(43, None)
+        });
+
+        // The extras of a task cell are replaced.
+        remote.spawn_with_extras(
+            TaskCell::new(
+                async move { res_tx.send(3).unwrap() },
+                Extras::single_level(),
+            ),
+            Extras::new_multilevel(44, Some(2)),
+        );
+
+        let mut runner = Runner::default();
+        for (id, level) in [(42, 1), (43, 0), (44, 2)] {
+            let t = locals[0].pop().unwrap().task_cell;
+            assert_eq!(t.extras().task_id(), id);
+            assert_eq!(t.extras().current_level(), level);
+            runner.handle(&mut locals[0], t);
+        }
+        for i in 1..=3 {
+            assert_eq!(res_rx.recv().unwrap(), i);
+        }
+    }
+
+    #[test]
+    fn test_spawn_with_extras_builder() {
+        use crate::queue::{ExtrasBuilder, TaskCell as _};
+
+        let (remote, mut locals) =
+            build_spawn(QueueType::StrictPriority { classes: 2 }, Default::default());
+        let (res_tx, res_rx) = mpsc::channel();
+        let tx = res_tx.clone();
+        remote.spawn_with_extras(
+            async move { tx.send(1).unwrap() },
+            ExtrasBuilder::single_level()
+                .task_id(1)
+                .priority_class(1)
+                .group(5),
+        );
+        let tx = res_tx.clone();
+        remote.spawn_with_extras(
+            async move { tx.send(2).unwrap() },
+            ExtrasBuilder::multilevel()
+                .task_id(2)
+                .fixed_level(2)
+                .no_steal(true)
+                .metadata(vec![7]),
+        );
+
+        // The task of the higher class is popped first.
+        let mut t = locals[0].pop().unwrap().task_cell;
+        let extras = t.mut_extras();
+        assert_eq!(extras.task_id(), 2);
+        assert_eq!(extras.current_level(), 2);
+        assert!(extras.no_steal());
+        assert_eq!(extras.metadata(), [7]);
+        let mut runner = Runner::default();
+        runner.handle(&mut locals[0], t);
+        assert_eq!(res_rx.recv().unwrap(), 2);
+
+        // Tasks of cancelled groups are dropped.
+        remote.cancel_group(5);
+        assert!(locals[0].pop().is_none());
+        assert!(res_rx.try_recv().is_err());
     }
 
     #[test]
@@ -620,6 +1835,107 @@ mod tests {
         rx2.recv().unwrap();
     }
 
+    #[test]
+    fn test_in_worker_thread() {
+        let pool = Builder::new("test_in_worker_thread")
+            .max_thread_count(1)
+            .build_future_pool();
+        let (tx, rx) = mpsc::channel();
+        pool.spawn(async move {
+            tx.send(in_worker_thread()).unwrap();
+        });
+        assert!(rx.recv().unwrap());
+        assert!(!in_worker_thread());
+    }
+
+    #[test]
+    fn test_current_task_level() {
+        assert_eq!(current_task_level(), None);
+
+        let pool = Builder::new("test_current_task_level")
+            .max_thread_count(1)
+            .build_future_pool();
+        let (tx, rx) = mpsc::channel();
+        pool.spawn(async move {
+            tx.send(current_task_level()).unwrap();
+        });
+        assert_eq!(rx.recv().unwrap(), None);
+
+        let pool = Builder::new("test_current_task_level")
+            .max_thread_count(1)
+            .build_multilevel_future_pool();
+        let (tx, rx) = mpsc::channel();
+        pool.spawn(async move {
+            tx.send(current_task_level()).unwrap();
+            // Runs longer than the level 0 threshold.
+            thread::sleep(Duration::from_millis(10));
+            futures_timer::Delay::new(Duration::from_millis(1)).await;
+            tx.send(current_task_level()).unwrap();
+        });
+        assert_eq!(rx.recv().unwrap(), Some(0));
+        assert_eq!(rx.recv().unwrap(), Some(1));
+    }
+
+    #[test]
+    fn test_level_elapsed() {
+        let pool = Builder::new("test_level_elapsed_single")
+            .max_thread_count(1)
+            .build_future_pool();
+        assert_eq!(pool.remote().level_elapsed(), None);
+
+        let pool = Builder::new("test_level_elapsed")
+            .max_thread_count(1)
+            .build_multilevel_future_pool();
+        let (tx, rx) = mpsc::channel();
+        for _ in 0..10 {
+            let tx = tx.clone();
+            pool.spawn(async move { tx.send(()).unwrap() });
+        }
+        // Runs through level 0 and 1 into level 2, and flushes the local
+        // metrics several times.
+        pool.spawn(async move {
+            for _ in 0..80 {
+                thread::sleep(Duration::from_millis(5));
+                futures_timer::Delay::new(Duration::from_millis(1)).await;
+            }
+            tx.send(()).unwrap();
+        });
+        for _ in 0..11 {
+            rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        // Metrics are flushed after the task is handled.
+        assert!(pool.wait_idle(Duration::from_secs(3)));
+        let elapsed = pool.remote().level_elapsed().unwrap();
+        assert!(elapsed[0] >= Duration::from_millis(5), "{:?}", elapsed);
+        assert!(elapsed[1] > elapsed[0], "{:?}", elapsed);
+        assert!(elapsed[2] > Duration::ZERO, "{:?}", elapsed);
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_spawn_timeout() {
+        let pool = Builder::new("test_spawn_timeout")
+            .max_thread_count(1)
+            .build_future_pool();
+        let remote = pool.remote();
+        let (tx, rx) = mpsc::channel::<()>();
+        let res = remote.spawn_timeout(Duration::from_millis(50), async move {
+            std::future::pending::<()>().await;
+            drop(tx);
+        });
+        assert_eq!(
+            res.recv_timeout(Duration::from_secs(5)).unwrap(),
+            Err(TimedOut)
+        );
+        // The future has been dropped.
+        assert!(rx.recv().is_err());
+
+        let res = remote.spawn_timeout(Duration::from_secs(60), async {
+            reschedule().await;
+        });
+        assert_eq!(res.recv_timeout(Duration::from_secs(5)).unwrap(), Ok(()));
+    }
+
     #[cfg_attr(not(feature = "failpoints"), ignore)]
     #[test]
     fn test_repoll_limit() {
@@ -644,11 +1960,42 @@ mod tests {
         assert_eq!(res_rx.recv().unwrap(), 2);
         assert_eq!(res_rx.recv().unwrap(), 3);
         assert!(res_rx.try_recv().is_err());
+        #[cfg(feature = "metrics")]
+        assert_eq!(local.remote.core.repoll_limit_reached_count(), 1);
 
         local.handle_once();
         assert_eq!(res_rx.recv().unwrap(), 4);
     }
 
+    #[test]
+    fn test_repoll_limit_override() {
+        for (limit, polls) in [(None, 6), (Some(0), 1), (Some(2), 3)] {
+            let mut local = MockLocal::default();
+            let (res_tx, res_rx) = mpsc::channel();
+            let mut extras = Extras::single_level();
+            if let Some(limit) = limit {
+                extras.set_repoll_limit(limit);
+            }
+            local.remote.spawn_with_extras(
+                async move {
+                    for _ in 0..20 {
+                        res_tx.send(()).unwrap();
+                        PendingOnce::new().await;
+                    }
+                },
+                extras,
+            );
+            // Another task is waiting, so the task yields once it reaches
+            // the limit.
+            local.remote.spawn(async {});
+
+            for _ in 0..2 {
+                local.handle_once();
+                assert_eq!(res_rx.try_iter().count(), polls, "{:?}", limit);
+            }
+        }
+    }
+
     #[cfg_attr(not(feature = "failpoints"), ignore)]
     #[test]
     fn test_reschedule() {
@@ -669,11 +2016,86 @@ mod tests {
         local.handle_once();
         assert_eq!(res_rx.recv().unwrap(), 1);
         assert!(res_rx.try_recv().is_err());
+        #[cfg(feature = "metrics")]
+        assert_eq!(local.remote.core.migration_count(), 1);
         local.handle_once();
         assert_eq!(res_rx.recv().unwrap(), 2);
         assert_eq!(res_rx.recv().unwrap(), 3);
     }
 
+    #[test]
+    fn test_reschedule_fifo() {
+        let mut local = MockLocal::default();
+        let (res_tx, res_rx) = mpsc::channel();
+
+        let tx = res_tx.clone();
+        local.remote.spawn(async move {
+            for i in 0..3 {
+                tx.send(100 + i).unwrap();
+                reschedule().await;
+            }
+        });
+        let spawn = |local: &MockLocal, i| {
+            let tx = res_tx.clone();
+            local.remote.spawn(async move { tx.send(i).unwrap() });
+        };
+        spawn(&local, 0);
+        spawn(&local, 1);
+        local.handle_once();
+        // Spawned after the rescheduled task, so it runs after the task.
+        spawn(&local, 2);
+        for _ in 0..5 {
+            local.handle_once();
+        }
+        let res: Vec<_> = res_rx.try_iter().collect();
+        assert_eq!(res, vec![100, 0, 1, 101, 2, 102]);
+    }
+
+    #[test]
+    fn test_woken_task_order() {
+        let cases = [
+            (WokenTaskOrder::Spawned, vec![2, 0, 1]),
+            (WokenTaskOrder::Front, vec![0, 2, 1]),
+            (WokenTaskOrder::Back, vec![2, 1, 0]),
+        ];
+        for (order, expected) in cases {
+            let config = SchedConfig {
+                woken_task_order: order,
+                ..Default::default()
+            };
+            let (remote, locals) = build_spawn(QueueType::SingleLevel, config);
+            let mut local = MockLocal {
+                runner: Default::default(),
+                remote,
+                locals,
+            };
+            let (res_tx, res_rx) = mpsc::channel();
+            let (waker_tx, waker_rx) = mpsc::sync_channel(10);
+            let tx = res_tx.clone();
+            local.remote.spawn(async move {
+                WakeLater::new(waker_tx).await;
+                tx.send(0).unwrap();
+            });
+            local.handle_once();
+            let waker = waker_rx.try_recv().unwrap();
+
+            // The future is woken up on the worker between two spawns.
+            let spawned = |i: i32| {
+                let tx = res_tx.clone();
+                async move { tx.send(i).unwrap() }
+            };
+            local.locals[0].spawn(spawned(1));
+            local.locals[0].spawn(async move { waker.wake() });
+            local.handle_once();
+            local.locals[0].spawn(spawned(2));
+            for _ in 0..3 {
+                local.handle_once();
+            }
+            let res: Vec<_> = res_rx.try_iter().collect();
+            assert_eq!(res, expected, "{:?}", order);
+        }
+    }
+
     #[cfg_attr(not(feature = "failpoints"), ignore)]
     #[test]
     fn test_no_preemptive_task() {
@@ -693,4 +2115,72 @@ mod tests {
         assert_eq!(res_rx.recv().unwrap(), 1);
         assert_eq!(res_rx.recv().unwrap(), 2);
     }
+    #[test]
+    fn test_spawn_eager() {
+        let mut local = MockLocal::default();
+        let (res_tx, res_rx) = mpsc::channel();
+
+        // A ready future runs in place and never touches the queue.
+        let tx = res_tx.clone();
+        local.remote.spawn_eager(async move {
+            assert!(!in_worker_thread());
+            // The task is published to allocator wrappers on this thread.
+            assert!(crate::pool::current_task_id().is_some());
+            tx.send(1).unwrap();
+        });
+        assert_eq!(res_rx.try_recv().unwrap(), 1);
+        assert_eq!(crate::pool::current_task_id(), None);
+        assert_eq!(local.remote.core.global_queue_len(), 0);
+
+        // A pending future is queued when it's woken up.
+        let (waker_tx, waker_rx) = mpsc::sync_channel(10);
+        let tx = res_tx.clone();
+        local.remote.spawn_eager(async move {
+            WakeLater::new(waker_tx).await;
+            tx.send(2).unwrap();
+        });
+        assert_eq!(local.remote.core.global_queue_len(), 0);
+        waker_rx.try_recv().unwrap().wake();
+        assert_eq!(local.remote.core.global_queue_len(), 1);
+        local.handle_once();
+        assert_eq!(res_rx.try_recv().unwrap(), 2);
+
+        // A future woken up during the first poll is queued right away, also
+        // when it's spawned by a running task.
+        let remote = local.remote.clone();
+        local.remote.spawn(async move {
+            let tx = res_tx.clone();
+            remote.spawn_eager(async move {
+                reschedule().await;
+                tx.send(3).unwrap();
+            });
+            res_tx.send(4).unwrap();
+        });
+        local.handle_once();
+        assert_eq!(res_rx.try_recv().unwrap(), 4);
+        assert_eq!(local.remote.core.global_queue_len(), 1);
+        local.handle_once();
+        assert_eq!(res_rx.try_recv().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_spawn_local() {
+        let mut local = MockLocal::default();
+        let (res_tx, res_rx) = mpsc::channel();
+        local.remote.spawn(async move {
+            let tx = res_tx.clone();
+            spawn(async move { tx.send(2).unwrap() });
+            res_tx.send(1).unwrap();
+        });
+        local.handle_once();
+        assert_eq!(res_rx.try_recv().unwrap(), 1);
+        // The child goes to the local queue directly.
+        assert_eq!(local.remote.core.global_queue_len(), 0);
+        assert_eq!(local.locals[0].len(), 1);
+        local.handle_once();
+        assert_eq!(res_rx.try_recv().unwrap(), 2);
+
+        let res = std::panic::catch_unwind(|| spawn(async {}));
+        assert!(res.is_err());
+    }
 }
@@ -0,0 +1,165 @@
+// Copyright 2024 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Utilities for testing runners and tasks deterministically.
+//!
+//! It's only available with the `test-util` feature.
+
+use crate::pool::{build_spawn, Local, Remote, Runner, SchedConfig};
+use crate::queue::{QueueType, TaskCell, WithExtras};
+
+/// A single threaded driver that handles tasks one by one on the current
+/// thread.
+///
+/// The driver owns one worker, all tasks spawned to it are handled by the
+/// given runner when `step` is called. No threads are spawned, so the order
+/// of handling tasks is deterministic.
+pub struct Driver<R: Runner> {
+    runner: R,
+    remote: Remote<R::TaskCell>,
+    local: Local<R::TaskCell>,
+}
+
+impl<R> Driver<R>
+where
+    R: Runner,
+    R::TaskCell: TaskCell + Send,
+{
+    /// Creates a driver with a single level queue.
+    pub fn new(runner: R) -> Driver<R> {
+        Driver::with_queue(QueueType::SingleLevel, runner)
+    }
+
+    /// Creates a driver with the given queue type. The runner is started
+    /// immediately.
+    pub fn with_queue(queue_type: impl Into<QueueType>, mut runner: R) -> Driver<R> {
+        let config = SchedConfig {
+            max_thread_count: 1,
+            ..Default::default()
+        };
+        let (remote, mut locals) = build_spawn(queue_type, config);
+        let mut local = locals.pop().unwrap();
+        runner.start(&mut local);
+        Driver {
+            runner,
+            remote,
+            local,
+        }
+    }
+
+    /// Spawns a task to the global queue.
+    pub fn spawn(&self, t: impl WithExtras<R::TaskCell>) {
+        self.remote.spawn(t);
+    }
+
+    /// Handles exactly one task. Returns false if there are no tasks.
+    pub fn step(&mut self) -> bool {
+        match self.local.pop() {
+            Some(t) => {
+                self.runner.handle(&mut self.local, t.task_cell);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Handles tasks until there are no tasks left. Returns the number of
+    /// handled tasks.
+    pub fn run_until_idle(&mut self) -> usize {
+        let mut steps = 0;
+        while self.step() {
+            steps += 1;
+        }
+        steps
+    }
+
+    /// Returns the number of tasks in the local queue of the worker.
+    pub fn local_len(&self) -> usize {
+        self.local.len()
+    }
+
+    /// Returns the number of tasks in the global queue.
+    pub fn global_len(&self) -> usize {
+        self.remote.core.global_queue_len()
+    }
+
+    /// Checks whether there are no tasks to handle.
+    pub fn is_idle(&self) -> bool {
+        self.local_len() == 0 && self.global_len() == 0
+    }
+
+    /// Gets the remote of the driver.
+    pub fn remote(&self) -> &Remote<R::TaskCell> {
+        &self.remote
+    }
+
+    /// Gets the local of the worker.
+    pub fn local(&mut self) -> &mut Local<R::TaskCell> {
+        &mut self.local
+    }
+
+    /// Gets the runner.
+    pub fn runner(&mut self) -> &mut R {
+        &mut self.runner
+    }
+}
+
+impl<R: Runner> Drop for Driver<R> {
+    fn drop(&mut self) {
+        self.runner.end(&mut self.local);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::{callback, future};
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_step() {
+        let mut driver = Driver::new(callback::Runner::default());
+        assert!(driver.is_idle());
+        assert!(!driver.step());
+
+        let (tx, rx) = mpsc::channel();
+        for i in 0..2 {
+            let tx = tx.clone();
+            driver.spawn(move |h: &mut callback::Handle<'_>| {
+                let tx2 = tx.clone();
+                h.spawn(move |_: &mut callback::Handle<'_>| tx2.send(i + 10).unwrap());
+                tx.send(i).unwrap();
+            });
+        }
+        assert_eq!(driver.global_len(), 2);
+        assert!(driver.step());
+        assert_eq!(rx.try_recv().unwrap(), 0);
+        assert!(rx.try_recv().is_err());
+        // Only the first task is stolen from the global queue, and the spawned
+        // task goes to the local queue, so it's handled before the other one.
+        assert_eq!(driver.local_len(), 1);
+        assert_eq!(driver.global_len(), 1);
+        assert!(driver.step());
+        assert_eq!(rx.try_recv().unwrap(), 10);
+        assert_eq!(driver.run_until_idle(), 2);
+        assert_eq!(rx.try_recv().unwrap(), 1);
+        assert_eq!(rx.try_recv().unwrap(), 11);
+        assert!(driver.is_idle());
+    }
+
+    #[test]
+    fn test_future() {
+        let mut driver = Driver::new(future::Runner::default());
+        let (tx, rx) = mpsc::channel();
+        driver.spawn(async move {
+            tx.send(1).unwrap();
+            future::reschedule().await;
+            tx.send(2).unwrap();
+        });
+        assert!(driver.step());
+        assert_eq!(rx.try_recv().unwrap(), 1);
+        // The rescheduled future is repolled in place within the same step.
+        assert_eq!(rx.try_recv().unwrap(), 2);
+        assert_eq!(driver.run_until_idle(), 0);
+        assert!(driver.is_idle());
+    }
+}
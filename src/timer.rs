@@ -0,0 +1,146 @@
+// Copyright 2024 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! A simple timer for waking up tasks at given instants.
+//!
+//! All timers share one background thread, which is spawned when the first
+//! timer is registered.
+
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::mem;
+use std::sync::{Condvar, Mutex};
+use std::task::Waker;
+use std::thread;
+use std::time::Instant;
+
+lazy_static! {
+    static ref TIMER: Timer = Timer::new();
+}
+
+struct TimerInner {
+    /// The wakers of timers ordered by their deadlines. The id breaks ties
+    /// between timers of the same deadline.
+    timers: BTreeMap<(Instant, u64), Waker>,
+    next_id: u64,
+    started: bool,
+}
+
+struct Timer {
+    inner: Mutex<TimerInner>,
+    cond: Condvar,
+}
+
+impl Timer {
+    fn new() -> Timer {
+        Timer {
+            inner: Mutex::new(TimerInner {
+                timers: BTreeMap::new(),
+                next_id: 0,
+                started: false,
+            }),
+            cond: Condvar::new(),
+        }
+    }
+
+    fn run(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let rest = inner.timers.split_off(&(now, u64::MAX));
+            let expired = mem::replace(&mut inner.timers, rest);
+            if !expired.is_empty() {
+                // Wakers may register timers again, so they are woken up
+                // without holding the lock.
+                drop(inner);
+                for waker in expired.into_values() {
+                    waker.wake();
+                }
+                inner = self.inner.lock().unwrap();
+                continue;
+            }
+            inner = match inner.timers.keys().next() {
+                Some((deadline, _)) => {
+                    let timeout = deadline.saturating_duration_since(now);
+                    self.cond.wait_timeout(inner, timeout).unwrap().0
+                }
+                None => self.cond.wait(inner).unwrap(),
+            };
+        }
+    }
+
+    fn register(&'static self, deadline: Instant, waker: Waker) -> TimerHandle {
+        let mut inner = self.inner.lock().unwrap();
+        let key = (deadline, inner.next_id);
+        inner.next_id += 1;
+        inner.timers.insert(key, waker);
+        if !inner.started {
+            inner.started = true;
+            thread::Builder::new()
+                .name("yatp-timer".to_owned())
+                .spawn(move || self.run())
+                .unwrap();
+        }
+        self.cond.notify_one();
+        TimerHandle { timer: self, key }
+    }
+
+    /// Returns the number of timers that are neither fired nor cancelled.
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.inner.lock().unwrap().timers.len()
+    }
+}
+
+/// A registered timer. The timer is cancelled when it's dropped.
+pub(crate) struct TimerHandle {
+    timer: &'static Timer,
+    key: (Instant, u64),
+}
+
+impl Drop for TimerHandle {
+    fn drop(&mut self) {
+        self.timer.inner.lock().unwrap().timers.remove(&self.key);
+    }
+}
+
+/// Wakes up `waker` at `deadline`.
+pub(crate) fn register(deadline: Instant, waker: Waker) -> TimerHandle {
+    TIMER.register(deadline, waker)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{self, Sender};
+    use std::sync::Arc;
+    use std::task::Wake;
+    use std::time::Duration;
+
+    struct ChannelWaker(Mutex<Sender<()>>);
+
+    impl Wake for ChannelWaker {
+        fn wake(self: Arc<Self>) {
+            let _ = self.0.lock().unwrap().send(());
+        }
+    }
+
+    #[test]
+    fn test_timer() {
+        let timer: &'static Timer = Box::leak(Box::new(Timer::new()));
+        let (tx, rx) = mpsc::channel();
+        let w = Waker::from(Arc::new(ChannelWaker(Mutex::new(tx))));
+
+        let now = Instant::now();
+        let _fired = timer.register(now + Duration::from_millis(10), w.clone());
+        let cancelled = timer.register(now + Duration::from_millis(20), w);
+        assert_eq!(timer.len(), 2);
+        drop(cancelled);
+        assert_eq!(timer.len(), 1);
+
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(now.elapsed() >= Duration::from_millis(10));
+        assert_eq!(timer.len(), 0);
+        // The cancelled timer never fires.
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+    }
+}